@@ -0,0 +1,82 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A source of time for a [`ClashCache`](super::ClashCache) or [`ClashMap`](crate::ClashMap), used to expire entries.
+pub trait Clock: Send + Sync {
+    /// Returns the current time. It must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system. This is the default clock of a cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when told to, so that tests can expire entries deterministically.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::cache::ManualClock;
+/// use clashmap::ClashCache;
+/// use std::sync::Arc;
+/// use std::time::Duration;
+///
+/// let clock = Arc::new(ManualClock::new());
+/// let sessions = ClashCache::new(100).with_clock(clock.clone());
+/// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+///
+/// clock.advance(Duration::from_secs(59));
+/// assert!(sessions.contains_key("alice"));
+/// clock.advance(Duration::from_secs(1));
+/// assert!(!sessions.contains_key("alice"));
+/// ```
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: AtomicU64,
+}
+
+impl ManualClock {
+    /// Creates a new clock, stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: AtomicU64::new(0),
+        }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, by: Duration) {
+        let nanos = u64::try_from(by.as_nanos()).unwrap_or(u64::MAX);
+        let _ = self
+            .elapsed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |elapsed| {
+                Some(elapsed.saturating_add(nanos))
+            });
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + Duration::from_nanos(self.elapsed.load(Ordering::Relaxed))
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
@@ -0,0 +1,920 @@
+//! A concurrent cache holding a bounded number of entries, which may expire.
+//!
+//! See [`ClashCache`].
+
+mod clock;
+mod shard;
+pub(crate) mod wheel;
+
+pub use clock::{Clock, ManualClock, SystemClock};
+
+use self::shard::{Budget, CacheShard, Lookup, Removed};
+use self::wheel::NEVER;
+use crate::default_shard_amount;
+use crate::lock::{RwLock, RwLockReadGuardDetached};
+use crate::mapref::multiple::RefMulti;
+use crate::mapref::one::{Ref, RefMut};
+use crate::sharded::ClashCollection;
+use crate::tableref;
+use core::fmt;
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::slice;
+use crossbeam_utils::CachePadded;
+use hashbrown::Equivalent;
+use std::collections::hash_map::RandomState;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How a [`ClashCache`] picks the entries to evict once a shard is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used entry.
+    ///
+    /// Reads only mark an entry as used. The eviction order is fixed up lazily when a shard is full,
+    /// by giving marked entries a second chance, in the manner of the CLOCK algorithm.
+    #[default]
+    Lru,
+    /// Evicts the least frequently used entry.
+    ///
+    /// Reads count the uses of an entry, up to 15. The counts decay as entries are passed over
+    /// for eviction, so entries that were popular a long time ago are eventually evicted.
+    Lfu,
+    /// W-TinyLFU admission.
+    ///
+    /// New entries go to a small LRU window holding 1% of the capacity. They are only admitted to
+    /// the main region if they were used more often than the entry they would replace. Use counts
+    /// are kept in a compact sketch, which also remembers keys that are not in the cache.
+    TinyLfu,
+}
+
+/// Why an entry was passed to the removal listener of a [`ClashCache`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalCause {
+    /// The entry was evicted to make room for another one.
+    Evicted,
+    /// The entry expired.
+    Expired,
+    /// The entry weighed more than its shard can hold, so it was never inserted.
+    Rejected,
+    /// The entry was removed by [`ClashCache::clear`].
+    Cleared,
+}
+
+type RemovalListener<K, V> = Box<dyn Fn(K, V, RemovalCause) + Send + Sync>;
+type Weigher<K, V> = Box<dyn Fn(&K, &V) -> usize + Send + Sync>;
+
+/// ClashCache is a concurrent map that holds at most a fixed number of entries.
+///
+/// The capacity is split evenly between the shards, and each shard evicts its own entries
+/// according to the [`EvictionPolicy`] once it is full. Reads update the eviction state with
+/// atomic operations under the shard's read lock, so there is no lock shared by the whole cache.
+///
+/// Since each shard is bounded on its own, the cache may start evicting before it is completely full
+/// if keys are not spread evenly across the shards.
+///
+/// By default every entry counts as one towards the capacity. With a weigher, see
+/// [`with_weigher`](ClashCache::with_weigher), the capacity is a budget for the total weight of the entries
+/// instead, which is shared by the shards.
+///
+/// Entries may also be given a time-to-live. Expired entries are treated as absent, and are removed
+/// lazily by the reads that find them and by the next write to their shard, or eagerly by
+/// [`purge_expired`](ClashCache::purge_expired). A cache made with [`unbounded`](ClashCache::unbounded)
+/// never evicts, so it can be used as a map whose entries expire.
+///
+/// Documentation mentioning locking behaviour acts in the reference frame of the calling thread.
+/// This means that it is safe to ignore it across multiple threads.
+pub struct ClashCache<K, V, S = RandomState> {
+    shards: ClashCollection<CacheShard<K, V>>,
+    hasher: S,
+    policy: EvictionPolicy,
+    max_capacity: usize,
+    listener: Option<RemovalListener<K, V>>,
+    weigher: Option<Weigher<K, V>>,
+    budget: Option<Arc<Budget>>,
+    clock: Box<dyn Clock>,
+    origin: Instant,
+}
+
+impl<K, V> ClashCache<K, V, RandomState> {
+    /// Creates a new ClashCache holding at most `max_capacity` entries, using the LRU policy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let cache = ClashCache::new(100);
+    /// cache.insert("Johnny", 21);
+    /// ```
+    pub fn new(max_capacity: usize) -> Self {
+        Self::with_policy(max_capacity, EvictionPolicy::default())
+    }
+
+    /// Creates a new ClashCache holding at most `max_capacity` entries, using the provided policy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::cache::EvictionPolicy;
+    /// use clashmap::ClashCache;
+    ///
+    /// let cache = ClashCache::with_policy(100, EvictionPolicy::TinyLfu);
+    /// cache.insert("Johnny", 21);
+    /// ```
+    pub fn with_policy(max_capacity: usize, policy: EvictionPolicy) -> Self {
+        Self::with_policy_and_hasher(max_capacity, policy, RandomState::default())
+    }
+
+    /// Creates a new ClashCache without a maximum capacity. Its entries are only removed
+    /// when they expire, or by the caller.
+    ///
+    /// It keeps no eviction state, so reads do not record uses, and the eviction policy has no effect.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = ClashCache::unbounded();
+    /// for i in 0..1000 {
+    ///     sessions.insert_with_ttl(i, "session", Duration::from_secs(60));
+    /// }
+    /// assert_eq!(sessions.len(), 1000);
+    /// ```
+    pub fn unbounded() -> Self {
+        Self::unbounded_with_hasher(RandomState::default())
+    }
+}
+
+impl<K, V, S> ClashCache<K, V, S> {
+    /// Creates a new ClashCache holding at most `max_capacity` entries, using the provided hasher.
+    pub fn with_hasher(max_capacity: usize, hasher: S) -> Self {
+        Self::with_policy_and_hasher(max_capacity, EvictionPolicy::default(), hasher)
+    }
+
+    /// Creates a new ClashCache without a maximum capacity, using the provided hasher.
+    ///
+    /// See [`unbounded`](ClashCache::unbounded).
+    pub fn unbounded_with_hasher(hasher: S) -> Self {
+        Self::with_hasher(usize::MAX, hasher)
+    }
+
+    /// Creates a new ClashCache without a maximum capacity, using the provided hasher and shard amount.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn unbounded_with_hasher_and_shard_amount(hasher: S, shard_amount: usize) -> Self {
+        Self::with_policy_and_hasher_and_shard_amount(
+            usize::MAX,
+            EvictionPolicy::default(),
+            hasher,
+            shard_amount,
+        )
+    }
+
+    /// Creates a new ClashCache holding at most `max_capacity` entries, using the provided policy and hasher.
+    pub fn with_policy_and_hasher(max_capacity: usize, policy: EvictionPolicy, hasher: S) -> Self {
+        let shard_amount = default_shard_amount().min(max_capacity_shards(max_capacity));
+        Self::with_policy_and_hasher_and_shard_amount(max_capacity, policy, hasher, shard_amount)
+    }
+
+    /// Creates a new ClashCache holding at most `max_capacity` entries, using the provided policy, hasher and shard amount.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::cache::EvictionPolicy;
+    /// use clashmap::ClashCache;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let cache = ClashCache::with_policy_and_hasher_and_shard_amount(64, EvictionPolicy::Lfu, s, 4);
+    /// cache.insert(2, 4);
+    /// ```
+    pub fn with_policy_and_hasher_and_shard_amount(
+        max_capacity: usize,
+        policy: EvictionPolicy,
+        hasher: S,
+        shard_amount: usize,
+    ) -> Self {
+        let mut idx = 0;
+        let shards = ClashCollection::with_shard_amount(shard_amount, || {
+            let capacity = if max_capacity == usize::MAX {
+                // splitting it would give every shard a capacity, and make it track uses for nothing
+                usize::MAX
+            } else {
+                max_capacity / shard_amount + usize::from(idx < max_capacity % shard_amount)
+            };
+            idx += 1;
+            CacheShard::new(capacity, policy)
+        });
+
+        Self {
+            shards,
+            hasher,
+            policy,
+            max_capacity,
+            listener: None,
+            weigher: None,
+            budget: None,
+            clock: Box::new(SystemClock),
+            origin: Instant::now(),
+        }
+    }
+
+    /// Sets a callback that receives the entries removed by the cache itself,
+    /// such as the ones evicted to make room for others.
+    ///
+    /// The listener is called after the shard lock is released, so it may access the cache.
+    /// Entries handed back to the caller, for example by [`remove`](ClashCache::remove)
+    /// or [`insert`](ClashCache::insert), are not passed to the listener.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let evicted = Arc::new(AtomicUsize::new(0));
+    /// let cache = ClashCache::new(4).with_removal_listener({
+    ///     let evicted = evicted.clone();
+    ///     move |_k, _v, _cause| {
+    ///         evicted.fetch_add(1, Ordering::Relaxed);
+    ///     }
+    /// });
+    ///
+    /// for i in 0..10 {
+    ///     cache.insert(i, i);
+    /// }
+    /// assert_eq!(cache.len() + evicted.load(Ordering::Relaxed), 10);
+    /// ```
+    pub fn with_removal_listener(
+        mut self,
+        listener: impl Fn(K, V, RemovalCause) + Send + Sync + 'static,
+    ) -> Self {
+        self.listener = Some(Box::new(listener));
+        self
+    }
+
+    /// Sets a function weighing each entry, making the capacity of the cache a budget
+    /// for the total weight of its entries rather than for their number.
+    /// This should be called before inserting any entries.
+    ///
+    /// Entries are weighed when they are inserted. Changes made through [`get_mut`](ClashCache::get_mut)
+    /// do not update their weight. An entry weighing more than the whole budget is rejected,
+    /// and passed to the removal listener with [`RemovalCause::Rejected`].
+    ///
+    /// The budget is shared by the shards rather than split between them, so an entry may weigh more than
+    /// the capacity divided by the number of shards. A shard may hold more than its share while the cache
+    /// is within budget. An insert that takes the cache over budget evicts from its own shard while that
+    /// shard holds more than its share, and then from the other shards, starting with the ones holding
+    /// more than their share. While other threads insert concurrently, the cache may briefly weigh
+    /// more than its budget.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let cache = ClashCache::new(1024).with_weigher(|_k: &u32, v: &Vec<u8>| v.len());
+    /// for i in 0..100 {
+    ///     cache.insert(i, vec![0; 100]);
+    /// }
+    /// assert!(cache.weight() <= 1024);
+    /// ```
+    pub fn with_weigher(
+        mut self,
+        weigher: impl Fn(&K, &V) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.weigher = Some(Box::new(weigher));
+
+        let budget = Arc::new(Budget::new(self.weight(), self.max_capacity));
+        for shard in self.shards.shards.iter_mut() {
+            shard.get_mut().set_budget(budget.clone());
+        }
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Weighs entries by the memory they use, as measured by [`typesize::TypeSize::get_size`].
+    /// This should be called before inserting any entries.
+    ///
+    /// Requires the `typesize` feature to be enabled.
+    #[cfg(feature = "typesize")]
+    pub fn with_typesize_weigher(self) -> Self
+    where
+        K: typesize::TypeSize,
+        V: typesize::TypeSize,
+    {
+        self.with_weigher(|k, v| k.get_size() + v.get_size())
+    }
+
+    fn weigh(&self, key: &K, value: &V) -> usize {
+        match &self.weigher {
+            Some(weigher) => weigher(key, value),
+            None => 1,
+        }
+    }
+
+    /// Sets the clock used to expire entries. This should be called before inserting any entries.
+    ///
+    /// See [`ManualClock`] for an example.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.origin = clock.now();
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Returns the current time, in nanoseconds since the origin of the clock.
+    fn now(&self) -> u64 {
+        let elapsed = self.clock.now().saturating_duration_since(self.origin);
+        u64::try_from(elapsed.as_nanos()).unwrap_or(NEVER - 1)
+    }
+
+    /// Returns the deadline of an entry inserted now with the given time-to-live.
+    fn deadline(&self, ttl: Duration) -> u64 {
+        let ttl = u64::try_from(ttl.as_nanos()).unwrap_or(NEVER);
+        self.now().saturating_add(ttl)
+    }
+
+    /// Returns a reference to the cache's [`BuildHasher`].
+    pub fn hasher(&self) -> &S {
+        &self.hasher
+    }
+
+    /// Returns the eviction policy of the cache.
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Returns the maximum number of entries the cache holds, or their maximum total weight if it has a weigher.
+    /// This is `usize::MAX` for an [`unbounded`](ClashCache::unbounded) cache.
+    pub fn max_capacity(&self) -> usize {
+        self.max_capacity
+    }
+
+    /// Fetches the total weight of the entries stored in the cache.
+    /// Without a weigher, this is the number of entries.
+    ///
+    /// This includes expired entries that were not removed yet.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the cache.
+    pub fn weight(&self) -> usize {
+        self.shards.shards.iter().map(|s| s.read().weight()).sum()
+    }
+
+    /// Fetches the total weight of the entries stored in each shard, in shard order.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the cache.
+    pub fn shard_weights(&self) -> Vec<usize> {
+        self.shards
+            .shards
+            .iter()
+            .map(|s| s.read().weight())
+            .collect()
+    }
+
+    /// Fetches the total number of entries stored in the cache.
+    ///
+    /// This includes expired entries that were not removed yet.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the cache.
+    pub fn len(&self) -> usize {
+        self.shards.shards.iter().map(|s| s.read().len()).sum()
+    }
+
+    /// Checks if the cache is empty or not.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the cache.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all entries in the cache, passing them to the removal listener.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    pub fn clear(&self) {
+        for shard in self.shards.shards.iter() {
+            let removed = shard.write().clear();
+            if let Some(listener) = &self.listener {
+                for (k, v) in removed {
+                    listener(k, v, RemovalCause::Cleared);
+                }
+            }
+        }
+    }
+
+    /// Removes every expired entry, passing them to the removal listener.
+    ///
+    /// Each shard is write-locked in turn, and its expired entries are found with a timing wheel,
+    /// without scanning the entries that have not expired.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    pub fn purge_expired(&self) {
+        for shard in self.shards.shards.iter() {
+            let mut removed = Removed::new();
+            shard.write().expire(|| self.now(), &mut removed);
+            self.notify(removed);
+        }
+    }
+
+    /// Iterates over the entries that have not expired, locking one shard at a time.
+    ///
+    /// Entries are not marked as used.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let cache = ClashCache::new(100);
+    /// cache.insert("hello", "world");
+    /// assert_eq!(cache.iter().count(), 1);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            shards: self.shards.shards.iter(),
+            current: None,
+            now: self.now(),
+        }
+    }
+
+    fn notify(&self, removed: Removed<K, V>) {
+        if let Some(listener) = &self.listener {
+            for (k, v, cause) in removed {
+                listener(k, v, cause);
+            }
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> ClashCache<K, V, S> {
+    fn hash_u64<T: Hash + ?Sized>(&self, item: &T) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Inserts a key and a value into the cache. Returns the old value associated with the key if there was one.
+    ///
+    /// If the shard of the key is full, an entry is evicted and passed to the removal listener.
+    /// With [`EvictionPolicy::TinyLfu`], that entry may be the one just inserted.
+    ///
+    /// The entry never expires, even if it replaces one that had a time-to-live.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let cache = ClashCache::new(100);
+    /// assert_eq!(cache.insert("Johnny", 21), None);
+    /// assert_eq!(cache.insert("Johnny", 22), Some(21));
+    /// ```
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self._insert(key, value, NEVER)
+    }
+
+    /// Inserts a key and a value into the cache, which expires after `ttl`.
+    /// Returns the old value associated with the key if there was one.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = ClashCache::new(100);
+    /// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+    /// assert!(sessions.contains_key("alice"));
+    /// ```
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V> {
+        self._insert(key, value, self.deadline(ttl))
+    }
+
+    fn _insert(&self, key: K, value: V, expires_at: u64) -> Option<V> {
+        let hash = self.hash_u64(&key);
+        let weight = self.weigh(&key, &value);
+        let mut removed = Removed::new();
+        let old = self.shards.get_write_shard(hash).insert(
+            hash,
+            key,
+            value,
+            weight,
+            expires_at,
+            || self.now(),
+            &mut removed,
+        );
+        self.notify(removed);
+
+        if let Some(budget) = &self.budget {
+            if budget.is_exceeded() {
+                self.evict_over_budget(budget, self.shards._determine_shard(hash as usize));
+            }
+        }
+        old
+    }
+
+    /// Makes room in the shards other than `inserted` once an insert into it took the cache over its budget,
+    /// first in the shards holding more than their share of it.
+    fn evict_over_budget(&self, budget: &Budget, inserted: usize) {
+        let shards = &self.shards.shards;
+        let others = (inserted + 1..shards.len()).chain(0..inserted);
+        for over_capacity in [true, false] {
+            for idx in others.clone() {
+                if !budget.is_exceeded() {
+                    return;
+                }
+                let mut removed = Removed::new();
+                shards[idx]
+                    .write()
+                    .evict_over_budget(over_capacity, &mut removed);
+                self.notify(removed);
+            }
+        }
+    }
+
+    /// Changes the time-to-live of an entry, counting from now. `None` means that the entry never expires.
+    /// Returns `false` if the cache holds no entry for the key, or if it has already expired.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = ClashCache::new(100);
+    /// sessions.insert("alice", 1);
+    /// assert!(sessions.set_expiry("alice", Some(Duration::from_secs(60))));
+    /// assert!(!sessions.set_expiry("bob", None));
+    /// ```
+    pub fn set_expiry<Q>(&self, key: &Q, ttl: Option<Duration>) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let expires_at = ttl.map_or(NEVER, |ttl| self.deadline(ttl));
+        let mut removed = Removed::new();
+        let found = self.shards.get_write_shard(hash).set_expiry(
+            hash,
+            expires_at,
+            || self.now(),
+            |k| key.equivalent(k),
+            &mut removed,
+        );
+        self.notify(removed);
+        found
+    }
+
+    /// Get an immutable reference to an entry in the cache, and mark it as used.
+    ///
+    /// An expired entry is removed and passed to the removal listener.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let youtubers = ClashCache::new(100);
+    /// youtubers.insert("Bosnian Bill", 457000);
+    /// assert_eq!(*youtubers.get(&"Bosnian Bill").unwrap(), 457000);
+    /// ```
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let shard = self.shards.get_read_shard(hash);
+        let mut expired = None;
+        let found =
+            shard.try_map(
+                |shard| match shard.get(hash, || self.now(), |k| key.equivalent(k)) {
+                    Lookup::Hit(_, kv) => Some(kv),
+                    Lookup::Expired(idx) => {
+                        expired = Some(idx);
+                        None
+                    }
+                    Lookup::Absent => None,
+                },
+            );
+        match found {
+            Ok(r) => Some(Ref::from(r)),
+            Err(shard) => {
+                drop(shard);
+                if let Some(idx) = expired {
+                    self.remove_expired(idx, hash, key);
+                }
+                None
+            }
+        }
+    }
+
+    /// Get a mutable reference to an entry in the cache, and mark it as used.
+    ///
+    /// An expired entry is removed and passed to the removal listener.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let class = ClashCache::new(100);
+    /// class.insert("Albin", 15);
+    /// *class.get_mut(&"Albin").unwrap() -= 1;
+    /// assert_eq!(*class.get(&"Albin").unwrap(), 14);
+    /// ```
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let mut removed = Removed::new();
+        let shard = self.shards.get_write_shard(hash);
+        match shard.try_map(|shard| {
+            shard.get_mut(hash, || self.now(), |k| key.equivalent(k), &mut removed)
+        }) {
+            Ok(r) => Some(RefMut::from(r)),
+            Err(shard) => {
+                drop(shard);
+                self.notify(removed);
+                None
+            }
+        }
+    }
+
+    /// Checks if the cache contains a specific key, without marking it as used.
+    ///
+    /// An expired entry is removed and passed to the removal listener.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let shard = self.shards.get_read_shard(hash);
+        let lookup = shard.lookup(hash, || self.now(), |k| key.equivalent(k));
+        let expired = match lookup {
+            Lookup::Hit(..) => return true,
+            Lookup::Expired(idx) => idx,
+            Lookup::Absent => return false,
+        };
+        drop(shard);
+        self.remove_expired(expired, hash, key);
+        false
+    }
+
+    /// Removes the entry at `idx` in the slab of the key's shard, after a read found it expired under the read lock.
+    fn remove_expired<Q>(&self, idx: usize, hash: u64, key: &Q)
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let mut removed = Removed::new();
+        self.shards.get_write_shard(hash).remove_expired(
+            idx,
+            hash,
+            || self.now(),
+            |k| key.equivalent(k),
+            &mut removed,
+        );
+        self.notify(removed);
+    }
+
+    /// Removes an entry from the cache, returning the key and value if they existed in the cache.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashCache;
+    ///
+    /// let soccer_team = ClashCache::new(100);
+    /// soccer_team.insert("Jack", "Goalie");
+    /// assert_eq!(soccer_team.remove("Jack").unwrap().1, "Goalie");
+    /// ```
+    pub fn remove<Q>(&self, key: &Q) -> Option<(K, V)>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let mut removed = Removed::new();
+        let old = self.shards.get_write_shard(hash).remove(
+            hash,
+            || self.now(),
+            |k| key.equivalent(k),
+            &mut removed,
+        );
+        self.notify(removed);
+        old
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug, S> fmt::Debug for ClashCache<K, V, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let now = self.now();
+        let mut pmap = f.debug_map();
+        for shard in self.shards.shards.iter() {
+            let shard = shard.read();
+            for (k, v) in shard.iter(now) {
+                pmap.entry(k, v);
+            }
+        }
+        pmap.finish()
+    }
+}
+
+/// Picks a shard amount that leaves room for at least one entry in every shard.
+fn max_capacity_shards(max_capacity: usize) -> usize {
+    if max_capacity < 4 {
+        2
+    } else {
+        1 << (usize::BITS - 1 - max_capacity.leading_zeros())
+    }
+}
+
+pub(crate) const NIL: usize = usize::MAX;
+
+type GuardIter<'a, K, V> = (Arc<RwLockReadGuardDetached<'a>>, shard::Iter<'a, K, V>);
+
+/// Iterator over a ClashCache yielding the entries that have not expired.
+pub struct Iter<'a, K, V> {
+    shards: slice::Iter<'a, CachePadded<RwLock<CacheShard<K, V>>>>,
+    current: Option<GuardIter<'a, K, V>>,
+    now: u64,
+}
+
+impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
+    type Item = RefMulti<'a, K, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((guard, iter)) = self.current.as_mut() {
+                if let Some(pair) = iter.next() {
+                    let r = tableref::multiple::RefMulti::new(guard.clone(), pair);
+                    return Some(RefMulti::new(r));
+                }
+            }
+
+            let guard = self.shards.next()?.read();
+
+            // SAFETY: we keep the guard alive with the shard iterator
+            let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(guard) };
+            self.current = Some((Arc::new(guard), shard.iter(self.now)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EvictionPolicy, ManualClock, RemovalCause};
+    use crate::ClashCache;
+    use std::collections::hash_map::RandomState;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_capacity_and_listener() {
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let cache = ClashCache::with_policy(16, EvictionPolicy::TinyLfu).with_removal_listener({
+            let removed = removed.clone();
+            move |k, _v, cause| removed.lock().unwrap().push((k, cause))
+        });
+
+        for i in 0..100 {
+            cache.insert(i, i);
+            assert!(cache.len() <= 16);
+        }
+        let len = cache.len();
+        assert_eq!(removed.lock().unwrap().len() + len, 100);
+
+        cache.clear();
+        let removed = removed.lock().unwrap();
+        assert_eq!(removed.len(), 100);
+        assert_eq!(
+            removed
+                .iter()
+                .filter(|(_, cause)| *cause == RemovalCause::Cleared)
+                .count(),
+            len
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let cache = ClashCache::new(1000)
+            .with_clock(clock.clone())
+            .with_removal_listener({
+                let expired = expired.clone();
+                move |k, _v, cause| {
+                    assert_eq!(cause, RemovalCause::Expired);
+                    expired.lock().unwrap().push(k);
+                }
+            });
+
+        for i in 0..100 {
+            cache.insert_with_ttl(i, i, Duration::from_secs(i + 1));
+        }
+        cache.insert(100, 100);
+        assert!(cache.set_expiry(&0, None));
+        assert!(cache.set_expiry(&100, Some(Duration::from_secs(30))));
+
+        clock.advance(Duration::from_secs(50));
+        assert!(cache.get(&1).is_none());
+        assert!(!cache.contains_key(&49));
+        assert!(cache.get(&50).is_some());
+        assert!(cache.get_mut(&10).is_none());
+        assert_eq!(cache.iter().count(), 51);
+        // the reads removed the expired entries they found
+        assert_eq!(expired.lock().unwrap().len(), 3);
+        assert_eq!(cache.len(), 98);
+
+        cache.purge_expired();
+        let mut expired = expired.lock().unwrap();
+        expired.sort();
+        assert_eq!(*expired, (1..50).chain([100]).collect::<Vec<_>>());
+        assert_eq!(cache.len(), 51);
+    }
+
+    #[test]
+    fn test_unbounded_ttl() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = ClashCache::unbounded_with_hasher_and_shard_amount(RandomState::new(), 4)
+            .with_clock(clock.clone());
+        assert_eq!(cache.max_capacity(), usize::MAX);
+        assert!(cache.shards.shards.iter().all(|s| s.read().is_unbounded()));
+
+        for i in 0..10_000 {
+            cache.insert_with_ttl(i, i, Duration::from_secs(i % 2 + 1));
+        }
+        assert_eq!(cache.len(), 10_000);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(cache.get(&0).is_none());
+        assert!(cache.get(&1).is_some());
+        cache.purge_expired();
+        assert_eq!(cache.len(), 5_000);
+    }
+
+    #[test]
+    fn test_weigher() {
+        let rejected = Arc::new(Mutex::new(Vec::new()));
+        let cache = ClashCache::with_policy_and_hasher_and_shard_amount(
+            400,
+            EvictionPolicy::Lru,
+            RandomState::new(),
+            4,
+        )
+        .with_weigher(|_k: &u32, v: &String| v.len())
+        .with_removal_listener({
+            let rejected = rejected.clone();
+            move |k, _v, cause| {
+                if cause == RemovalCause::Rejected {
+                    rejected.lock().unwrap().push(k);
+                }
+            }
+        });
+
+        for i in 0..100 {
+            cache.insert(i, "x".repeat(10));
+            assert!(cache.weight() <= 400);
+        }
+        assert_eq!(cache.weight(), cache.len() * 10);
+
+        // heavier than the share of a shard, but within the budget of the cache
+        cache.insert(1000, "x".repeat(300));
+        assert_eq!(cache.get(&1000).map(|v| v.len()), Some(300));
+        assert!(cache.weight() <= 400);
+        assert!(cache.shard_weights().iter().any(|&w| w >= 300));
+
+        for i in 100..200 {
+            cache.insert(i, "x".repeat(10));
+            assert!(cache.weight() <= 400);
+        }
+        assert_eq!(cache.weight(), cache.len() * 10);
+
+        cache.insert(0, "x".repeat(401));
+        assert!(!cache.contains_key(&0));
+        assert_eq!(*rejected.lock().unwrap(), vec![0]);
+    }
+}
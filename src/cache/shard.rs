@@ -0,0 +1,857 @@
+use super::wheel::{TimerWheel, NEVER};
+use super::{EvictionPolicy, RemovalCause, NIL};
+use core::mem;
+use core::slice;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use hashbrown::HashTable;
+use std::sync::Arc;
+
+const MAX_HITS: u8 = 15;
+
+/// Entries removed from a shard by the cache itself, to be passed to the removal listener.
+pub(crate) type Removed<K, V> = Vec<(K, V, RemovalCause)>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Region {
+    Window,
+    Main,
+}
+
+struct Node<K, V> {
+    pair: (K, V),
+    hash: u64,
+    weight: usize,
+    hits: AtomicU8,
+    region: Region,
+    prev: usize,
+    next: usize,
+    expires_at: u64,
+    timer_slot: usize,
+    timer_prev: usize,
+    timer_next: usize,
+}
+
+impl<K, V> Node<K, V> {
+    fn is_expired(&self, now: impl FnOnce() -> u64) -> bool {
+        self.expires_at != NEVER && self.expires_at <= now()
+    }
+}
+
+/// An intrusive doubly linked list over the nodes of a shard, most recently linked first.
+struct List {
+    head: usize,
+    tail: usize,
+    weight: usize,
+}
+
+impl List {
+    const fn new() -> Self {
+        Self {
+            head: NIL,
+            tail: NIL,
+            weight: 0,
+        }
+    }
+}
+
+/// The total weight of the entries of a cache with a weigher, shared by its shards.
+///
+/// With a budget, a shard may hold more than its share of the capacity while the whole cache is within it.
+pub(crate) struct Budget {
+    weight: AtomicUsize,
+    max: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(weight: usize, max: usize) -> Self {
+        Self {
+            weight: AtomicUsize::new(weight),
+            max,
+        }
+    }
+
+    /// Returns true if the entries of the cache weigh more than its capacity.
+    pub(crate) fn is_exceeded(&self) -> bool {
+        self.weight.load(Ordering::Relaxed) > self.max
+    }
+}
+
+/// The result of looking up a key in a shard.
+pub(crate) enum Lookup<'a, K, V> {
+    /// The entry for the key, and its index in the slab.
+    Hit(usize, &'a (K, V)),
+    /// The shard holds an entry for the key at this index in the slab, but it has expired.
+    Expired(usize),
+    Absent,
+}
+
+/// The entries of a single cache shard, along with their eviction and expiry state.
+///
+/// Nodes are kept in a slab and linked into the window or main list, and into the timing wheel, by index.
+/// The hash table only maps keys to slab indices.
+pub(crate) struct CacheShard<K, V> {
+    index: HashTable<usize>,
+    nodes: Vec<Option<Node<K, V>>>,
+    free: Vec<usize>,
+    window: List,
+    main: List,
+    wheel: TimerWheel,
+    weight: usize,
+    /// The share of the capacity of the cache this shard holds, or may exceed if it has a budget.
+    capacity: usize,
+    window_capacity: usize,
+    policy: EvictionPolicy,
+    sketch: Option<FrequencySketch>,
+    budget: Option<Arc<Budget>>,
+}
+
+fn node_hash<K, V>(nodes: &[Option<Node<K, V>>], idx: usize) -> u64 {
+    match &nodes[idx] {
+        Some(node) => node.hash,
+        None => unreachable!("cache index points to a free slot"),
+    }
+}
+
+impl<K, V> CacheShard<K, V> {
+    pub(crate) fn new(capacity: usize, policy: EvictionPolicy) -> Self {
+        let (window_capacity, sketch) = match policy {
+            EvictionPolicy::TinyLfu => ((capacity / 100).max(1), Some(FrequencySketch::new())),
+            EvictionPolicy::Lru | EvictionPolicy::Lfu => (0, None),
+        };
+
+        Self {
+            index: HashTable::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            window: List::new(),
+            main: List::new(),
+            wheel: TimerWheel::new(),
+            weight: 0,
+            capacity,
+            window_capacity,
+            policy,
+            sketch,
+            budget: None,
+        }
+    }
+
+    /// Shares the weight of the shard's entries with the other shards of the cache.
+    pub(crate) fn set_budget(&mut self, budget: Arc<Budget>) {
+        self.budget = Some(budget);
+    }
+
+    /// Returns true if entries should be evicted to bring the shard, or the cache if it has a budget,
+    /// back to its capacity.
+    fn is_over_budget(&self) -> bool {
+        match &self.budget {
+            Some(budget) => budget.is_exceeded(),
+            None => self.weight > self.capacity,
+        }
+    }
+
+    fn add_weight(&mut self, weight: usize) {
+        self.weight += weight;
+        if let Some(budget) = &self.budget {
+            budget.weight.fetch_add(weight, Ordering::Relaxed);
+        }
+    }
+
+    fn sub_weight(&mut self, weight: usize) {
+        self.weight -= weight;
+        if let Some(budget) = &self.budget {
+            budget.weight.fetch_sub(weight, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of entries in the shard, including expired ones that were not removed yet.
+    pub(crate) fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns the total weight of the entries in the shard, including expired ones that were not removed yet.
+    pub(crate) fn weight(&self) -> usize {
+        self.weight
+    }
+
+    /// Iterates over the entries that have not expired at `now`.
+    pub(crate) fn iter(&self, now: u64) -> Iter<'_, K, V> {
+        Iter {
+            nodes: self.nodes.iter(),
+            now,
+        }
+    }
+
+    fn node(&self, idx: usize) -> &Node<K, V> {
+        match &self.nodes[idx] {
+            Some(node) => node,
+            None => unreachable!("cache list points to a free slot"),
+        }
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut Node<K, V> {
+        match &mut self.nodes[idx] {
+            Some(node) => node,
+            None => unreachable!("cache list points to a free slot"),
+        }
+    }
+
+    fn list(&mut self, region: Region) -> &mut List {
+        match region {
+            Region::Window => &mut self.window,
+            Region::Main => &mut self.main,
+        }
+    }
+
+    fn find(&self, hash: u64, mut eq: impl FnMut(&K) -> bool) -> Option<usize> {
+        self.index
+            .find(hash, |&idx| eq(&self.node(idx).pair.0))
+            .copied()
+    }
+
+    /// Records a use of the key with this hash in the frequency sketch, whether or not it is present.
+    fn record(&self, hash: u64) {
+        if let Some(sketch) = &self.sketch {
+            sketch.increment(hash);
+        }
+    }
+
+    /// Returns true if the shard has no capacity, and so never evicts.
+    pub(crate) fn is_unbounded(&self) -> bool {
+        self.capacity == usize::MAX
+    }
+
+    /// Marks a node as used. This only needs a shared reference, so it can be done under a read lock.
+    fn touch(&self, node: &Node<K, V>) {
+        if self.is_unbounded() {
+            // an unbounded shard never evicts, so uses are never looked at
+            return;
+        }
+        match self.policy {
+            EvictionPolicy::Lfu => {
+                let _ = node
+                    .hits
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |hits| {
+                        (hits < MAX_HITS).then_some(hits + 1)
+                    });
+            }
+            EvictionPolicy::Lru | EvictionPolicy::TinyLfu => {
+                // avoid writing to the cache line if the node is already marked
+                if node.hits.load(Ordering::Relaxed) == 0 {
+                    node.hits.store(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// Looks up the key, and marks it as used if it is found and has not expired.
+    pub(crate) fn get(
+        &self,
+        hash: u64,
+        now: impl FnOnce() -> u64,
+        eq: impl FnMut(&K) -> bool,
+    ) -> Lookup<'_, K, V> {
+        self.record(hash);
+        let lookup = self.lookup(hash, now, eq);
+        if let Lookup::Hit(idx, _) = lookup {
+            self.touch(self.node(idx));
+        }
+        lookup
+    }
+
+    /// Like [`get`](Self::get), but also removes the entry if it has expired.
+    pub(crate) fn get_mut(
+        &mut self,
+        hash: u64,
+        now: impl FnOnce() -> u64,
+        eq: impl FnMut(&K) -> bool,
+        removed: &mut Removed<K, V>,
+    ) -> Option<&mut (K, V)> {
+        self.record(hash);
+        let idx = self.find(hash, eq)?;
+        if self.node(idx).is_expired(now) {
+            let (k, v) = self.remove_node(idx);
+            removed.push((k, v, RemovalCause::Expired));
+            return None;
+        }
+
+        self.touch(self.node(idx));
+        Some(&mut self.node_mut(idx).pair)
+    }
+
+    /// Looks up the key without marking it as used.
+    pub(crate) fn lookup(
+        &self,
+        hash: u64,
+        now: impl FnOnce() -> u64,
+        eq: impl FnMut(&K) -> bool,
+    ) -> Lookup<'_, K, V> {
+        let Some(idx) = self.find(hash, eq) else {
+            return Lookup::Absent;
+        };
+        let node = self.node(idx);
+        if node.is_expired(now) {
+            Lookup::Expired(idx)
+        } else {
+            Lookup::Hit(idx, &node.pair)
+        }
+    }
+
+    /// Removes the entry that a [`Lookup::Expired`] found at `idx`, if it is still there and
+    /// expired by `now`, pushing it onto `removed`.
+    ///
+    /// The slot is checked again, as the entry may have been replaced since it was looked up.
+    pub(crate) fn remove_expired(
+        &mut self,
+        idx: usize,
+        hash: u64,
+        now: impl FnOnce() -> u64,
+        mut eq: impl FnMut(&K) -> bool,
+        removed: &mut Removed<K, V>,
+    ) {
+        let still_expired = match &self.nodes[idx] {
+            Some(node) => node.hash == hash && eq(&node.pair.0) && node.is_expired(now),
+            None => false,
+        };
+        if still_expired {
+            let (k, v) = self.remove_node(idx);
+            removed.push((k, v, RemovalCause::Expired));
+        }
+    }
+
+    /// Inserts an entry expiring at `expires_at`, after removing the entries that expired by `now`.
+    /// The entries evicted to make room for it are pushed onto `removed`.
+    ///
+    /// An entry weighing more than the capacity of the shard, or of the cache if the shard has a budget,
+    /// is rejected, and replaces no other entry.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn insert(
+        &mut self,
+        hash: u64,
+        key: K,
+        value: V,
+        weight: usize,
+        expires_at: u64,
+        now: impl FnOnce() -> u64,
+        removed: &mut Removed<K, V>,
+    ) -> Option<V>
+    where
+        K: Eq,
+    {
+        self.expire(now, removed);
+        self.record(hash);
+        let existing = self.find(hash, |k| *k == key);
+
+        let max_weight = self
+            .budget
+            .as_ref()
+            .map_or(self.capacity, |budget| budget.max);
+        if weight > max_weight {
+            removed.push((key, value, RemovalCause::Rejected));
+            return existing.map(|idx| self.remove_node(idx).1);
+        }
+
+        if let Some(idx) = existing {
+            self.touch(self.node(idx));
+            self.set_expiry_node(idx, expires_at);
+            self.reweigh(idx, weight);
+            let old = mem::replace(&mut self.node_mut(idx).pair.1, value);
+            self.evict(removed);
+            return Some(old);
+        }
+
+        let node = Node {
+            pair: (key, value),
+            hash,
+            weight,
+            hits: AtomicU8::new(0),
+            region: Region::Main,
+            prev: NIL,
+            next: NIL,
+            expires_at: NEVER,
+            timer_slot: NIL,
+            timer_prev: NIL,
+            timer_next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+
+        let nodes = &self.nodes;
+        self.index
+            .insert_unique(hash, idx, |&idx| node_hash(nodes, idx));
+        self.add_weight(weight);
+        if let Some(sketch) = &mut self.sketch {
+            sketch.ensure_capacity(self.index.len());
+        }
+
+        match self.policy {
+            EvictionPolicy::TinyLfu => self.link(idx, Region::Window),
+            EvictionPolicy::Lru | EvictionPolicy::Lfu => self.link(idx, Region::Main),
+        }
+        self.set_expiry_node(idx, expires_at);
+        self.evict(removed);
+        None
+    }
+
+    /// Removes an entry that has not expired, after removing the entries that expired by `now`.
+    pub(crate) fn remove(
+        &mut self,
+        hash: u64,
+        now: impl FnOnce() -> u64,
+        eq: impl FnMut(&K) -> bool,
+        removed: &mut Removed<K, V>,
+    ) -> Option<(K, V)> {
+        self.expire(now, removed);
+        let idx = self.find(hash, eq)?;
+        Some(self.remove_node(idx))
+    }
+
+    /// Changes when an entry that has not expired yet expires. Returns `false` if there is no such entry.
+    pub(crate) fn set_expiry(
+        &mut self,
+        hash: u64,
+        expires_at: u64,
+        now: impl FnOnce() -> u64,
+        eq: impl FnMut(&K) -> bool,
+        removed: &mut Removed<K, V>,
+    ) -> bool {
+        self.expire(now, removed);
+        match self.find(hash, eq) {
+            Some(idx) => {
+                self.set_expiry_node(idx, expires_at);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes every entry, returning them.
+    pub(crate) fn clear(&mut self) -> Vec<(K, V)> {
+        self.index.clear();
+        self.free.clear();
+        self.window = List::new();
+        self.main = List::new();
+        self.wheel = TimerWheel::new();
+        self.sub_weight(self.weight);
+        self.nodes
+            .drain(..)
+            .flatten()
+            .map(|node| node.pair)
+            .collect()
+    }
+
+    /// Removes the entries that expired by `now`, pushing them onto `removed`.
+    ///
+    /// The time is only read if the shard holds entries that expire.
+    pub(crate) fn expire(&mut self, now: impl FnOnce() -> u64, removed: &mut Removed<K, V>) {
+        if self.wheel.len() == 0 {
+            return;
+        }
+
+        let now = now();
+        for slot in self.wheel.advance(now) {
+            let mut idx = self.wheel.head(slot);
+            let mut count = 0;
+            while idx != NIL {
+                idx = self.node(idx).timer_next;
+                count += 1;
+            }
+
+            let mut idx = self.wheel.take(slot, count);
+            while idx != NIL {
+                let node = self.node_mut(idx);
+                let next = node.timer_next;
+                node.timer_slot = NIL;
+
+                if node.expires_at <= now {
+                    let (k, v) = self.remove_node(idx);
+                    removed.push((k, v, RemovalCause::Expired));
+                } else {
+                    self.schedule(idx);
+                }
+                idx = next;
+            }
+        }
+    }
+
+    fn set_expiry_node(&mut self, idx: usize, expires_at: u64) {
+        self.unschedule(idx);
+        self.node_mut(idx).expires_at = expires_at;
+        if expires_at != NEVER {
+            self.schedule(idx);
+        }
+    }
+
+    fn schedule(&mut self, idx: usize) {
+        let slot = self.wheel.slot(self.node(idx).expires_at);
+        let next = self.wheel.push(slot, idx);
+        if next != NIL {
+            self.node_mut(next).timer_prev = idx;
+        }
+
+        let node = self.node_mut(idx);
+        node.timer_slot = slot;
+        node.timer_prev = NIL;
+        node.timer_next = next;
+    }
+
+    fn unschedule(&mut self, idx: usize) {
+        let node = self.node_mut(idx);
+        let (slot, prev, next) = (node.timer_slot, node.timer_prev, node.timer_next);
+        if slot == NIL {
+            return;
+        }
+        node.timer_slot = NIL;
+
+        if prev != NIL {
+            self.node_mut(prev).timer_next = next;
+        } else {
+            self.wheel.pop(slot, next);
+        }
+        if next != NIL {
+            self.node_mut(next).timer_prev = prev;
+        }
+    }
+
+    fn link(&mut self, idx: usize, region: Region) {
+        let head = self.list(region).head;
+        let node = self.node_mut(idx);
+        node.region = region;
+        node.prev = NIL;
+        node.next = head;
+
+        if head != NIL {
+            self.node_mut(head).prev = idx;
+        } else {
+            self.list(region).tail = idx;
+        }
+        let weight = self.node(idx).weight;
+        let list = self.list(region);
+        list.head = idx;
+        list.weight += weight;
+    }
+
+    fn unlink(&mut self, idx: usize) {
+        let node = self.node(idx);
+        let (region, prev, next, weight) = (node.region, node.prev, node.next, node.weight);
+
+        if prev != NIL {
+            self.node_mut(prev).next = next;
+        } else {
+            self.list(region).head = next;
+        }
+        if next != NIL {
+            self.node_mut(next).prev = prev;
+        } else {
+            self.list(region).tail = prev;
+        }
+        self.list(region).weight -= weight;
+    }
+
+    /// Changes the weight of a linked node.
+    fn reweigh(&mut self, idx: usize, weight: usize) {
+        let node = self.node_mut(idx);
+        let (region, old) = (node.region, mem::replace(&mut node.weight, weight));
+        self.add_weight(weight);
+        self.sub_weight(old);
+        let list = self.list(region);
+        list.weight = list.weight - old + weight;
+    }
+
+    fn remove_node(&mut self, idx: usize) -> (K, V) {
+        self.unlink(idx);
+        self.unschedule(idx);
+        self.sub_weight(self.node(idx).weight);
+        let hash = self.node(idx).hash;
+        if let Ok(entry) = self.index.find_entry(hash, |&other| other == idx) {
+            entry.remove();
+        }
+
+        self.free.push(idx);
+        match self.nodes[idx].take() {
+            Some(node) => node.pair,
+            None => unreachable!("cache list points to a free slot"),
+        }
+    }
+
+    /// Picks the next entry to evict from a list, giving a second chance to the entries used since the last pass.
+    ///
+    /// The list must not be empty.
+    fn victim(&mut self, region: Region) -> usize {
+        loop {
+            let idx = self.list(region).tail;
+            let policy = self.policy;
+            let hits = self.node_mut(idx).hits.get_mut();
+            if *hits == 0 {
+                return idx;
+            }
+
+            *hits = match policy {
+                EvictionPolicy::Lfu => *hits - 1,
+                EvictionPolicy::Lru | EvictionPolicy::TinyLfu => 0,
+            };
+            self.unlink(idx);
+            self.link(idx, region);
+        }
+    }
+
+    /// Evicts entries after an insert, while the shard holds more than its capacity.
+    ///
+    /// With a budget, the shard only evicts while the cache is over it, and keeps its last entry,
+    /// which may be the one just inserted. The cache then makes room in the other shards.
+    fn evict(&mut self, removed: &mut Removed<K, V>) {
+        while self.weight > self.capacity && self.index.len() > 1 && self.is_over_budget() {
+            self.evict_one(removed);
+        }
+
+        // Below capacity, entries leaving the window are admitted without a contest.
+        while self.window.weight > self.window_capacity {
+            let idx = self.window.tail;
+            self.unlink(idx);
+            self.link(idx, Region::Main);
+        }
+    }
+
+    /// Evicts entries while the cache is over its budget, and the shard holds entries.
+    /// If `over_capacity` is set, only while the shard also holds more than its capacity.
+    pub(crate) fn evict_over_budget(&mut self, over_capacity: bool, removed: &mut Removed<K, V>) {
+        while !self.index.is_empty()
+            && (!over_capacity || self.weight > self.capacity)
+            && self.is_over_budget()
+        {
+            self.evict_one(removed);
+        }
+    }
+
+    /// Evicts the entry picked by the policy. The shard must not be empty.
+    fn evict_one(&mut self, removed: &mut Removed<K, V>) {
+        let idx = match self.policy {
+            EvictionPolicy::TinyLfu => self.admission_victim(),
+            EvictionPolicy::Lru | EvictionPolicy::Lfu => self.victim(Region::Main),
+        };
+        let (k, v) = self.remove_node(idx);
+        removed.push((k, v, RemovalCause::Evicted));
+    }
+
+    /// Picks the entry to evict under W-TinyLFU: either the entry leaving the window,
+    /// or the main region's victim if the entry leaving the window was used more often.
+    fn admission_victim(&mut self) -> usize {
+        if self.window.weight <= self.window_capacity && self.main.head != NIL {
+            return self.victim(Region::Main);
+        }
+
+        let candidate = self.victim(Region::Window);
+        if self.main.head == NIL {
+            return candidate;
+        }
+
+        let victim = self.victim(Region::Main);
+        let admit = match &self.sketch {
+            Some(sketch) => {
+                sketch.frequency(self.node(candidate).hash)
+                    > sketch.frequency(self.node(victim).hash)
+            }
+            None => false,
+        };
+
+        if admit {
+            self.unlink(candidate);
+            self.link(candidate, Region::Main);
+            victim
+        } else {
+            candidate
+        }
+    }
+}
+
+/// Iterator over the entries of a shard that have not expired.
+pub(crate) struct Iter<'a, K, V> {
+    nodes: slice::Iter<'a, Option<Node<K, V>>>,
+    now: u64,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = &'a (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let now = self.now;
+        self.nodes
+            .by_ref()
+            .flatten()
+            .find(|node| !node.is_expired(|| now))
+            .map(|node| &node.pair)
+    }
+}
+
+/// A count-min sketch of 4-bit counters, estimating how often each hash was used recently.
+///
+/// All counters are halved once enough uses are recorded, so that old uses fade away.
+/// The sketch starts small, and grows along with the number of entries in its shard.
+struct FrequencySketch {
+    counters: Box<[AtomicU8]>,
+    mask: usize,
+    additions: AtomicUsize,
+    sample_size: usize,
+}
+
+const SKETCH_SEEDS: [u64; 4] = [
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+    0xcbf2_9ce4_8422_2325,
+];
+
+impl FrequencySketch {
+    fn new() -> Self {
+        Self::with_width(64)
+    }
+
+    fn with_width(width: usize) -> Self {
+        Self {
+            counters: (0..width * SKETCH_SEEDS.len())
+                .map(|_| AtomicU8::new(0))
+                .collect(),
+            mask: width - 1,
+            additions: AtomicUsize::new(0),
+            sample_size: width * 10,
+        }
+    }
+
+    /// Grows the sketch to track about `len` keys. The counts are reset when it grows.
+    fn ensure_capacity(&mut self, len: usize) {
+        if len > self.mask + 1 {
+            *self = Self::with_width(len.next_power_of_two());
+        }
+    }
+
+    fn counters(&self, hash: u64) -> impl Iterator<Item = &AtomicU8> {
+        SKETCH_SEEDS.iter().enumerate().map(move |(row, seed)| {
+            let mixed = hash.wrapping_add(*seed).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+            let col = (mixed >> 32) as usize & self.mask;
+            &self.counters[row * (self.mask + 1) + col]
+        })
+    }
+
+    fn increment(&self, hash: u64) {
+        for counter in self.counters(hash) {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < MAX_HITS).then_some(count + 1)
+            });
+        }
+
+        if self.additions.fetch_add(1, Ordering::Relaxed) + 1 == self.sample_size {
+            self.additions.store(0, Ordering::Relaxed);
+            for counter in self.counters.iter() {
+                counter.store(counter.load(Ordering::Relaxed) / 2, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn frequency(&self, hash: u64) -> u8 {
+        self.counters(hash)
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wheel::NEVER;
+    use super::{CacheShard, Lookup, Removed};
+    use crate::cache::{EvictionPolicy, RemovalCause};
+
+    const MS: u64 = 1_000_000;
+
+    fn fill(shard: &mut CacheShard<u64, u64>, keys: impl IntoIterator<Item = u64>) -> Vec<u64> {
+        let mut removed = Removed::new();
+        for k in keys {
+            shard.insert(k, k, k, 1, NEVER, || 0, &mut removed);
+        }
+        removed.into_iter().map(|(k, _, _)| k).collect()
+    }
+
+    fn get(shard: &CacheShard<u64, u64>, k: u64) -> bool {
+        matches!(shard.get(k, || 0, |other| *other == k), Lookup::Hit(..))
+    }
+
+    #[test]
+    fn test_lru_keeps_recently_used() {
+        let mut shard = CacheShard::new(3, EvictionPolicy::Lru);
+        assert!(fill(&mut shard, 0..3).is_empty());
+
+        assert!(get(&shard, 0));
+        assert_eq!(fill(&mut shard, [3]), vec![1]);
+        assert_eq!(fill(&mut shard, [4]), vec![2]);
+        assert!(get(&shard, 0));
+    }
+
+    #[test]
+    fn test_lfu_keeps_frequently_used() {
+        let mut shard = CacheShard::new(3, EvictionPolicy::Lfu);
+        fill(&mut shard, 0..3);
+
+        for _ in 0..3 {
+            assert!(get(&shard, 0));
+        }
+        assert!(get(&shard, 1));
+
+        assert_eq!(fill(&mut shard, [3]), vec![2]);
+        assert_eq!(fill(&mut shard, [4]), vec![3]);
+        assert!(get(&shard, 0) && get(&shard, 1));
+    }
+
+    #[test]
+    fn test_tiny_lfu_rejects_one_hit_wonders() {
+        let mut shard = CacheShard::new(10, EvictionPolicy::TinyLfu);
+        fill(&mut shard, 0..10);
+        for _ in 0..20 {
+            for k in 0..10 {
+                assert!(get(&shard, k));
+            }
+        }
+
+        // a scan of keys used only once does not flush the popular ones
+        let evicted = fill(&mut shard, 100..200);
+        assert_eq!(evicted.len(), 100);
+        assert_eq!((0..10).filter(|&k| get(&shard, k)).count(), 9);
+    }
+
+    #[test]
+    fn test_expire() {
+        let mut shard = CacheShard::new(1000, EvictionPolicy::Lru);
+        let mut removed = Removed::new();
+        for k in 0..500 {
+            shard.insert(k, k, k, 1, k * 10 * MS, || 0, &mut removed);
+        }
+        shard.set_expiry(100, NEVER, || 0, |k| *k == 100, &mut removed);
+
+        let now = 2000 * MS;
+        assert!(matches!(
+            shard.get(200, || now, |k| *k == 200),
+            Lookup::Expired(_)
+        ));
+        assert!(matches!(
+            shard.get(201, || now, |k| *k == 201),
+            Lookup::Hit(..)
+        ));
+        assert_eq!(shard.iter(now).count(), 300);
+
+        shard.expire(|| now, &mut removed);
+        assert_eq!(removed.len(), 200);
+        assert!(removed
+            .iter()
+            .all(|&(k, _, cause)| { k <= 200 && k != 100 && cause == RemovalCause::Expired }));
+        assert_eq!(shard.len(), 300);
+
+        shard.expire(|| u64::MAX - 1, &mut removed);
+        assert_eq!(shard.len(), 1);
+    }
+}
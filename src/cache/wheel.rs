@@ -0,0 +1,121 @@
+//! A hierarchical timing wheel, ordering the expiry of the entries in a cache shard,
+//! or of the keys of a map that were given a time-to-live.
+//!
+//! Times are nanoseconds since the cache's or map's origin. Level 0 has one slot per tick of 2^20ns,
+//! about a millisecond, and every level above it has slots 64 times as wide. Timers are kept in
+//! the slot of the highest level at which their deadline differs from the current time. As time
+//! advances, the slots that were passed over are handed back to their owner, which expires their
+//! timers or reschedules them into lower levels.
+
+use super::NIL;
+
+/// The deadline of entries that never expire.
+pub(crate) const NEVER: u64 = u64::MAX;
+
+const LEVELS: usize = 6;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const TICK_BITS: u32 = 20;
+
+#[derive(Clone)]
+pub(crate) struct TimerWheel {
+    heads: Box<[usize]>,
+    time: u64,
+    len: usize,
+}
+
+impl TimerWheel {
+    pub(crate) fn new() -> Self {
+        Self {
+            heads: vec![NIL; LEVELS * SLOTS].into_boxed_slice(),
+            time: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the number of scheduled timers.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn head(&self, slot: usize) -> usize {
+        self.heads[slot]
+    }
+
+    /// Links a timer at the head of a slot, returning the previous head.
+    pub(crate) fn push(&mut self, slot: usize, idx: usize) -> usize {
+        self.len += 1;
+        core::mem::replace(&mut self.heads[slot], idx)
+    }
+
+    /// Unlinks the head of a slot, replacing it with `next`.
+    pub(crate) fn pop(&mut self, slot: usize, next: usize) {
+        self.len -= 1;
+        self.heads[slot] = next;
+    }
+
+    /// Unlinks every timer of a slot at once, returning its former head.
+    pub(crate) fn take(&mut self, slot: usize, count: usize) -> usize {
+        self.len -= count;
+        core::mem::replace(&mut self.heads[slot], NIL)
+    }
+
+    /// Returns the slot that a timer for `deadline` belongs in, relative to the current time.
+    ///
+    /// Deadlines that have already passed are put in the current slot of level 0.
+    pub(crate) fn slot(&self, deadline: u64) -> usize {
+        let deadline = deadline.max(self.time);
+        let diff = (deadline ^ self.time) >> TICK_BITS;
+        let level = match diff {
+            0 => 0,
+            diff => ((u64::BITS - 1 - diff.leading_zeros()) / SLOT_BITS) as usize,
+        };
+        // Deadlines beyond the last level wrap around it, and are rescheduled when their slot comes up.
+        let level = level.min(LEVELS - 1);
+
+        let tick = deadline >> (TICK_BITS + SLOT_BITS * level as u32);
+        level * SLOTS + (tick as usize & (SLOTS - 1))
+    }
+
+    /// Advances the current time to `now`, returning the slots whose timers may have become due.
+    ///
+    /// Every timer in those slots must be taken out, and then either expired or rescheduled.
+    pub(crate) fn advance(&mut self, now: u64) -> Vec<usize> {
+        let now = now.max(self.time);
+        let mut due = Vec::new();
+        for level in 0..LEVELS {
+            let shift = TICK_BITS + SLOT_BITS * level as u32;
+            let ticks = (self.time >> shift)..=(now >> shift);
+            due.extend(
+                ticks
+                    .take(SLOTS)
+                    .map(|tick| level * SLOTS + (tick as usize & (SLOTS - 1))),
+            );
+        }
+
+        self.time = now;
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TimerWheel, SLOTS, TICK_BITS};
+
+    #[test]
+    fn test_slot_levels() {
+        let mut wheel = TimerWheel::new();
+        let tick = 1 << TICK_BITS;
+
+        assert_eq!(wheel.slot(0), 0);
+        assert_eq!(wheel.slot(5 * tick), 5);
+        assert_eq!(wheel.slot(64 * tick), SLOTS + 1);
+        assert_eq!(wheel.slot(u64::MAX - 1) / SLOTS, 5);
+
+        // a deadline in a later slot of a higher level is found once time reaches that slot
+        let slot = wheel.slot(130 * tick);
+        assert!(!wheel.advance(100 * tick).contains(&slot));
+        assert!(wheel.advance(130 * tick).contains(&slot));
+        assert_eq!(wheel.slot(130 * tick), 130 % SLOTS);
+    }
+}
@@ -0,0 +1,320 @@
+//! Detection of re-entrant shard locking.
+//!
+//! Requires the `deadlock-detection` feature to be enabled.
+//!
+//! Every thread keeps a list of the [`RawRwLock`](crate::lock::RawRwLock)s that it holds.
+//! When a thread would block on a shard that it already holds in a conflicting mode,
+//! it can never make progress, so we panic instead of hanging forever.
+//!
+//! Guards may be sent to another thread before being dropped. That thread cannot reach
+//! the list of the thread that acquired the lock, so it moves the lock on to a new epoch
+//! instead, and records from an older epoch are no longer trusted. A lock has one epoch
+//! for its read holders and one for its write and upgradable holders. There is only ever
+//! one write or upgradable holder, so no record of another guard is ever lost. A read
+//! guard released on another thread stops the read records taken before it from being
+//! reported, until the lock is read again.
+
+use crate::lock::Mode;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::backtrace::Backtrace;
+use std::thread;
+
+const UNKNOWN_SHARD: usize = usize::MAX;
+
+/// A lock held by the current thread.
+struct Held {
+    /// The address of the lock's [`Holders`].
+    lock: usize,
+    mode: Mode,
+    /// The epoch of the lock for `mode` when it was acquired.
+    epoch: usize,
+}
+
+thread_local! {
+    static HELD: RefCell<Vec<Held>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Runs `f` on the locks held by the current thread.
+///
+/// Returns `None` if the thread is exiting and its list was already destroyed.
+fn held<R>(f: impl FnOnce(&mut Vec<Held>) -> R) -> Option<R> {
+    HELD.try_with(|held| f(&mut held.borrow_mut())).ok()
+}
+
+/// What a single lock tracks of its holders.
+pub(crate) struct Holders {
+    shard: AtomicUsize,
+    read_epoch: AtomicUsize,
+    write_epoch: AtomicUsize,
+}
+
+impl Holders {
+    pub(crate) const fn new() -> Self {
+        Self {
+            shard: AtomicUsize::new(UNKNOWN_SHARD),
+            read_epoch: AtomicUsize::new(0),
+            write_epoch: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the index of the shard that this lock guards.
+    pub(crate) fn set_shard(&self, shard: usize) {
+        self.shard.store(shard, Ordering::Relaxed);
+    }
+
+    fn addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn epoch(&self, mode: Mode) -> &AtomicUsize {
+        match mode {
+            Mode::Shared => &self.read_epoch,
+            Mode::Upgradable | Mode::Exclusive => &self.write_epoch,
+        }
+    }
+
+    /// Whether `record` is of this lock, and was not released by another thread since.
+    fn holds(&self, record: &Held) -> bool {
+        record.lock == self.addr()
+            && record.epoch == self.epoch(record.mode).load(Ordering::Relaxed)
+    }
+
+    /// Panics if the current thread already holds this lock in a mode that conflicts with `mode`.
+    pub(crate) fn check(&self, mode: Mode) {
+        let held = held(|held| {
+            held.iter()
+                .filter(|record| self.holds(record))
+                .map(|record| record.mode)
+                .find(|&held| conflicts(held, mode))
+        });
+
+        if let Some(Some(held)) = held {
+            self.deadlock(
+                &format!("acquire a {} lock on {}", mode.name(), self.shard_name()),
+                held,
+            );
+        }
+    }
+
+    /// Panics if the current thread holds read locks besides the upgradable lock it is about to upgrade.
+    pub(crate) fn check_upgrade(&self) {
+        let reading = held(|held| {
+            held.iter()
+                .any(|record| record.mode == Mode::Shared && self.holds(record))
+        });
+
+        if reading == Some(true) {
+            self.deadlock(
+                &format!("upgrade its lock on {}", self.shard_name()),
+                Mode::Shared,
+            );
+        }
+    }
+
+    fn shard_name(&self) -> String {
+        match self.shard.load(Ordering::Relaxed) {
+            UNKNOWN_SHARD => String::from("a shard"),
+            idx => format!("shard {idx}"),
+        }
+    }
+
+    fn deadlock(&self, action: &str, held: Mode) -> ! {
+        panic!(
+            "deadlock detected: thread {:?} tried to {action} while already holding a {} lock on it\n\
+             call site:\n{}",
+            thread::current().name().unwrap_or("<unnamed>"),
+            held.name(),
+            Backtrace::force_capture(),
+        );
+    }
+
+    /// Records that the current thread acquired this lock.
+    pub(crate) fn acquired(&self, mode: Mode) {
+        let record = Held {
+            lock: self.addr(),
+            mode,
+            epoch: self.epoch(mode).load(Ordering::Relaxed),
+        };
+        held(|held| held.push(record));
+    }
+
+    /// Records that a holder released this lock.
+    ///
+    /// Must be called before the lock is actually released, so that the record
+    /// cannot be confused with one from the next holder.
+    pub(crate) fn released(&self, mode: Mode) {
+        let found = held(|held| {
+            // Records left behind by guards that another thread released are dropped here.
+            held.retain(|record| record.lock != self.addr() || self.holds(record));
+            let pos = held
+                .iter()
+                .rposition(|record| record.lock == self.addr() && record.mode == mode)?;
+            held.swap_remove(pos);
+            Some(())
+        });
+
+        if found.flatten().is_none() {
+            // The guard was acquired by another thread.
+            self.epoch(mode).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the holder of this lock changed it from `from` to `to`,
+    /// by upgrading or downgrading it.
+    pub(crate) fn changed(&self, from: Mode, to: Mode) {
+        let epoch = self.epoch(to).load(Ordering::Relaxed);
+        let found = held(|held| {
+            let record = held
+                .iter_mut()
+                .rev()
+                .find(|record| record.mode == from && self.holds(record))?;
+            record.mode = to;
+            record.epoch = epoch;
+            Some(())
+        });
+
+        if found.flatten().is_none() {
+            // The guard was acquired by another thread, which still has a record of it as `from`.
+            self.epoch(from).fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether a thread holding the lock as `held` would block when trying to lock it as `wanted`.
+fn conflicts(held: Mode, wanted: Mode) -> bool {
+    match wanted {
+        Mode::Shared => held == Mode::Exclusive,
+        Mode::Upgradable => held != Mode::Shared,
+        Mode::Exclusive => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ClashMap, RawRwLock};
+    use std::collections::hash_map::RandomState;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    #[should_panic = "deadlock detected"]
+    fn test_insert_while_reading() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let _r = map.get(&1);
+        map.insert(1, 2);
+    }
+
+    #[test]
+    #[should_panic = "tried to acquire a read lock on shard"]
+    fn test_get_while_writing() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let _r = map.get_mut(&1);
+        map.get(&1);
+    }
+
+    #[test]
+    #[should_panic = "tried to upgrade its lock on shard"]
+    fn test_upgrade_while_reading() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let u = map.get_upgradable(&1).unwrap();
+        let _r = map.get(&1);
+        u.upgrade();
+    }
+
+    #[test]
+    #[should_panic = "tried to acquire a read lock on shard"]
+    fn test_get_while_writing_after_reshard() {
+        let mut map = ClashMap::with_shard_amount(4);
+        map.insert(1, 1);
+        map.reshard(16);
+
+        let _r = map.get_mut(&1);
+        map.get(&1);
+    }
+
+    #[test]
+    #[should_panic = "tried to acquire a read lock on shard"]
+    fn test_get_while_writing_with_lock_parameter() {
+        let map: ClashMap<i32, i32, RandomState, RawRwLock> =
+            ClashMap::with_capacity_and_hasher_and_shard_amount_and_lock(0, RandomState::new(), 4);
+        map.insert(1, 1);
+
+        let _r = map.get_mut(&1);
+        map.get(&1);
+    }
+
+    #[test]
+    fn test_nested_reads() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let a = map.get(&1).unwrap();
+        let b = map.get(&1).unwrap();
+        assert_eq!(*a, *b);
+        drop((a, b));
+
+        let r = map.get_mut(&1).unwrap().downgrade();
+        assert_eq!(*map.get(&1).unwrap(), *r);
+        drop(r);
+
+        map.insert(1, 2);
+    }
+
+    #[test]
+    fn test_other_thread_waits() {
+        let map = Arc::new(ClashMap::new());
+        map.insert(1, 1);
+
+        let r = map.get(&1).unwrap();
+        let handle = thread::spawn({
+            let map = map.clone();
+            move || map.insert(1, 2)
+        });
+        thread::sleep(std::time::Duration::from_millis(10));
+        drop(r);
+
+        assert_eq!(handle.join().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_guard_released_on_other_thread() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let w = map.get_mut(&1).unwrap();
+        thread::scope(|s| {
+            s.spawn(move || drop(w));
+        });
+        assert_eq!(map.insert(1, 2), Some(1));
+
+        let r = map.get(&1).unwrap();
+        thread::scope(|s| {
+            s.spawn(move || drop(r));
+        });
+        assert_eq!(map.insert(1, 3), Some(2));
+    }
+
+    #[test]
+    #[should_panic = "tried to acquire a read lock on shard"]
+    fn test_write_guard_of_other_thread_released() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        thread::scope(|s| {
+            let w = s.spawn(|| map.get_mut(&1).unwrap()).join().unwrap();
+            drop(w);
+        });
+
+        // The write lock acquired by the other thread and released here
+        // must not hide the one this thread holds.
+        let _w = map.get_mut(&1).unwrap();
+        map.get(&1);
+    }
+}
@@ -0,0 +1,431 @@
+//! The deadlines of the keys of a map that were given a time-to-live.
+//!
+//! See [`ClashMap::insert_with_ttl`](crate::ClashMap::insert_with_ttl).
+
+use crate::cache::wheel::TimerWheel;
+use crate::cache::{Clock, SystemClock, NIL};
+use crate::lock::RwLock;
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use core::hash::{BuildHasher, Hash, Hasher};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crossbeam_utils::CachePadded;
+use hashbrown::HashTable;
+use once_cell::race::OnceBox;
+use std::collections::hash_map::RandomState;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The deadline of a key, linked into the slot of the timing wheel it is scheduled in.
+#[derive(Clone)]
+struct Deadline<K> {
+    key: K,
+    hash: u64,
+    at: u64,
+    /// Tells this deadline apart from the ones that were stored at the same index before it.
+    stamp: u64,
+    slot: usize,
+    prev: usize,
+    next: usize,
+}
+
+/// The deadlines of the keys whose hash selects this table, ordered by a timing wheel.
+#[derive(Clone)]
+struct Table<K> {
+    index: HashTable<usize>,
+    deadlines: Vec<Option<Deadline<K>>>,
+    free: Vec<usize>,
+    wheel: TimerWheel,
+    stamps: u64,
+}
+
+fn deadline_hash<K>(deadlines: &[Option<Deadline<K>>], idx: usize) -> u64 {
+    match &deadlines[idx] {
+        Some(deadline) => deadline.hash,
+        None => unreachable!("expiry index points to a free slot"),
+    }
+}
+
+impl<K> Table<K> {
+    fn new() -> Self {
+        Self {
+            index: HashTable::new(),
+            deadlines: Vec::new(),
+            free: Vec::new(),
+            wheel: TimerWheel::new(),
+            stamps: 0,
+        }
+    }
+
+    fn deadline(&self, idx: usize) -> &Deadline<K> {
+        match &self.deadlines[idx] {
+            Some(deadline) => deadline,
+            None => unreachable!("expiry table points to a free slot"),
+        }
+    }
+
+    fn deadline_mut(&mut self, idx: usize) -> &mut Deadline<K> {
+        match &mut self.deadlines[idx] {
+            Some(deadline) => deadline,
+            None => unreachable!("expiry table points to a free slot"),
+        }
+    }
+
+    fn find(&self, hash: u64, key: &K, eq: fn(&K, &K) -> bool) -> Option<usize> {
+        self.index
+            .find(hash, |&idx| eq(&self.deadline(idx).key, key))
+            .copied()
+    }
+
+    /// Sets the deadline of `key` to `at`, returning true if it had none before.
+    fn set(&mut self, hash: u64, key: K, at: u64, eq: fn(&K, &K) -> bool) -> bool {
+        if let Some(idx) = self.find(hash, &key, eq) {
+            self.unschedule(idx);
+            self.deadline_mut(idx).at = at;
+            self.schedule(idx);
+            return false;
+        }
+
+        self.stamps += 1;
+        let deadline = Deadline {
+            key,
+            hash,
+            at,
+            stamp: self.stamps,
+            slot: NIL,
+            prev: NIL,
+            next: NIL,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.deadlines[idx] = Some(deadline);
+                idx
+            }
+            None => {
+                self.deadlines.push(Some(deadline));
+                self.deadlines.len() - 1
+            }
+        };
+
+        let deadlines = &self.deadlines;
+        self.index
+            .insert_unique(hash, idx, |&idx| deadline_hash(deadlines, idx));
+        self.schedule(idx);
+        true
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.unschedule(idx);
+        let hash = self.deadline(idx).hash;
+        if let Ok(entry) = self.index.find_entry(hash, |&other| other == idx) {
+            entry.remove();
+        }
+        self.deadlines[idx] = None;
+        self.free.push(idx);
+    }
+
+    /// Returns the deadline at `idx`, if it is still the one that was given `stamp`.
+    fn stamped(&self, idx: usize, stamp: u64) -> Option<&Deadline<K>> {
+        self.deadlines
+            .get(idx)?
+            .as_ref()
+            .filter(|deadline| deadline.stamp == stamp)
+    }
+
+    /// Takes the deadlines that passed by `now` off the wheel, and returns their index and stamp.
+    ///
+    /// They stay in the table, so that their keys read as expired until they are removed.
+    fn take_due(&mut self, now: u64) -> Vec<(usize, u64)> {
+        let mut due = Vec::new();
+        for slot in self.wheel.advance(now) {
+            let mut idx = self.wheel.head(slot);
+            let mut count = 0;
+            while idx != NIL {
+                idx = self.deadline(idx).next;
+                count += 1;
+            }
+
+            let mut idx = self.wheel.take(slot, count);
+            while idx != NIL {
+                let deadline = self.deadline_mut(idx);
+                let next = deadline.next;
+                deadline.slot = NIL;
+
+                if deadline.at <= now {
+                    due.push((idx, deadline.stamp));
+                } else {
+                    self.schedule(idx);
+                }
+                idx = next;
+            }
+        }
+        due
+    }
+
+    fn schedule(&mut self, idx: usize) {
+        let slot = self.wheel.slot(self.deadline(idx).at);
+        let next = self.wheel.push(slot, idx);
+        if next != NIL {
+            self.deadline_mut(next).prev = idx;
+        }
+
+        let deadline = self.deadline_mut(idx);
+        deadline.slot = slot;
+        deadline.prev = NIL;
+        deadline.next = next;
+    }
+
+    fn unschedule(&mut self, idx: usize) {
+        let deadline = self.deadline_mut(idx);
+        let (slot, prev, next) = (deadline.slot, deadline.prev, deadline.next);
+        if slot == NIL {
+            return;
+        }
+        deadline.slot = NIL;
+
+        if prev != NIL {
+            self.deadline_mut(prev).next = next;
+        } else {
+            self.wheel.pop(slot, next);
+        }
+        if next != NIL {
+            self.deadline_mut(next).prev = prev;
+        }
+    }
+}
+
+/// The tables of deadlines of a map.
+///
+/// Keys are hashed with a hasher of their own, and a table is picked from that hash alone,
+/// so the map can be resharded without moving its deadlines. Hashing and comparing keys needs
+/// bounds that the map only requires where deadlines are set, so the functions doing it are kept
+/// alongside the tables, and deadlines can be read wherever the map can be.
+struct Tables<K> {
+    tables: Box<[CachePadded<RwLock<Table<K>>>]>,
+    hasher: RandomState,
+    hash: fn(&RandomState, &K) -> u64,
+    eq: fn(&K, &K) -> bool,
+    /// The number of keys with a deadline, so that maps without any do not hash keys on every read.
+    len: AtomicUsize,
+}
+
+impl<K> Tables<K> {
+    fn new(table_amount: usize) -> Self
+    where
+        K: Eq + Hash,
+    {
+        Self {
+            tables: (0..table_amount)
+                .map(|_| CachePadded::new(RwLock::new(Table::new())))
+                .collect(),
+            hasher: RandomState::new(),
+            hash: |hasher, key| {
+                let mut hasher = hasher.build_hasher();
+                key.hash(&mut hasher);
+                hasher.finish()
+            },
+            eq: |a, b| a == b,
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    fn hash(&self, key: &K) -> u64 {
+        (self.hash)(&self.hasher, key)
+    }
+
+    fn table(&self, hash: u64) -> &RwLock<Table<K>> {
+        &self.tables[DefaultShardSelector.select_shard(hash as usize, self.tables.len())]
+    }
+}
+
+impl<K: Clone> Clone for Tables<K> {
+    fn clone(&self) -> Self {
+        Self {
+            tables: self
+                .tables
+                .iter()
+                .map(|table| CachePadded::new(RwLock::new(table.read().clone())))
+                .collect(),
+            hasher: self.hasher.clone(),
+            hash: self.hash,
+            eq: self.eq,
+            len: AtomicUsize::new(self.len.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A deadline that had passed when [`Expiries::take_due`] was called.
+pub(crate) struct Due {
+    /// The hash of the key in the map, rather than in the tables of deadlines.
+    pub(crate) hash: u64,
+    table: usize,
+    idx: usize,
+    stamp: u64,
+}
+
+/// The deadlines of the keys of a map that were given a time-to-live, read from a pluggable clock.
+///
+/// A key's deadline must only be set or removed while its shard of the map is write-locked, so that
+/// it changes along with the entry. The shard is always locked before the table of deadlines.
+/// The tables are only allocated once a deadline is set, so maps whose keys never expire do not pay for them.
+pub(crate) struct Expiries<K> {
+    tables: OnceBox<Tables<K>>,
+    clock: Arc<dyn Clock>,
+    /// The time of the clock that deadlines count from, in nanoseconds since `base`.
+    origin: Instant,
+    base: u64,
+}
+
+impl<K> Expiries<K> {
+    pub(crate) fn new() -> Self {
+        Self {
+            tables: OnceBox::new(),
+            clock: Arc::new(SystemClock),
+            origin: Instant::now(),
+            base: 0,
+        }
+    }
+
+    /// Replaces the clock, keeping the time left until every deadline.
+    pub(crate) fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.base = self.now();
+        self.origin = clock.now();
+        self.clock = Arc::new(clock);
+    }
+
+    /// Returns the current time, in nanoseconds.
+    pub(crate) fn now(&self) -> u64 {
+        let elapsed = self.clock.now().saturating_duration_since(self.origin);
+        self.base.saturating_add(nanos(elapsed))
+    }
+
+    /// Returns the tables, if any key has a deadline.
+    fn active(&self) -> Option<&Tables<K>> {
+        self.tables
+            .get()
+            .filter(|tables| tables.len.load(Ordering::Relaxed) != 0)
+    }
+
+    /// Returns true if no key has a deadline.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.active().is_none()
+    }
+
+    /// Returns true if `key` has a deadline that has passed.
+    ///
+    /// The time is only read if the key has a deadline.
+    pub(crate) fn is_expired(&self, key: &K) -> bool {
+        let Some(tables) = self.active() else {
+            return false;
+        };
+        let hash = tables.hash(key);
+        let table = tables.table(hash).read();
+        match table.find(hash, key, tables.eq) {
+            Some(idx) => table.deadline(idx).at <= self.now(),
+            None => false,
+        }
+    }
+
+    /// Gives `key` a deadline `ttl` from now, replacing the one it had.
+    pub(crate) fn set(&self, key: K, ttl: Duration)
+    where
+        K: Eq + Hash,
+    {
+        let tables = self
+            .tables
+            .get_or_init(|| Box::new(Tables::new(crate::default_shard_amount())));
+        let at = self.now().saturating_add(nanos(ttl));
+        let hash = tables.hash(&key);
+        if tables.table(hash).write().set(hash, key, at, tables.eq) {
+            tables.len.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the deadline of `key`, if it has one.
+    pub(crate) fn forget(&self, key: &K) {
+        let Some(tables) = self.active() else {
+            return;
+        };
+        let hash = tables.hash(key);
+        let mut table = tables.table(hash).write();
+        if let Some(idx) = table.find(hash, key, tables.eq) {
+            table.remove(idx);
+            tables.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the number of tables, or 0 if none were allocated.
+    pub(crate) fn table_amount(&self) -> usize {
+        self.tables.get().map_or(0, |tables| tables.tables.len())
+    }
+
+    /// Returns the deadlines of the table at index `table` that passed by `now`, taking them off its wheel.
+    ///
+    /// `map_hash` gives the hash of a key in the map. Every deadline returned must then be passed
+    /// to [`remove_due`](Expiries::remove_due), with the shard of the map holding it write-locked.
+    pub(crate) fn take_due(
+        &self,
+        table: usize,
+        now: u64,
+        map_hash: impl Fn(&K) -> u64,
+    ) -> Vec<Due> {
+        let Some(tables) = self.active() else {
+            return Vec::new();
+        };
+        let mut guard = tables.tables[table].write();
+        let due = guard.take_due(now);
+        due.into_iter()
+            .map(|(idx, stamp)| Due {
+                hash: map_hash(&guard.deadline(idx).key),
+                table,
+                idx,
+                stamp,
+            })
+            .collect()
+    }
+
+    /// Returns true if `key` is the key of a deadline returned by [`take_due`](Expiries::take_due),
+    /// and the deadline has not been changed or removed since.
+    pub(crate) fn is_due(&self, due: &Due, key: &K, now: u64) -> bool {
+        let Some(tables) = self.tables.get() else {
+            return false;
+        };
+        let table = tables.tables[due.table].read();
+        table
+            .stamped(due.idx, due.stamp)
+            .is_some_and(|deadline| deadline.at <= now && (tables.eq)(&deadline.key, key))
+    }
+
+    /// Removes a deadline returned by [`take_due`](Expiries::take_due), once its key is gone from the map.
+    pub(crate) fn remove_due(&self, due: &Due, now: u64) {
+        let Some(tables) = self.tables.get() else {
+            return;
+        };
+        let mut table = tables.tables[due.table].write();
+        if table
+            .stamped(due.idx, due.stamp)
+            .is_some_and(|deadline| deadline.at <= now)
+        {
+            table.remove(due.idx);
+            tables.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K: Clone> Clone for Expiries<K> {
+    fn clone(&self) -> Self {
+        let tables = OnceBox::new();
+        if let Some(existing) = self.tables.get() {
+            let _ = tables.set(Box::new(existing.clone()));
+        }
+        Self {
+            tables,
+            clock: self.clock.clone(),
+            origin: self.origin,
+            base: self.base,
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
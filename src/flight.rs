@@ -0,0 +1,107 @@
+//! Single-flight computation of missing values.
+//!
+//! See [`ClashMap::get_or_compute`](crate::ClashMap::get_or_compute).
+
+use crate::lock::{RawRwLock, RwLock};
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use crossbeam_utils::CachePadded;
+use hashbrown::HashTable;
+use lock_api::RawRwLock as _;
+use once_cell::race::OnceBox;
+
+/// A computation in progress. The computing thread holds it exclusively until it is done,
+/// so waiting for the computation is taking a shared lock on it.
+type Flight = Arc<RawRwLock>;
+
+/// The computations in progress in one shard, by the hash of their key.
+type ShardFlights = RwLock<HashTable<(u64, Flight)>>;
+
+/// The computations in progress in a map, by the hash of their key.
+///
+/// There are as many tables as the map had shards when the first computation started,
+/// so misses in different tables do not contend. A table is picked from the hash alone,
+/// so the map can be resharded while computations are in progress.
+/// The tables are only allocated by the first computation, so maps that never compute a value
+/// do not pay for them.
+/// Keys are not stored, so computations of different keys with the same hash wait for each other.
+/// This only costs time, as waiters look the key up again once the computation is done.
+pub(crate) struct Flights {
+    shards: OnceBox<Box<[CachePadded<ShardFlights>]>>,
+}
+
+impl Flights {
+    pub(crate) fn new() -> Self {
+        Self {
+            shards: OnceBox::new(),
+        }
+    }
+
+    /// Whether the tables were allocated by a computation.
+    #[cfg(test)]
+    pub(crate) fn is_allocated(&self) -> bool {
+        self.shards.get().is_some()
+    }
+
+    /// Starts a computation for `hash`, or returns the computation already in progress.
+    ///
+    /// `shard_amount` is the number of tables to allocate, if this is the first computation.
+    pub(crate) fn start(&self, shard_amount: usize, hash: u64) -> Result<Departure<'_>, Waiting> {
+        let shards = self.shards.get_or_init(|| {
+            Box::new(
+                (0..shard_amount)
+                    .map(|_| CachePadded::new(RwLock::new(HashTable::new())))
+                    .collect(),
+            )
+        });
+        let shard = DefaultShardSelector.select_shard(hash as usize, shards.len());
+        let mut flights = shards[shard].write();
+        if let Some((_, flight)) = flights.find(hash, |(h, _)| *h == hash) {
+            return Err(Waiting(flight.clone()));
+        }
+
+        let flight = Flight::new(RawRwLock::INIT);
+        flight.lock_exclusive();
+        flights.insert_unique(hash, (hash, flight.clone()), |(h, _)| *h);
+        Ok(Departure {
+            flights: &shards[shard],
+            hash,
+            flight,
+        })
+    }
+}
+
+/// A computation in progress on another thread.
+pub(crate) struct Waiting(Flight);
+
+impl Waiting {
+    /// Blocks until the computation is done, whether it succeeded or not.
+    pub(crate) fn wait(self) {
+        self.0.lock_shared();
+        // SAFETY: We took the shared lock on the line above.
+        unsafe { self.0.unlock_shared() };
+    }
+}
+
+/// A computation in progress on this thread.
+///
+/// Dropping it, including while unwinding from a panic, ends the computation and wakes its waiters.
+pub(crate) struct Departure<'a> {
+    flights: &'a ShardFlights,
+    hash: u64,
+    flight: Flight,
+}
+
+impl Drop for Departure<'_> {
+    fn drop(&mut self) {
+        let mut flights = self.flights.write();
+        if let Ok(entry) = flights.find_entry(self.hash, |(_, f)| Arc::ptr_eq(f, &self.flight)) {
+            entry.remove();
+        }
+        drop(flights);
+
+        // SAFETY: The exclusive lock was taken in `Flights::start`, and is only released here.
+        unsafe { self.flight.unlock_exclusive() };
+    }
+}
@@ -0,0 +1,641 @@
+//! Guards holding the locks of every shard in a map at once, or of a single shard.
+//!
+//! See [`ClashMap::read_all`], [`ClashMap::write_all`] and [`ClashMap::with_shard_for`].
+
+use crate::layout::LayoutGuard;
+use crate::lock::RawRwLock;
+use crate::mapref::entrymut::{EntryMut, OccupiedEntryMut, VacantEntryMut};
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use crate::{ClashMap, HashMap};
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash, Hasher};
+use hashbrown::{hash_table, Equivalent};
+use lock_api::{RwLockReadGuard, RwLockWriteGuard};
+
+/// A read lock on every shard of a [`ClashMap`].
+///
+/// Lookups through this guard need no further locking. Other threads can still read the map,
+/// but cannot write to it until the guard is dropped.
+pub struct ReadAllGuard<'a, K, V, S, L: lock_api::RawRwLock = RawRwLock, Sel = DefaultShardSelector>
+{
+    map: &'a ClashMap<K, V, S, L, Sel>,
+    shards: Vec<RwLockReadGuard<'a, L, HashMap<K, V>>>,
+    // Keeps the entries from moving to another layout while the shards are locked.
+    _layout: LayoutGuard<'a>,
+}
+
+/// A write lock on every shard of a [`ClashMap`].
+///
+/// This gives the same access as holding a `&mut ClashMap`, while the map itself stays shared.
+/// No other thread can access the map until the guard is dropped.
+pub struct WriteAllGuard<
+    'a,
+    K,
+    V,
+    S,
+    L: lock_api::RawRwLock = RawRwLock,
+    Sel = DefaultShardSelector,
+> {
+    map: &'a ClashMap<K, V, S, L, Sel>,
+    shards: Vec<RwLockWriteGuard<'a, L, HashMap<K, V>>>,
+    // Keeps the entries from moving to another layout while the shards are locked.
+    _layout: LayoutGuard<'a>,
+}
+
+/// A write lock on a single shard of a [`ClashMap`].
+///
+/// Only keys stored in this shard can be used with the guard. Other threads can still access
+/// the rest of the map while the guard is held.
+///
+/// # Panics
+///
+/// Every method taking a key panics if the key belongs to another shard.
+pub struct ShardWriteGuard<
+    'a,
+    K,
+    V,
+    S,
+    L: lock_api::RawRwLock = RawRwLock,
+    Sel = DefaultShardSelector,
+> {
+    map: &'a ClashMap<K, V, S, L, Sel>,
+    idx: usize,
+    shard: RwLockWriteGuard<'a, L, HashMap<K, V>>,
+}
+
+fn hash_u64<S: BuildHasher, T: Hash + ?Sized>(hasher: &S, item: &T) -> u64 {
+    let mut hasher = hasher.build_hasher();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    ReadAllGuard<'a, K, V, S, L, Sel>
+{
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> (u64, &HashMap<K, V>) {
+        let hash = hash_u64(&self.map.hasher, key);
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        (hash, &self.shards[idx])
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    /// Returns `true` if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of elements the map can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|s| s.capacity()).sum()
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get_key_value(key).map(|(_k, v)| v)
+    }
+
+    /// Returns the key-value pair corresponding to the supplied key.
+    pub fn get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let (hash, shard) = self.shard(key);
+        shard
+            .find(hash, |(k, _v)| key.equivalent(k))
+            .map(|(k, v)| (k, v))
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order. The iterator element type is `(&K, &V)`.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .map(|(k, v)| (k, v))
+    }
+
+    /// An iterator visiting all keys in arbitrary order. The iterator element type is `&K`.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.iter().map(|(k, _v)| k)
+    }
+
+    /// An iterator visiting all values in arbitrary order. The iterator element type is `&V`.
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_k, v)| v)
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    WriteAllGuard<'a, K, V, S, L, Sel>
+{
+    fn shard_mut<Q: Hash + ?Sized>(&mut self, key: &Q) -> (u64, &mut HashMap<K, V>) {
+        let hash = hash_u64(&self.map.hasher, key);
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        (hash, &mut self.shards[idx])
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    /// Returns `true` if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = hash_u64(&self.map.hasher, key);
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        self.shards[idx]
+            .find(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let (hash, shard) = self.shard_mut(key);
+        shard
+            .find_mut(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
+    pub fn insert_mut(&mut self, key: K, value: V) -> Option<V> {
+        match self.entry_mut(key) {
+            EntryMut::Occupied(mut o) => Some(o.insert(value)),
+            EntryMut::Vacant(v) => {
+                v.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    pub fn entry_mut(&mut self, key: K) -> EntryMut<'_, K, V> {
+        let hash = hash_u64(&self.map.hasher, &key);
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        let hasher = &self.map.hasher;
+        let notifier = self.map.notifier(idx);
+        match self.shards[idx].entry(hash, |(k, _v)| k == &key, |(k, _v)| hash_u64(hasher, k)) {
+            hash_table::Entry::Occupied(entry) => {
+                EntryMut::Occupied(OccupiedEntryMut::new(key, entry, notifier))
+            }
+            hash_table::Entry::Vacant(entry) => {
+                EntryMut::Vacant(VacantEntryMut::new(key, entry, notifier))
+            }
+        }
+    }
+
+    /// Removes an entry from the map, returning the key and value if they existed in the map.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = hash_u64(&self.map.hasher, key);
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        match self.shards[idx].find_entry(hash, |(k, _v)| key.equivalent(k)) {
+            Ok(entry) => {
+                let (k, v) = entry.remove().0;
+                self.map.notifier(idx).removed(&k, &v);
+                Some((k, v))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Retain elements that whose predicates return true
+    /// and discard elements whose predicates return false.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        for (idx, shard) in self.shards.iter_mut().enumerate() {
+            let notifier = self.map.notifier(idx);
+            shard.retain(|(k, v)| {
+                let keep = f(k, v);
+                if !keep {
+                    notifier.removed(k, v);
+                }
+                keep
+            });
+        }
+    }
+
+    /// Removes all key-value pairs in the map.
+    pub fn clear(&mut self) {
+        for (idx, shard) in self.shards.iter_mut().enumerate() {
+            shard.clear();
+            self.map.notifier(idx).cleared();
+        }
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order. The iterator element type is `(&K, &V)`.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| shard.iter())
+            .map(|(k, v)| (k, v))
+    }
+
+    /// An iterator visiting all key-value pairs in arbitrary order, with mutable references to the values.
+    /// The iterator element type is `(&K, &mut V)`.
+    pub fn iter_mut(&mut self) -> IterMut<'_, 'a, K, V, L> {
+        IterMut {
+            shards: self.shards.iter_mut(),
+            current: None,
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    ShardWriteGuard<'_, K, V, S, L, Sel>
+{
+    fn hash<Q: Hash + ?Sized>(&self, key: &Q) -> u64 {
+        let hash = hash_u64(&self.map.hasher, key);
+        assert_eq!(
+            self.map.table.tables._determine_shard(hash as usize),
+            self.idx,
+            "key does not belong to this shard"
+        );
+        hash
+    }
+
+    /// Returns the index of the locked shard.
+    pub fn index(&self) -> usize {
+        self.idx
+    }
+
+    /// Returns the number of elements in the shard.
+    pub fn len(&self) -> usize {
+        self.shard.len()
+    }
+
+    /// Returns `true` if the shard contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the shard contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash(key);
+        self.shard
+            .find(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash(key);
+        self.shard
+            .find_mut(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Inserts a key and a value into the shard. Returns the old value associated with the key if there was one.
+    pub fn insert_mut(&mut self, key: K, value: V) -> Option<V> {
+        match self.entry_mut(key) {
+            EntryMut::Occupied(mut o) => Some(o.insert(value)),
+            EntryMut::Vacant(v) => {
+                v.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    pub fn entry_mut(&mut self, key: K) -> EntryMut<'_, K, V> {
+        let hash = self.hash(&key);
+        let hasher = &self.map.hasher;
+        let notifier = self.map.notifier(self.idx);
+        match self
+            .shard
+            .entry(hash, |(k, _v)| k == &key, |(k, _v)| hash_u64(hasher, k))
+        {
+            hash_table::Entry::Occupied(entry) => {
+                EntryMut::Occupied(OccupiedEntryMut::new(key, entry, notifier))
+            }
+            hash_table::Entry::Vacant(entry) => {
+                EntryMut::Vacant(VacantEntryMut::new(key, entry, notifier))
+            }
+        }
+    }
+
+    /// Removes an entry from the shard, returning the key and value if they existed in the shard.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash(key);
+        match self.shard.find_entry(hash, |(k, _v)| key.equivalent(k)) {
+            Ok(entry) => {
+                let (k, v) = entry.remove().0;
+                self.map.notifier(self.idx).removed(&k, &v);
+                Some((k, v))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Retain elements of the shard that whose predicates return true
+    /// and discard elements whose predicates return false.
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let notifier = self.map.notifier(self.idx);
+        self.shard.retain(|(k, v)| {
+            let keep = f(k, v);
+            if !keep {
+                notifier.removed(k, v);
+            }
+            keep
+        });
+    }
+
+    /// An iterator visiting all key-value pairs of the shard in arbitrary order. The iterator element type is `(&K, &V)`.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.shard.iter().map(|(k, v)| (k, v))
+    }
+
+    /// An iterator visiting all key-value pairs of the shard in arbitrary order, with mutable references to the values.
+    /// The iterator element type is `(&K, &mut V)`.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.shard.iter_mut().map(|(k, v)| (&*k, v))
+    }
+}
+
+/// An iterator over the entries of a [`WriteAllGuard`], with mutable references to the values.
+pub struct IterMut<'b, 'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    shards: core::slice::IterMut<'b, RwLockWriteGuard<'a, L, HashMap<K, V>>>,
+    current: Option<hash_table::IterMut<'b, (K, V)>>,
+}
+
+impl<'b, K, V, L: lock_api::RawRwLock> Iterator for IterMut<'b, '_, K, V, L> {
+    type Item = (&'b K, &'b mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.current.as_mut().and_then(|current| current.next()) {
+                return Some((k, v));
+            }
+            self.current = Some(self.shards.next()?.iter_mut());
+        }
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> ClashMap<K, V, S, L, Sel> {
+    /// Read-locks every shard of the map, and returns a guard for lock-free lookups.
+    ///
+    /// Shards are locked in index order, like all other operations that lock more than one shard.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("Johnny", 21);
+    ///
+    /// let all = map.read_all();
+    /// assert_eq!(all.get("Johnny"), Some(&21));
+    /// assert_eq!(all.len(), 1);
+    /// ```
+    pub fn read_all(&self) -> ReadAllGuard<'_, K, V, S, L, Sel> {
+        let (layout, shards) = self.table.tables.enter();
+        let shards = shards.iter().map(|s| s.read()).collect();
+        ReadAllGuard {
+            map: self,
+            shards,
+            _layout: layout,
+        }
+    }
+
+    /// Write-locks every shard of the map, and returns a guard with exclusive access to all of it.
+    ///
+    /// Shards are locked in index order, like all other operations that lock more than one shard.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("Johnny", 21);
+    ///
+    /// let mut all = map.write_all();
+    /// all.clear();
+    /// all.insert_mut("Jane", 35);
+    /// all.entry_mut("Jane").or_insert(0).1 += 1;
+    /// drop(all);
+    ///
+    /// assert!(!map.contains_key("Johnny"));
+    /// assert_eq!(*map.get("Jane").unwrap(), 36);
+    /// ```
+    pub fn write_all(&self) -> WriteAllGuard<'_, K, V, S, L, Sel> {
+        let (layout, shards) = self.table.tables.enter();
+        let shards = shards.iter().map(|s| s.write()).collect();
+        WriteAllGuard {
+            map: self,
+            shards,
+            _layout: layout,
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    ClashMap<K, V, S, L, Sel>
+{
+    /// Write-locks the shard that `key` is stored in, and calls `f` with a guard for that shard.
+    ///
+    /// `key` does not need to be in the map, or even be of the key type: with a
+    /// [`ShardSelector`](crate::shard_selector::ShardSelector) that groups keys by a part of
+    /// their hash, such as a tenant id, this locks the shard of a whole group at once.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("Johnny", 21);
+    ///
+    /// let age = map.with_shard_for("Johnny", |shard| {
+    ///     *shard.get_mut("Johnny").unwrap() += 1;
+    ///     shard.get("Johnny").copied()
+    /// });
+    /// assert_eq!(age, Some(22));
+    /// ```
+    pub fn with_shard_for<Q, R>(
+        &self,
+        key: &Q,
+        f: impl FnOnce(&mut ShardWriteGuard<'_, K, V, S, L, Sel>) -> R,
+    ) -> R
+    where
+        Q: Hash + ?Sized,
+    {
+        // The guard checks keys against the shard index, so entries must not move to another layout until `f` returns.
+        let (_layout, shards) = self.table.tables.enter();
+        let idx = self
+            .table
+            .tables
+            ._determine_shard(hash_u64(&self.hasher, key) as usize);
+        let mut guard = ShardWriteGuard {
+            map: self,
+            idx,
+            shard: shards[idx].write(),
+        };
+        f(&mut guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ClashMap;
+    use core::hash::{BuildHasherDefault, Hasher};
+    use std::collections::hash_map::DefaultHasher;
+
+    #[test]
+    fn test_write_all_excludes_others() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..32 {
+            map.insert(i, i);
+        }
+
+        let mut all = map.write_all();
+        assert!(map.try_get(&0).is_locked());
+        all.iter_mut().for_each(|(_, v)| *v *= 2);
+        all.retain(|k, _| k % 2 == 0);
+        assert_eq!(all.remove(&0), Some((0, 0)));
+        drop(all);
+
+        let all = map.read_all();
+        assert!(map.try_get(&2).is_present());
+        assert!(map.try_get_mut(&2).is_locked());
+        assert_eq!(all.len(), 15);
+        assert_eq!(all.get(&2), Some(&4));
+        assert_eq!(
+            all.values().sum::<i32>(),
+            (2..32).step_by(2).map(|i| i * 2).sum()
+        );
+    }
+
+    /// Where the tenant id goes in the hash: above the bits hashbrown uses for the bucket index,
+    /// and below the top 7 bits it uses for the tag.
+    const TENANT_SHIFT: u32 = 25;
+
+    /// Puts the first `u32` written, the tenant id, at [`TENANT_SHIFT`] in the hash,
+    /// and the hash of the rest of the key in the other bits.
+    #[derive(Default)]
+    struct TenantHasher {
+        tenant: Option<u32>,
+        rest: DefaultHasher,
+    }
+
+    impl Hasher for TenantHasher {
+        fn finish(&self) -> u64 {
+            let tenant_bits = u64::from(u32::MAX) << TENANT_SHIFT;
+            (u64::from(self.tenant.unwrap_or(0)) << TENANT_SHIFT)
+                | (self.rest.finish() & !tenant_bits)
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.rest.write(bytes);
+        }
+
+        fn write_u32(&mut self, i: u32) {
+            match self.tenant {
+                None => self.tenant = Some(i),
+                Some(_) => self.rest.write_u32(i),
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_shard_for_tenant() {
+        let map: ClashMap<(u32, u64), u64, BuildHasherDefault<TenantHasher>, _, _> =
+            ClashMap::with_hasher_and_shard_selector(Default::default(), 8, |hash, amount| {
+                (hash >> TENANT_SHIFT) % amount
+            });
+        for tenant in 0..4 {
+            for id in 0..100 {
+                map.insert((tenant, id), id);
+            }
+        }
+
+        let info = map.shard_info();
+        assert_eq!(info.shards.iter().filter(|s| s.len == 100).count(), 4);
+
+        let sum = map.with_shard_for(&2u32, |shard| {
+            assert_eq!(shard.index(), 2);
+            assert!(map.try_get(&(2, 0)).is_locked());
+            assert!(map.try_get(&(3, 0)).is_present());
+            shard.retain(|_, v| *v < 10);
+            shard.insert_mut((2, 1000), 1000);
+            shard.iter().map(|(_, v)| v).sum::<u64>()
+        });
+        assert_eq!(sum, 45 + 1000);
+        assert_eq!(map.len(), 300 + 11);
+
+        let mut view = map.into_read_only().into_inner();
+        view.reshard(16);
+        assert_eq!(*view.get(&(2, 1000)).unwrap(), 1000);
+        assert_eq!(view.with_shard_for(&3u32, |shard| shard.len()), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "key does not belong to this shard")]
+    fn test_with_shard_for_other_key() {
+        let map = ClashMap::with_shard_amount(4);
+        let other = (0..)
+            .find(|i| map.table.tables._determine_shard(map.hash_usize(i)) != 0)
+            .unwrap();
+        let first = (0..)
+            .find(|i| map.table.tables._determine_shard(map.hash_usize(i)) == 0)
+            .unwrap();
+        map.with_shard_for(&first, |shard| shard.insert_mut(other, ()));
+    }
+}
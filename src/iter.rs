@@ -1,8 +1,17 @@
+use alloc::vec::Vec;
 use hashbrown::hash_table;
 
 use super::mapref::multiple::{RefMulti, RefMutMulti};
+#[cfg(feature = "std")]
+use crate::expiry::Expiries;
+use crate::layout::LayoutGuard;
+use crate::listener::{Listener, Notifier};
+use crate::lock::{RawRwLock, RawRwLockAsync, RwLockReadGuardDetached};
+use crate::shard_selector::ShardSelector;
 use crate::{tableref, ClashMap, Shard};
 use core::hash::BuildHasher;
+use core::slice;
+use core::task::{ready, Context, Poll};
 
 /// Iterator over a ClashMap yielding key value pairs.
 ///
@@ -17,15 +26,15 @@ use core::hash::BuildHasher;
 /// let pairs: Vec<(&'static str, &'static str)> = map.into_iter().collect();
 /// assert_eq!(pairs.len(), 2);
 /// ```
-pub struct OwningIter<K, V> {
-    shards: std::vec::IntoIter<Shard<K, V>>,
+pub struct OwningIter<K, V, L = RawRwLock> {
+    shards: alloc::vec::IntoIter<Shard<K, V, L>>,
     current: Option<GuardOwningIter<K, V>>,
 }
 
-impl<K, V> OwningIter<K, V> {
-    pub(crate) fn new<S: BuildHasher>(map: ClashMap<K, V, S>) -> Self {
+impl<K, V, L: lock_api::RawRwLock> OwningIter<K, V, L> {
+    pub(crate) fn new<S: BuildHasher, Sel: ShardSelector>(map: ClashMap<K, V, S, L, Sel>) -> Self {
         Self {
-            shards: map.table.tables.shards.into_vec().into_iter(),
+            shards: map.table.tables.into_shards().into_vec().into_iter(),
             current: None,
         }
     }
@@ -33,7 +42,7 @@ impl<K, V> OwningIter<K, V> {
 
 type GuardOwningIter<K, V> = hash_table::IntoIter<(K, V)>;
 
-impl<K, V> Iterator for OwningIter<K, V> {
+impl<K, V, L: lock_api::RawRwLock> Iterator for OwningIter<K, V, L> {
     type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -61,30 +70,40 @@ impl<K, V> Iterator for OwningIter<K, V> {
 /// map.insert("hello", "world");
 /// assert_eq!(map.iter().count(), 1);
 /// ```
-pub struct Iter<'a, K, V> {
-    inner: tableref::iter::Iter<'a, (K, V)>,
+pub struct Iter<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::Iter<'a, (K, V), L>,
+    #[cfg(feature = "std")]
+    expiries: &'a Expiries<K>,
 }
 
-impl<K, V> Clone for Iter<'_, K, V> {
+impl<K, V, L: lock_api::RawRwLock> Clone for Iter<'_, K, V, L> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            #[cfg(feature = "std")]
+            expiries: self.expiries,
         }
     }
 }
 
-impl<'a, K: 'a, V: 'a> Iter<'a, K, V> {
-    pub(crate) fn new<S>(map: &'a ClashMap<K, V, S>) -> Self {
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Iter<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(map: &'a ClashMap<K, V, S, L, Sel>) -> Self {
         Self {
             inner: map.table.iter(),
+            #[cfg(feature = "std")]
+            expiries: &map.expiries,
         }
     }
 }
 
-impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
-    type Item = RefMulti<'a, K, V>;
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Iterator for Iter<'a, K, V, L> {
+    type Item = RefMulti<'a, K, V, L>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // entries whose time-to-live has run out are skipped
+        #[cfg(feature = "std")]
+        let r = self.inner.find(|r| !self.expiries.is_expired(&r.0))?;
+        #[cfg(not(feature = "std"))]
         let r = self.inner.next()?;
         Some(RefMulti::new(r))
     }
@@ -102,27 +121,309 @@ impl<'a, K: 'a, V: 'a> Iterator for Iter<'a, K, V> {
 /// map.iter_mut().for_each(|mut r| *r += 1);
 /// assert_eq!(*map.get("Johnny").unwrap(), 22);
 /// ```
-pub struct IterMut<'a, K, V> {
-    inner: tableref::iter::IterMut<'a, (K, V)>,
+pub struct IterMut<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::IterMut<'a, (K, V), L>,
+    #[cfg(feature = "std")]
+    expiries: &'a Expiries<K>,
 }
 
-impl<'a, K: 'a, V: 'a> IterMut<'a, K, V> {
-    pub(crate) fn new<S>(map: &'a ClashMap<K, V, S>) -> Self {
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> IterMut<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(map: &'a ClashMap<K, V, S, L, Sel>) -> Self {
         Self {
             inner: map.table.iter_mut(),
+            #[cfg(feature = "std")]
+            expiries: &map.expiries,
         }
     }
 }
 
-impl<'a, K: 'a, V: 'a> Iterator for IterMut<'a, K, V> {
-    type Item = RefMutMulti<'a, K, V>;
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Iterator for IterMut<'a, K, V, L> {
+    type Item = RefMutMulti<'a, K, V, L>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        // entries whose time-to-live has run out are skipped
+        #[cfg(feature = "std")]
+        let r = self.inner.find(|r| !self.expiries.is_expired(&r.0))?;
+        #[cfg(not(feature = "std"))]
         let r = self.inner.next()?;
         Some(RefMutMulti::new(r))
     }
 }
 
+/// Draining iterator over a ClashMap, removing every key value pair as it is yielded.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// let map = ClashMap::new();
+/// map.insert("hello", "world");
+/// let pairs: Vec<_> = map.drain().collect();
+/// assert_eq!(pairs, vec![("hello", "world")]);
+/// assert!(map.is_empty());
+/// ```
+pub struct Drain<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::Drain<'a, (K, V), L>,
+    listener: Option<&'a dyn Listener<K, V>>,
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Drain<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(map: &'a ClashMap<K, V, S, L, Sel>) -> Self {
+        Self {
+            inner: map.table.drain(),
+            listener: map.listener.as_deref(),
+        }
+    }
+}
+
+impl<K, V, L: lock_api::RawRwLock> Iterator for Drain<'_, K, V, L> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        Notifier::new(self.listener, self.inner.shard()).removed(&k, &v);
+        Some((k, v))
+    }
+}
+
+impl<K, V, L: lock_api::RawRwLock> Drop for Drain<'_, K, V, L> {
+    fn drop(&mut self) {
+        // the listener must see every removal, so drain the rest one by one
+        if self.listener.is_some() {
+            self.for_each(drop);
+        }
+    }
+}
+
+/// Draining iterator over a mutably borrowed ClashMap, which takes no locks.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// let mut map = ClashMap::new();
+/// map.insert("hello", "world");
+/// let pairs: Vec<_> = map.drain_mut().collect();
+/// assert_eq!(pairs, vec![("hello", "world")]);
+/// assert!(map.is_empty());
+/// ```
+pub struct DrainMut<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::DrainMut<'a, (K, V), L>,
+    listener: Option<&'a dyn Listener<K, V>>,
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> DrainMut<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(map: &'a mut ClashMap<K, V, S, L, Sel>) -> Self {
+        Self {
+            inner: map.table.drain_mut(),
+            listener: map.listener.as_deref(),
+        }
+    }
+}
+
+impl<K, V, L: lock_api::RawRwLock> Iterator for DrainMut<'_, K, V, L> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        Notifier::new(self.listener, self.inner.shard()).removed(&k, &v);
+        Some((k, v))
+    }
+}
+
+impl<K, V, L: lock_api::RawRwLock> Drop for DrainMut<'_, K, V, L> {
+    fn drop(&mut self) {
+        // the listener must see every removal, so drain the rest one by one
+        if self.listener.is_some() {
+            self.for_each(drop);
+        }
+    }
+}
+
+/// Iterator over a ClashMap removing and yielding the key value pairs matching a predicate.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// let map = ClashMap::new();
+/// map.insert("Johnny", 21);
+/// map.insert("Jane", 35);
+/// let old: Vec<_> = map.extract_if(|_, age| *age > 30).collect();
+/// assert_eq!(old, vec![("Jane", 35)]);
+/// assert_eq!(map.len(), 1);
+/// ```
+pub struct ExtractIf<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::ExtractIf<'a, (K, V), L>,
+    listener: Option<&'a dyn Listener<K, V>>,
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> ExtractIf<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(
+        map: &'a ClashMap<K, V, S, L, Sel>,
+        mut pred: impl FnMut(&K, &mut V) -> bool + 'a,
+    ) -> Self {
+        Self {
+            inner: map.table.extract_if(move |(k, v)| pred(k, v)),
+            listener: map.listener.as_deref(),
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Iterator for ExtractIf<'a, K, V, L> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        Notifier::new(self.listener, self.inner.shard()).removed(&k, &v);
+        Some((k, v))
+    }
+}
+
+/// Iterator over a mutably borrowed ClashMap removing and yielding the key value pairs
+/// matching a predicate, which takes no locks.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// let mut map = ClashMap::new();
+/// map.insert("Johnny", 21);
+/// map.insert("Jane", 35);
+/// let old: Vec<_> = map.extract_if_mut(|_, age| *age > 30).collect();
+/// assert_eq!(old, vec![("Jane", 35)]);
+/// assert_eq!(map.len(), 1);
+/// ```
+pub struct ExtractIfMut<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::iter::ExtractIfMut<'a, (K, V), L>,
+    listener: Option<&'a dyn Listener<K, V>>,
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> ExtractIfMut<'a, K, V, L> {
+    pub(crate) fn new<S, Sel: ShardSelector>(
+        map: &'a mut ClashMap<K, V, S, L, Sel>,
+        mut pred: impl FnMut(&K, &mut V) -> bool + 'a,
+    ) -> Self {
+        Self {
+            inner: map.table.extract_if_mut(move |(k, v)| pred(k, v)),
+            listener: map.listener.as_deref(),
+        }
+    }
+}
+
+impl<'a, K: 'a, V: 'a, L: lock_api::RawRwLock> Iterator for ExtractIfMut<'a, K, V, L> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (k, v) = self.inner.next()?;
+        Notifier::new(self.listener, self.inner.shard()).removed(&k, &v);
+        Some((k, v))
+    }
+}
+
+/// Asynchronous stream over a ClashMap yielding cloned key value pairs.
+///
+/// Each shard is locked without blocking the thread, and its entries are cloned out
+/// before the lock is released, so no lock is held while the stream yields to the executor.
+/// This means a whole shard is cloned into a buffer before any of its entries are yielded.
+///
+/// This does not depend on any particular runtime. With the `futures` feature enabled,
+/// it implements [`Stream`](futures_core::Stream) when the keys and values are [`Unpin`],
+/// so it works with stream combinators.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// # async fn run() {
+/// let map = ClashMap::new();
+/// map.insert("hello", "world");
+///
+/// let mut stream = map.stream();
+/// while let Some((k, v)) = stream.next().await {
+///     assert_eq!((k, v), ("hello", "world"));
+/// }
+/// # }
+/// ```
+pub struct EntryStream<'a, K, V, L = RawRwLock> {
+    shards: slice::Iter<'a, Shard<K, V, L>>,
+    current: Option<&'a Shard<K, V, L>>,
+    buffer: alloc::vec::IntoIter<(K, V)>,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
+}
+
+impl<'a, K: Clone, V: Clone, L: RawRwLockAsync> EntryStream<'a, K, V, L> {
+    pub(crate) fn new<S: BuildHasher, Sel: ShardSelector>(
+        map: &'a ClashMap<K, V, S, L, Sel>,
+    ) -> Self {
+        let (layout, shards) = map.table.tables.enter();
+        Self {
+            shards: shards.iter(),
+            current: None,
+            buffer: Vec::new().into_iter(),
+            _layout: layout,
+        }
+    }
+
+    /// Attempts to pull out the next entry of the stream.
+    ///
+    /// Returns [`Poll::Pending`] if the next shard is locked,
+    /// in which case the waker is woken once the shard is released.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<(K, V)>> {
+        loop {
+            if let Some(kv) = self.buffer.next() {
+                return Poll::Ready(Some(kv));
+            }
+
+            let shard = match self.current {
+                Some(shard) => shard,
+                None => match self.shards.next() {
+                    Some(shard) => *self.current.insert(shard),
+                    None => return Poll::Ready(None),
+                },
+            };
+
+            // SAFETY: The raw lock is only used to acquire the lock, which is then owned by the guard.
+            let lock = unsafe { shard.raw() };
+            ready!(lock.poll_lock_shared(cx));
+            self.current = None;
+
+            // SAFETY: We have just acquired the shared lock.
+            let guard = unsafe { RwLockReadGuardDetached::<L>::from_raw(lock) };
+            // SAFETY: The data does not outlive the guard, we only use it to clone the entries.
+            let data = unsafe { &*shard.data_ptr() };
+            self.buffer = data.iter().cloned().collect::<Vec<_>>().into_iter();
+            drop(guard);
+        }
+    }
+
+    /// Returns the next entry of the stream, or `None` once every shard has been visited.
+    pub async fn next(&mut self) -> Option<(K, V)> {
+        core::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<K: Clone + Unpin, V: Clone + Unpin, L: RawRwLockAsync> futures_core::Stream
+    for EntryStream<'_, K, V, L>
+{
+    type Item = (K, V);
+
+    fn poll_next(self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<(K, V)>> {
+        EntryStream::poll_next(self.get_mut(), cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.buffer.len(), None)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ClashMap;
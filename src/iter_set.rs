@@ -1,17 +1,18 @@
+use crate::lock::RawRwLock;
 use crate::setref::multiple::RefMulti;
 use core::hash::Hash;
 
-pub struct OwningIter<K> {
-    inner: crate::iter::OwningIter<K, ()>,
+pub struct OwningIter<K, L = RawRwLock> {
+    inner: crate::iter::OwningIter<K, (), L>,
 }
 
-impl<K: Eq + Hash> OwningIter<K> {
-    pub(crate) fn new(inner: crate::iter::OwningIter<K, ()>) -> Self {
+impl<K: Eq + Hash, L: lock_api::RawRwLock> OwningIter<K, L> {
+    pub(crate) fn new(inner: crate::iter::OwningIter<K, (), L>) -> Self {
         Self { inner }
     }
 }
 
-impl<K: Eq + Hash> Iterator for OwningIter<K> {
+impl<K: Eq + Hash, L: lock_api::RawRwLock> Iterator for OwningIter<K, L> {
     type Item = K;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -19,11 +20,11 @@ impl<K: Eq + Hash> Iterator for OwningIter<K> {
     }
 }
 
-pub struct Iter<'a, K> {
-    inner: crate::iter::Iter<'a, K, ()>,
+pub struct Iter<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: crate::iter::Iter<'a, K, (), L>,
 }
 
-impl<K> Clone for Iter<'_, K> {
+impl<K, L: lock_api::RawRwLock> Clone for Iter<'_, K, L> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -31,20 +32,92 @@ impl<K> Clone for Iter<'_, K> {
     }
 }
 
-impl<'a, K: 'a> Iter<'a, K> {
-    pub(crate) fn new(inner: crate::iter::Iter<'a, K, ()>) -> Self {
+impl<'a, K: 'a, L: lock_api::RawRwLock> Iter<'a, K, L> {
+    pub(crate) fn new(inner: crate::iter::Iter<'a, K, (), L>) -> Self {
         Self { inner }
     }
 }
 
-impl<'a, K: 'a> Iterator for Iter<'a, K> {
-    type Item = RefMulti<'a, K>;
+impl<'a, K: 'a, L: lock_api::RawRwLock> Iterator for Iter<'a, K, L> {
+    type Item = RefMulti<'a, K, L>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.inner.next().map(RefMulti::new)
     }
 }
 
+pub struct Drain<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: crate::iter::Drain<'a, K, (), L>,
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> Drain<'a, K, L> {
+    pub(crate) fn new(inner: crate::iter::Drain<'a, K, (), L>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<K, L: lock_api::RawRwLock> Iterator for Drain<'_, K, L> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+pub struct DrainMut<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: crate::iter::DrainMut<'a, K, (), L>,
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> DrainMut<'a, K, L> {
+    pub(crate) fn new(inner: crate::iter::DrainMut<'a, K, (), L>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<K, L: lock_api::RawRwLock> Iterator for DrainMut<'_, K, L> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+pub struct ExtractIf<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: crate::iter::ExtractIf<'a, K, (), L>,
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> ExtractIf<'a, K, L> {
+    pub(crate) fn new(inner: crate::iter::ExtractIf<'a, K, (), L>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> Iterator for ExtractIf<'a, K, L> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
+pub struct ExtractIfMut<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: crate::iter::ExtractIfMut<'a, K, (), L>,
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> ExtractIfMut<'a, K, L> {
+    pub(crate) fn new(inner: crate::iter::ExtractIfMut<'a, K, (), L>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, K: 'a, L: lock_api::RawRwLock> Iterator for ExtractIfMut<'a, K, L> {
+    type Item = K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, _)| k)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ClashSet;
@@ -85,4 +158,23 @@ mod tests {
         assert_eq!(iter.count(), 1);
         assert_eq!(iter2.count(), 1);
     }
+
+    #[test]
+    fn drain_and_extract_if() {
+        let mut set = ClashSet::new();
+        for i in 0..16 {
+            set.insert(i);
+        }
+
+        let mut odd: Vec<_> = set.extract_if(|k| k % 2 == 1).collect();
+        odd.sort();
+        assert_eq!(odd, (1..16).step_by(2).collect::<Vec<_>>());
+
+        assert_eq!(set.extract_if_mut(|k| *k == 0).collect::<Vec<_>>(), vec![0]);
+        assert_eq!(set.drain().count(), 7);
+        assert!(set.is_empty());
+
+        set.insert(1);
+        assert_eq!(set.drain_mut().collect::<Vec<_>>(), vec![1]);
+    }
 }
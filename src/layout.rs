@@ -0,0 +1,176 @@
+//! The layouts a collection moves its entries to when it is resharded while it is shared.
+//!
+//! See [`ClashMap::reshard_incremental`](crate::ClashMap::reshard_incremental).
+
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use crossbeam_utils::CachePadded;
+
+/// The shards a collection was resharded to while it was shared.
+///
+/// The entries of a shard of the previous layout are moved into this one while both are write-locked,
+/// and the shard is marked as moved before it is unlocked. A thread that locks a shard and finds it
+/// marked moves on to this layout, so every key is found in whichever layout it is in.
+pub(crate) struct Layout<T, L> {
+    pub(crate) shards: Box<[CachePadded<lock_api::RwLock<L, T>>]>,
+    /// For every shard of the previous layout, whether its entries were moved into this one.
+    moved: Box<[AtomicBool]>,
+    pub(crate) next: NextLayout<T, L>,
+}
+
+impl<T, L> Layout<T, L> {
+    pub(crate) fn new(
+        shards: Box<[CachePadded<lock_api::RwLock<L, T>>]>,
+        previous_shard_amount: usize,
+    ) -> Self {
+        Self {
+            shards,
+            moved: (0..previous_shard_amount)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            next: NextLayout::new(),
+        }
+    }
+
+    /// Returns true if the entries of the shard at `idx` in the previous layout were moved into this one.
+    ///
+    /// The previous shard must be locked, which orders this with the write lock it was marked under.
+    pub(crate) fn is_moved(&self, idx: usize) -> bool {
+        self.moved[idx].load(Ordering::Relaxed)
+    }
+
+    /// Marks the shard at `idx` in the previous layout as moved. It must be write-locked.
+    pub(crate) fn set_moved(&self, idx: usize) {
+        self.moved[idx].store(true, Ordering::Relaxed);
+    }
+}
+
+/// The layout following another, which is set once the collection is resharded while shared.
+pub(crate) struct NextLayout<T, L> {
+    ptr: AtomicPtr<Layout<T, L>>,
+    _marker: PhantomData<Option<Box<Layout<T, L>>>>,
+}
+
+impl<T, L> NextLayout<T, L> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn get(&self) -> Option<&Layout<T, L>> {
+        let layout = self.ptr.load(Ordering::Acquire);
+        // SAFETY: A set layout is only freed by `take` or on drop, which both take `self` mutably.
+        unsafe { layout.as_ref() }
+    }
+
+    /// Sets the next layout. Only the thread moving entries sets it, so it must not be set yet.
+    pub(crate) fn set(&self, layout: Box<Layout<T, L>>) -> &Layout<T, L> {
+        let layout = Box::into_raw(layout);
+        let old = self.ptr.swap(layout, Ordering::AcqRel);
+        debug_assert!(old.is_null(), "the next layout was already set");
+        // SAFETY: The layout was just leaked, and is only freed along with `self`.
+        unsafe { &*layout }
+    }
+
+    pub(crate) fn take(&mut self) -> Option<Box<Layout<T, L>>> {
+        let layout = mem::replace(self.ptr.get_mut(), ptr::null_mut());
+        // SAFETY: A set pointer always comes from `Box::into_raw`, and is now owned by us.
+        (!layout.is_null()).then(|| unsafe { Box::from_raw(layout) })
+    }
+}
+
+impl<T, L> Drop for NextLayout<T, L> {
+    fn drop(&mut self) {
+        // Drop the layouts one after another, rather than recursively.
+        let mut next = self.take();
+        while let Some(mut layout) = next {
+            next = layout.next.take();
+        }
+    }
+}
+
+/// Lets operations over all shards of the current layout run, or entries move to the next layout,
+/// but not both at once.
+///
+/// The count of operations in progress is kept in the low bits, and the top bit is set while entries are moving.
+pub(crate) struct Gate(AtomicUsize);
+
+const MOVING: usize = 1 << (usize::BITS - 1);
+
+impl Gate {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicUsize::new(0))
+    }
+
+    /// Waits for entries to stop moving, and keeps them from moving until the guard is dropped.
+    pub(crate) fn enter(&self) -> LayoutGuard<'_> {
+        let mut state = self.0.load(Ordering::Relaxed);
+        loop {
+            if state & MOVING != 0 {
+                wait();
+                state = self.0.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.0.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return LayoutGuard(self),
+                Err(actual) => state = actual,
+            }
+        }
+    }
+
+    /// Waits for the operations over all shards to end, and for other threads to stop moving entries,
+    /// then marks entries as moving until the guard is dropped.
+    pub(crate) fn start_moving(&self) -> MovingGuard<'_> {
+        while self
+            .0
+            .compare_exchange_weak(0, MOVING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            wait();
+        }
+        MovingGuard(self)
+    }
+}
+
+pub(crate) fn wait() {
+    #[cfg(feature = "std")]
+    std::thread::yield_now();
+    #[cfg(not(feature = "std"))]
+    core::hint::spin_loop();
+}
+
+/// Keeps the entries of a collection in the shards of its current layout while alive.
+pub(crate) struct LayoutGuard<'a>(&'a Gate);
+
+impl Clone for LayoutGuard<'_> {
+    fn clone(&self) -> Self {
+        // Entries cannot start moving while this guard is alive, so the count can be increased directly.
+        self.0 .0.fetch_add(1, Ordering::Relaxed);
+        Self(self.0)
+    }
+}
+
+impl Drop for LayoutGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Marks the entries of a collection as moving while alive.
+pub(crate) struct MovingGuard<'a>(&'a Gate);
+
+impl Drop for MovingGuard<'_> {
+    fn drop(&mut self) {
+        self.0 .0.store(0, Ordering::Release);
+    }
+}
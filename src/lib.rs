@@ -1,4 +1,5 @@
 #![doc = include_str!("../README.md")]
+#![cfg_attr(not(feature = "std"), no_std)]
 #![warn(
     unsafe_op_in_unsafe_fn,
     clippy::missing_safety_doc,
@@ -6,19 +7,39 @@
     clippy::undocumented_unsafe_blocks
 )]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod cache;
+pub mod guard;
 pub mod iter;
 pub mod iter_set;
+pub mod listener;
 pub mod mapref;
+pub mod scan;
 pub mod setref;
+pub mod shard_info;
+pub mod shard_selector;
+#[cfg(feature = "stats")]
+pub mod stats;
 pub mod tableref;
+pub mod transaction;
 pub mod try_result;
 
+#[cfg(feature = "deadlock-detection")]
+mod deadlock;
+#[cfg(feature = "std")]
+mod expiry;
+mod flight;
+mod layout;
 mod lock;
 mod map;
 mod read_only;
 mod set;
 mod sharded;
 mod table;
+#[cfg(test)]
+mod test_util;
 mod util;
 
 #[cfg(feature = "serde")]
@@ -31,16 +52,15 @@ pub mod rayon {
     pub mod set;
 }
 
-#[cfg(not(feature = "raw-api"))]
-use crate::lock::RwLock;
-
 #[cfg(feature = "raw-api")]
-pub use crate::lock::{RawRwLock, RwLock};
+pub use crate::lock::RwLock;
+pub use crate::lock::{RawRwLock, RawRwLockAsync};
 
 use crossbeam_utils::CachePadded;
 use hashbrown::hash_table;
-use std::sync::OnceLock;
 
+#[cfg(feature = "std")]
+pub use cache::ClashCache;
 pub use map::ClashMap;
 pub use mapref::entry::{Entry, OccupiedEntry, VacantEntry};
 pub use mapref::entry_ref::{EntryRef, VacantEntryRef};
@@ -51,7 +71,21 @@ pub use sharded::ClashCollection;
 pub use table::ClashTable;
 
 pub(crate) type HashMap<K, V> = hash_table::HashTable<(K, V)>;
-pub(crate) type Shard<K, V> = CachePadded<RwLock<HashMap<K, V>>>;
+pub(crate) type Shard<K, V, L = RawRwLock> = CachePadded<lock_api::RwLock<L, HashMap<K, V>>>;
+
+/// The hasher of maps and sets that are not given one.
+#[cfg(feature = "std")]
+pub(crate) type DefaultHashBuilder = std::collections::hash_map::RandomState;
+#[cfg(not(feature = "std"))]
+pub(crate) type DefaultHashBuilder = NoDefaultHasher;
+
+/// Stands in for the default hasher of [`ClashMap`] and [`ClashSet`] when the `std` feature is disabled.
+///
+/// Without `std` there is no source of randomness to seed a hasher with,
+/// so the hasher type has to be named, and a hasher passed to the constructor.
+#[cfg(not(feature = "std"))]
+#[derive(Debug)]
+pub enum NoDefaultHasher {}
 
 // Temporary reimplementation of [`std::collections::TryReserveError`]
 // util [`std::collections::TryReserveError`] stabilises.
@@ -61,8 +95,11 @@ pub(crate) type Shard<K, V> = CachePadded<RwLock<HashMap<K, V>>>;
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub struct TryReserveError {}
 
+#[cfg(feature = "std")]
 #[no_mangle]
 fn default_shard_amount() -> usize {
+    use std::sync::OnceLock;
+
     static DEFAULT_SHARD_AMOUNT: OnceLock<usize> = OnceLock::new();
     *DEFAULT_SHARD_AMOUNT.get_or_init(|| {
         (std::thread::available_parallelism().map_or(1, usize::from) * 4).next_power_of_two()
@@ -0,0 +1,137 @@
+//! Observing the changes made to a map.
+//!
+//! See [`ClashMap::with_listener`](crate::ClashMap::with_listener).
+
+#[cfg(feature = "std")]
+use crate::expiry::Expiries;
+
+/// A change made to a [`ClashMap`](crate::ClashMap), as seen by a [`Listener`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Event<'a, K, V> {
+    /// A key that was absent has been inserted.
+    Inserted { key: &'a K, value: &'a V },
+    /// The value of a key that was present has been replaced. `value` is the new value.
+    Replaced { key: &'a K, value: &'a V },
+    /// A key has been removed. `value` is the value it had.
+    Removed { key: &'a K, value: &'a V },
+    /// Every key of the shard has been removed at once.
+    Cleared,
+}
+
+/// Receives an [`Event`] for every change made to a [`ClashMap`](crate::ClashMap).
+///
+/// Events are delivered while the write lock of the shard is still held,
+/// so the events for any one key arrive in the exact order of the changes.
+/// This also means a listener must not access the map it is listening to, or it will deadlock.
+///
+/// Changes made in place through a mutable reference, such as the ones returned by
+/// [`get_mut`](crate::ClashMap::get_mut) or [`iter_mut`](crate::ClashMap::iter_mut), are not reported.
+///
+/// Any `Fn(usize, Event<'_, K, V>)` closure is a listener.
+pub trait Listener<K, V>: Send + Sync {
+    /// Called with the index of the shard that changed, and the change.
+    fn on_event(&self, shard: usize, event: Event<'_, K, V>);
+}
+
+impl<K, V, F> Listener<K, V> for F
+where
+    F: Fn(usize, Event<'_, K, V>) + Send + Sync,
+{
+    fn on_event(&self, shard: usize, event: Event<'_, K, V>) {
+        self(shard, event)
+    }
+}
+
+/// The listener of a map, together with the shard being changed.
+///
+/// It also drops the time-to-live of the keys that are inserted, removed or overwritten.
+pub(crate) struct Notifier<'a, K, V> {
+    listener: Option<&'a dyn Listener<K, V>>,
+    shard: usize,
+    #[cfg(feature = "std")]
+    expiries: Option<&'a Expiries<K>>,
+}
+
+impl<K, V> Clone for Notifier<'_, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for Notifier<'_, K, V> {}
+
+impl<'a, K, V> Notifier<'a, K, V> {
+    pub(crate) fn new(listener: Option<&'a dyn Listener<K, V>>, shard: usize) -> Self {
+        Self {
+            listener,
+            shard,
+            #[cfg(feature = "std")]
+            expiries: None,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn with_expiries(self, expiries: &'a Expiries<K>) -> Self {
+        Self {
+            expiries: Some(expiries),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "std")]
+    pub(crate) fn expiries(&self) -> Option<&'a Expiries<K>> {
+        self.expiries
+    }
+
+    /// Returns true if `key` was given a time-to-live that has run out.
+    pub(crate) fn is_expired(&self, key: &K) -> bool {
+        #[cfg(feature = "std")]
+        if let Some(expiries) = self.expiries {
+            return expiries.is_expired(key);
+        }
+        #[cfg(not(feature = "std"))]
+        let _ = key;
+        false
+    }
+
+    fn forget(&self, key: &K) {
+        #[cfg(feature = "std")]
+        if let Some(expiries) = self.expiries {
+            expiries.forget(key);
+        }
+        #[cfg(not(feature = "std"))]
+        let _ = key;
+    }
+
+    fn notify(&self, event: Event<'_, K, V>) {
+        if let Some(listener) = self.listener {
+            listener.on_event(self.shard, event);
+        }
+    }
+
+    pub(crate) fn inserted(&self, key: &K, value: &V) {
+        self.forget(key);
+        self.notify(Event::Inserted { key, value });
+    }
+
+    pub(crate) fn replaced(&self, key: &K, value: &V) {
+        self.notify(Event::Replaced { key, value });
+    }
+
+    /// The value of a key was replaced by a new one, rather than computed from the old one,
+    /// so the key loses its time-to-live.
+    pub(crate) fn overwritten(&self, key: &K, value: &V) {
+        self.forget(key);
+        self.notify(Event::Replaced { key, value });
+    }
+
+    pub(crate) fn removed(&self, key: &K, value: &V) {
+        self.forget(key);
+        self.notify(Event::Removed { key, value });
+    }
+
+    pub(crate) fn cleared(&self) {
+        self.notify(Event::Cleared);
+    }
+}
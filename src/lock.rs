@@ -1,17 +1,166 @@
+use alloc::vec::Vec;
+use core::ops::DerefMut;
 use core::sync::atomic::{AtomicUsize, Ordering};
-use parking_lot_core::{ParkToken, SpinWait, UnparkToken};
+use core::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use parking_lot_core::{ParkResult, ParkToken, SpinWait, UnparkToken};
+#[cfg(feature = "std")]
+use std::sync::{Mutex, PoisonError};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 pub type RwLock<T> = lock_api::RwLock<RawRwLock, T>;
-pub(crate) type RwLockReadGuardDetached<'a> = crate::util::RwLockReadGuardDetached<'a, RawRwLock>;
-pub(crate) type RwLockWriteGuardDetached<'a> = crate::util::RwLockWriteGuardDetached<'a, RawRwLock>;
+pub(crate) type RwLockReadGuardDetached<'a, L = RawRwLock> =
+    crate::util::RwLockReadGuardDetached<'a, L>;
+pub(crate) type RwLockWriteGuardDetached<'a, L = RawRwLock> =
+    crate::util::RwLockWriteGuardDetached<'a, L>;
+pub(crate) type RwLockUpgradableReadGuardDetached<'a, L = RawRwLock> =
+    crate::util::RwLockUpgradableReadGuardDetached<'a, L>;
 
-const READERS_PARKED: usize = 0b0001;
-const WRITERS_PARKED: usize = 0b0010;
-const ONE_READER: usize = 0b0100;
+const READERS_PARKED: usize = 0b00001;
+const WRITERS_PARKED: usize = 0b00010;
+// Set while an upgradable lock is held. The holder is also counted as a reader.
+const UPGRADABLE: usize = 0b00100;
+// Set while the upgradable holder is parked, waiting for the other readers to leave.
+const UPGRADING: usize = 0b01000;
+const ONE_READER: usize = 0b10000;
 const ONE_WRITER: usize = !(READERS_PARKED | WRITERS_PARKED);
 
+/// The ways in which a lock can be held.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum Mode {
+    Shared,
+    Upgradable,
+    Exclusive,
+}
+
+impl Mode {
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Mode::Shared => "read",
+            Mode::Upgradable => "upgradable read",
+            Mode::Exclusive => "write",
+        }
+    }
+}
+
 pub struct RawRwLock {
     state: AtomicUsize,
+    #[cfg(feature = "deadlock-detection")]
+    holders: crate::deadlock::Holders,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::Counters,
+}
+
+/// A raw lock that can also be acquired from async code, without blocking the thread.
+///
+/// # Safety
+///
+/// When a `poll_lock_*` method returns [`Poll::Ready`], the lock must be held in that mode,
+/// just as if the matching `lock_*` method of [`lock_api::RawRwLock`] had returned.
+pub unsafe trait RawRwLockAsync: lock_api::RawRwLock {
+    /// Attempts to acquire a shared lock.
+    ///
+    /// If the lock is held exclusively, the waker is registered and woken once the lock is released.
+    fn poll_lock_shared(&self, cx: &mut Context<'_>) -> Poll<()>;
+
+    /// Attempts to acquire an exclusive lock.
+    ///
+    /// If the lock is held, the waker is registered and woken once the lock is released.
+    fn poll_lock_exclusive(&self, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+// SAFETY: Both methods only return `Poll::Ready` once they have acquired the lock.
+unsafe impl RawRwLockAsync for RawRwLock {
+    fn poll_lock_shared(&self, cx: &mut Context<'_>) -> Poll<()> {
+        RawRwLock::poll_lock_shared(self, cx)
+    }
+
+    fn poll_lock_exclusive(&self, cx: &mut Context<'_>) -> Poll<()> {
+        RawRwLock::poll_lock_exclusive(self, cx)
+    }
+}
+
+#[cfg(feature = "stats")]
+impl crate::stats::RawRwLockStats for RawRwLock {
+    fn stats(&self) -> crate::stats::ShardStats {
+        self.stats.get()
+    }
+
+    fn reset_stats(&self) {
+        self.stats.reset()
+    }
+}
+
+/// When the lock has to wait for a deadline to pass.
+#[cfg(feature = "std")]
+type Deadline = Option<Instant>;
+/// Without `std` there are no timed locks, so there is never a deadline.
+#[cfg(not(feature = "std"))]
+type Deadline = Option<core::convert::Infallible>;
+
+#[cfg(feature = "std")]
+type AsyncWaiters = Mutex<Vec<(usize, Waker)>>;
+/// Without `std`, the waiters are guarded by a lock of this very kind, which then only spins.
+#[cfg(not(feature = "std"))]
+type AsyncWaiters = RwLock<Vec<(usize, Waker)>>;
+
+#[cfg(feature = "std")]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ASYNC_WAITERS: AsyncWaiters = Mutex::new(Vec::new());
+#[cfg(not(feature = "std"))]
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_ASYNC_WAITERS: AsyncWaiters =
+    RwLock::const_new(<RawRwLock as lock_api::RawRwLock>::INIT, Vec::new());
+
+/// Tasks waiting asynchronously for a lock, bucketed by the address of the lock.
+///
+/// Async waiters set the same parked bits as parked threads, so the unlock slow paths
+/// know to check here as well as in the parking lot.
+static ASYNC_WAITERS: [AsyncWaiters; 64] = [NO_ASYNC_WAITERS; 64];
+
+fn async_waiters(addr: usize) -> impl DerefMut<Target = Vec<(usize, Waker)>> {
+    // locks are usually cache padded, so skip the low bits which are often identical
+    let bucket = &ASYNC_WAITERS[(addr >> 7) % ASYNC_WAITERS.len()];
+    #[cfg(feature = "std")]
+    return bucket.lock().unwrap_or_else(PoisonError::into_inner);
+    #[cfg(not(feature = "std"))]
+    return bucket.write();
+}
+
+/// The spinning strategy of `parking_lot_core`, which cannot yield the thread without `std`.
+#[cfg(not(feature = "std"))]
+struct SpinWait {
+    counter: u32,
+}
+
+#[cfg(not(feature = "std"))]
+impl SpinWait {
+    fn new() -> Self {
+        Self { counter: 0 }
+    }
+
+    /// Spins for a little longer every time. Returns false once the caller should park instead.
+    fn spin(&mut self) -> bool {
+        if self.counter >= 10 {
+            return false;
+        }
+        self.counter += 1;
+        Self::relax(self.counter.min(3));
+        true
+    }
+
+    fn spin_no_yield(&mut self) {
+        self.counter = (self.counter + 1).min(10);
+        Self::relax(self.counter);
+    }
+
+    fn relax(exp: u32) {
+        for _ in 0..1 << exp {
+            core::hint::spin_loop();
+        }
+    }
 }
 
 // Safety:
@@ -20,15 +169,17 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
     #[allow(clippy::declare_interior_mutable_const)]
     const INIT: Self = Self {
         state: AtomicUsize::new(0),
+        #[cfg(feature = "deadlock-detection")]
+        holders: crate::deadlock::Holders::new(),
+        #[cfg(feature = "stats")]
+        stats: crate::stats::Counters::new(),
     };
 
     type GuardMarker = lock_api::GuardSend;
 
     #[inline]
     fn try_lock_exclusive(&self) -> bool {
-        self.state
-            .compare_exchange(0, ONE_WRITER, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
+        self.try_lock_exclusive_fast() && self.acquired(Mode::Exclusive)
     }
 
     #[inline]
@@ -36,14 +187,19 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
         if self
             .state
             .compare_exchange_weak(0, ONE_WRITER, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
+            .is_ok()
         {
-            self.lock_exclusive_slow();
+            self.acquired_fast();
+        } else {
+            self.check_reentrancy(Mode::Exclusive);
+            self.lock_exclusive_slow(None);
         }
+        self.acquired(Mode::Exclusive);
     }
 
     #[inline]
     unsafe fn unlock_exclusive(&self) {
+        self.released(Mode::Exclusive);
         if self
             .state
             .compare_exchange(ONE_WRITER, 0, Ordering::Release, Ordering::Relaxed)
@@ -55,48 +211,448 @@ unsafe impl lock_api::RawRwLock for RawRwLock {
 
     #[inline]
     fn try_lock_shared(&self) -> bool {
-        self.try_lock_shared_fast() || self.try_lock_shared_slow()
+        (self.try_lock_shared_fast() || self.try_lock_shared_slow()) && self.acquired(Mode::Shared)
     }
 
     #[inline]
     fn lock_shared(&self) {
         if !self.try_lock_shared_fast() {
-            self.lock_shared_slow();
+            self.check_reentrancy(Mode::Shared);
+            self.lock_shared_slow(None);
         }
+        self.acquired(Mode::Shared);
     }
 
     #[inline]
     unsafe fn unlock_shared(&self) {
+        self.released(Mode::Shared);
         let state = self.state.fetch_sub(ONE_READER, Ordering::Release);
 
         if state == (ONE_READER | WRITERS_PARKED) {
             self.unlock_shared_slow();
+        } else if state & UPGRADING != 0 {
+            self.wake_upgrader();
         }
     }
 }
 
+// Safety:
+// An upgradable lock excludes writers and other upgradable locks, but not readers.
+// It is only upgraded once all other readers have left.
+unsafe impl lock_api::RawRwLockUpgrade for RawRwLock {
+    #[inline]
+    fn lock_upgradable(&self) {
+        if !self.try_lock_upgradable_fast() {
+            self.check_reentrancy(Mode::Upgradable);
+            self.lock_upgradable_slow();
+        }
+        self.acquired(Mode::Upgradable);
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & UPGRADABLE == 0 {
+            let Some(new_state) = state.checked_add(ONE_READER) else {
+                return false;
+            };
+            match self.state.compare_exchange_weak(
+                state,
+                new_state | UPGRADABLE,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return self.acquired_fast() && self.acquired(Mode::Upgradable),
+                Err(e) => state = e,
+            }
+        }
+        false
+    }
+
+    #[inline]
+    unsafe fn unlock_upgradable(&self) {
+        self.released(Mode::Upgradable);
+        let state = self
+            .state
+            .fetch_sub(ONE_READER | UPGRADABLE, Ordering::Release);
+
+        if state & WRITERS_PARKED != 0 {
+            self.wake_writers();
+        }
+    }
+
+    #[inline]
+    unsafe fn upgrade(&self) {
+        if self
+            .state
+            .compare_exchange(
+                ONE_READER | UPGRADABLE,
+                ONE_WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            #[cfg(feature = "deadlock-detection")]
+            self.holders.check_upgrade();
+            self.upgrade_slow();
+        }
+        self.changed(Mode::Upgradable, Mode::Exclusive);
+    }
+
+    #[inline]
+    unsafe fn try_upgrade(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & !(READERS_PARKED | WRITERS_PARKED) == ONE_READER | UPGRADABLE {
+            match self.state.compare_exchange_weak(
+                state,
+                state | ONE_WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.changed(Mode::Upgradable, Mode::Exclusive);
+                    return true;
+                }
+                Err(e) => state = e,
+            }
+        }
+        false
+    }
+}
+
+// Safety:
+// Both downgrades keep the lock held in a weaker mode, and wake the waiters that may now proceed.
+unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawRwLock {
+    #[inline]
+    unsafe fn downgrade_upgradable(&self) {
+        self.changed(Mode::Upgradable, Mode::Shared);
+        let state = self.state.fetch_and(!UPGRADABLE, Ordering::Release);
+
+        if state & WRITERS_PARKED != 0 {
+            self.wake_writers();
+        }
+    }
+
+    #[inline]
+    unsafe fn downgrade_to_upgradable(&self) {
+        self.changed(Mode::Exclusive, Mode::Upgradable);
+        let state = self
+            .state
+            .fetch_and(ONE_READER | UPGRADABLE | WRITERS_PARKED, Ordering::Release);
+
+        if state & READERS_PARKED != 0 {
+            self.unpark_all(1);
+            self.wake_async();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+// Safety:
+// The timed lock methods only return true once the respective lock is held,
+// same as the untimed methods above.
+unsafe impl lock_api::RawRwLockTimed for RawRwLock {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        (self.try_lock_shared_fast() || self.lock_shared_slow(Instant::now().checked_add(timeout)))
+            && self.acquired(Mode::Shared)
+    }
+
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Instant) -> bool {
+        (self.try_lock_shared_fast() || self.lock_shared_slow(Some(timeout)))
+            && self.acquired(Mode::Shared)
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        (self.try_lock_exclusive_fast()
+            || self.lock_exclusive_slow(Instant::now().checked_add(timeout)))
+            && self.acquired(Mode::Exclusive)
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+        (self.try_lock_exclusive_fast() || self.lock_exclusive_slow(Some(timeout)))
+            && self.acquired(Mode::Exclusive)
+    }
+}
+
 // Safety:
 // `lock_api::RawRwLockDowngrade` has no explicit safety requirements,
 // so I will assume it just requires the `downgrade` be implemented correctly.
 unsafe impl lock_api::RawRwLockDowngrade for RawRwLock {
     #[inline]
     unsafe fn downgrade(&self) {
+        self.changed(Mode::Exclusive, Mode::Shared);
+
         let state = self
             .state
             .fetch_and(ONE_READER | WRITERS_PARKED, Ordering::Release);
         if state & READERS_PARKED != 0 {
-            // SAFETY:
-            // 1. We call unpark with an address that we control.
-            unsafe {
-                parking_lot_core::unpark_all((self as *const _ as usize) + 1, UnparkToken(0));
-            }
+            self.unpark_all(1);
+            self.wake_async();
         }
     }
 }
 
 impl RawRwLock {
+    /// Tells the lock the index of the shard it guards, after it was created with [`INIT`](lock_api::RawRwLock::INIT).
+    ///
+    /// The index is only used to report re-entrant locking with the `deadlock-detection` feature.
+    #[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+    pub(crate) fn set_shard(&self, idx: usize) {
+        #[cfg(feature = "deadlock-detection")]
+        self.holders.set_shard(idx);
+    }
+
+    /// Panics if blocking on this lock would deadlock the current thread.
+    #[inline(always)]
+    #[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+    fn check_reentrancy(&self, mode: Mode) {
+        #[cfg(feature = "deadlock-detection")]
+        self.holders.check(mode);
+    }
+
+    /// Records that the lock was acquired. Always returns true, for use in boolean chains.
+    #[inline(always)]
+    #[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+    fn acquired(&self, mode: Mode) -> bool {
+        #[cfg(feature = "deadlock-detection")]
+        self.holders.acquired(mode);
+        true
+    }
+
+    /// Records that the lock is about to be released.
+    #[inline(always)]
+    #[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+    fn released(&self, mode: Mode) {
+        #[cfg(feature = "deadlock-detection")]
+        self.holders.released(mode);
+    }
+
+    /// Records that the lock was upgraded or downgraded.
+    #[inline(always)]
+    #[cfg_attr(not(feature = "deadlock-detection"), allow(unused_variables))]
+    fn changed(&self, from: Mode, to: Mode) {
+        #[cfg(feature = "deadlock-detection")]
+        self.holders.changed(from, to);
+    }
+
+    /// Records that the lock was acquired without waiting. Always returns true, for use in boolean chains.
+    #[inline(always)]
+    fn acquired_fast(&self) -> bool {
+        #[cfg(feature = "stats")]
+        self.stats.fast_acquisition();
+        true
+    }
+
+    /// Records a spin iteration while waiting for the lock.
+    #[inline(always)]
+    fn spun(&self) {
+        #[cfg(feature = "stats")]
+        self.stats.spin();
+    }
+
+    /// Calls `park` to park the thread while waiting to lock in `mode`, and records how long it was parked.
+    #[inline(always)]
+    #[cfg_attr(
+        not(feature = "stats"),
+        allow(unused_variables, clippy::let_and_return)
+    )]
+    fn timed_park<R>(&self, mode: Mode, park: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "stats")]
+        let start = Instant::now();
+        let result = park();
+        #[cfg(feature = "stats")]
+        self.stats.parked(mode, start.elapsed());
+        result
+    }
+
+    /// Parks the thread on the key `self + offset` while `validate` returns true,
+    /// until it is unparked through the same key. Returns false if the deadline passed first.
+    #[cfg(feature = "std")]
+    fn park(&self, offset: usize, validate: impl Fn() -> bool, deadline: Deadline) -> bool {
+        // SAFETY:
+        // 1. We call park with an address that we control.
+        // 2. `validate` will not panic.
+        // 3. `before_sleep` and `timed_out` are no-ops.
+        let result = unsafe {
+            parking_lot_core::park(
+                (self as *const _ as usize) + offset,
+                validate,
+                || {},
+                |_, _| {},
+                ParkToken(0),
+                deadline,
+            )
+        };
+        !matches!(result, ParkResult::TimedOut)
+    }
+
+    /// Without `std` threads cannot be parked, so this spins until `validate` returns false.
+    ///
+    /// Every unpark follows a change of the state that makes `validate` fail,
+    /// so this returns no later than a parked thread would be woken.
+    #[cfg(not(feature = "std"))]
+    fn park(&self, _offset: usize, validate: impl Fn() -> bool, _deadline: Deadline) -> bool {
+        while validate() {
+            core::hint::spin_loop();
+        }
+        true
+    }
+
+    /// Wakes all threads parked on the key `self + offset`.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn unpark_all(&self, offset: usize) {
+        #[cfg(feature = "std")]
+        // SAFETY:
+        // 1. We call unpark with an address that we control.
+        unsafe {
+            parking_lot_core::unpark_all((self as *const _ as usize) + offset, UnparkToken(0));
+        }
+    }
+
+    /// Wakes one thread parked on the key `self + offset`.
+    #[cfg_attr(not(feature = "std"), allow(unused_variables))]
+    fn unpark_one(&self, offset: usize) {
+        #[cfg(feature = "std")]
+        // SAFETY:
+        // 1. We call unpark with an address that we control.
+        // 2. `callback` will not panic.
+        unsafe {
+            parking_lot_core::unpark_one((self as *const _ as usize) + offset, |_| UnparkToken(0));
+        }
+    }
+
+    #[inline(always)]
+    fn try_lock_exclusive_fast(&self) -> bool {
+        self.state
+            .compare_exchange(0, ONE_WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+            && self.acquired_fast()
+    }
+
+    /// Attempts to acquire an exclusive lock without blocking the thread.
+    ///
+    /// If the lock is currently held, the waker is registered and woken once the lock is released.
+    pub(crate) fn poll_lock_exclusive(&self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if self.try_lock_exclusive_parked() {
+                self.acquired(Mode::Exclusive);
+                return Poll::Ready(());
+            }
+            if self.register_async(cx.waker(), WRITERS_PARKED, |state| state & ONE_WRITER != 0) {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Attempts to acquire a shared lock without blocking the thread.
+    ///
+    /// If the lock is currently held exclusively, the waker is registered and woken once the lock is released.
+    pub(crate) fn poll_lock_shared(&self, cx: &mut Context<'_>) -> Poll<()> {
+        use lock_api::RawRwLock;
+        loop {
+            if self.try_lock_shared() {
+                return Poll::Ready(());
+            }
+            if self.register_async(cx.waker(), READERS_PARKED, |state| {
+                state & ONE_WRITER == ONE_WRITER
+            }) {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    /// Like `try_lock_exclusive`, but also succeeds if the lock is free and has parked waiters,
+    /// leaving the parked bits set so the waiters are woken on unlock.
+    fn try_lock_exclusive_parked(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & ONE_WRITER == 0 {
+            match self.state.compare_exchange_weak(
+                state,
+                state | ONE_WRITER,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(e) => state = e,
+            }
+        }
+        false
+    }
+
+    /// Registers the waker to be woken on the next unlock, and sets the `parked` bit
+    /// so that the unlock takes the slow path.
+    ///
+    /// Returns false without registering if the lock is no longer `blocked`,
+    /// in which case the caller should try to acquire it again.
+    #[cold]
+    fn register_async(
+        &self,
+        waker: &Waker,
+        parked: usize,
+        blocked: impl Fn(usize) -> bool,
+    ) -> bool {
+        let addr = self as *const _ as usize;
+
+        // Holding the bucket lock while setting the parked bit ensures that
+        // an unlocker which observes the bit also observes our waker.
+        let mut waiters = async_waiters(addr);
+
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & parked == 0 {
+            if !blocked(state) {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state | parked,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(e) => state = e,
+            }
+        }
+        if !blocked(self.state.load(Ordering::Relaxed)) {
+            return false;
+        }
+
+        if !waiters
+            .iter()
+            .any(|(a, w)| *a == addr && w.will_wake(waker))
+        {
+            waiters.push((addr, waker.clone()));
+        }
+        true
+    }
+
+    /// Wakes all tasks waiting asynchronously for this lock.
     #[cold]
-    fn lock_exclusive_slow(&self) {
+    fn wake_async(&self) {
+        let addr = self as *const _ as usize;
+
+        let mut wakers = Vec::new();
+        async_waiters(addr).retain(|(a, waker)| {
+            if *a == addr {
+                wakers.push(waker.clone());
+            }
+            *a != addr
+        });
+
+        // wake outside of the bucket lock, in case the executor polls the task inline.
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Returns false if the timeout elapsed before the lock could be acquired.
+    #[cold]
+    fn lock_exclusive_slow(&self, timeout: Deadline) -> bool {
         let mut acquire_with = 0;
         loop {
             let mut spin = SpinWait::new();
@@ -110,13 +666,14 @@ impl RawRwLock {
                         Ordering::Acquire,
                         Ordering::Relaxed,
                     ) {
-                        Ok(_) => return,
+                        Ok(_) => return true,
                         Err(e) => state = e,
                     }
                 }
 
                 if state & WRITERS_PARKED == 0 {
                     if spin.spin() {
+                        self.spun();
                         state = self.state.load(Ordering::Relaxed);
                         continue;
                     }
@@ -132,23 +689,22 @@ impl RawRwLock {
                     }
                 }
 
-                // SAFETY:
-                // 1. We call park with an address that we control.
-                // 2. `validate` will not panic.
-                // 3. `before_sleep` and `timed_out` are no-ops.
-                let _ = unsafe {
-                    parking_lot_core::park(
-                        self as *const _ as usize,
+                let woken = self.timed_park(Mode::Exclusive, || {
+                    self.park(
+                        0,
                         || {
                             let state = self.state.load(Ordering::Relaxed);
                             (state & ONE_WRITER != 0) && (state & WRITERS_PARKED != 0)
                         },
-                        || {},
-                        |_, _| {},
-                        ParkToken(0),
-                        None,
+                        timeout,
                     )
-                };
+                });
+
+                // We leave `WRITERS_PARKED` set, even if we were the last parked thread.
+                // Other waiters may rely on it, and a spurious slow unlock is harmless.
+                if !woken {
+                    return false;
+                }
 
                 acquire_with = WRITERS_PARKED;
                 break;
@@ -180,21 +736,14 @@ impl RawRwLock {
         }
 
         if parked == READERS_PARKED {
-            // SAFETY:
-            // 1. We call unpark with an address that we control.
-            return unsafe {
-                parking_lot_core::unpark_all((self as *const _ as usize) + 1, UnparkToken(0));
-            };
+            self.unpark_all(1);
+            return self.wake_async();
         }
 
         assert_eq!(parked, WRITERS_PARKED);
 
-        // SAFETY:
-        // 1. We call unpark with an address that we control.
-        // 2. `callback` will not panic.
-        unsafe {
-            parking_lot_core::unpark_one(self as *const _ as usize, |_| UnparkToken(0));
-        }
+        self.unpark_one(0);
+        self.wake_async();
     }
 
     #[inline(always)]
@@ -206,7 +755,8 @@ impl RawRwLock {
                 return self
                     .state
                     .compare_exchange_weak(state, new_state, Ordering::Acquire, Ordering::Relaxed)
-                    .is_ok();
+                    .is_ok()
+                    && self.acquired_fast();
             }
         }
 
@@ -228,7 +778,7 @@ impl RawRwLock {
                 Ordering::Acquire,
                 Ordering::Relaxed,
             ) {
-                Ok(_) => return true,
+                Ok(_) => return self.acquired_fast(),
                 Err(e) => state = e,
             }
         }
@@ -236,8 +786,9 @@ impl RawRwLock {
         false
     }
 
+    /// Returns false if the timeout elapsed before the lock could be acquired.
     #[cold]
-    fn lock_shared_slow(&self) {
+    fn lock_shared_slow(&self, timeout: Deadline) -> bool {
         loop {
             let mut spin = SpinWait::new();
             let mut state = self.state.load(Ordering::Relaxed);
@@ -261,15 +812,17 @@ impl RawRwLock {
                         )
                         .is_ok()
                     {
-                        return;
+                        return true;
                     }
 
                     backoff.spin_no_yield();
+                    self.spun();
                     state = self.state.load(Ordering::Relaxed);
                 }
 
                 if state & READERS_PARKED == 0 {
                     if spin.spin() {
+                        self.spun();
                         state = self.state.load(Ordering::Relaxed);
                         continue;
                     }
@@ -285,29 +838,196 @@ impl RawRwLock {
                     }
                 }
 
-                // SAFETY:
-                // 1. We call park with an address that we control.
-                // 2. `validate` will not panic.
-                // 3. `before_sleep` and `timed_out` are no-ops.
-                let _ = unsafe {
-                    parking_lot_core::park(
-                        (self as *const _ as usize) + 1,
+                let woken = self.timed_park(Mode::Shared, || {
+                    self.park(
+                        1,
                         || {
                             let state = self.state.load(Ordering::Relaxed);
                             (state & ONE_WRITER == ONE_WRITER) && (state & READERS_PARKED != 0)
                         },
-                        || {},
-                        |_, _| {},
-                        ParkToken(0),
-                        None,
+                        timeout,
                     )
-                };
+                });
+
+                // We leave `READERS_PARKED` set, even if we were the last parked thread.
+                // Other waiters may rely on it, and a spurious slow unlock is harmless.
+                if !woken {
+                    return false;
+                }
 
                 break;
             }
         }
     }
 
+    #[inline(always)]
+    fn try_lock_upgradable_fast(&self) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+
+        if state & UPGRADABLE == 0 {
+            if let Some(new_state) = state.checked_add(ONE_READER) {
+                return self
+                    .state
+                    .compare_exchange_weak(
+                        state,
+                        new_state | UPGRADABLE,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+                    && self.acquired_fast();
+            }
+        }
+
+        false
+    }
+
+    /// Upgradable readers park alongside the writers, as they are blocked by the same locks.
+    #[cold]
+    fn lock_upgradable_slow(&self) {
+        let mut acquire_with = 0;
+        loop {
+            let mut spin = SpinWait::new();
+            let mut state = self.state.load(Ordering::Relaxed);
+
+            loop {
+                while state & UPGRADABLE == 0 {
+                    let new_state = state
+                        .checked_add(ONE_READER)
+                        .expect("reader count overflowed");
+                    match self.state.compare_exchange_weak(
+                        state,
+                        new_state | UPGRADABLE | acquire_with,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return,
+                        Err(e) => state = e,
+                    }
+                }
+
+                if state & WRITERS_PARKED == 0 {
+                    if spin.spin() {
+                        self.spun();
+                        state = self.state.load(Ordering::Relaxed);
+                        continue;
+                    }
+
+                    if let Err(e) = self.state.compare_exchange_weak(
+                        state,
+                        state | WRITERS_PARKED,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        state = e;
+                        continue;
+                    }
+                }
+
+                self.timed_park(Mode::Upgradable, || {
+                    self.park(
+                        0,
+                        || {
+                            let state = self.state.load(Ordering::Relaxed);
+                            (state & UPGRADABLE != 0) && (state & WRITERS_PARKED != 0)
+                        },
+                        None,
+                    );
+                });
+
+                acquire_with = WRITERS_PARKED;
+                break;
+            }
+        }
+    }
+
+    /// Waits for all other readers to leave, then turns the upgradable lock into an exclusive lock.
+    #[cold]
+    fn upgrade_slow(&self) {
+        let mut spin = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+
+        loop {
+            if state & !(READERS_PARKED | WRITERS_PARKED | UPGRADING) == ONE_READER | UPGRADABLE {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | ONE_WRITER,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return,
+                    Err(e) => {
+                        state = e;
+                        continue;
+                    }
+                }
+            }
+
+            if state & UPGRADING == 0 {
+                if spin.spin() {
+                    self.spun();
+                    state = self.state.load(Ordering::Relaxed);
+                    continue;
+                }
+
+                if let Err(e) = self.state.compare_exchange_weak(
+                    state,
+                    state | UPGRADING,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = e;
+                    continue;
+                }
+            }
+
+            self.timed_park(Mode::Exclusive, || {
+                self.park(
+                    2,
+                    || {
+                        let state = self.state.load(Ordering::Relaxed);
+                        (state & UPGRADING != 0)
+                            && (state & !(READERS_PARKED | WRITERS_PARKED | UPGRADING)
+                                != ONE_READER | UPGRADABLE)
+                    },
+                    None,
+                );
+            });
+
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    /// Wakes the upgradable holder if it is waiting to upgrade and all other readers have left.
+    #[cold]
+    fn wake_upgrader(&self) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while state & !(READERS_PARKED | WRITERS_PARKED) == ONE_READER | UPGRADABLE | UPGRADING {
+            match self.state.compare_exchange_weak(
+                state,
+                state & !UPGRADING,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    self.unpark_one(2);
+                    return;
+                }
+                Err(e) => state = e,
+            }
+        }
+    }
+
+    /// Wakes all parked writers and upgradable readers after an upgradable lock was released.
+    ///
+    /// We cannot tell which of them can now proceed, so they all try again.
+    /// `WRITERS_PARKED` stays set, and is cleared by the next slow unlock that finds no waiters.
+    #[cold]
+    fn wake_writers(&self) {
+        self.unpark_all(0);
+        self.wake_async();
+    }
+
     #[cold]
     fn unlock_shared_slow(&self) {
         if self
@@ -315,12 +1035,8 @@ impl RawRwLock {
             .compare_exchange(WRITERS_PARKED, 0, Ordering::Relaxed, Ordering::Relaxed)
             .is_ok()
         {
-            // SAFETY:
-            // 1. We call unpark with an address that we control.
-            // 2. `callback` will not panic.
-            unsafe {
-                parking_lot_core::unpark_one(self as *const _ as usize, |_| UnparkToken(0));
-            }
+            self.unpark_one(0);
+            self.wake_async();
         }
     }
 }
@@ -359,6 +1075,69 @@ mod tests {
         assert_eq!(*r, 2);
     }
 
+    #[test]
+    fn timed_out() {
+        let lock = super::RwLock::new(1);
+
+        let r = lock.read();
+        assert!(lock.try_write_for(Duration::from_millis(50)).is_none());
+        assert!(lock.try_read_for(Duration::from_millis(50)).is_some());
+        drop(r);
+
+        let w = lock.write();
+        assert!(lock.try_read_for(Duration::from_millis(50)).is_none());
+        assert!(lock.try_write_for(Duration::from_millis(50)).is_none());
+        drop(w);
+
+        // the lock is still usable after waiters have timed out
+        *lock.try_write_for(Duration::from_millis(50)).unwrap() = 2;
+        assert_eq!(*lock.try_read_for(Duration::from_millis(50)).unwrap(), 2);
+    }
+
+    #[test]
+    fn timed_acquire() {
+        let lock = super::RwLock::new(1);
+
+        thread::scope(|s| {
+            let mut w = lock.write();
+            s.spawn(|| {
+                let r = lock.try_read_for(Duration::from_secs(10));
+                assert_eq!(*r.unwrap(), 2);
+            });
+            thread::sleep(Duration::from_millis(100));
+            *w = 2;
+        });
+    }
+
+    #[test]
+    fn upgradable_contention() {
+        use lock_api::RwLockUpgradableReadGuard;
+
+        let lock = super::RwLock::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let u = lock.upgradable_read();
+                        let old = *u;
+                        let mut w = RwLockUpgradableReadGuard::upgrade(u);
+                        assert_eq!(*w, old, "value changed while upgrading");
+                        *w += 1;
+                    }
+                });
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        drop(lock.read());
+                        *lock.write() += 1;
+                    }
+                });
+            }
+        });
+
+        assert_eq!(*lock.read(), 8000);
+    }
+
     #[test]
     fn force_reader_wait() {
         let lock = super::RwLock::new(1);
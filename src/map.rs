@@ -1,20 +1,44 @@
-use crate::iter::{Iter, IterMut, OwningIter};
+#[cfg(feature = "std")]
+use crate::cache::Clock;
+#[cfg(feature = "std")]
+use crate::default_shard_amount;
+#[cfg(feature = "std")]
+use crate::expiry::Expiries;
+use crate::flight::Flights;
+use crate::iter::{
+    Drain, DrainMut, EntryStream, ExtractIf, ExtractIfMut, Iter, IterMut, OwningIter,
+};
+use crate::listener::{Listener, Notifier};
+use crate::lock::{RawRwLock, RawRwLockAsync};
 use crate::mapref::entry_ref::{EntryRef, VacantEntryRef};
 use crate::mapref::entrymut::{EntryMut, OccupiedEntryMut, VacantEntryMut};
-use crate::mapref::multiple::RefMulti;
-use crate::mapref::one::{Ref, RefMut};
+use crate::mapref::multiple::{RefMulti, RefMutMulti};
+use crate::mapref::one::{Ref, RefMut, RefUpgradable};
+use crate::mapref::owned::{ArcRef, ArcRefMut};
+use crate::scan::Cursor;
+use crate::shard_info::ShardInfo;
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
 use crate::try_result::TryResult;
+use crate::util::replace_with_or_abort;
+#[cfg(not(feature = "std"))]
+use crate::NoDefaultHasher as RandomState;
 use crate::{
-    default_shard_amount, ClashTable, Entry, OccupiedEntry, ReadOnlyView, TryReserveError,
-    VacantEntry,
+    tableref, ClashTable, Entry, HashMap, OccupiedEntry, ReadOnlyView, TryReserveError, VacantEntry,
 };
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::Infallible;
 use core::fmt;
 use core::hash::{BuildHasher, Hash, Hasher};
+#[cfg(feature = "std")]
 use core::iter::FromIterator;
 use core::ops::{BitAnd, BitOr, Shl, Shr, Sub};
-use hashbrown::Equivalent;
-use replace_with::replace_with_or_abort;
+use core::{iter, mem};
+use hashbrown::{hash_table, Equivalent};
+#[cfg(feature = "std")]
 use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 /// ClashMap is an implementation of a concurrent associative array/hashmap in Rust.
 ///
@@ -25,32 +49,57 @@ use std::collections::hash_map::RandomState;
 /// To accomplish this, all methods take `&self` instead of modifying methods taking `&mut self`.
 /// This allows you to put a ClashMap in an `Arc<T>` and share it between threads while being able to modify it.
 ///
+/// Entries can be given a time-to-live with [`insert_with_ttl`](ClashMap::insert_with_ttl) or
+/// [`OccupiedEntry::set_expiry`], after which lookups treat them as absent.
+/// See [`insert_with_ttl`](ClashMap::insert_with_ttl) for the details.
+///
 /// Documentation mentioning locking behaviour acts in the reference frame of the calling thread.
 /// This means that it is safe to ignore it across multiple threads.
-pub struct ClashMap<K, V, S = RandomState> {
-    pub(crate) table: ClashTable<(K, V)>,
+pub struct ClashMap<K, V, S = RandomState, L = RawRwLock, Sel = DefaultShardSelector> {
+    pub(crate) table: ClashTable<(K, V), L, Sel>,
     pub(crate) hasher: S,
+    pub(crate) listener: Option<Arc<dyn Listener<K, V>>>,
+    pub(crate) flights: Flights,
+    #[cfg(feature = "std")]
+    pub(crate) expiries: Expiries<K>,
 }
 
-impl<K: Clone, V: Clone, S: Clone> Clone for ClashMap<K, V, S> {
+impl<K: Clone, V: Clone, S: Clone, L: lock_api::RawRwLock, Sel: Clone> Clone
+    for ClashMap<K, V, S, L, Sel>
+{
     fn clone(&self) -> Self {
         Self {
             table: self.table.clone(),
             hasher: self.hasher.clone(),
+            listener: self.listener.clone(),
+            flights: Flights::new(),
+            #[cfg(feature = "std")]
+            expiries: self.expiries.clone(),
         }
     }
 }
 
-impl<K, V, S> Default for ClashMap<K, V, S>
+#[cfg(feature = "std")]
+impl<K, V, S, L, Sel> Default for ClashMap<K, V, S, L, Sel>
 where
     K: Eq + Hash,
     S: Default + BuildHasher,
+    L: lock_api::RawRwLock,
+    Sel: Default,
 {
     fn default() -> Self {
-        Self::with_hasher(Default::default())
+        Self {
+            table: ClashTable::default(),
+            hasher: Default::default(),
+            listener: None,
+            flights: Flights::new(),
+            #[cfg(feature = "std")]
+            expiries: Expiries::new(),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl<K, V> ClashMap<K, V, RandomState> {
     /// Creates a new ClashMap with a capacity of 0.
     ///
@@ -123,7 +172,9 @@ impl<K, V> ClashMap<K, V, RandomState> {
 }
 
 #[cfg(feature = "raw-api")]
-impl<K: Eq + Hash, V, S: BuildHasher> ClashMap<K, V, S> {
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    ClashMap<K, V, S, L, Sel>
+{
     /// Allows you to peek at the inner shards that store your data.
     /// You should probably not use this unless you know what you are doing.
     ///
@@ -137,7 +188,7 @@ impl<K: Eq + Hash, V, S: BuildHasher> ClashMap<K, V, S> {
     /// let map = ClashMap::<(), ()>::new();
     /// println!("Amount of shards: {}", map.shards().len());
     /// ```
-    pub fn shards(&self) -> &[crate::Shard<K, V>] {
+    pub fn shards(&self) -> &[crate::Shard<K, V, L>] {
         self.table.shards()
     }
 
@@ -165,7 +216,7 @@ impl<K: Eq + Hash, V, S: BuildHasher> ClashMap<K, V, S> {
     /// map.shards_mut()[shard_ind].get_mut().insert_unique(hash, data, hasher);
     /// assert_eq!(*map.get(&42).unwrap(), "forty two");
     /// ```
-    pub fn shards_mut(&mut self) -> &mut [crate::Shard<K, V>] {
+    pub fn shards_mut(&mut self) -> &mut [crate::Shard<K, V, L>] {
         self.table.shards_mut()
     }
 
@@ -175,7 +226,7 @@ impl<K: Eq + Hash, V, S: BuildHasher> ClashMap<K, V, S> {
     /// Requires the `raw-api` feature to be enabled.
     ///
     /// See [`ClashMap::shards()`] and [`ClashMap::shards_mut()`] for more information.
-    pub fn into_shards(self) -> Box<[crate::Shard<K, V>]> {
+    pub fn into_shards(self) -> alloc::boxed::Box<[crate::Shard<K, V, L>]> {
         self.table.into_shards()
     }
 
@@ -221,91 +272,66 @@ impl<K: Eq + Hash, V, S: BuildHasher> ClashMap<K, V, S> {
     }
 }
 
-impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
-    /// Wraps this `ClashMap` into a read-only view. This view allows to obtain raw references to the stored values.
-    pub fn into_read_only(self) -> ReadOnlyView<K, V, S> {
-        ReadOnlyView::new(self)
-    }
-
-    /// Creates a new ClashMap with a capacity of 0 and the provided hasher.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use clashmap::ClashMap;
-    /// use std::collections::hash_map::RandomState;
+impl<K, V, S: BuildHasher, L: lock_api::RawRwLock> ClashMap<K, V, S, L> {
+    /// Creates a new ClashMap with a specified starting capacity and hasher,
+    /// that guards its shards with the lock `L`.
     ///
-    /// let s = RandomState::new();
-    /// let reviews = ClashMap::with_hasher(s);
-    /// reviews.insert("Veloren", "What a fantastic game!");
-    /// ```
-    pub fn with_hasher(hasher: S) -> Self {
-        Self::with_capacity_and_hasher(0, hasher)
-    }
-
-    /// Creates a new ClashMap with a specified starting capacity and hasher.
+    /// Any [`lock_api::RawRwLock`] can be used, such as the one of `parking_lot` or a spin lock.
+    /// The `async` methods need a lock that implements [`RawRwLockAsync`], and
+    /// [`get_upgradable`](ClashMap::get_upgradable) and the timed `try_*` methods
+    /// need a lock that supports them.
     ///
     /// # Examples
     ///
     /// ```
-    /// use clashmap::ClashMap;
+    /// use clashmap::{ClashMap, RawRwLock};
     /// use std::collections::hash_map::RandomState;
     ///
-    /// let s = RandomState::new();
-    /// let mappings = ClashMap::with_capacity_and_hasher(2, s);
-    /// mappings.insert(2, 4);
-    /// mappings.insert(8, 16);
+    /// let map: ClashMap<_, _, _, RawRwLock> =
+    ///     ClashMap::with_capacity_and_hasher_and_lock(2, RandomState::new());
+    /// map.insert("Johnny", 21);
+    /// assert_eq!(*map.get("Johnny").unwrap(), 21);
     /// ```
-    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
-        Self::with_capacity_and_hasher_and_shard_amount(capacity, hasher, default_shard_amount())
+    #[cfg(feature = "std")]
+    pub fn with_capacity_and_hasher_and_lock(capacity: usize, hasher: S) -> Self {
+        Self::with_capacity_and_hasher_and_shard_amount_and_lock(
+            capacity,
+            hasher,
+            default_shard_amount(),
+        )
     }
 
-    /// Creates a new ClashMap with a specified hasher and shard amount
-    ///
-    /// shard_amount should be greater than 0 and a power of two.
-    /// If a shard_amount which is not a power of two is provided, the function will panic.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use clashmap::ClashMap;
-    /// use std::collections::hash_map::RandomState;
+    /// Creates a new ClashMap with a specified starting capacity, hasher and shard amount,
+    /// that guards its shards with the lock `L`.
     ///
-    /// let s = RandomState::new();
-    /// let mappings = ClashMap::with_hasher_and_shard_amount(s, 32);
-    /// mappings.insert(2, 4);
-    /// mappings.insert(8, 16);
-    /// ```
-    pub fn with_hasher_and_shard_amount(hasher: S, shard_amount: usize) -> Self {
-        Self::with_capacity_and_hasher_and_shard_amount(0, hasher, shard_amount)
-    }
-
-    /// Creates a new ClashMap with a specified starting capacity, hasher and shard_amount.
+    /// See [`with_capacity_and_hasher_and_lock`](ClashMap::with_capacity_and_hasher_and_lock)
+    /// for the locks that can be used.
     ///
     /// shard_amount should greater than 0 and be a power of two.
     /// If a shard_amount which is not a power of two is provided, the function will panic.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use clashmap::ClashMap;
-    /// use std::collections::hash_map::RandomState;
-    ///
-    /// let s = RandomState::new();
-    /// let mappings = ClashMap::with_capacity_and_hasher_and_shard_amount(2, s, 32);
-    /// mappings.insert(2, 4);
-    /// mappings.insert(8, 16);
-    /// ```
-    pub fn with_capacity_and_hasher_and_shard_amount(
+    pub fn with_capacity_and_hasher_and_shard_amount_and_lock(
         capacity: usize,
         hasher: S,
         shard_amount: usize,
     ) -> Self {
         Self {
-            table: ClashTable::with_capacity_and_shard_amount(capacity, shard_amount),
+            table: ClashTable::with_capacity_and_shard_amount_and_lock(capacity, shard_amount),
             hasher,
+            listener: None,
+            flights: Flights::new(),
+            #[cfg(feature = "std")]
+            expiries: Expiries::new(),
         }
     }
+}
+
+impl<K, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Wraps this `ClashMap` into a read-only view. This view allows to obtain raw references to the stored values.
+    ///
+    /// Entries whose time-to-live has run out are left out of the view, and the others no longer expire.
+    pub fn into_read_only(self) -> ReadOnlyView<K, V, S, Sel> {
+        ReadOnlyView::new(self)
+    }
 
     /// Hash a given item to produce a usize.
     /// Uses the provided or default HashBuilder.
@@ -313,7 +339,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         self.hash_u64(item) as usize
     }
 
-    fn hash_u64<T: Hash>(&self, item: &T) -> u64 {
+    pub(crate) fn hash_u64<T: Hash>(&self, item: &T) -> u64 {
         let mut hasher = self.hasher.build_hasher();
         item.hash(&mut hasher);
         hasher.finish()
@@ -337,6 +363,75 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         &self.hasher
     }
 
+    /// Sets a listener that is told about every change made to the map, and returns the map.
+    ///
+    /// The listener receives the index of the shard that changed, and an [`Event`] with
+    /// references to the key and value involved. It is called while the write lock of the shard
+    /// is still held, so the events for any one key are seen in the exact order of the changes.
+    ///
+    /// See [`Listener`] for the changes that are not reported.
+    /// The listener is not carried over by [`into_read_only`](ClashMap::into_read_only).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::listener::Event;
+    /// use clashmap::ClashMap;
+    /// use std::sync::atomic::{AtomicIsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// let live = Arc::new(AtomicIsize::new(0));
+    /// let counter = live.clone();
+    /// let map = ClashMap::new().with_listener(move |_shard, event: Event<'_, &str, i32>| {
+    ///     match event {
+    ///         Event::Inserted { .. } => counter.fetch_add(1, Ordering::Relaxed),
+    ///         Event::Removed { .. } => counter.fetch_sub(1, Ordering::Relaxed),
+    ///         _ => 0,
+    ///     };
+    /// });
+    ///
+    /// map.insert("Johnny", 21);
+    /// map.insert("Jane", 35);
+    /// map.alter_all(|_, age| age + 1);
+    /// map.retain(|_, age| *age < 30);
+    /// assert_eq!(live.load(Ordering::Relaxed), 1);
+    /// ```
+    ///
+    /// [`Event`]: crate::listener::Event
+    pub fn with_listener(mut self, listener: impl Listener<K, V> + 'static) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    /// Sets the clock that times the entries given a time-to-live, and returns the map.
+    ///
+    /// The map uses the monotonic clock of the system by default. A [`ManualClock`] only moves
+    /// when told to, so tests can expire entries deterministically.
+    /// Entries that already have a time-to-live keep the time they had left.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::cache::ManualClock;
+    /// use clashmap::ClashMap;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sessions = ClashMap::new().with_clock(clock.clone());
+    /// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+    ///
+    /// clock.advance(Duration::from_secs(60));
+    /// assert!(!sessions.contains_key("alice"));
+    /// ```
+    ///
+    /// [`ManualClock`]: crate::cache::ManualClock
+    #[cfg(feature = "std")]
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.expiries.set_clock(clock);
+        self
+    }
+
     /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
@@ -362,6 +457,50 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         }
     }
 
+    /// Inserts a key and a value into the map that expire once `ttl` has passed.
+    /// Returns the old value associated with the key if there was one.
+    ///
+    /// Once it has expired, [`get`](ClashMap::get), [`get_mut`](ClashMap::get_mut),
+    /// [`contains_key`](ClashMap::contains_key), [`entry`](ClashMap::entry) and the iterators
+    /// treat the entry as absent, and the lookups remove it. Other methods, such as [`len`](ClashMap::len),
+    /// still count it until it is removed, which [`purge_expired`](ClashMap::purge_expired) does
+    /// for all of them.
+    ///
+    /// The time-to-live is dropped when the value is overwritten by [`insert`](ClashMap::insert)
+    /// or an entry, but kept when it is changed in place, such as by [`alter`](ClashMap::alter).
+    /// [`OccupiedEntry::set_expiry`] changes or drops it. Time is read from the system's
+    /// monotonic clock unless [`with_clock`](ClashMap::with_clock) sets another.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::time::Duration;
+    ///
+    /// let sessions = ClashMap::new();
+    /// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+    /// assert!(sessions.contains_key("alice"));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: Duration) -> Option<V>
+    where
+        K: Eq + Hash + Clone,
+    {
+        match self.entry(key) {
+            Entry::Occupied(mut o) => {
+                let old = o.insert(value);
+                o.set_expiry(Some(ttl));
+                Some(old)
+            }
+            Entry::Vacant(v) => {
+                v.insert_entry(value).set_expiry(Some(ttl));
+                None
+            }
+        }
+    }
+
     /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
     //
     /// # Examples
@@ -404,7 +543,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     {
         let hash = self.hash_u64(&key);
         match self.table.find_entry(hash, |(k, _v)| key.equivalent(k)) {
-            Ok(e) => Some(e.remove()),
+            Ok(e) => Some(remove_occupied(e, self.notifier_for(hash))),
             Err(_) => None,
         }
     }
@@ -439,7 +578,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
             Ok(e) => {
                 let (k, v) = e.get();
                 if f(k, v) {
-                    Some(e.remove())
+                    Some(remove_occupied(e, self.notifier_for(hash)))
                 } else {
                     None
                 }
@@ -457,7 +596,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
             Ok(mut e) => {
                 let (k, v) = e.get_mut();
                 if f(k, v) {
-                    Some(e.remove())
+                    Some(remove_occupied(e, self.notifier_for(hash)))
                 } else {
                     None
                 }
@@ -466,6 +605,185 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         }
     }
 
+    /// Inserts many key-value pairs, locking each shard only once.
+    ///
+    /// Every key is hashed first, and the pairs are then inserted shard by shard,
+    /// so a batch touching a shard many times takes its write lock once.
+    /// Returns the old value of each key in the order of `pairs`, as [`insert`](ClashMap::insert) would.
+    /// If a key appears more than once, the later value wins.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("a", 1);
+    /// assert_eq!(map.insert_many([("a", 10), ("b", 20)]), [Some(1), None]);
+    /// assert_eq!(*map.get("a").unwrap(), 10);
+    /// ```
+    pub fn insert_many(&self, pairs: impl IntoIterator<Item = (K, V)>) -> Vec<Option<V>>
+    where
+        K: Eq + Hash,
+    {
+        let mut replaced = Vec::new();
+        let len = self.insert_by_shard(pairs, |i, v| replaced.push((i, v)));
+
+        let mut old: Vec<Option<V>> = iter::repeat_with(|| None).take(len).collect();
+        for (i, v) in replaced {
+            old[i] = Some(v);
+        }
+        old
+    }
+
+    /// Looks up many keys, locking each shard only once, and returns `f` of each pair found
+    /// in the order of `keys`.
+    ///
+    /// Every key is hashed first, and the keys are then looked up shard by shard,
+    /// so a batch touching a shard many times takes its read lock once.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    /// `f` is called while a shard is read-locked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("a", 1);
+    /// map.insert("b", 2);
+    /// assert_eq!(map.get_many_with(["a", "c", "b"], |_, v| v * 10), [Some(10), None, Some(20)]);
+    /// ```
+    pub fn get_many_with<'q, Q, R>(
+        &self,
+        keys: impl IntoIterator<Item = &'q Q>,
+        mut f: impl FnMut(&K, &V) -> R,
+    ) -> Vec<Option<R>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized + 'q,
+    {
+        let (_layout, shards) = self.table.tables.enter();
+        let batch = self.group_by_shard(keys, |key| key);
+        let mut found: Vec<Option<R>> = iter::repeat_with(|| None).take(batch.len()).collect();
+
+        let mut batch = batch.into_iter().peekable();
+        while let Some(&(idx, ..)) = batch.peek() {
+            let shard = shards[idx].read();
+            while let Some((_, hash, (i, key))) = batch.next_if(|&(s, ..)| s == idx) {
+                found[i] = shard
+                    .find(hash, |(k, _v)| key.equivalent(k))
+                    .map(|(k, v)| f(k, v));
+            }
+        }
+        found
+    }
+
+    /// Removes many keys, locking each shard only once, and returns the removed pairs
+    /// in the order of `keys`, as [`remove`](ClashMap::remove) would.
+    ///
+    /// Every key is hashed first, and the keys are then removed shard by shard,
+    /// so a batch touching a shard many times takes its write lock once.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("a", 1);
+    /// map.insert("b", 2);
+    /// assert_eq!(map.remove_many(["a", "c"]), [Some(("a", 1)), None]);
+    /// assert_eq!(map.len(), 1);
+    /// ```
+    pub fn remove_many<'q, Q>(&self, keys: impl IntoIterator<Item = &'q Q>) -> Vec<Option<(K, V)>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized + 'q,
+    {
+        let (_layout, shards) = self.table.tables.enter();
+        let batch = self.group_by_shard(keys, |key| key);
+        let mut removed: Vec<Option<(K, V)>> =
+            iter::repeat_with(|| None).take(batch.len()).collect();
+
+        let mut batch = batch.into_iter().peekable();
+        while let Some(&(idx, ..)) = batch.peek() {
+            let mut shard = shards[idx].write();
+            let notifier = self.notifier(idx);
+            while let Some((_, hash, (i, key))) = batch.next_if(|&(s, ..)| s == idx) {
+                if let Ok(e) = shard.find_entry(hash, |(k, _v)| key.equivalent(k)) {
+                    let ((k, v), _vacant) = e.remove();
+                    notifier.removed(&k, &v);
+                    removed[i] = Some((k, v));
+                }
+            }
+        }
+        removed
+    }
+
+    /// Inserts `pairs` shard by shard, and passes the position in `pairs` and old value
+    /// of every replaced pair to `replaced`. Returns the number of pairs.
+    fn insert_by_shard(
+        &self,
+        pairs: impl IntoIterator<Item = (K, V)>,
+        mut replaced: impl FnMut(usize, V),
+    ) -> usize
+    where
+        K: Eq + Hash,
+    {
+        let (_layout, shards) = self.table.tables.enter();
+        let batch = self.group_by_shard(pairs, |(k, _v)| k);
+        let len = batch.len();
+
+        let mut batch = batch.into_iter().peekable();
+        while let Some(&(idx, ..)) = batch.peek() {
+            let mut shard = shards[idx].write();
+            let notifier = self.notifier(idx);
+            while let Some((_, hash, (i, (key, value)))) = batch.next_if(|&(s, ..)| s == idx) {
+                match shard.entry(hash, |(k, _v)| *k == key, |(k, _v)| self.hash_u64(k)) {
+                    hash_table::Entry::Occupied(mut e) => {
+                        let (k, v) = e.get_mut();
+                        let old = mem::replace(v, value);
+                        notifier.overwritten(k, v);
+                        replaced(i, old);
+                    }
+                    hash_table::Entry::Vacant(e) => {
+                        let e = e.insert((key, value));
+                        let (k, v) = e.get();
+                        notifier.inserted(k, v);
+                    }
+                }
+            }
+        }
+        len
+    }
+
+    /// Hashes every item, and sorts the items by the shard holding their hash
+    /// so that a batch can lock each shard once. Each item keeps its position in `items`,
+    /// and items in the same shard stay in that order.
+    ///
+    /// The indices are only valid while entries cannot move to another layout, so the caller enters the layout first.
+    fn group_by_shard<T, Q: Hash>(
+        &self,
+        items: impl IntoIterator<Item = T>,
+        key: impl Fn(&T) -> &Q,
+    ) -> Vec<(usize, u64, (usize, T))> {
+        let mut batch: Vec<_> = items
+            .into_iter()
+            .enumerate()
+            .map(|(i, item)| {
+                let hash = self.hash_u64(key(&item));
+                let idx = self.table.tables._determine_shard(hash as usize);
+                (idx, hash, (i, item))
+            })
+            .collect();
+        batch.sort_by_key(|&(idx, ..)| idx);
+        batch
+    }
+
     /// Get an immutable reference to an entry in the map
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
@@ -479,14 +797,18 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// youtubers.insert("Bosnian Bill", 457000);
     /// assert_eq!(*youtubers.get("Bosnian Bill").unwrap(), 457000);
     /// ```
-    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V>>
+    pub fn get<Q>(&self, key: &Q) -> Option<Ref<'_, K, V, L>>
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
         let hash = self.hash_u64(&key);
-        self.table
-            .find(hash, |(k, _v)| key.equivalent(k))
-            .map(Ref::from)
+        let found = self.table.find(hash, |(k, _v)| key.equivalent(k))?;
+        if self.is_expired(&found.t.0) {
+            drop(found);
+            self.remove_expired(hash, key);
+            return None;
+        }
+        Some(Ref::from(found))
     }
 
     /// Get a mutable reference to an entry in the map
@@ -503,14 +825,95 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// *class.get_mut("Albin").unwrap() -= 1;
     /// assert_eq!(*class.get("Albin").unwrap(), 14);
     /// ```
-    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V>>
+    pub fn get_mut<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, L>>
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
         let hash = self.hash_u64(&key);
+        let found = self.table.find_mut(hash, |(k, _v)| key.equivalent(k))?;
+        if self.is_expired(&found.t.0) {
+            drop(found);
+            self.remove_expired(hash, key);
+            return None;
+        }
+        Some(RefMut::from(found))
+    }
+
+    /// Get immutable references to `N` entries in the map at once.
+    ///
+    /// Returns an array of length `N` with the results of each query.
+    /// Keys that are not in the map produce `None`.
+    ///
+    /// Each shard involved is locked only once, and shards are locked in index order.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let youtubers = ClashMap::new();
+    /// youtubers.insert("Bosnian Bill", 457000);
+    /// youtubers.insert("Technology Connections", 2000000);
+    /// let [bill, connections, other] = youtubers.get_many(["Bosnian Bill", "Technology Connections", "Unknown"]);
+    /// assert_eq!(*bill.unwrap(), 457000);
+    /// assert_eq!(*connections.unwrap(), 2000000);
+    /// assert!(other.is_none());
+    /// ```
+    pub fn get_many<Q, const N: usize>(&self, keys: [&Q; N]) -> [Option<RefMulti<'_, K, V, L>>; N]
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hashes = keys.map(|key| self.hash_u64(&key));
         self.table
-            .find_mut(hash, |(k, _v)| key.equivalent(k))
-            .map(RefMut::from)
+            .find_many(hashes, |i, (k, _v)| keys[i].equivalent(k))
+            .map(|r| r.map(RefMulti::new))
+    }
+
+    /// Get mutable references to `N` entries in the map at once.
+    ///
+    /// Returns an array of length `N` with the results of each query.
+    /// Keys that are not in the map produce `None`.
+    ///
+    /// Unlike calling [`get_mut`](ClashMap::get_mut) several times, this does not deadlock when
+    /// two keys are stored in the same shard. Each shard involved is locked only once,
+    /// and shards are locked in index order.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the keys are equal.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let accounts = ClashMap::new();
+    /// accounts.insert("Alice", 100);
+    /// accounts.insert("Bob", 50);
+    ///
+    /// if let [Some(mut alice), Some(mut bob)] = accounts.get_many_mut(["Alice", "Bob"]) {
+    ///     *alice -= 30;
+    ///     *bob += 30;
+    /// }
+    ///
+    /// assert_eq!(*accounts.get("Alice").unwrap(), 70);
+    /// assert_eq!(*accounts.get("Bob").unwrap(), 80);
+    /// ```
+    pub fn get_many_mut<Q, const N: usize>(
+        &self,
+        keys: [&Q; N],
+    ) -> [Option<RefMutMulti<'_, K, V, L>>; N]
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hashes = keys.map(|key| self.hash_u64(&key));
+        self.table
+            .find_many_mut(hashes, |i, (k, _v)| keys[i].equivalent(k))
+            .map(|r| r.map(RefMutMulti::new))
     }
 
     /// Get an immutable reference to an entry in the map, if the shard is not locked.
@@ -532,7 +935,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// let result2 = map.try_get("Johnny");
     /// assert!(result2.is_locked());
     /// ```
-    pub fn try_get<Q>(&self, key: &Q) -> TryResult<Ref<'_, K, V>>
+    pub fn try_get<Q>(&self, key: &Q) -> TryResult<Ref<'_, K, V, L>>
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
@@ -564,7 +967,7 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// let result2 = map.try_get_mut("Johnny");
     /// assert!(result2.is_locked());
     /// ```
-    pub fn try_get_mut<Q>(&self, key: &Q) -> TryResult<RefMut<'_, K, V>>
+    pub fn try_get_mut<Q>(&self, key: &Q) -> TryResult<RefMut<'_, K, V, L>>
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
@@ -603,28 +1006,300 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         });
     }
 
-    /// Retain elements that whose predicates return true
-    /// and discard elements whose predicates return false.
+    /// Changes the number of shards, moving every key-value pair to its shard in the new layout.
     ///
-    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    /// Maps that start small and grow large can be given more shards once they have grown,
+    /// as the shard amount is otherwise fixed when the map is created.
+    ///
+    /// This takes the map mutably and moves every pair at once, so it cannot run while the map is shared.
+    /// See [`reshard_incremental`](ClashMap::reshard_incremental) for a map that is.
+    /// Every key is hashed before any pair is moved, so if hashing a key panics, the map is left as it was.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
     ///
     /// # Examples
     ///
     /// ```
     /// use clashmap::ClashMap;
     ///
-    /// let people = ClashMap::new();
-    /// people.insert("Albin", 15);
-    /// people.insert("Jones", 22);
-    /// people.insert("Charlie", 27);
-    /// people.retain(|_, v| *v > 20);
-    /// assert_eq!(people.len(), 2);
+    /// let mut map = ClashMap::with_shard_amount(2);
+    /// map.insert("Johnny", 21);
+    /// map.reshard(64);
+    /// assert_eq!(map.shard_info().shards.len(), 64);
+    /// assert_eq!(*map.get("Johnny").unwrap(), 21);
     /// ```
-    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
-        self.table.retain(|(k, v)| f(k, v));
+    pub fn reshard(&mut self, shard_amount: usize)
+    where
+        K: Hash,
+    {
+        let hasher = &self.hasher;
+        self.table.reshard(shard_amount, |(k, _v)| {
+            let mut hasher = hasher.build_hasher();
+            k.hash(&mut hasher);
+            hasher.finish()
+        });
+        self.flights = Flights::new();
     }
 
-    /// Fetches the total number of key-value pairs stored in the map.
+    /// Changes the number of shards while the map is shared, moving the pairs of one shard at a time.
+    ///
+    /// While the pairs move, the map has two layouts of shards, and every key is in one of them.
+    /// Operations on a single key, such as [`get`](ClashMap::get), [`insert`](ClashMap::insert) and
+    /// [`entry`](ClashMap::entry), keep working, and look the key up in the layout it is in.
+    /// They only wait for the shard that is moving at that moment.
+    /// Operations over the whole map, such as iterating, [`len`](ClashMap::len), [`retain`](ClashMap::retain)
+    /// or [`read_all`](ClashMap::read_all), wait for every pair to move. A reshard in turn waits for
+    /// those that are running to end, and for any other reshard.
+    ///
+    /// The shard index passed to a [`Listener`](crate::listener::Listener) is the index in the new layout.
+    /// The tables of the old shards are freed as they are emptied, while their locks are kept
+    /// until the map is next borrowed mutably.
+    ///
+    /// Hashing a key that panics aborts the process, as the pairs of a shard would be left in both layouts.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    /// While it runs, operations over the whole map may deadlock if their thread holds a reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::thread;
+    ///
+    /// let map = ClashMap::with_shard_amount(2);
+    /// for i in 0..1000 {
+    ///     map.insert(i, i * 2);
+    /// }
+    ///
+    /// thread::scope(|s| {
+    ///     s.spawn(|| map.reshard_incremental(64));
+    ///     for i in 0..1000 {
+    ///         assert_eq!(*map.get(&i).unwrap(), i * 2);
+    ///     }
+    /// });
+    /// assert_eq!(map.shard_info().shards.len(), 64);
+    /// ```
+    pub fn reshard_incremental(&self, shard_amount: usize)
+    where
+        K: Hash,
+    {
+        self.table.reshard_incremental(shard_amount, |(k, _v)| {
+            let mut hasher = self.hasher.build_hasher();
+            k.hash(&mut hasher);
+            hasher.finish()
+        });
+    }
+
+    /// Visits up to `max_items` key-value pairs from `cursor` on, and returns the cursor to continue from.
+    ///
+    /// Start a scan with [`Cursor::new`], and pass the returned cursor to the next call until it
+    /// [`is_finished`](Cursor::is_finished). No lock is held between calls, so a scan of a large map
+    /// can be spread out over time while the map is in use.
+    ///
+    /// Every key that is in the map for the whole scan is visited at least once, even if shards grow
+    /// or shrink between calls. Keys inserted or removed during the scan may or may not be visited.
+    /// If the map is [resharded](ClashMap::reshard) during the scan, the scan starts over,
+    /// so keys may be visited twice.
+    ///
+    /// A call visits all keys with the same hash together, so it may stop a few pairs short of `max_items`,
+    /// and only visits more than `max_items` pairs if more than that many keys share a hash.
+    ///
+    /// **The first call into each shard is not bounded by `max_items`.** It hashes every key of the shard
+    /// while holding the shard's read lock, so writers to that shard wait for as long as that takes,
+    /// and the cursor keeps the hashes, 8 bytes per key, until the scan leaves the shard. After that,
+    /// the cost of a call grows with `max_items` rather than with the size of the shards. To cap the stall
+    /// and the memory, give the map more shards with [`with_shard_amount`](ClashMap::with_shard_amount)
+    /// or [`reshard`](ClashMap::reshard): with 4096 shards, a map of 200 million keys hashes about
+    /// 50 thousand keys at a time.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    /// `f` is called while a shard is read-locked.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_items` is 0, as the scan would never move.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::{scan::Cursor, ClashMap};
+    ///
+    /// let map = ClashMap::new();
+    /// for i in 0..100 {
+    ///     map.insert(i, i * 2);
+    /// }
+    ///
+    /// let mut sum = 0;
+    /// let mut cursor = Cursor::new();
+    /// while !cursor.is_finished() {
+    ///     cursor = map.scan(cursor, 10, |_, v| sum += v);
+    /// }
+    /// assert_eq!(sum, 9900);
+    /// ```
+    pub fn scan(&self, cursor: Cursor, max_items: usize, mut f: impl FnMut(&K, &V)) -> Cursor
+    where
+        K: Hash,
+    {
+        self.table.scan(
+            cursor,
+            max_items,
+            |(k, _v)| self.hash_u64(k),
+            |(k, v)| f(k, v),
+        )
+    }
+
+    /// Retain elements that whose predicates return true
+    /// and discard elements whose predicates return false.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let people = ClashMap::new();
+    /// people.insert("Albin", 15);
+    /// people.insert("Jones", 22);
+    /// people.insert("Charlie", 27);
+    /// people.retain(|_, v| *v > 20);
+    /// assert_eq!(people.len(), 2);
+    /// ```
+    pub fn retain(&self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        self.table.retain_in_shards(|shard, (k, v)| {
+            let keep = f(k, v);
+            if !keep {
+                self.notifier(shard).removed(k, v);
+            }
+            keep
+        });
+    }
+
+    /// Removes every entry whose time-to-live has run out, and tells the listener about each one.
+    ///
+    /// Lookups already treat expired entries as absent, but only remove the ones they come across,
+    /// so calling this now and then keeps the others from taking up memory.
+    /// The deadlines are kept in a hierarchical timing wheel and swept one table at a time, so only
+    /// the entries that expired are visited, and their shard is only write-locked to remove them.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::cache::ManualClock;
+    /// use clashmap::ClashMap;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sessions = ClashMap::new().with_clock(clock.clone());
+    /// sessions.insert_with_ttl("alice", 1, Duration::from_secs(60));
+    /// sessions.insert("bob", 2);
+    ///
+    /// clock.advance(Duration::from_secs(60));
+    /// assert_eq!(sessions.len(), 2);
+    /// sessions.purge_expired();
+    /// assert_eq!(sessions.len(), 1);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn purge_expired(&self)
+    where
+        K: Eq + Hash,
+    {
+        let now = self.expiries.now();
+        for table in 0..self.expiries.table_amount() {
+            for due in self.expiries.take_due(table, now, |k| self.hash_u64(k)) {
+                match self
+                    .table
+                    .find_entry(due.hash, |(k, _v)| self.expiries.is_due(&due, k, now))
+                {
+                    Ok(entry) => {
+                        remove_occupied(entry, self.notifier_for(due.hash));
+                    }
+                    Err(absent) => {
+                        // the entry is gone, and its shard stays locked until the deadline is too
+                        self.expiries.remove_due(&due, now);
+                        drop(absent);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes all key-value pairs from the map, yielding them one at a time.
+    ///
+    /// Only one shard is write-locked at a time, so pairs inserted concurrently may or may not be drained.
+    /// If the iterator is dropped early, the remaining pairs are still removed.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let jobs = ClashMap::new();
+    /// jobs.insert(1, "build");
+    /// jobs.insert(2, "test");
+    /// let mut batch: Vec<_> = jobs.drain().collect();
+    /// batch.sort();
+    /// assert_eq!(batch, vec![(1, "build"), (2, "test")]);
+    /// assert!(jobs.is_empty());
+    /// ```
+    pub fn drain(&self) -> Drain<'_, K, V, L> {
+        Drain::new(self)
+    }
+
+    /// Removes all key-value pairs from the map, yielding them one at a time, without taking any locks.
+    ///
+    /// If the iterator is dropped early, the remaining pairs are still removed.
+    pub fn drain_mut(&mut self) -> DrainMut<'_, K, V, L> {
+        DrainMut::new(self)
+    }
+
+    /// Removes the key-value pairs for which `pred` returns true, yielding them one at a time.
+    ///
+    /// Only one shard is write-locked at a time. If the iterator is dropped early,
+    /// the pairs that have not been visited yet are kept.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let people = ClashMap::new();
+    /// people.insert("Albin", 15);
+    /// people.insert("Jones", 22);
+    /// let minors: Vec<_> = people.extract_if(|_, v| *v < 18).collect();
+    /// assert_eq!(minors, vec![("Albin", 15)]);
+    /// assert_eq!(people.len(), 1);
+    /// ```
+    pub fn extract_if<'b>(
+        &'b self,
+        pred: impl FnMut(&K, &mut V) -> bool + 'b,
+    ) -> ExtractIf<'b, K, V, L> {
+        ExtractIf::new(self, pred)
+    }
+
+    /// Removes the key-value pairs for which `pred` returns true, yielding them one at a time,
+    /// without taking any locks.
+    ///
+    /// If the iterator is dropped early, the pairs that have not been visited yet are kept.
+    pub fn extract_if_mut<'b>(
+        &'b mut self,
+        pred: impl FnMut(&K, &mut V) -> bool + 'b,
+    ) -> ExtractIfMut<'b, K, V, L> {
+        ExtractIfMut::new(self, pred)
+    }
+
+    /// Fetches the total number of key-value pairs stored in the map.
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
     ///
@@ -675,7 +1350,8 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// assert!(stats.is_empty());
     /// ```
     pub fn clear(&self) {
-        self.retain(|_, _| false)
+        self.table
+            .clear_shards(|shard| self.notifier(shard).cleared());
     }
 
     /// Returns how many key-value pairs the map can store without reallocating.
@@ -707,9 +1383,11 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
-        if let Some(mut r) = self.get_mut(key) {
-            let (k, v) = r.pair_mut();
-            replace_with_or_abort(v, |v| f(k, v))
+        let hash = self.hash_u64(&key);
+        if let Some(r) = self.table.find_mut(hash, |(k, _v)| key.equivalent(k)) {
+            let (k, v) = r.t;
+            replace_with_or_abort(v, |v| f(k, v));
+            self.notifier_for(hash).replaced(k, v);
         }
     }
 
@@ -734,12 +1412,211 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     ///
     /// If the given closure panics, then `alter_all` will abort the process
     pub fn alter_all(&self, mut f: impl FnMut(&K, V) -> V) {
-        self.iter_mut().for_each(|mut m| {
-            let (k, v) = m.pair_mut();
-            replace_with_or_abort(v, |v| f(k, v))
+        self.table.for_each_in_shards(|shard, (k, v)| {
+            replace_with_or_abort(v, |v| f(k, v));
+            self.notifier(shard).replaced(k, v);
         })
     }
 
+    /// Computes a new value for a key from its current value, if any.
+    ///
+    /// The closure receives the current value, or `None` if the key is absent.
+    /// Returning `Some` stores the new value, returning `None` removes the entry.
+    /// The shard is write-locked only once for the whole operation.
+    ///
+    /// The current value is taken out of the map and handed to the closure, so if the closure
+    /// panics, the entry is removed. A listener sees the entry removed before the closure is called,
+    /// and the new value inserted if there is one.
+    ///
+    /// Returns a reference to the new value, if there is one.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let stock = ClashMap::new();
+    /// stock.compute("apples", |_, v| Some(v.unwrap_or(0) + 3));
+    /// assert_eq!(*stock.get("apples").unwrap(), 3);
+    ///
+    /// // sell all the apples
+    /// stock.compute("apples", |_, v| v.map(|v| v - 3).filter(|&v| v > 0));
+    /// assert!(!stock.contains_key("apples"));
+    /// ```
+    pub fn compute(
+        &self,
+        key: K,
+        f: impl FnOnce(&K, Option<V>) -> Option<V>,
+    ) -> Option<RefMut<'_, K, V, L>>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table.entry(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            ),
+            self.notifier_for(hash),
+        ) {
+            tableref::entry::Entry::Occupied(entry) => {
+                compute_occupied(entry, self.notifier_for(hash), |k, v| f(k, Some(v)))
+            }
+            tableref::entry::Entry::Vacant(entry) => {
+                let value = f(&key, None)?;
+                Some(VacantEntry::new(entry, key, self.notifier_for(hash)).insert(value))
+            }
+        }
+    }
+
+    /// Computes a new value for a key from its current value, if the key is present.
+    ///
+    /// Returning `Some` stores the new value, returning `None` removes the entry.
+    /// The shard is write-locked only once for the whole operation.
+    ///
+    /// As with [`compute`](ClashMap::compute), the current value is taken out of the map,
+    /// so if the closure panics, the entry is removed.
+    ///
+    /// Returns a reference to the new value, if there is one.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let sessions = ClashMap::new();
+    /// sessions.insert("alice", 1);
+    /// sessions.compute_if_present("alice", |_, uses| (uses < 1).then_some(uses + 1));
+    /// assert!(!sessions.contains_key("alice"));
+    /// ```
+    pub fn compute_if_present<Q>(
+        &self,
+        key: &Q,
+        f: impl FnOnce(&K, V) -> Option<V>,
+    ) -> Option<RefMut<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        match self.table.find_entry(hash, |(k, _v)| key.equivalent(k)) {
+            Ok(entry) => compute_occupied(entry, self.notifier_for(hash), f),
+            Err(_) => None,
+        }
+    }
+
+    /// Computes a value for a key if the key is absent.
+    ///
+    /// Returning `None` leaves the key absent.
+    /// The shard is write-locked only once for the whole operation.
+    ///
+    /// Returns a reference to the existing or newly computed value, if there is one.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let lengths = ClashMap::new();
+    /// lengths.compute_if_absent("hello", |k| Some(k.len()));
+    /// lengths.compute_if_absent("hello", |_| unreachable!());
+    /// assert_eq!(*lengths.get("hello").unwrap(), 5);
+    /// ```
+    pub fn compute_if_absent(
+        &self,
+        key: K,
+        f: impl FnOnce(&K) -> Option<V>,
+    ) -> Option<RefMut<'_, K, V, L>>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table.entry(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            ),
+            self.notifier_for(hash),
+        ) {
+            tableref::entry::Entry::Occupied(entry) => Some(entry.into_mut().into()),
+            tableref::entry::Entry::Vacant(entry) => {
+                let value = f(&key)?;
+                Some(VacantEntry::new(entry, key, self.notifier_for(hash)).insert(value))
+            }
+        }
+    }
+
+    /// Inserts the value if the key is absent, otherwise merges it with the existing value.
+    ///
+    /// The closure receives the existing value and the new value.
+    /// Returning `Some` stores the merged value, returning `None` removes the entry.
+    /// The shard is write-locked only once for the whole operation.
+    ///
+    /// As with [`compute`](ClashMap::compute), the existing value is taken out of the map,
+    /// so if the closure panics, the entry is removed.
+    ///
+    /// Returns a reference to the new value, if there is one.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let word_counts = ClashMap::new();
+    /// for word in ["a", "b", "a"] {
+    ///     word_counts.merge(word, 1, |old, new| Some(old + new));
+    /// }
+    /// assert_eq!(*word_counts.get("a").unwrap(), 2);
+    /// assert_eq!(*word_counts.get("b").unwrap(), 1);
+    /// ```
+    pub fn merge(
+        &self,
+        key: K,
+        value: V,
+        f: impl FnOnce(V, V) -> Option<V>,
+    ) -> Option<RefMut<'_, K, V, L>>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table.entry(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            ),
+            self.notifier_for(hash),
+        ) {
+            tableref::entry::Entry::Occupied(entry) => {
+                compute_occupied(entry, self.notifier_for(hash), |_, old| f(old, value))
+            }
+            tableref::entry::Entry::Vacant(entry) => {
+                Some(VacantEntry::new(entry, key, self.notifier_for(hash)).insert(value))
+            }
+        }
+    }
+
     /// Scoped access into an item of the map according to a function.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
@@ -769,6 +1646,63 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         })
     }
 
+    /// Scoped mutable access into an item of the map according to a function.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let warehouse = ClashMap::new();
+    /// warehouse.insert(4267, ("Banana", 100));
+    /// let remaining = warehouse.view_mut(&4267, |_k, v| {
+    ///     v.1 -= 10;
+    ///     v.1
+    /// });
+    /// assert_eq!(remaining, Some(90));
+    /// ```
+    pub fn view_mut<Q, R>(&self, key: &Q, f: impl FnOnce(&K, &mut V) -> R) -> Option<R>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get_mut(key).map(|mut r| {
+            let (k, v) = r.pair_mut();
+            f(k, v)
+        })
+    }
+
+    /// Scoped access into an item of the map according to a function, if the shard is not locked.
+    /// If the shard is locked, the function will return [TryResult::Locked].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let warehouse = ClashMap::new();
+    /// warehouse.insert(4267, ("Banana", 100));
+    /// let fruit = warehouse.try_view(&4267, |_k, v| v.0);
+    /// assert_eq!(fruit.unwrap(), "Banana");
+    ///
+    /// let _lock = warehouse.get_mut(&4267);
+    /// assert!(warehouse.try_view(&4267, |_k, v| v.0).is_locked());
+    /// ```
+    pub fn try_view<Q, R>(&self, key: &Q, f: impl FnOnce(&K, &V) -> R) -> TryResult<R>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        match self.try_get(key) {
+            TryResult::Present(r) => {
+                let (k, v) = r.pair();
+                TryResult::Present(f(k, v))
+            }
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
     /// Checks if the map contains a specific key.
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
@@ -795,6 +1729,10 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
         K: Eq + Hash,
     {
         let hash = self.hash_u64(&key);
+        let shard = self.table.tables._determine_shard(hash as usize);
+        let notifier = Notifier::new(self.listener.as_deref(), shard);
+        #[cfg(feature = "std")]
+        let notifier = notifier.with_expiries(&self.expiries);
         match self.table.entry_mut(
             hash,
             |(k, _v)| k == &key,
@@ -804,11 +1742,22 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
                 hasher.finish()
             },
         ) {
+            crate::tableref::entrymut::EntryMut::Occupied(occupied_entry_mut)
+                if notifier.is_expired(&occupied_entry_mut.entry.get().0) =>
+            {
+                let ((k, v), vacant_entry) = occupied_entry_mut.entry.remove();
+                notifier.removed(&k, &v);
+                EntryMut::Vacant(VacantEntryMut::new(key, vacant_entry, notifier))
+            }
             crate::tableref::entrymut::EntryMut::Occupied(occupied_entry_mut) => {
-                EntryMut::Occupied(OccupiedEntryMut::new(key, occupied_entry_mut.entry))
+                EntryMut::Occupied(OccupiedEntryMut::new(
+                    key,
+                    occupied_entry_mut.entry,
+                    notifier,
+                ))
             }
             crate::tableref::entrymut::EntryMut::Vacant(vacant_entry_mut) => {
-                EntryMut::Vacant(VacantEntryMut::new(key, vacant_entry_mut.entry))
+                EntryMut::Vacant(VacantEntryMut::new(key, vacant_entry_mut.entry, notifier))
             }
         }
     }
@@ -817,25 +1766,28 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// See the documentation on `clashmap::mapref::entry` for more details.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
-    pub fn entry(&self, key: K) -> Entry<'_, K, V>
+    pub fn entry(&self, key: K) -> Entry<'_, K, V, L>
     where
         K: Eq + Hash,
     {
         let hash = self.hash_u64(&key);
-        match self.table.entry(
-            hash,
-            |(k, _v)| k == &key,
-            |(k, _v)| {
-                let mut hasher = self.hasher.build_hasher();
-                k.hash(&mut hasher);
-                hasher.finish()
-            },
+        match expire_entry(
+            self.table.entry(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            ),
+            self.notifier_for(hash),
         ) {
             crate::tableref::entry::Entry::Occupied(entry) => {
-                Entry::Occupied(OccupiedEntry::new(entry, key))
+                Entry::Occupied(OccupiedEntry::new(entry, key, self.notifier_for(hash)))
             }
             crate::tableref::entry::Entry::Vacant(entry) => {
-                Entry::Vacant(VacantEntry::new(entry, key))
+                Entry::Vacant(VacantEntry::new(entry, key, self.notifier_for(hash)))
             }
         }
     }
@@ -844,28 +1796,31 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// See the documentation on `clashmap::mapref::entry` for more details.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
-    pub fn entry_ref<Q>(&self, key: &Q) -> EntryRef<'_, K, V>
+    pub fn entry_ref<Q>(&self, key: &Q) -> EntryRef<'_, K, V, L>
     where
         Q: Hash + Equivalent<K> + ?Sized,
         K: Clone + Hash,
     {
         let hash = self.hash_u64(&key);
 
-        match self.table.entry(
-            hash,
-            |(k, _v)| key.equivalent(k),
-            |(k, _v)| {
-                let mut hasher = self.hasher.build_hasher();
-                k.hash(&mut hasher);
-                hasher.finish()
-            },
+        match expire_entry(
+            self.table.entry(
+                hash,
+                |(k, _v)| key.equivalent(k),
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            ),
+            self.notifier_for(hash),
         ) {
             crate::tableref::entry::Entry::Occupied(entry) => {
                 let key = entry.get().0.clone();
-                EntryRef::Occupied(OccupiedEntry::new(entry, key))
+                EntryRef::Occupied(OccupiedEntry::new(entry, key, self.notifier_for(hash)))
             }
             crate::tableref::entry::Entry::Vacant(entry) => {
-                EntryRef::Vacant(VacantEntryRef::new(entry))
+                EntryRef::Vacant(VacantEntryRef::new(entry, self.notifier_for(hash)))
             }
         }
     }
@@ -874,26 +1829,29 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     /// See the documentation on `clashmap::mapref::entry` for more details.
     ///
     /// Returns None if the shard is currently locked.
-    pub fn try_entry(&self, key: K) -> Option<Entry<'_, K, V>>
+    pub fn try_entry(&self, key: K) -> Option<Entry<'_, K, V, L>>
     where
         K: Eq + Hash,
     {
         let hash = self.hash_u64(&key);
-        match self.table.try_entry(
-            hash,
-            |(k, _v)| k == &key,
-            |(k, _v)| {
-                let mut hasher = self.hasher.build_hasher();
-                k.hash(&mut hasher);
-                hasher.finish()
-            },
-        )? {
-            crate::tableref::entry::Entry::Occupied(occupied_entry) => {
-                Some(Entry::Occupied(OccupiedEntry::new(occupied_entry, key)))
-            }
-            crate::tableref::entry::Entry::Vacant(vacant_entry) => {
-                Some(Entry::Vacant(VacantEntry::new(vacant_entry, key)))
-            }
+        match expire_entry(
+            self.table.try_entry(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+            )?,
+            self.notifier_for(hash),
+        ) {
+            crate::tableref::entry::Entry::Occupied(occupied_entry) => Some(Entry::Occupied(
+                OccupiedEntry::new(occupied_entry, key, self.notifier_for(hash)),
+            )),
+            crate::tableref::entry::Entry::Vacant(vacant_entry) => Some(Entry::Vacant(
+                VacantEntry::new(vacant_entry, key, self.notifier_for(hash)),
+            )),
         }
     }
 
@@ -917,318 +1875,2275 @@ impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
     }
 }
 
-impl<K, V, S> ClashMap<K, V, S> {
-    /// Creates an iterator over a ClashMap yielding immutable references.
+impl<K, V, S: BuildHasher> ClashMap<K, V, S> {
+    #[cfg(feature = "std")]
+    /// Creates a new ClashMap with a capacity of 0 and the provided hasher.
     ///
-    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let reviews = ClashMap::with_hasher(s);
+    /// reviews.insert("Veloren", "What a fantastic game!");
+    /// ```
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    #[cfg(feature = "std")]
+    /// Creates a new ClashMap with a specified starting capacity and hasher.
     ///
     /// # Examples
     ///
     /// ```
     /// use clashmap::ClashMap;
+    /// use std::collections::hash_map::RandomState;
     ///
-    /// let words = ClashMap::new();
-    /// words.insert("hello", "world");
-    /// assert_eq!(words.iter().count(), 1);
+    /// let s = RandomState::new();
+    /// let mappings = ClashMap::with_capacity_and_hasher(2, s);
+    /// mappings.insert(2, 4);
+    /// mappings.insert(8, 16);
     /// ```
-    pub fn iter(&self) -> Iter<'_, K, V> {
-        Iter::new(self)
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self::with_capacity_and_hasher_and_shard_amount(capacity, hasher, default_shard_amount())
     }
 
-    /// Iterator over a ClashMap yielding mutable references.
+    /// Creates a new ClashMap with a specified hasher and shard amount
     ///
-    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    /// shard_amount should be greater than 0 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let mappings = ClashMap::with_hasher_and_shard_amount(s, 32);
+    /// mappings.insert(2, 4);
+    /// mappings.insert(8, 16);
+    /// ```
+    pub fn with_hasher_and_shard_amount(hasher: S, shard_amount: usize) -> Self {
+        Self::with_capacity_and_hasher_and_shard_amount(0, hasher, shard_amount)
+    }
+
+    /// Creates a new ClashMap with a specified starting capacity, hasher and shard_amount.
+    ///
+    /// shard_amount should greater than 0 and be a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let mappings = ClashMap::with_capacity_and_hasher_and_shard_amount(2, s, 32);
+    /// mappings.insert(2, 4);
+    /// mappings.insert(8, 16);
+    /// ```
+    pub fn with_capacity_and_hasher_and_shard_amount(
+        capacity: usize,
+        hasher: S,
+        shard_amount: usize,
+    ) -> Self {
+        Self {
+            table: ClashTable::with_capacity_and_shard_amount(capacity, shard_amount),
+            hasher,
+            listener: None,
+            flights: Flights::new(),
+            #[cfg(feature = "std")]
+            expiries: Expiries::new(),
+        }
+    }
+}
+
+impl<K, V, S: BuildHasher, Sel: ShardSelector> ClashMap<K, V, S, RawRwLock, Sel> {
+    /// Creates a new ClashMap with a specified hasher and shard amount, using `selector` to choose
+    /// the shard of each key's hash instead of the [`DefaultShardSelector`].
+    ///
+    /// Keys that the selector puts in the same shard can be worked on together
+    /// with [`with_shard_for`](ClashMap::with_shard_for), under a single lock.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// Keys are `(tenant, id)` pairs. The hasher puts the tenant in bits 16 to 23 of the hash,
+    /// and the selector takes the shard from those bits, so the keys of a tenant share a shard.
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::{BuildHasherDefault, Hasher};
+    ///
+    /// #[derive(Default)]
+    /// struct TenantHasher {
+    ///     tenant: Option<u8>,
+    ///     rest: DefaultHasher,
+    /// }
+    ///
+    /// impl Hasher for TenantHasher {
+    ///     fn finish(&self) -> u64 {
+    ///         let tenant = u64::from(self.tenant.unwrap_or(0)) << 16;
+    ///         tenant | (self.rest.finish() & !(0xff << 16))
+    ///     }
+    ///
+    ///     fn write(&mut self, bytes: &[u8]) {
+    ///         self.rest.write(bytes);
+    ///     }
+    ///
+    ///     fn write_u8(&mut self, i: u8) {
+    ///         match self.tenant {
+    ///             None => self.tenant = Some(i),
+    ///             Some(_) => self.rest.write_u8(i),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let sessions = ClashMap::with_hasher_and_shard_selector(
+    ///     BuildHasherDefault::<TenantHasher>::default(),
+    ///     8,
+    ///     |hash: usize, shard_amount: usize| (hash >> 16) % shard_amount,
+    /// );
+    /// for tenant in 0..4u8 {
+    ///     for id in 0..100u64 {
+    ///         sessions.insert((tenant, id), "active");
+    ///     }
+    /// }
+    ///
+    /// // Ends every session of tenant 2 under the lock of a single shard.
+    /// let ended = sessions.with_shard_for(&2u8, |shard| {
+    ///     let before = shard.len();
+    ///     shard.retain(|&(tenant, _), _| tenant != 2);
+    ///     before - shard.len()
+    /// });
+    /// assert_eq!(ended, 100);
+    /// assert_eq!(sessions.len(), 300);
+    /// ```
+    ///
+    /// [`DefaultShardSelector`]: crate::shard_selector::DefaultShardSelector
+    pub fn with_hasher_and_shard_selector(hasher: S, shard_amount: usize, selector: Sel) -> Self {
+        Self::with_capacity_and_hasher_and_shard_selector(0, hasher, shard_amount, selector)
+    }
+
+    /// Creates a new ClashMap with a specified starting capacity, hasher and shard amount,
+    /// using `selector` to choose the shard of each key's hash instead of the [`DefaultShardSelector`].
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// [`DefaultShardSelector`]: crate::shard_selector::DefaultShardSelector
+    pub fn with_capacity_and_hasher_and_shard_selector(
+        capacity: usize,
+        hasher: S,
+        shard_amount: usize,
+        selector: Sel,
+    ) -> Self {
+        Self::with_capacity_and_hasher_and_shard_selector_and_lock(
+            capacity,
+            hasher,
+            shard_amount,
+            selector,
+        )
+    }
+}
+
+impl<K, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Creates a new ClashMap with a specified starting capacity, hasher and shard amount,
+    /// using `selector` to choose the shard of each key's hash, that guards its shards with the lock `L`.
+    ///
+    /// See [`with_hasher_and_shard_selector`](ClashMap::with_hasher_and_shard_selector) for selectors,
+    /// and [`with_capacity_and_hasher_and_lock`](ClashMap::with_capacity_and_hasher_and_lock) for locks.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::{ClashMap, RawRwLock};
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let map: ClashMap<_, _, _, RawRwLock, _> =
+    ///     ClashMap::with_capacity_and_hasher_and_shard_selector_and_lock(
+    ///         0,
+    ///         RandomState::new(),
+    ///         8,
+    ///         |hash: usize, shard_amount: usize| (hash >> 16) % shard_amount,
+    ///     );
+    /// map.insert("Johnny", 21);
+    /// assert_eq!(*map.get("Johnny").unwrap(), 21);
+    /// ```
+    pub fn with_capacity_and_hasher_and_shard_selector_and_lock(
+        capacity: usize,
+        hasher: S,
+        shard_amount: usize,
+        selector: Sel,
+    ) -> Self {
+        Self {
+            table: ClashTable::with_capacity_and_shard_selector_and_lock(
+                capacity,
+                shard_amount,
+                selector,
+            ),
+            hasher,
+            listener: None,
+            flights: Flights::new(),
+            #[cfg(feature = "std")]
+            expiries: Expiries::new(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<K, V, S, L, Sel: ShardSelector> ClashMap<K, V, S, L, Sel>
+where
+    S: BuildHasher,
+    L: lock_api::RawRwLockTimed<Duration = Duration, Instant = Instant>,
+{
+    /// Get an immutable reference to an entry in the map, waiting at most `timeout` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
     ///
     /// # Examples
     ///
     /// ```
     /// use clashmap::ClashMap;
+    /// use std::time::Duration;
     ///
     /// let map = ClashMap::new();
     /// map.insert("Johnny", 21);
-    /// map.iter_mut().for_each(|mut r| *r += 1);
-    /// assert_eq!(*map.get("Johnny").unwrap(), 22);
+    ///
+    /// assert_eq!(*map.try_get_for("Johnny", Duration::from_millis(10)).unwrap(), 21);
+    ///
+    /// let _result1_locking = map.get_mut("Johnny");
+    ///
+    /// let result2 = map.try_get_for("Johnny", Duration::from_millis(10));
+    /// assert!(result2.is_locked());
     /// ```
-    pub fn iter_mut(&self) -> IterMut<'_, K, V> {
-        IterMut::new(self)
+    pub fn try_get_for<Q>(&self, key: &Q, timeout: Duration) -> TryResult<Ref<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        match self
+            .table
+            .try_find_for(hash, |(k, _v)| key.equivalent(k), timeout)
+        {
+            TryResult::Present(r) => TryResult::Present(r.into()),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Get an immutable reference to an entry in the map, waiting until `deadline` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_get_until<Q>(&self, key: &Q, deadline: Instant) -> TryResult<Ref<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        match self
+            .table
+            .try_find_until(hash, |(k, _v)| key.equivalent(k), deadline)
+        {
+            TryResult::Present(r) => TryResult::Present(r.into()),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Get a mutable reference to an entry in the map, waiting at most `timeout` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_get_mut_for<Q>(&self, key: &Q, timeout: Duration) -> TryResult<RefMut<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        match self
+            .table
+            .try_find_mut_for(hash, |(k, _v)| key.equivalent(k), timeout)
+        {
+            TryResult::Present(r) => TryResult::Present(r.into()),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Get a mutable reference to an entry in the map, waiting until `deadline` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_get_mut_until<Q>(&self, key: &Q, deadline: Instant) -> TryResult<RefMut<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        match self
+            .table
+            .try_find_mut_until(hash, |(k, _v)| key.equivalent(k), deadline)
+        {
+            TryResult::Present(r) => TryResult::Present(r.into()),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Inserts a key and a value into the map, waiting at most `timeout` for the shard to be unlocked.
+    ///
+    /// Returns [TryResult::Present] with the old value if the key was already in the map,
+    /// [TryResult::Absent] if it was not, and [TryResult::Locked] if the shard was still locked,
+    /// in which case the map is left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::time::Duration;
+    ///
+    /// let map = ClashMap::new();
+    /// assert!(map.try_insert_for("Johnny", 21, Duration::from_millis(10)).is_absent());
+    /// assert_eq!(map.try_insert_for("Johnny", 22, Duration::from_millis(10)).unwrap(), 21);
+    ///
+    /// let _locking = map.get("Johnny");
+    /// assert!(map.try_insert_for("Johnny", 23, Duration::from_millis(10)).is_locked());
+    /// ```
+    pub fn try_insert_for(&self, key: K, value: V, timeout: Duration) -> TryResult<V>
+    where
+        K: Eq + Hash,
+    {
+        match self.try_entry_for(key, timeout) {
+            Some(Entry::Occupied(mut entry)) => TryResult::Present(entry.insert(value)),
+            Some(Entry::Vacant(entry)) => {
+                entry.insert(value);
+                TryResult::Absent
+            }
+            None => TryResult::Locked,
+        }
+    }
+
+    /// Inserts a key and a value into the map, waiting until `deadline` for the shard to be unlocked.
+    ///
+    /// See [`try_insert_for`](ClashMap::try_insert_for) for the meaning of the result.
+    pub fn try_insert_until(&self, key: K, value: V, deadline: Instant) -> TryResult<V>
+    where
+        K: Eq + Hash,
+    {
+        match self.try_entry_until(key, deadline) {
+            Some(Entry::Occupied(mut entry)) => TryResult::Present(entry.insert(value)),
+            Some(Entry::Vacant(entry)) => {
+                entry.insert(value);
+                TryResult::Absent
+            }
+            None => TryResult::Locked,
+        }
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// Returns None if the shard is still locked after waiting for `timeout`.
+    pub fn try_entry_for(&self, key: K, timeout: Duration) -> Option<Entry<'_, K, V, L>>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table.try_entry_for(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+                timeout,
+            )?,
+            self.notifier_for(hash),
+        ) {
+            crate::tableref::entry::Entry::Occupied(occupied_entry) => Some(Entry::Occupied(
+                OccupiedEntry::new(occupied_entry, key, self.notifier_for(hash)),
+            )),
+            crate::tableref::entry::Entry::Vacant(vacant_entry) => Some(Entry::Vacant(
+                VacantEntry::new(vacant_entry, key, self.notifier_for(hash)),
+            )),
+        }
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// Returns None if the shard is still locked at `deadline`.
+    pub fn try_entry_until(&self, key: K, deadline: Instant) -> Option<Entry<'_, K, V, L>>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table.try_entry_until(
+                hash,
+                |(k, _v)| k == &key,
+                |(k, _v)| {
+                    let mut hasher = self.hasher.build_hasher();
+                    k.hash(&mut hasher);
+                    hasher.finish()
+                },
+                deadline,
+            )?,
+            self.notifier_for(hash),
+        ) {
+            crate::tableref::entry::Entry::Occupied(occupied_entry) => Some(Entry::Occupied(
+                OccupiedEntry::new(occupied_entry, key, self.notifier_for(hash)),
+            )),
+            crate::tableref::entry::Entry::Vacant(vacant_entry) => Some(Entry::Vacant(
+                VacantEntry::new(vacant_entry, key, self.notifier_for(hash)),
+            )),
+        }
     }
 }
 
-impl<K: Eq + Hash + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug for ClashMap<K, V, S> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut pmap = f.debug_map();
-        self.table.for_each(|(k, v)| {
-            pmap.entry(k, v);
-        });
-        pmap.finish()
+impl<K, V, S: BuildHasher, L: lock_api::RawRwLockUpgradeDowngrade, Sel: ShardSelector>
+    ClashMap<K, V, S, L, Sel>
+{
+    /// Get an upgradable reference to an entry in the map.
+    ///
+    /// Other threads can still read the shard, but cannot write to it or take another upgradable
+    /// reference into it, until the reference is dropped. Calling [`upgrade`](RefUpgradable::upgrade)
+    /// turns it into a [`RefMut`] without releasing the lock in between, so nothing that was read
+    /// through it can be changed by another thread first.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable or upgradable reference into the map.
+    /// Upgrading may deadlock if the same thread also holds any other reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let stock = ClashMap::new();
+    /// stock.insert("apples", 3);
+    ///
+    /// let apples = stock.get_upgradable("apples").unwrap();
+    /// if *apples > 0 {
+    ///     *apples.upgrade() -= 1;
+    /// }
+    /// assert_eq!(*stock.get("apples").unwrap(), 2);
+    /// ```
+    pub fn get_upgradable<Q>(&self, key: &Q) -> Option<RefUpgradable<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        self.table
+            .find_upgradable(hash, |(k, _v)| key.equivalent(k))
+            .map(RefUpgradable::from)
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLockDowngrade, Sel: ShardSelector>
+    ClashMap<K, V, S, L, Sel>
+{
+    /// Returns the value of `key`, computing it with `f` if the key is absent.
+    ///
+    /// Unlike [`Entry::or_insert_with`], `f` runs without holding the lock of a shard, so other keys
+    /// stay available while it runs. Only one computation runs for a key at a time: other threads
+    /// calling this for the same key wait for it to finish, and then get the value it computed.
+    ///
+    /// If the key is inserted by other means while `f` runs, that value is kept and the computed value
+    /// is dropped. See [`get_or_try_compute`](ClashMap::get_or_try_compute) for computations that can fail.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    /// `f` may use the map, but deadlocks if it computes the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let squares = ClashMap::new();
+    /// assert_eq!(*squares.get_or_compute(4, || 4 * 4), 16);
+    /// assert_eq!(*squares.get_or_compute(4, || unreachable!()), 16);
+    /// ```
+    pub fn get_or_compute(&self, key: K, f: impl FnOnce() -> V) -> Ref<'_, K, V, L> {
+        match self.get_or_try_compute(key, || Ok::<_, Infallible>(f())) {
+            Ok(r) => r,
+            Err(e) => match e {},
+        }
+    }
+
+    /// Returns the value of `key`, computing it with `f` if the key is absent.
+    ///
+    /// Works like [`get_or_compute`](ClashMap::get_or_compute), but `f` can fail.
+    /// If `f` returns an error or panics, nothing is inserted and the error is returned to this caller.
+    /// Threads that were waiting for the computation then try again, and one of them runs its own `f`.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    /// `f` may use the map, but deadlocks if it computes the same key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let pages = ClashMap::new();
+    /// assert_eq!(pages.get_or_try_compute("/", || Err("timed out")).unwrap_err(), "timed out");
+    /// assert!(pages.get("/").is_none());
+    ///
+    /// let page = pages.get_or_try_compute("/", || Ok::<_, &str>("<html>")).unwrap();
+    /// assert_eq!(*page, "<html>");
+    /// ```
+    pub fn get_or_try_compute<E>(
+        &self,
+        key: K,
+        f: impl FnOnce() -> Result<V, E>,
+    ) -> Result<Ref<'_, K, V, L>, E> {
+        let hash = self.hash_u64(&key);
+        let departure = loop {
+            if let Some(r) = self.get(&key) {
+                return Ok(r);
+            }
+
+            let shard_amount = self.table.tables.shards().len();
+            match self.flights.start(shard_amount, hash) {
+                // A computation may have published the key and ended since we looked.
+                Ok(departure) => match self.get(&key) {
+                    Some(r) => return Ok(r),
+                    None => break departure,
+                },
+                Err(waiting) => waiting.wait(),
+            }
+        };
+
+        let value = f()?;
+        let r = self.entry(key).or_insert(value).downgrade();
+        // Waiters look the key up again, so it has to be published before the computation ends.
+        drop(departure);
+        Ok(r)
+    }
+}
+
+impl<K, V, S, L, Sel: ShardSelector> ClashMap<K, V, S, L, Sel>
+where
+    K: Eq + Hash,
+    S: BuildHasher,
+    L: lock_api::RawRwLock,
+{
+    /// Get an immutable reference to an entry in the map, which holds a clone of the [`Arc`] around the map
+    /// instead of borrowing it.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::mapref::owned::ArcRef;
+    /// use clashmap::ClashMap;
+    /// use std::sync::Arc;
+    ///
+    /// fn lookup(map: Arc<ClashMap<&'static str, u32>>) -> Option<ArcRef<&'static str, u32>> {
+    ///     map.get_owned("Bosnian Bill")
+    /// }
+    ///
+    /// let youtubers = Arc::new(ClashMap::new());
+    /// youtubers.insert("Bosnian Bill", 457000);
+    /// assert_eq!(*lookup(youtubers).unwrap(), 457000);
+    /// ```
+    pub fn get_owned<Q>(self: &Arc<Self>, key: &Q) -> Option<ArcRef<K, V, S, L, Sel>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let r = self.get(key)?;
+        // SAFETY: `r` is a reference into this map.
+        Some(unsafe { ArcRef::new(r, self.clone()) })
+    }
+
+    /// Get a mutable reference to an entry in the map, which holds a clone of the [`Arc`] around the map
+    /// instead of borrowing it.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    /// use std::sync::Arc;
+    ///
+    /// let class = Arc::new(ClashMap::new());
+    /// class.insert("Albin", 15);
+    /// let mut albin = class.clone().get_mut_owned("Albin").unwrap();
+    /// *albin -= 1;
+    /// drop(albin);
+    /// assert_eq!(*class.get("Albin").unwrap(), 14);
+    /// ```
+    pub fn get_mut_owned<Q>(self: &Arc<Self>, key: &Q) -> Option<ArcRefMut<K, V, S, L, Sel>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let r = self.get_mut(key)?;
+        // SAFETY: `r` is a reference into this map.
+        Some(unsafe { ArcRefMut::new(r, self.clone()) })
+    }
+}
+
+/// Passes the value of an occupied entry to `f`, and replaces it with the result.
+/// The entry is only removed if `f` returns `None`, so it is left as it was if `f` panics.
+fn compute_occupied<'a, K, V, L: lock_api::RawRwLock>(
+    entry: tableref::entry::OccupiedEntry<'a, (K, V), L>,
+    notifier: Notifier<'_, K, V>,
+    f: impl FnOnce(&K, V) -> Option<V>,
+) -> Option<RefMut<'a, K, V, L>> {
+    let ((k, v), vacant) = entry.remove_entry();
+    // `f` consumes the old value, so the listener has to be told about it first.
+    notifier.removed(&k, &v);
+    let v = f(&k, v)?;
+    notifier.inserted(&k, &v);
+    Some(vacant.insert((k, v)).into())
+}
+
+/// Removes an occupied entry, telling the listener before the shard is unlocked.
+fn remove_occupied<K, V, L: lock_api::RawRwLock>(
+    entry: tableref::entry::OccupiedEntry<'_, (K, V), L>,
+    notifier: Notifier<'_, K, V>,
+) -> (K, V) {
+    let ((k, v), _vacant) = entry.remove_entry();
+    notifier.removed(&k, &v);
+    (k, v)
+}
+
+/// Removes the entry if its time-to-live has run out, handing back the vacant entry in its place.
+fn expire_entry<'a, K, V, L: lock_api::RawRwLock>(
+    entry: tableref::entry::Entry<'a, (K, V), L>,
+    notifier: Notifier<'_, K, V>,
+) -> tableref::entry::Entry<'a, (K, V), L> {
+    match entry {
+        tableref::entry::Entry::Occupied(entry) if notifier.is_expired(&entry.get().0) => {
+            let ((k, v), vacant) = entry.remove_entry();
+            notifier.removed(&k, &v);
+            tableref::entry::Entry::Vacant(vacant)
+        }
+        entry => entry,
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Returns the notifier for changes to the shard at index `shard`.
+    #[cfg(feature = "std")]
+    pub(crate) fn notifier(&self, shard: usize) -> Notifier<'_, K, V> {
+        Notifier::new(self.listener.as_deref(), shard).with_expiries(&self.expiries)
+    }
+
+    /// Returns the notifier for changes to the shard at index `shard`.
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn notifier(&self, shard: usize) -> Notifier<'_, K, V> {
+        Notifier::new(self.listener.as_deref(), shard)
+    }
+
+    /// Returns the notifier for changes to the shard holding `hash`.
+    pub(crate) fn notifier_for(&self, hash: u64) -> Notifier<'_, K, V> {
+        self.notifier(self.table.tables._determine_shard(hash as usize))
+    }
+
+    /// Returns true if `key` was given a time-to-live that has run out.
+    #[cfg(feature = "std")]
+    fn is_expired(&self, key: &K) -> bool {
+        self.expiries.is_expired(key)
+    }
+
+    /// Returns true if `key` was given a time-to-live that has run out.
+    #[cfg(not(feature = "std"))]
+    fn is_expired(&self, _key: &K) -> bool {
+        false
+    }
+
+    /// Removes the entry of `key` if it has expired.
+    ///
+    /// The shard is left alone if it is locked, as the caller may still hold a reference into it.
+    fn remove_expired<Q>(&self, hash: u64, key: &Q)
+    where
+        Q: Equivalent<K> + ?Sized,
+    {
+        if let Some(Ok(entry)) = self
+            .table
+            .try_find_entry(hash, |(k, _v)| key.equivalent(k) && self.is_expired(k))
+        {
+            remove_occupied(entry, self.notifier_for(hash));
+        }
+    }
+
+    /// Returns the size of every shard, and statistics about how evenly the entries are spread.
+    ///
+    /// This can be used to check that a custom hasher spreads keys evenly across shards.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::with_shard_amount(4);
+    /// map.insert("Johnny", 21);
+    ///
+    /// let info = map.shard_info();
+    /// assert_eq!(info.shards.len(), 4);
+    /// assert_eq!(info.shards.iter().filter(|s| s.len == 1).count(), 1);
+    /// assert_eq!(info.max_imbalance(), 4.0);
+    /// ```
+    pub fn shard_info(&self) -> ShardInfo {
+        self.table.shard_info()
+    }
+
+    /// Creates an iterator over a ClashMap yielding immutable references.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let words = ClashMap::new();
+    /// words.insert("hello", "world");
+    /// assert_eq!(words.iter().count(), 1);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V, L> {
+        Iter::new(self)
+    }
+
+    /// Iterator over a ClashMap yielding mutable references.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("Johnny", 21);
+    /// map.iter_mut().for_each(|mut r| *r += 1);
+    /// assert_eq!(*map.get("Johnny").unwrap(), 22);
+    /// ```
+    pub fn iter_mut(&self) -> IterMut<'_, K, V, L> {
+        IterMut::new(self)
+    }
+
+    /// Creates a consistent point-in-time copy of the map.
+    ///
+    /// Unlike [`iter`](ClashMap::iter), which locks one shard at a time, this read-locks all shards
+    /// at once while they are cloned. The copy therefore reflects a state that the map was actually in,
+    /// even while other threads are writing to it.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::new();
+    /// map.insert("Johnny", 21);
+    ///
+    /// let snapshot = map.snapshot();
+    /// map.insert("Johnny", 22);
+    /// assert_eq!(snapshot.get("Johnny"), Some(&21));
+    /// ```
+    pub fn snapshot(&self) -> ReadOnlyView<K, V, S, Sel>
+    where
+        K: Clone,
+        V: Clone,
+        S: Clone,
+        Sel: Clone,
+    {
+        let mut shards = Vec::with_capacity(self.table.tables.shards().len());
+        self.snapshot_with(|_, shard| shards.push(shard.clone()));
+        ReadOnlyView::from_shards(
+            shards.into_boxed_slice(),
+            self.table.tables.selector.clone(),
+            self.hasher.clone(),
+        )
+    }
+
+    /// Calls a closure with the index and contents of every shard, while holding
+    /// read locks on all shards at once.
+    ///
+    /// This sees the same consistent state as [`snapshot`](ClashMap::snapshot), without cloning the map.
+    /// All shards stay locked until the last call returns, so the closure should be quick.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let balances = ClashMap::new();
+    /// balances.insert("Alice", 70);
+    /// balances.insert("Bob", 80);
+    ///
+    /// let mut total = 0;
+    /// balances.snapshot_with(|_, shard| total += shard.iter().map(|(_, v)| v).sum::<i32>());
+    /// assert_eq!(total, 150);
+    /// ```
+    pub fn snapshot_with(&self, mut f: impl FnMut(usize, &HashMap<K, V>)) {
+        let (_layout, shards) = self.table.tables.enter();
+        let guards: Vec<_> = shards.iter().map(|shard| shard.read()).collect();
+
+        for (idx, shard) in guards.iter().enumerate() {
+            f(idx, shard);
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl<K, V, S, L: crate::stats::RawRwLockStats, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Returns the lock contention counters of every shard, in shard index order.
+    ///
+    /// The counters keep growing until they are reset with [`reset_shard_stats`](ClashMap::reset_shard_stats).
+    ///
+    /// Requires the `stats` feature to be enabled.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let map = ClashMap::with_shard_amount(4);
+    /// map.insert("Johnny", 21);
+    ///
+    /// let stats = map.shard_stats();
+    /// assert_eq!(stats.len(), 4);
+    /// assert_eq!(stats.iter().map(|s| s.fast_acquisitions).sum::<u64>(), 1);
+    /// assert_eq!(stats.iter().map(|s| s.reader_parks + s.writer_parks).sum::<u64>(), 0);
+    /// ```
+    pub fn shard_stats(&self) -> Vec<crate::stats::ShardStats> {
+        self.table.shard_stats()
+    }
+
+    /// Resets the lock contention counters of every shard to zero.
+    ///
+    /// Requires the `stats` feature to be enabled.
+    pub fn reset_shard_stats(&self) {
+        self.table.reset_shard_stats()
+    }
+}
+
+impl<K, V, S: BuildHasher, L: RawRwLockAsync, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    ///
+    /// **Locking behaviour:** May deadlock if awaited when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// # async fn run() {
+    /// let map = ClashMap::new();
+    /// map.insert_async("I am the key!", "And I am the value!").await;
+    /// # }
+    /// ```
+    pub async fn insert_async(&self, key: K, value: V) -> Option<V>
+    where
+        K: Eq + Hash,
+    {
+        match self.entry_async(key).await {
+            Entry::Occupied(mut o) => Some(o.insert(value)),
+            Entry::Vacant(v) => {
+                v.insert(value);
+                None
+            }
+        }
+    }
+
+    /// Get an immutable reference to an entry in the map.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    ///
+    /// **Locking behaviour:** May deadlock if awaited when holding a mutable reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// # async fn run() {
+    /// let youtubers = ClashMap::new();
+    /// youtubers.insert("Bosnian Bill", 457000);
+    /// assert_eq!(*youtubers.get_async("Bosnian Bill").await.unwrap(), 457000);
+    /// # }
+    /// ```
+    pub async fn get_async<Q>(&self, key: &Q) -> Option<Ref<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        self.table
+            .find_async(hash, |(k, _v)| key.equivalent(k))
+            .await
+            .map(Ref::from)
+    }
+
+    /// Get a mutable reference to an entry in the map.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    ///
+    /// **Locking behaviour:** May deadlock if awaited when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// # async fn run() {
+    /// let class = ClashMap::new();
+    /// class.insert("Albin", 15);
+    /// *class.get_mut_async("Albin").await.unwrap() -= 1;
+    /// assert_eq!(*class.get("Albin").unwrap(), 14);
+    /// # }
+    /// ```
+    pub async fn get_mut_async<Q>(&self, key: &Q) -> Option<RefMut<'_, K, V, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(&key);
+        self.table
+            .find_mut_async(hash, |(k, _v)| key.equivalent(k))
+            .await
+            .map(RefMut::from)
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    ///
+    /// **Locking behaviour:** May deadlock if awaited when holding any sort of reference into the map.
+    pub async fn entry_async(&self, key: K) -> Entry<'_, K, V, L>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        match expire_entry(
+            self.table
+                .entry_async(
+                    hash,
+                    |(k, _v)| k == &key,
+                    |(k, _v)| {
+                        let mut hasher = self.hasher.build_hasher();
+                        k.hash(&mut hasher);
+                        hasher.finish()
+                    },
+                )
+                .await,
+            self.notifier_for(hash),
+        ) {
+            crate::tableref::entry::Entry::Occupied(entry) => {
+                Entry::Occupied(OccupiedEntry::new(entry, key, self.notifier_for(hash)))
+            }
+            crate::tableref::entry::Entry::Vacant(entry) => {
+                Entry::Vacant(VacantEntry::new(entry, key, self.notifier_for(hash)))
+            }
+        }
+    }
+
+    /// Creates an asynchronous stream over a ClashMap yielding cloned key value pairs.
+    ///
+    /// Each shard is released before any of its entries are yielded,
+    /// so the stream never holds a lock while it is waiting to be polled.
+    /// To do so, the stream clones every entry of a shard into a buffer before it yields
+    /// the first of them, so it holds up to a whole shard's worth of clones at a time.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// # async fn run() {
+    /// let words = ClashMap::new();
+    /// words.insert("hello", "world");
+    ///
+    /// let mut stream = words.stream();
+    /// while let Some((k, v)) = stream.next().await {
+    ///     println!("{k} {v}");
+    /// }
+    /// # }
+    /// ```
+    pub fn stream(&self) -> EntryStream<'_, K, V, L>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        EntryStream::new(self)
+    }
+}
+
+impl<
+        K: Eq + Hash + fmt::Debug,
+        V: fmt::Debug,
+        S: BuildHasher,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector,
+    > fmt::Debug for ClashMap<K, V, S, L, Sel>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pmap = f.debug_map();
+        self.table.for_each(|(k, v)| {
+            pmap.entry(k, v);
+        });
+        pmap.finish()
+    }
+}
+
+impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    Shl<(K, V)> for &'a ClashMap<K, V, S, L, Sel>
+{
+    type Output = Option<V>;
+
+    fn shl(self, pair: (K, V)) -> Self::Output {
+        self.insert(pair.0, pair.1)
+    }
+}
+
+impl<
+        'a,
+        K: 'a + Eq + Hash,
+        V: 'a,
+        S: BuildHasher,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector,
+        Q,
+    > Shr<&Q> for &'a ClashMap<K, V, S, L, Sel>
+where
+    Q: Hash + Equivalent<K> + ?Sized,
+{
+    type Output = Ref<'a, K, V, L>;
+
+    fn shr(self, key: &Q) -> Self::Output {
+        self.get(key).unwrap()
+    }
+}
+
+impl<
+        'a,
+        K: 'a + Eq + Hash,
+        V: 'a,
+        S: BuildHasher,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector,
+        Q,
+    > BitOr<&Q> for &'a ClashMap<K, V, S, L, Sel>
+where
+    Q: Hash + Equivalent<K> + ?Sized,
+{
+    type Output = RefMut<'a, K, V, L>;
+
+    fn bitor(self, key: &Q) -> Self::Output {
+        self.get_mut(key).unwrap()
+    }
+}
+
+impl<
+        'a,
+        K: 'a + Eq + Hash,
+        V: 'a,
+        S: BuildHasher,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector,
+        Q,
+    > Sub<&Q> for &'a ClashMap<K, V, S, L, Sel>
+where
+    Q: Hash + Equivalent<K> + ?Sized,
+{
+    type Output = Option<(K, V)>;
+
+    fn sub(self, key: &Q) -> Self::Output {
+        self.remove(key)
+    }
+}
+
+impl<
+        'a,
+        K: 'a + Eq + Hash,
+        V: 'a,
+        S: BuildHasher,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector,
+        Q,
+    > BitAnd<&Q> for &'a ClashMap<K, V, S, L, Sel>
+where
+    Q: Hash + Equivalent<K> + ?Sized,
+{
+    type Output = bool;
+
+    fn bitand(self, key: &Q) -> Self::Output {
+        self.contains_key(key)
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> IntoIterator
+    for ClashMap<K, V, S, L, Sel>
+{
+    type Item = (K, V);
+
+    type IntoIter = OwningIter<K, V, L>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        OwningIter::new(self)
+    }
+}
+
+impl<'a, K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> IntoIterator
+    for &'a ClashMap<K, V, S, L, Sel>
+{
+    type Item = RefMulti<'a, K, V, L>;
+
+    type IntoIter = Iter<'a, K, V, L>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> Extend<(K, V)>
+    for ClashMap<K, V, S, L, Sel>
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, intoiter: I) {
+        for pair in intoiter.into_iter() {
+            self.insert_mut(pair.0, pair.1);
+        }
+    }
+}
+
+/// Inserts the pairs shard by shard, like [`ClashMap::insert_many`].
+impl<K: Eq + Hash, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> Extend<(K, V)>
+    for &ClashMap<K, V, S, L, Sel>
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, intoiter: I) {
+        self.insert_by_shard(intoiter, |_, _| {});
+    }
+}
+
+#[cfg(feature = "std")]
+impl<
+        K: Eq + Hash,
+        V,
+        S: BuildHasher + Default,
+        L: lock_api::RawRwLock,
+        Sel: ShardSelector + Default,
+    > FromIterator<(K, V)> for ClashMap<K, V, S, L, Sel>
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(intoiter: I) -> Self {
+        let mut map = ClashMap::default();
+
+        map.extend(intoiter);
+
+        map
+    }
+}
+
+#[cfg(feature = "typesize")]
+impl<K, V, S, L: lock_api::RawRwLock, Sel: ShardSelector> typesize::TypeSize
+    for ClashMap<K, V, S, L, Sel>
+where
+    K: typesize::TypeSize + Eq + Hash,
+    V: typesize::TypeSize,
+    S: typesize::TypeSize + BuildHasher,
+{
+    fn extra_size(&self) -> usize {
+        self.hasher.extra_size() + self.table.extra_size()
+    }
+
+    typesize::if_typesize_details! {
+        fn get_collection_item_count(&self) -> Option<usize> {
+            Some(self.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ClashMap;
+    use core::marker::PhantomData;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::collections::hash_map::RandomState;
+    use std::future::Future;
+    use std::thread;
+
+    #[test]
+    fn test_basic() {
+        let dm = ClashMap::new();
+
+        dm.insert(0, 0);
+
+        assert_eq!(dm.get(&0).unwrap().value(), &0);
+    }
+
+    #[test]
+    fn test_default() {
+        let dm: ClashMap<u32, u32> = ClashMap::default();
+
+        dm.insert(0, 0);
+
+        assert_eq!(dm.get(&0).unwrap().value(), &0);
+    }
+
+    #[test]
+    fn test_multiple_hashes() {
+        let dm: ClashMap<u32, u32> = ClashMap::default();
+
+        for i in 0..100 {
+            dm.insert(0, i);
+
+            dm.insert(i, i);
+        }
+
+        for i in 1..100 {
+            let r = dm.get(&i).unwrap();
+
+            assert_eq!(i, *r.value());
+
+            assert_eq!(i, *r.key());
+        }
+
+        let r = dm.get(&0).unwrap();
+
+        assert_eq!(99, *r.value());
+    }
+
+    #[test]
+    fn test_more_complex_values() {
+        #[derive(Hash, PartialEq, Debug, Clone)]
+
+        struct T0 {
+            s: String,
+            u: u8,
+        }
+
+        let dm = ClashMap::new();
+
+        let range = 0..10;
+
+        for i in range {
+            let t = T0 {
+                s: i.to_string(),
+                u: i as u8,
+            };
+
+            dm.insert(i, t.clone());
+
+            assert_eq!(&t, dm.get(&i).unwrap().value());
+        }
+    }
+
+    #[test]
+    fn test_different_hashers_randomstate() {
+        let dm_hm_default: ClashMap<u32, u32, RandomState> =
+            ClashMap::with_hasher(RandomState::new());
+
+        for i in 0..10 {
+            dm_hm_default.insert(i, i);
+
+            assert_eq!(i, *dm_hm_default.get(&i).unwrap().value());
+        }
+    }
+
+    #[test]
+    fn test_map_view() {
+        let dm = ClashMap::new();
+
+        let vegetables: [String; 4] = [
+            "Salad".to_string(),
+            "Beans".to_string(),
+            "Potato".to_string(),
+            "Tomato".to_string(),
+        ];
+
+        // Give it some values
+        dm.insert(0, "Banana".to_string());
+        dm.insert(4, "Pear".to_string());
+        dm.insert(9, "Potato".to_string());
+        dm.insert(12, "Chicken".to_string());
+
+        let potato_vegetableness = dm.view(&9, |_, v| vegetables.contains(v));
+        assert_eq!(potato_vegetableness, Some(true));
+
+        let chicken_vegetableness = dm.view(&12, |_, v| vegetables.contains(v));
+        assert_eq!(chicken_vegetableness, Some(false));
+
+        let not_in_map = dm.view(&30, |_k, _v| false);
+        assert_eq!(not_in_map, None);
+    }
+
+    #[test]
+    fn test_try_get() {
+        {
+            let map = ClashMap::new();
+            map.insert("Johnny", 21);
+
+            assert_eq!(*map.try_get("Johnny").unwrap(), 21);
+
+            let _result1_locking = map.get_mut("Johnny");
+
+            let result2 = map.try_get("Johnny");
+            assert!(result2.is_locked());
+        }
+
+        {
+            let map = ClashMap::new();
+            map.insert("Johnny", 21);
+
+            *map.try_get_mut("Johnny").unwrap() += 1;
+            assert_eq!(*map.get("Johnny").unwrap(), 22);
+
+            let _result1_locking = map.get("Johnny");
+
+            let result2 = map.try_get_mut("Johnny");
+            assert!(result2.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_get_many_mut_same_shard() {
+        let map = ClashMap::with_shard_amount(2);
+        for i in 0..8 {
+            map.insert(i, i);
+        }
+
+        // with only two shards, at least two of these keys share a shard
+        let refs = map.get_many_mut([&1, &2, &3, &100]);
+        let [a, b, c, d] = refs;
+        assert!(d.is_none());
+        for mut r in [a, b, c].into_iter().flatten() {
+            *r *= 10;
+        }
+
+        let values = map.get_many([&1, &2, &3]).map(|r| *r.unwrap());
+        assert_eq!(values, [10, 20, 30]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_get_many_mut_duplicate_keys() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+        let _refs = map.get_many_mut([&1, &1]);
+    }
+
+    #[test]
+    fn test_compute() {
+        let map = ClashMap::new();
+
+        assert!(map.compute(1, |_, v| v).is_none());
+        assert!(!map.contains_key(&1));
+
+        assert_eq!(*map.compute(1, |_, v| Some(v.unwrap_or(0) + 1)).unwrap(), 1);
+        assert_eq!(*map.compute(1, |_, v| Some(v.unwrap_or(0) + 1)).unwrap(), 2);
+
+        assert!(map.compute_if_present(&2, |_, v| Some(v)).is_none());
+        assert!(!map.contains_key(&2));
+        assert!(map.compute_if_present(&1, |_, _| None).is_none());
+        assert!(!map.contains_key(&1));
+
+        assert!(map.compute_if_absent(1, |_| None).is_none());
+        assert!(!map.contains_key(&1));
+        assert_eq!(*map.compute_if_absent(1, |k| Some(k * 10)).unwrap(), 10);
+
+        assert_eq!(*map.merge(1, 5, |old, new| Some(old + new)).unwrap(), 15);
+        assert!(map.merge(1, 5, |_, _| None).is_none());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn test_compute_panic_removes_entry() {
+        let map = ClashMap::new();
+        map.insert(1, 10);
+        map.insert(2, 20);
+
+        let computed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.compute(1, |_, _| panic!("compute failed"));
+        }));
+        assert!(computed.is_err());
+        assert!(!map.contains_key(&1));
+
+        let merged = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.merge(2, 5, |_, _| panic!("merge failed"));
+        }));
+        assert!(merged.is_err());
+        assert!(!map.contains_key(&2));
+
+        // the shard was unlocked while unwinding
+        map.insert(1, 11);
+        assert_eq!(*map.get(&1).unwrap(), 11);
+    }
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        let waker = std::sync::Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut f = std::pin::pin!(f);
+        loop {
+            if let std::task::Poll::Ready(out) = f.as_mut().poll(&mut cx) {
+                return out;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_async_waits_for_lock() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake, Waker};
+
+        #[derive(Default)]
+        struct Flag(AtomicBool);
+
+        impl Wake for Flag {
+            fn wake(self: Arc<Self>) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let flag = Arc::new(Flag::default());
+        let waker = Waker::from(flag.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let reader = map.get(&1).unwrap();
+        let mut writer = std::pin::pin!(map.get_mut_async(&1));
+        assert!(writer.as_mut().poll(&mut cx).is_pending());
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        drop(reader);
+        assert!(flag.0.load(Ordering::SeqCst));
+
+        match writer.as_mut().poll(&mut cx) {
+            Poll::Ready(Some(mut r)) => *r += 1,
+            _ => panic!("lock should be available"),
+        }
+        assert_eq!(*map.get(&1).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_async_contended() {
+        let map = ClashMap::with_shard_amount(2);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for i in 0..1000 {
+                        block_on(async {
+                            *map.entry_async(i % 4).await.or_insert(0) += 1;
+                        });
+                        map.insert(100 + i % 4, i);
+                    }
+                });
+            }
+        });
+
+        let total: i32 = block_on(async {
+            let mut total = 0;
+            for i in 0..4 {
+                total += *map.get_async(&i).await.unwrap();
+            }
+            total
+        });
+        assert_eq!(total, 4000);
+    }
+
+    #[test]
+    fn test_stream() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..100 {
+            block_on(map.insert_async(i, i * 2));
+        }
+
+        let mut entries = block_on(async {
+            let mut stream = map.stream();
+            let mut entries = Vec::new();
+            while let Some(kv) = stream.next().await {
+                entries.push(kv);
+            }
+            entries
+        });
+        entries.sort();
+
+        assert_eq!(entries, (0..100).map(|i| (i, i * 2)).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_stream_trait() {
+        use crate::test_util::FnvHasher;
+        use core::hash::BuildHasherDefault;
+        use futures_core::Stream;
+
+        let hasher = BuildHasherDefault::<FnvHasher>::default();
+        let map = ClashMap::with_hasher_and_shard_amount(hasher, 4);
+        for i in 0..100 {
+            map.insert(i, i * 2);
+        }
+
+        let mut stream = map.stream();
+        let mut entries = Vec::new();
+        block_on(core::future::poll_fn(|cx| loop {
+            match std::task::ready!(Stream::poll_next(std::pin::Pin::new(&mut stream), cx)) {
+                Some(kv) => entries.push(kv),
+                None => return std::task::Poll::Ready(()),
+            }
+        }));
+        entries.sort();
+
+        assert_eq!(entries, (0..100).map(|i| (i, i * 2)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_snapshot_is_consistent() {
+        let map = ClashMap::with_shard_amount(8);
+        for i in 0..64 {
+            map.insert(i, 100);
+        }
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for n in 0..10_000 {
+                    let (from, to) = (n % 64, (n * 7 + 1) % 64);
+                    if from != to {
+                        // two separate writes, so the total is only off in between
+                        *map.get_mut(&from).unwrap() -= 1;
+                        *map.get_mut(&to).unwrap() += 1;
+                    }
+                }
+            });
+
+            for _ in 0..100 {
+                let snapshot = map.snapshot();
+                assert_eq!(snapshot.len(), 64);
+
+                let mut total = 0;
+                map.snapshot_with(|_, shard| total += shard.iter().map(|(_, v)| v).sum::<i32>());
+                assert!((6399..=6400).contains(&total));
+                assert!((6399..=6400).contains(&snapshot.values().sum::<i32>()));
+            }
+        });
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut map: ClashMap<i32, i32> = ClashMap::new();
+        // ClashMap is empty and doesn't allocate memory
+        assert_eq!(map.capacity(), 0);
+
+        map.try_reserve(10).unwrap();
+
+        // And now map can hold at least 10 elements
+        assert!(map.capacity() >= 10);
     }
-}
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher> Shl<(K, V)> for &'a ClashMap<K, V, S> {
-    type Output = Option<V>;
+    #[test]
+    fn test_try_reserve_errors() {
+        let mut map: ClashMap<i32, i32> = ClashMap::new();
 
-    fn shl(self, pair: (K, V)) -> Self::Output {
-        self.insert(pair.0, pair.1)
+        match map.try_reserve(usize::MAX) {
+            Err(_) => {}
+            _ => panic!("should have raised CapacityOverflow error"),
+        }
     }
-}
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, Q> Shr<&Q> for &'a ClashMap<K, V, S>
-where
-    Q: Hash + Equivalent<K> + ?Sized,
-{
-    type Output = Ref<'a, K, V>;
+    #[test]
+    fn test_listener() {
+        use crate::listener::Event;
+        use std::sync::{Arc, Mutex};
 
-    fn shr(self, key: &Q) -> Self::Output {
-        self.get(key).unwrap()
-    }
-}
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let events = log.clone();
+        let mut map = ClashMap::with_shard_amount(4).with_listener(
+            move |shard, event: Event<'_, i32, i32>| {
+                let event = match event {
+                    Event::Inserted { key, value } => format!("insert {key} {value}"),
+                    Event::Replaced { key, value } => format!("replace {key} {value}"),
+                    Event::Removed { key, value } => format!("remove {key} {value}"),
+                    Event::Cleared => format!("clear {shard}"),
+                };
+                events.lock().unwrap().push(event);
+            },
+        );
+        let take = || std::mem::take(&mut *log.lock().unwrap());
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, Q> BitOr<&Q> for &'a ClashMap<K, V, S>
-where
-    Q: Hash + Equivalent<K> + ?Sized,
-{
-    type Output = RefMut<'a, K, V>;
+        map.insert(1, 10);
+        map.insert(1, 11);
+        map.entry(2).or_insert(20);
+        map.alter(&2, |_, v| v + 1);
+        map.insert_mut(3, 30);
+        assert_eq!(
+            take(),
+            [
+                "insert 1 10",
+                "replace 1 11",
+                "insert 2 20",
+                "replace 2 21",
+                "insert 3 30"
+            ]
+        );
 
-    fn bitor(self, key: &Q) -> Self::Output {
-        self.get_mut(key).unwrap()
-    }
-}
+        map.compute(1, |_, _| None);
+        map.merge(2, 1, |old, new| Some(old + new));
+        map.remove(&3);
+        assert_eq!(
+            take(),
+            ["remove 1 11", "remove 2 21", "insert 2 22", "remove 3 30"]
+        );
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, Q> Sub<&Q> for &'a ClashMap<K, V, S>
-where
-    Q: Hash + Equivalent<K> + ?Sized,
-{
-    type Output = Option<(K, V)>;
+        map.insert(4, 40);
+        map.retain(|k, _| *k != 4);
+        map.alter_all(|_, v| v * 2);
+        drop(map.drain());
+        assert_eq!(
+            take(),
+            ["insert 4 40", "remove 4 40", "replace 2 44", "remove 2 44"]
+        );
 
-    fn sub(self, key: &Q) -> Self::Output {
-        self.remove(key)
+        map.insert(5, 50);
+        map.write_all().remove(&5);
+        map.clear();
+        let mut events = take();
+        assert_eq!(
+            events.drain(..2).collect::<Vec<_>>(),
+            ["insert 5 50", "remove 5 50"]
+        );
+        assert_eq!(events, ["clear 0", "clear 1", "clear 2", "clear 3"]);
     }
-}
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, Q> BitAnd<&Q> for &'a ClashMap<K, V, S>
-where
-    Q: Hash + Equivalent<K> + ?Sized,
-{
-    type Output = bool;
+    #[test]
+    fn test_reshard() {
+        let mut map = ClashMap::with_shard_amount(4);
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
 
-    fn bitand(self, key: &Q) -> Self::Output {
-        self.contains_key(key)
+        map.reshard(64);
+        assert_eq!(map.shard_info().shards.len(), 64);
+        assert_eq!(map.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(*map.get(&i).unwrap(), i * 2);
+        }
+
+        map.reshard(2);
+        assert_eq!(map.shard_info().shards.len(), 2);
+        assert_eq!(map.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(*map.get(&i).unwrap(), i * 2);
+        }
     }
-}
 
-impl<K: Eq + Hash, V, S: BuildHasher> IntoIterator for ClashMap<K, V, S> {
-    type Item = (K, V);
+    #[test]
+    #[should_panic = "shard_amount > 1"]
+    fn test_reshard_to_zero() {
+        let mut map = ClashMap::<u32, u32>::with_shard_amount(4);
+        map.insert(1, 1);
+        map.reshard(0);
+    }
+
+    #[test]
+    fn test_reshard_incremental() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..10_000 {
+            map.insert(i, i * 2);
+        }
 
-    type IntoIter = OwningIter<K, V>;
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            let readers: Vec<_> = (0..4)
+                .map(|t| {
+                    let (map, done) = (&map, &done);
+                    s.spawn(move || {
+                        let mut rounds = 0;
+                        while !done.load(Ordering::Relaxed) || rounds == 0 {
+                            for i in 0..10_000 {
+                                assert_eq!(*map.get(&i).unwrap(), i * 2);
+                            }
+                            map.insert(10_000 + t * 100 + rounds % 100, 0);
+                            rounds += 1;
+                        }
+                    })
+                })
+                .collect();
 
-    fn into_iter(self) -> Self::IntoIter {
-        OwningIter::new(self)
+            map.reshard_incremental(64);
+            assert_eq!(map.shard_info().shards.len(), 64);
+            map.reshard_incremental(8);
+            done.store(true, Ordering::Relaxed);
+            for reader in readers {
+                reader.join().unwrap();
+            }
+        });
+
+        assert_eq!(map.shard_info().shards.len(), 8);
+        assert!(map.len() > 10_000);
+        assert_eq!(map.iter().count(), map.len());
+        for i in 0..10_000 {
+            assert_eq!(*map.get(&i).unwrap(), i * 2);
+        }
     }
-}
 
-impl<'a, K: Eq + Hash, V, S: BuildHasher> IntoIterator for &'a ClashMap<K, V, S> {
-    type Item = RefMulti<'a, K, V>;
+    #[test]
+    fn test_reshard_incremental_waits_for_iterators() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..100 {
+            map.insert(i, i);
+        }
 
-    type IntoIter = Iter<'a, K, V>;
+        thread::scope(|s| {
+            let mut iter = map.iter();
+            let first = iter.next().map(|r| *r.key());
+            let resharding = s.spawn(|| map.reshard_incremental(16));
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter()
+            // The entries stay in their shards until the iterator is dropped.
+            let mut keys: Vec<_> = first.into_iter().chain(iter.map(|r| *r.key())).collect();
+            keys.sort_unstable();
+            assert_eq!(keys, (0..100).collect::<Vec<_>>());
+
+            resharding.join().unwrap();
+        });
+        assert_eq!(map.shard_info().shards.len(), 16);
     }
-}
 
-impl<K: Eq + Hash, V, S: BuildHasher> Extend<(K, V)> for ClashMap<K, V, S> {
-    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, intoiter: I) {
-        for pair in intoiter.into_iter() {
-            self.insert_mut(pair.0, pair.1);
+    #[test]
+    fn test_reshard_after_reshard_incremental() {
+        let mut map = ClashMap::with_shard_amount(4);
+        for i in 0..1000 {
+            map.insert(i, i * 2);
         }
+
+        map.reshard_incremental(32);
+        map.reshard_incremental(16);
+        *map.get_mut(&1).unwrap() += 1;
+        map.reshard(8);
+        assert_eq!(map.shard_info().shards.len(), 8);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(*map.get(&1).unwrap(), 3);
+        assert_eq!(map.into_iter().count(), 1000);
     }
-}
 
-impl<K: Eq + Hash, V, S: BuildHasher + Default> FromIterator<(K, V)> for ClashMap<K, V, S> {
-    fn from_iter<I: IntoIterator<Item = (K, V)>>(intoiter: I) -> Self {
-        let mut map = ClashMap::default();
+    static HASH_PANICS: AtomicBool = AtomicBool::new(false);
 
-        map.extend(intoiter);
+    /// A key whose hash panics while `HASH_PANICS` is set.
+    #[derive(PartialEq, Eq)]
+    struct PanickyKey(u32);
 
-        map
+    impl core::hash::Hash for PanickyKey {
+        fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+            assert!(!(HASH_PANICS.load(Ordering::Relaxed) && self.0 == 500));
+            self.0.hash(state);
+        }
     }
-}
 
-#[cfg(feature = "typesize")]
-impl<K, V, S> typesize::TypeSize for ClashMap<K, V, S>
-where
-    K: typesize::TypeSize + Eq + Hash,
-    V: typesize::TypeSize,
-    S: typesize::TypeSize + BuildHasher,
-{
-    fn extra_size(&self) -> usize {
-        self.hasher.extra_size() + self.table.extra_size()
-    }
+    #[test]
+    fn test_reshard_keeps_entries_when_hash_panics() {
+        let mut map = ClashMap::with_shard_amount(4);
+        for i in 0..1000 {
+            map.insert(PanickyKey(i), i);
+        }
 
-    typesize::if_typesize_details! {
-        fn get_collection_item_count(&self) -> Option<usize> {
-            Some(self.len())
+        HASH_PANICS.store(true, Ordering::Relaxed);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| map.reshard(16)));
+        HASH_PANICS.store(false, Ordering::Relaxed);
+        assert!(result.is_err());
+
+        assert_eq!(map.shard_info().shards.len(), 4);
+        assert_eq!(map.len(), 1000);
+        for i in 0..1000 {
+            assert_eq!(*map.get(&PanickyKey(i)).unwrap(), i);
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::ClashMap;
-    use std::collections::hash_map::RandomState;
+    /// A minimal spin lock, counting how often it was locked for writing.
+    struct SpinLock(AtomicUsize);
 
-    #[test]
-    fn test_basic() {
-        let dm = ClashMap::new();
+    static SPIN_WRITES: AtomicUsize = AtomicUsize::new(0);
 
-        dm.insert(0, 0);
+    const SPIN_WRITER: usize = usize::MAX;
 
-        assert_eq!(dm.get(&0).unwrap().value(), &0);
+    // SAFETY: the state is only changed by successful compare exchanges, so a writer
+    // excludes everyone else and readers only exclude writers.
+    unsafe impl lock_api::RawRwLock for SpinLock {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = SpinLock(AtomicUsize::new(0));
+
+        type GuardMarker = lock_api::GuardSend;
+
+        fn lock_shared(&self) {
+            while !self.try_lock_shared() {
+                std::hint::spin_loop();
+            }
+        }
+
+        fn try_lock_shared(&self) -> bool {
+            let state = self.0.load(Ordering::Relaxed);
+            state != SPIN_WRITER
+                && self
+                    .0
+                    .compare_exchange(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+        }
+
+        unsafe fn unlock_shared(&self) {
+            self.0.fetch_sub(1, Ordering::Release);
+        }
+
+        fn lock_exclusive(&self) {
+            while !self.try_lock_exclusive() {
+                std::hint::spin_loop();
+            }
+        }
+
+        fn try_lock_exclusive(&self) -> bool {
+            let locked = self
+                .0
+                .compare_exchange(0, SPIN_WRITER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok();
+            if locked {
+                SPIN_WRITES.fetch_add(1, Ordering::Relaxed);
+            }
+            locked
+        }
+
+        unsafe fn unlock_exclusive(&self) {
+            self.0.store(0, Ordering::Release);
+        }
     }
 
     #[test]
-    fn test_default() {
-        let dm: ClashMap<u32, u32> = ClashMap::default();
+    fn test_custom_lock() {
+        let mut map: ClashMap<i32, i32, RandomState, SpinLock> =
+            ClashMap::with_capacity_and_hasher_and_shard_amount_and_lock(16, RandomState::new(), 4);
+        assert_eq!(map.shard_info().shards.len(), 4);
+        std::thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in 0..100 {
+                        map.insert(t * 100 + i, i);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), 400);
+        assert!(SPIN_WRITES.load(Ordering::Relaxed) >= 400);
 
-        dm.insert(0, 0);
+        *map.entry(1).or_default() += 10;
+        assert_eq!(*map.get(&1).unwrap(), 11);
+        assert_eq!(map.remove(&2), Some((2, 2)));
+        map.retain(|_, v| *v < 50);
+        assert_eq!(map.iter().count(), 199);
 
-        assert_eq!(dm.get(&0).unwrap().value(), &0);
+        let cloned = map.clone();
+        assert_eq!(cloned.len(), 199);
+        assert_eq!(*cloned.get(&1).unwrap(), 11);
+
+        map.reshard(8);
+        assert_eq!(map.shard_info().shards.len(), 8);
+        assert_eq!(map.len(), 199);
+        assert_eq!(*map.get(&1).unwrap(), 11);
     }
 
     #[test]
-    fn test_multiple_hashes() {
-        let dm: ClashMap<u32, u32> = ClashMap::default();
+    fn test_custom_lock_and_selector() {
+        let map: ClashMap<u64, u64, RandomState, SpinLock, _> =
+            ClashMap::with_capacity_and_hasher_and_shard_selector_and_lock(
+                0,
+                RandomState::new(),
+                4,
+                |_hash: usize, _amount: usize| 3,
+            );
+        for i in 0..10 {
+            map.insert(i, i);
+        }
 
-        for i in 0..100 {
-            dm.insert(0, i);
+        let info = map.shard_info();
+        assert_eq!(info.shards[3].len, 10);
+        assert_eq!(map.with_shard_for(&0u64, |shard| shard.index()), 3);
+    }
 
-            dm.insert(i, i);
+    /// A lock borrowing nothing, whose type still has a lifetime.
+    struct ScopedLock<'a>(SpinLock, PhantomData<&'a ()>);
+
+    // SAFETY: every call is forwarded to the spin lock.
+    unsafe impl lock_api::RawRwLock for ScopedLock<'_> {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const INIT: Self = ScopedLock(SpinLock::INIT, PhantomData);
+
+        type GuardMarker = lock_api::GuardSend;
+
+        fn lock_shared(&self) {
+            self.0.lock_shared()
         }
 
-        for i in 1..100 {
-            let r = dm.get(&i).unwrap();
+        fn try_lock_shared(&self) -> bool {
+            self.0.try_lock_shared()
+        }
 
-            assert_eq!(i, *r.value());
+        unsafe fn unlock_shared(&self) {
+            // SAFETY: the caller holds a shared lock.
+            unsafe { self.0.unlock_shared() }
+        }
 
-            assert_eq!(i, *r.key());
+        fn lock_exclusive(&self) {
+            self.0.lock_exclusive()
         }
 
-        let r = dm.get(&0).unwrap();
+        fn try_lock_exclusive(&self) -> bool {
+            self.0.try_lock_exclusive()
+        }
 
-        assert_eq!(99, *r.value());
+        unsafe fn unlock_exclusive(&self) {
+            // SAFETY: the caller holds the exclusive lock.
+            unsafe { self.0.unlock_exclusive() }
+        }
+    }
+
+    fn reshard_scoped(map: &mut ClashMap<i32, i32, RandomState, ScopedLock<'_>>) {
+        map.reshard(8);
     }
 
     #[test]
-    fn test_more_complex_values() {
-        #[derive(Hash, PartialEq, Debug, Clone)]
+    fn test_reshard_non_static_lock() {
+        let mut map =
+            ClashMap::with_capacity_and_hasher_and_shard_amount_and_lock(0, RandomState::new(), 4);
+        map.insert(1, 1);
+        reshard_scoped(&mut map);
+        assert_eq!(map.shard_info().shards.len(), 8);
+        assert_eq!(*map.get(&1).unwrap(), 1);
+    }
 
-        struct T0 {
-            s: String,
-            u: u8,
-        }
+    #[test]
+    fn test_batches() {
+        let map: ClashMap<u32, u32> = ClashMap::with_shard_amount(4);
+        map.insert(1, 1);
 
-        let dm = ClashMap::new();
+        let old = map.insert_many((0..100).map(|i| (i, i * 10)).chain([(5, 0)]));
+        assert_eq!(old.len(), 101);
+        assert_eq!(old[1], Some(1));
+        assert_eq!(old[100], Some(50));
+        assert_eq!(old.iter().filter(|o| o.is_some()).count(), 2);
+        assert_eq!(map.len(), 100);
+        assert_eq!(*map.get(&5).unwrap(), 0);
 
-        let range = 0..10;
+        let keys = [3, 200, 7, 3];
+        assert_eq!(
+            map.get_many_with(&keys, |k, v| k + v),
+            [Some(33), None, Some(77), Some(33)]
+        );
 
-        for i in range {
-            let t = T0 {
-                s: i.to_string(),
-                u: i as u8,
-            };
+        assert_eq!(
+            map.remove_many(&keys),
+            [Some((3, 30)), None, Some((7, 70)), None]
+        );
+        assert_eq!(map.len(), 98);
 
-            dm.insert(i, t.clone());
+        let mut target = &map;
+        target.extend((100..200).map(|i| (i, i)));
+        assert_eq!(map.len(), 198);
+        assert_eq!(*map.get(&150).unwrap(), 150);
+    }
 
-            assert_eq!(&t, dm.get(&i).unwrap().value());
+    #[test]
+    fn test_batch_listener() {
+        use crate::listener::Event;
+        use std::sync::{Arc, Mutex};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let events = log.clone();
+        let map = ClashMap::with_shard_amount(4).with_listener(
+            move |shard, event: Event<'_, i32, i32>| {
+                let (kind, key) = match event {
+                    Event::Inserted { key, .. } => ("insert", *key),
+                    Event::Replaced { key, .. } => ("replace", *key),
+                    Event::Removed { key, .. } => ("remove", *key),
+                    Event::Cleared => ("clear", 0),
+                };
+                events.lock().unwrap().push((kind, key, shard));
+            },
+        );
+
+        map.insert_many((0..50).map(|i| (i, i)));
+        map.insert_many((40..60).map(|i| (i, i)));
+        map.remove_many(&[1, 2, 100]);
+
+        let log = log.lock().unwrap();
+        let count = |kind| log.iter().filter(|e| e.0 == kind).count();
+        assert_eq!(count("insert"), 60);
+        assert_eq!(count("replace"), 10);
+        assert_eq!(count("remove"), 2);
+        for &(_, key, shard) in log.iter() {
+            assert_eq!(
+                shard,
+                map.table.tables._determine_shard(map.hash_usize(&key))
+            );
         }
     }
 
     #[test]
-    fn test_different_hashers_randomstate() {
-        let dm_hm_default: ClashMap<u32, u32, RandomState> =
-            ClashMap::with_hasher(RandomState::new());
+    fn test_get_or_compute_single_flight() {
+        let map = ClashMap::new();
+        let computed = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    let v = map.get_or_compute(1, || {
+                        computed.fetch_add(1, Ordering::Relaxed);
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        10
+                    });
+                    assert_eq!(*v, 10);
+                });
+            }
+        });
+        assert_eq!(computed.load(Ordering::Relaxed), 1);
+    }
 
-        for i in 0..10 {
-            dm_hm_default.insert(i, i);
+    #[test]
+    fn test_get_or_compute_releases_shard() {
+        let map = ClashMap::with_shard_amount(2);
+        let v = map.get_or_compute(1, || {
+            // the shard is not locked while computing, and the key is not there yet
+            assert!(map.get(&1).is_none());
+            map.insert(2, 20);
+            10
+        });
+        assert_eq!(*v, 10);
+        drop(v);
+        assert_eq!(*map.get(&2).unwrap(), 20);
 
-            assert_eq!(i, *dm_hm_default.get(&i).unwrap().value());
+        // a value inserted while computing wins
+        let v = map.get_or_compute(3, || {
+            map.insert(3, 30);
+            0
+        });
+        assert_eq!(*v, 30);
+    }
+
+    #[test]
+    fn test_get_or_compute_after_reshard() {
+        let mut map = ClashMap::with_shard_amount(2);
+        map.reshard(16);
+        for i in 0..100 {
+            assert_eq!(*map.get_or_compute(i, || i * 2), i * 2);
         }
+        assert_eq!(map.len(), 100);
     }
 
     #[test]
-    fn test_map_view() {
-        let dm = ClashMap::new();
+    fn test_flights_allocated_by_first_compute() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+        assert_eq!(*map.get_or_compute(1, || unreachable!()), 1);
+        assert!(!map.flights.is_allocated());
 
-        let vegetables: [String; 4] = [
-            "Salad".to_string(),
-            "Beans".to_string(),
-            "Potato".to_string(),
-            "Tomato".to_string(),
-        ];
+        assert_eq!(*map.get_or_compute(2, || 4), 4);
+        assert!(map.flights.is_allocated());
+    }
 
-        // Give it some values
-        dm.insert(0, "Banana".to_string());
-        dm.insert(4, "Pear".to_string());
-        dm.insert(9, "Potato".to_string());
-        dm.insert(12, "Chicken".to_string());
+    #[test]
+    fn test_get_or_try_compute_failure() {
+        let map = ClashMap::new();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let res = map.get_or_try_compute(1, || {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    Err("failed")
+                });
+                assert_eq!(res.unwrap_err(), "failed");
+            });
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                // waits for the failed computation, then computes the value itself
+                let v = map.get_or_try_compute(1, || Ok::<_, &str>(10)).unwrap();
+                assert_eq!(*v, 10);
+            });
+        });
 
-        let potato_vegetableness = dm.view(&9, |_, v| vegetables.contains(v));
-        assert_eq!(potato_vegetableness, Some(true));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.get_or_compute(2, || panic!("computation panicked"));
+        }));
+        assert!(res.is_err());
+        assert_eq!(*map.get_or_compute(2, || 20), 20);
+    }
 
-        let chicken_vegetableness = dm.view(&12, |_, v| vegetables.contains(v));
-        assert_eq!(chicken_vegetableness, Some(false));
+    #[test]
+    fn test_insert_with_ttl() {
+        use crate::cache::ManualClock;
+        use std::sync::Arc;
+        use std::time::Duration;
 
-        let not_in_map = dm.view(&30, |_k, _v| false);
-        assert_eq!(not_in_map, None);
+        let clock = Arc::new(ManualClock::new());
+        let map = ClashMap::with_shard_amount(4).with_clock(clock.clone());
+        assert_eq!(map.insert_with_ttl(1, 10, Duration::from_secs(10)), None);
+        map.insert_with_ttl(2, 20, Duration::from_secs(20));
+        map.insert(3, 30);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(*map.get(&1).unwrap(), 10);
+        assert_eq!(map.iter().count(), 3);
+
+        clock.advance(Duration::from_secs(1));
+        assert!(map.get(&1).is_none());
+        assert!(map.get_mut(&1).is_none());
+        assert!(!map.contains_key(&1));
+        let mut keys: Vec<_> = map.iter().map(|r| *r.key()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, [2, 3]);
+        assert_eq!(map.iter_mut().count(), 2);
+        // the lookups removed the expired entry
+        assert_eq!(map.len(), 2);
+
+        // the expired value is not handed back when the key is inserted again
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(map.insert_with_ttl(2, 21, Duration::from_secs(5)), None);
+        assert_eq!(*map.get(&2).unwrap(), 21);
+        assert_eq!(*map.get(&3).unwrap(), 30);
     }
 
     #[test]
-    fn test_try_get() {
-        {
-            let map = ClashMap::new();
-            map.insert("Johnny", 21);
+    fn test_ttl_kept_or_dropped() {
+        use crate::cache::ManualClock;
+        use crate::mapref::entry::Entry;
+        use std::sync::Arc;
+        use std::time::Duration;
 
-            assert_eq!(*map.try_get("Johnny").unwrap(), 21);
+        let clock = Arc::new(ManualClock::new());
+        let map = ClashMap::new().with_clock(clock.clone());
+        let ttl = Duration::from_secs(10);
 
-            let _result1_locking = map.get_mut("Johnny");
+        // overwriting the value drops the time-to-live, changing it in place keeps it
+        map.insert_with_ttl(1, 10, ttl);
+        map.insert(1, 11);
+        map.insert_with_ttl(2, 20, ttl);
+        map.alter(&2, |_, v| v + 1);
+        map.insert_with_ttl(3, 30, ttl);
+        map.remove(&3);
+        map.insert(3, 31);
 
-            let result2 = map.try_get("Johnny");
-            assert!(result2.is_locked());
+        // the time-to-live is set and dropped through an entry
+        map.insert(4, 40);
+        match map.entry(4) {
+            Entry::Occupied(mut entry) => entry.set_expiry(Some(ttl)),
+            Entry::Vacant(_) => unreachable!(),
+        }
+        map.insert_with_ttl(5, 50, ttl);
+        match map.entry(5) {
+            Entry::Occupied(mut entry) => entry.set_expiry(None),
+            Entry::Vacant(_) => unreachable!(),
         }
 
-        {
-            let map = ClashMap::new();
-            map.insert("Johnny", 21);
+        clock.advance(ttl);
+        let mut keys: Vec<_> = map.iter().map(|r| *r.key()).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, [1, 3, 5]);
+        assert!(matches!(map.entry(2), Entry::Vacant(_)));
+        assert!(matches!(map.entry(4), Entry::Vacant(_)));
+        assert_eq!(map.len(), 3);
+    }
 
-            *map.try_get_mut("Johnny").unwrap() += 1;
-            assert_eq!(*map.get("Johnny").unwrap(), 22);
+    #[test]
+    fn test_get_expired_while_holding_ref() {
+        use crate::cache::ManualClock;
+        use std::sync::Arc;
+        use std::time::Duration;
 
-            let _result1_locking = map.get("Johnny");
+        let clock = Arc::new(ManualClock::new());
+        let map = ClashMap::with_shard_amount(2).with_clock(clock.clone());
+        let shard = |k: &i32| map.table.tables._determine_shard(map.hash_usize(k));
+        let other = (2..).find(|k| shard(k) == shard(&1)).unwrap();
+        map.insert(1, 10);
+        map.insert_with_ttl(other, 20, Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
 
-            let result2 = map.try_get_mut("Johnny");
-            assert!(result2.is_locked());
-        }
+        // the shard is read-locked, so the expired entry is left for later instead of deadlocking
+        let one = map.get(&1).unwrap();
+        assert!(map.get(&other).is_none());
+        assert_eq!(map.len(), 2);
+        drop(one);
+
+        assert!(map.get(&other).is_none());
+        assert_eq!(map.len(), 1);
     }
 
     #[test]
-    fn test_try_reserve() {
-        let mut map: ClashMap<i32, i32> = ClashMap::new();
-        // ClashMap is empty and doesn't allocate memory
-        assert_eq!(map.capacity(), 0);
+    fn test_purge_expired() {
+        use crate::cache::ManualClock;
+        use crate::listener::Event;
+        use std::sync::Arc;
+        use std::time::Duration;
 
-        map.try_reserve(10).unwrap();
+        let removed = Arc::new(AtomicUsize::new(0));
+        let counter = removed.clone();
+        let clock = Arc::new(ManualClock::new());
+        let map = ClashMap::with_shard_amount(4)
+            .with_listener(move |_shard, event: Event<'_, u64, u64>| {
+                if let Event::Removed { .. } = event {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+            .with_clock(clock.clone());
 
-        // And now map can hold at least 10 elements
-        assert!(map.capacity() >= 10);
+        // one entry expires every second, over the ranges of several levels of the wheel
+        for i in 1..=1000 {
+            map.insert_with_ttl(i, i, Duration::from_secs(i));
+        }
+        map.insert(0, 0);
+        map.purge_expired();
+        assert_eq!(map.len(), 1001);
+
+        let mut now = 0;
+        for elapsed in [1, 10, 100, 500, 999, 1000] {
+            clock.advance(Duration::from_secs(elapsed - now));
+            now = elapsed;
+            map.purge_expired();
+            assert_eq!(map.len() as u64, 1001 - elapsed);
+            assert_eq!(removed.load(Ordering::Relaxed) as u64, elapsed);
+        }
+        assert_eq!(*map.get(&0).unwrap(), 0);
+        assert!(map.expiries.is_empty());
     }
 
     #[test]
-    fn test_try_reserve_errors() {
-        let mut map: ClashMap<i32, i32> = ClashMap::new();
+    fn test_purge_expired_skips_changed_deadlines() {
+        use crate::cache::ManualClock;
+        use crate::mapref::entry::Entry;
+        use std::sync::Arc;
+        use std::time::Duration;
 
-        match map.try_reserve(usize::MAX) {
-            Err(_) => {}
-            _ => panic!("should have raised CapacityOverflow error"),
+        let clock = Arc::new(ManualClock::new());
+        let map = ClashMap::new().with_clock(clock.clone());
+        map.insert_with_ttl(1, 10, Duration::from_secs(1));
+        map.insert_with_ttl(2, 20, Duration::from_secs(1));
+        if let Entry::Occupied(mut entry) = map.entry(2) {
+            entry.set_expiry(Some(Duration::from_secs(3)));
         }
+
+        clock.advance(Duration::from_secs(2));
+        map.purge_expired();
+        assert!(!map.contains_key(&1));
+        assert_eq!(*map.get(&2).unwrap(), 20);
+
+        clock.advance(Duration::from_secs(1));
+        map.purge_expired();
+        assert!(map.is_empty());
     }
 }
@@ -1,13 +1,19 @@
 use super::one::RefMut;
+use crate::listener::Notifier;
+use crate::lock::RawRwLock;
 use crate::tableref;
+#[cfg(feature = "std")]
+use core::hash::Hash;
 use core::mem;
+#[cfg(feature = "std")]
+use std::time::Duration;
 
-pub enum Entry<'a, K, V> {
-    Occupied(OccupiedEntry<'a, K, V>),
-    Vacant(VacantEntry<'a, K, V>),
+pub enum Entry<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    Occupied(OccupiedEntry<'a, K, V, L>),
+    Vacant(VacantEntry<'a, K, V, L>),
 }
 
-impl<'a, K, V> Entry<'a, K, V> {
+impl<'a, K, V, L: lock_api::RawRwLock> Entry<'a, K, V, L> {
     /// Apply a function to the stored value if it exists.
     pub fn and_modify(self, f: impl FnOnce(&mut V)) -> Self {
         match self {
@@ -39,7 +45,7 @@ impl<'a, K, V> Entry<'a, K, V> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise insert the default and return a mutable reference to that.
-    pub fn or_default(self) -> RefMut<'a, K, V>
+    pub fn or_default(self) -> RefMut<'a, K, V, L>
     where
         V: Default,
     {
@@ -51,7 +57,7 @@ impl<'a, K, V> Entry<'a, K, V> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise a provided value and return a mutable reference to that.
-    pub fn or_insert(self, value: V) -> RefMut<'a, K, V> {
+    pub fn or_insert(self, value: V) -> RefMut<'a, K, V, L> {
         match self {
             Entry::Occupied(entry) => entry.into_ref(),
             Entry::Vacant(entry) => entry.insert(value),
@@ -60,7 +66,7 @@ impl<'a, K, V> Entry<'a, K, V> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise insert the result of a provided function and return a mutable reference to that.
-    pub fn or_insert_with(self, value: impl FnOnce() -> V) -> RefMut<'a, K, V> {
+    pub fn or_insert_with(self, value: impl FnOnce() -> V) -> RefMut<'a, K, V, L> {
         match self {
             Entry::Occupied(entry) => entry.into_ref(),
             Entry::Vacant(entry) => entry.insert(value()),
@@ -70,7 +76,7 @@ impl<'a, K, V> Entry<'a, K, V> {
     pub fn or_try_insert_with<E>(
         self,
         value: impl FnOnce() -> Result<V, E>,
-    ) -> Result<RefMut<'a, K, V>, E> {
+    ) -> Result<RefMut<'a, K, V, L>, E> {
         match self {
             Entry::Occupied(entry) => Ok(entry.into_ref()),
             Entry::Vacant(entry) => Ok(entry.insert(value()?)),
@@ -78,7 +84,7 @@ impl<'a, K, V> Entry<'a, K, V> {
     }
 
     /// Sets the value of the entry, and returns a reference to the inserted value.
-    pub fn insert(self, value: V) -> RefMut<'a, K, V> {
+    pub fn insert(self, value: V) -> RefMut<'a, K, V, L> {
         match self {
             Entry::Occupied(mut entry) => {
                 entry.insert(value);
@@ -94,7 +100,7 @@ impl<'a, K, V> Entry<'a, K, V> {
     /// consider [`insert`] as it doesn't need to clone the key.
     ///
     /// [`insert`]: Entry::insert
-    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V>
+    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V, L>
     where
         K: Clone,
     {
@@ -108,27 +114,41 @@ impl<'a, K, V> Entry<'a, K, V> {
     }
 }
 
-pub struct VacantEntry<'a, K, V> {
-    entry: tableref::entry::VacantEntry<'a, (K, V)>,
+pub struct VacantEntry<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    entry: tableref::entry::VacantEntry<'a, (K, V), L>,
     key: K,
+    notifier: Notifier<'a, K, V>,
 }
 
-impl<'a, K, V> VacantEntry<'a, K, V> {
-    pub(crate) fn new(entry: tableref::entry::VacantEntry<'a, (K, V)>, key: K) -> Self {
-        Self { key, entry }
+impl<'a, K, V, L: lock_api::RawRwLock> VacantEntry<'a, K, V, L> {
+    pub(crate) fn new(
+        entry: tableref::entry::VacantEntry<'a, (K, V), L>,
+        key: K,
+        notifier: Notifier<'a, K, V>,
+    ) -> Self {
+        Self {
+            key,
+            entry,
+            notifier,
+        }
     }
 
-    pub fn insert(self, value: V) -> RefMut<'a, K, V> {
-        self.entry.insert((self.key, value)).into()
+    pub fn insert(self, value: V) -> RefMut<'a, K, V, L> {
+        let inserted = self.entry.insert((self.key, value));
+        let (k, v) = &*inserted.t;
+        self.notifier.inserted(k, v);
+        inserted.into()
     }
 
     /// Sets the value of the entry with the VacantEntry’s key, and returns an OccupiedEntry.
-    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V>
+    pub fn insert_entry(self, value: V) -> OccupiedEntry<'a, K, V, L>
     where
         K: Clone,
     {
         let entry = self.entry.insert_entry((self.key.clone(), value));
-        OccupiedEntry::new(entry, self.key)
+        let (k, v) = entry.get();
+        self.notifier.inserted(k, v);
+        OccupiedEntry::new(entry, self.key, self.notifier)
     }
 
     pub fn into_key(self) -> K {
@@ -140,14 +160,23 @@ impl<'a, K, V> VacantEntry<'a, K, V> {
     }
 }
 
-pub struct OccupiedEntry<'a, K, V> {
-    entry: tableref::entry::OccupiedEntry<'a, (K, V)>,
+pub struct OccupiedEntry<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    entry: tableref::entry::OccupiedEntry<'a, (K, V), L>,
     key: K,
+    notifier: Notifier<'a, K, V>,
 }
 
-impl<'a, K, V> OccupiedEntry<'a, K, V> {
-    pub(crate) fn new(entry: tableref::entry::OccupiedEntry<'a, (K, V)>, key: K) -> Self {
-        Self { key, entry }
+impl<'a, K, V, L: lock_api::RawRwLock> OccupiedEntry<'a, K, V, L> {
+    pub(crate) fn new(
+        entry: tableref::entry::OccupiedEntry<'a, (K, V), L>,
+        key: K,
+        notifier: Notifier<'a, K, V>,
+    ) -> Self {
+        Self {
+            key,
+            entry,
+            notifier,
+        }
     }
 
     pub fn get(&self) -> &V {
@@ -159,10 +188,13 @@ impl<'a, K, V> OccupiedEntry<'a, K, V> {
     }
 
     pub fn insert(&mut self, value: V) -> V {
-        mem::replace(self.get_mut(), value)
+        let old = mem::replace(self.get_mut(), value);
+        let (k, v) = self.entry.get();
+        self.notifier.overwritten(k, v);
+        old
     }
 
-    pub fn into_ref(self) -> RefMut<'a, K, V> {
+    pub fn into_ref(self) -> RefMut<'a, K, V, L> {
         self.entry.into_mut().into()
     }
 
@@ -175,15 +207,58 @@ impl<'a, K, V> OccupiedEntry<'a, K, V> {
     }
 
     pub fn remove(self) -> V {
-        self.entry.remove().1
+        self.remove_entry().1
     }
 
     pub fn remove_entry(self) -> (K, V) {
-        self.entry.remove()
+        // keep the shard locked until the listener has seen the removal
+        let ((k, v), _vacant) = self.entry.remove_entry();
+        self.notifier.removed(&k, &v);
+        (k, v)
+    }
+
+    /// Sets the entry to expire once `ttl` has passed, or to never expire if it is `None`.
+    ///
+    /// See [`ClashMap::insert_with_ttl`](crate::ClashMap::insert_with_ttl) for how expired entries are treated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::cache::ManualClock;
+    /// use clashmap::mapref::entry::Entry;
+    /// use clashmap::ClashMap;
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    ///
+    /// let clock = Arc::new(ManualClock::new());
+    /// let sessions = ClashMap::new().with_clock(clock.clone());
+    /// sessions.insert("alice", 1);
+    /// if let Entry::Occupied(mut session) = sessions.entry("alice") {
+    ///     session.set_expiry(Some(Duration::from_secs(60)));
+    /// }
+    ///
+    /// clock.advance(Duration::from_secs(60));
+    /// assert!(!sessions.contains_key("alice"));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn set_expiry(&mut self, ttl: Option<Duration>)
+    where
+        K: Eq + Hash + Clone,
+    {
+        if let Some(expiries) = self.notifier.expiries() {
+            match ttl {
+                Some(ttl) => expiries.set(self.key().clone(), ttl),
+                None => expiries.forget(self.key()),
+            }
+        }
     }
 
     pub fn replace_entry(self, value: V) -> (K, V) {
-        mem::replace(self.entry.into_mut().t, (self.key, value))
+        let replaced = self.entry.into_mut();
+        let old = mem::replace(replaced.t, (self.key, value));
+        let (k, v) = &*replaced.t;
+        self.notifier.overwritten(k, v);
+        old
     }
 }
 
@@ -1,9 +1,11 @@
 use super::one::RefMut;
+use crate::listener::Notifier;
+use crate::lock::RawRwLock;
 use crate::{tableref, OccupiedEntry};
 
-pub enum EntryRef<'a, K, V> {
-    Occupied(OccupiedEntry<'a, K, V>),
-    Vacant(VacantEntryRef<'a, K, V>),
+pub enum EntryRef<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    Occupied(OccupiedEntry<'a, K, V, L>),
+    Vacant(VacantEntryRef<'a, K, V, L>),
 }
 
 // impl<'a, K, V> EntryRef<'a, K, V> {
@@ -107,27 +109,35 @@ pub enum EntryRef<'a, K, V> {
 //     }
 // }
 
-pub struct VacantEntryRef<'a, K, V> {
-    entry: tableref::entry::VacantEntry<'a, (K, V)>,
+pub struct VacantEntryRef<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    entry: tableref::entry::VacantEntry<'a, (K, V), L>,
+    notifier: Notifier<'a, K, V>,
 }
 
-impl<'a, K, V> VacantEntryRef<'a, K, V> {
-    pub(crate) fn new(entry: tableref::entry::VacantEntry<'a, (K, V)>) -> Self {
-        Self { entry }
+impl<'a, K, V, L: lock_api::RawRwLock> VacantEntryRef<'a, K, V, L> {
+    pub(crate) fn new(
+        entry: tableref::entry::VacantEntry<'a, (K, V), L>,
+        notifier: Notifier<'a, K, V>,
+    ) -> Self {
+        Self { entry, notifier }
     }
 
-    pub fn insert(self, key: K, value: V) -> RefMut<'a, K, V> {
+    pub fn insert(self, key: K, value: V) -> RefMut<'a, K, V, L> {
         let occupied = self.entry.insert((key, value));
+        let (k, v) = &*occupied.t;
+        self.notifier.inserted(k, v);
         RefMut::from(occupied)
     }
 
     /// Sets the value of the entry with the VacantEntry’s key, and returns an OccupiedEntry.
-    pub fn insert_entry(self, key: K, value: V) -> OccupiedEntry<'a, K, V>
+    pub fn insert_entry(self, key: K, value: V) -> OccupiedEntry<'a, K, V, L>
     where
         K: Clone,
     {
         let entry = self.entry.insert_entry((key.clone(), value));
-        OccupiedEntry::new(entry, key)
+        let (k, v) = entry.get();
+        self.notifier.inserted(k, v);
+        OccupiedEntry::new(entry, key, self.notifier)
     }
 }
 
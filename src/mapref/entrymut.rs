@@ -1,3 +1,4 @@
+use crate::listener::Notifier;
 use hashbrown::hash_table;
 
 use core::hash::Hash;
@@ -112,15 +113,26 @@ impl<'a, K: Eq + Hash, V> EntryMut<'a, K, V> {
 pub struct VacantEntryMut<'a, K, V> {
     key: K,
     entry: hash_table::VacantEntry<'a, (K, V)>,
+    notifier: Notifier<'a, K, V>,
 }
 
 impl<'a, K: Eq + Hash, V> VacantEntryMut<'a, K, V> {
-    pub(crate) fn new(key: K, entry: hash_table::VacantEntry<'a, (K, V)>) -> Self {
-        Self { key, entry }
+    pub(crate) fn new(
+        key: K,
+        entry: hash_table::VacantEntry<'a, (K, V)>,
+        notifier: Notifier<'a, K, V>,
+    ) -> Self {
+        Self {
+            key,
+            entry,
+            notifier,
+        }
     }
 
     pub fn insert(self, value: V) -> &'a mut (K, V) {
         let occupied = self.entry.insert((self.key, value));
+        let (k, v) = occupied.get();
+        self.notifier.inserted(k, v);
         occupied.into_mut()
     }
 
@@ -130,8 +142,9 @@ impl<'a, K: Eq + Hash, V> VacantEntryMut<'a, K, V> {
         K: Clone,
     {
         let entry = self.entry.insert((self.key.clone(), value));
-
-        OccupiedEntryMut::new(self.key, entry)
+        let (k, v) = entry.get();
+        self.notifier.inserted(k, v);
+        OccupiedEntryMut::new(self.key, entry, self.notifier)
     }
 
     pub fn into_key(self) -> K {
@@ -146,11 +159,20 @@ impl<'a, K: Eq + Hash, V> VacantEntryMut<'a, K, V> {
 pub struct OccupiedEntryMut<'a, K, V> {
     entry: hash_table::OccupiedEntry<'a, (K, V)>,
     key: K,
+    notifier: Notifier<'a, K, V>,
 }
 
 impl<'a, K: Eq + Hash, V> OccupiedEntryMut<'a, K, V> {
-    pub(crate) fn new(key: K, entry: hash_table::OccupiedEntry<'a, (K, V)>) -> Self {
-        Self { key, entry }
+    pub(crate) fn new(
+        key: K,
+        entry: hash_table::OccupiedEntry<'a, (K, V)>,
+        notifier: Notifier<'a, K, V>,
+    ) -> Self {
+        Self {
+            key,
+            entry,
+            notifier,
+        }
     }
 
     pub fn get(&self) -> &V {
@@ -162,7 +184,10 @@ impl<'a, K: Eq + Hash, V> OccupiedEntryMut<'a, K, V> {
     }
 
     pub fn insert(&mut self, value: V) -> V {
-        mem::replace(self.get_mut(), value)
+        let old = mem::replace(self.get_mut(), value);
+        let (k, v) = self.entry.get();
+        self.notifier.overwritten(k, v);
+        old
     }
 
     pub fn into_mut(self) -> &'a mut (K, V) {
@@ -178,17 +203,19 @@ impl<'a, K: Eq + Hash, V> OccupiedEntryMut<'a, K, V> {
     }
 
     pub fn remove(self) -> V {
-        let ((_k, v), _) = self.entry.remove();
-        v
+        self.remove_entry().1
     }
 
     pub fn remove_entry(self) -> (K, V) {
         let ((k, v), _) = self.entry.remove();
+        self.notifier.removed(&k, &v);
         (k, v)
     }
 
     pub fn replace_entry(self, value: V) -> (K, V) {
-        let (k, v) = mem::replace(self.entry.into_mut(), (self.key, value));
+        let replaced = self.entry.into_mut();
+        let (k, v) = mem::replace(replaced, (self.key, value));
+        self.notifier.overwritten(&replaced.0, &replaced.1);
         (k, v)
     }
 }
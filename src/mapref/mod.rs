@@ -3,3 +3,4 @@ pub mod entry_ref;
 pub mod entrymut;
 pub mod multiple;
 pub mod one;
+pub mod owned;
@@ -1,11 +1,12 @@
+use crate::lock::RawRwLock;
 use crate::tableref;
 use core::ops::{Deref, DerefMut};
 
-pub struct RefMulti<'a, K, V> {
-    inner: tableref::multiple::RefMulti<'a, (K, V)>,
+pub struct RefMulti<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::multiple::RefMulti<'a, (K, V), L>,
 }
 
-impl<K, V> Clone for RefMulti<'_, K, V> {
+impl<K, V, L: lock_api::RawRwLock> Clone for RefMulti<'_, K, V, L> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -13,8 +14,8 @@ impl<K, V> Clone for RefMulti<'_, K, V> {
     }
 }
 
-impl<'a, K, V> RefMulti<'a, K, V> {
-    pub(crate) fn new(inner: tableref::multiple::RefMulti<'a, (K, V)>) -> Self {
+impl<'a, K, V, L: lock_api::RawRwLock> RefMulti<'a, K, V, L> {
+    pub(crate) fn new(inner: tableref::multiple::RefMulti<'a, (K, V), L>) -> Self {
         Self { inner }
     }
 
@@ -32,7 +33,7 @@ impl<'a, K, V> RefMulti<'a, K, V> {
     }
 }
 
-impl<K, V> Deref for RefMulti<'_, K, V> {
+impl<K, V, L: lock_api::RawRwLock> Deref for RefMulti<'_, K, V, L> {
     type Target = V;
 
     fn deref(&self) -> &V {
@@ -40,12 +41,12 @@ impl<K, V> Deref for RefMulti<'_, K, V> {
     }
 }
 
-pub struct RefMutMulti<'a, K, V> {
-    inner: tableref::multiple::RefMutMulti<'a, (K, V)>,
+pub struct RefMutMulti<'a, K, V, L: lock_api::RawRwLock = RawRwLock> {
+    inner: tableref::multiple::RefMutMulti<'a, (K, V), L>,
 }
 
-impl<'a, K, V> RefMutMulti<'a, K, V> {
-    pub(crate) fn new(inner: tableref::multiple::RefMutMulti<'a, (K, V)>) -> Self {
+impl<'a, K, V, L: lock_api::RawRwLock> RefMutMulti<'a, K, V, L> {
+    pub(crate) fn new(inner: tableref::multiple::RefMutMulti<'a, (K, V), L>) -> Self {
         Self { inner }
     }
 
@@ -72,7 +73,7 @@ impl<'a, K, V> RefMutMulti<'a, K, V> {
     }
 }
 
-impl<K, V> Deref for RefMutMulti<'_, K, V> {
+impl<K, V, L: lock_api::RawRwLock> Deref for RefMutMulti<'_, K, V, L> {
     type Target = V;
 
     fn deref(&self) -> &V {
@@ -80,7 +81,7 @@ impl<K, V> Deref for RefMutMulti<'_, K, V> {
     }
 }
 
-impl<K, V> DerefMut for RefMutMulti<'_, K, V> {
+impl<K, V, L: lock_api::RawRwLock> DerefMut for RefMutMulti<'_, K, V, L> {
     fn deref_mut(&mut self) -> &mut V {
         self.value_mut()
     }
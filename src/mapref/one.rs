@@ -1,18 +1,18 @@
-use crate::lock::{RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::lock::{RawRwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
 use crate::tableref;
 use crate::util::try_map;
+use core::fmt::{Debug, Formatter};
 use core::ops::{Deref, DerefMut};
-use std::fmt::{Debug, Formatter};
 
-pub struct Ref<'a, K, V: ?Sized> {
-    _guard: RwLockReadGuardDetached<'a>,
+pub struct Ref<'a, K, V: ?Sized, L: lock_api::RawRwLock = RawRwLock> {
+    _guard: RwLockReadGuardDetached<'a, L>,
     k: &'a K,
     v: &'a V,
 }
 /// Kept for backwards compatiblity.
-pub type MappedRef<'a, K, V> = Ref<'a, K, V>;
+pub type MappedRef<'a, K, V, L = RawRwLock> = Ref<'a, K, V, L>;
 
-impl<'a, K, V: ?Sized> Ref<'a, K, V> {
+impl<'a, K, V: ?Sized, L: lock_api::RawRwLock> Ref<'a, K, V, L> {
     pub fn key(&self) -> &K {
         self.pair().0
     }
@@ -25,7 +25,7 @@ impl<'a, K, V: ?Sized> Ref<'a, K, V> {
         (self.k, self.v)
     }
 
-    pub fn map<F, T: ?Sized>(self, f: F) -> Ref<'a, K, T>
+    pub fn map<F, T: ?Sized>(self, f: F) -> Ref<'a, K, T, L>
     where
         F: FnOnce(&V) -> &T,
     {
@@ -36,7 +36,7 @@ impl<'a, K, V: ?Sized> Ref<'a, K, V> {
         }
     }
 
-    pub fn try_map<F, T: ?Sized>(self, f: F) -> Result<Ref<'a, K, T>, Self>
+    pub fn try_map<F, T: ?Sized>(self, f: F) -> Result<Ref<'a, K, T, L>, Self>
     where
         F: FnOnce(&V) -> Option<&T>,
     {
@@ -50,10 +50,17 @@ impl<'a, K, V: ?Sized> Ref<'a, K, V> {
             Err(self)
         }
     }
+
+    /// Gives up the guard, leaving the shard read-locked for the caller to unlock.
+    pub(crate) fn into_raw(self) -> (&'a L, &'a K, &'a V) {
+        (self._guard.into_raw(), self.k, self.v)
+    }
 }
 
-impl<'a, K, V> From<tableref::one::Ref<'a, (K, V)>> for Ref<'a, K, V> {
-    fn from(value: tableref::one::Ref<'a, (K, V)>) -> Self {
+impl<'a, K, V, L: lock_api::RawRwLock> From<tableref::one::Ref<'a, (K, V), L>>
+    for Ref<'a, K, V, L>
+{
+    fn from(value: tableref::one::Ref<'a, (K, V), L>) -> Self {
         Self {
             _guard: value._guard,
             k: &value.t.0,
@@ -62,8 +69,8 @@ impl<'a, K, V> From<tableref::one::Ref<'a, (K, V)>> for Ref<'a, K, V> {
     }
 }
 
-impl<K: Debug, V: Debug + ?Sized> Debug for Ref<'_, K, V> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<K: Debug, V: Debug + ?Sized, L: lock_api::RawRwLock> Debug for Ref<'_, K, V, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Ref")
             .field("k", &self.k)
             .field("v", &self.v)
@@ -71,7 +78,7 @@ impl<K: Debug, V: Debug + ?Sized> Debug for Ref<'_, K, V> {
     }
 }
 
-impl<K, V: ?Sized> Deref for Ref<'_, K, V> {
+impl<K, V: ?Sized, L: lock_api::RawRwLock> Deref for Ref<'_, K, V, L> {
     type Target = V;
 
     fn deref(&self) -> &V {
@@ -79,28 +86,34 @@ impl<K, V: ?Sized> Deref for Ref<'_, K, V> {
     }
 }
 
-impl<K, T: std::fmt::Display + ?Sized> std::fmt::Display for Ref<'_, K, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Display::fmt(self.value(), f)
+impl<K, T: core::fmt::Display + ?Sized, L: lock_api::RawRwLock> core::fmt::Display
+    for Ref<'_, K, T, L>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self.value(), f)
     }
 }
 
-impl<K, T: ?Sized + AsRef<TDeref>, TDeref: ?Sized> AsRef<TDeref> for Ref<'_, K, T> {
+impl<K, T: ?Sized + AsRef<TDeref>, TDeref: ?Sized, L: lock_api::RawRwLock> AsRef<TDeref>
+    for Ref<'_, K, T, L>
+{
     fn as_ref(&self) -> &TDeref {
         self.value().as_ref()
     }
 }
 
-pub struct RefMut<'a, K, V: ?Sized> {
-    _guard: RwLockWriteGuardDetached<'a>,
+pub struct RefMut<'a, K, V: ?Sized, L: lock_api::RawRwLock = RawRwLock> {
+    _guard: RwLockWriteGuardDetached<'a, L>,
     k: &'a K,
     v: &'a mut V,
 }
 /// Kept for backwards compatiblity.
-pub type MappedRefMut<'a, K, V> = RefMut<'a, K, V>;
+pub type MappedRefMut<'a, K, V, L = RawRwLock> = RefMut<'a, K, V, L>;
 
-impl<'a, K, V> From<tableref::one::RefMut<'a, (K, V)>> for RefMut<'a, K, V> {
-    fn from(inner: tableref::one::RefMut<'a, (K, V)>) -> Self {
+impl<'a, K, V, L: lock_api::RawRwLock> From<tableref::one::RefMut<'a, (K, V), L>>
+    for RefMut<'a, K, V, L>
+{
+    fn from(inner: tableref::one::RefMut<'a, (K, V), L>) -> Self {
         Self {
             _guard: inner.guard,
             k: &inner.t.0,
@@ -109,7 +122,7 @@ impl<'a, K, V> From<tableref::one::RefMut<'a, (K, V)>> for RefMut<'a, K, V> {
     }
 }
 
-impl<'a, K, V: ?Sized> RefMut<'a, K, V> {
+impl<'a, K, V: ?Sized, L: lock_api::RawRwLock> RefMut<'a, K, V, L> {
     pub fn key(&self) -> &K {
         self.pair().0
     }
@@ -130,16 +143,7 @@ impl<'a, K, V: ?Sized> RefMut<'a, K, V> {
         (self.k, self.v)
     }
 
-    pub fn downgrade(self) -> Ref<'a, K, V> {
-        Ref {
-            // SAFETY: `Ref` will prevent writes to the data.
-            _guard: unsafe { self._guard.downgrade() },
-            k: self.k,
-            v: self.v,
-        }
-    }
-
-    pub fn map<F, T: ?Sized>(self, f: F) -> RefMut<'a, K, T>
+    pub fn map<F, T: ?Sized>(self, f: F) -> RefMut<'a, K, T, L>
     where
         F: FnOnce(&mut V) -> &mut T,
     {
@@ -150,7 +154,7 @@ impl<'a, K, V: ?Sized> RefMut<'a, K, V> {
         }
     }
 
-    pub fn try_map<F, T: 'a + ?Sized>(self, f: F) -> Result<RefMut<'a, K, T>, Self>
+    pub fn try_map<F, T: 'a + ?Sized>(self, f: F) -> Result<RefMut<'a, K, T, L>, Self>
     where
         F: FnOnce(&mut V) -> Option<&mut T>,
     {
@@ -160,10 +164,26 @@ impl<'a, K, V: ?Sized> RefMut<'a, K, V> {
             Err(v) => Err(Self { _guard, k, v }),
         }
     }
+
+    /// Gives up the guard, leaving the shard write-locked for the caller to unlock.
+    pub(crate) fn into_raw(self) -> (&'a L, &'a K, &'a mut V) {
+        (self._guard.into_raw(), self.k, self.v)
+    }
 }
 
-impl<K: Debug, V: Debug + ?Sized> Debug for RefMut<'_, K, V> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a, K, V: ?Sized, L: lock_api::RawRwLockDowngrade> RefMut<'a, K, V, L> {
+    pub fn downgrade(self) -> Ref<'a, K, V, L> {
+        Ref {
+            // SAFETY: `Ref` will prevent writes to the data.
+            _guard: unsafe { self._guard.downgrade() },
+            k: self.k,
+            v: self.v,
+        }
+    }
+}
+
+impl<K: Debug, V: Debug + ?Sized, L: lock_api::RawRwLock> Debug for RefMut<'_, K, V, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("RefMut")
             .field("k", &self.key())
             .field("v", &self.value())
@@ -171,7 +191,7 @@ impl<K: Debug, V: Debug + ?Sized> Debug for RefMut<'_, K, V> {
     }
 }
 
-impl<K, V: ?Sized> Deref for RefMut<'_, K, V> {
+impl<K, V: ?Sized, L: lock_api::RawRwLock> Deref for RefMut<'_, K, V, L> {
     type Target = V;
 
     fn deref(&self) -> &V {
@@ -179,12 +199,78 @@ impl<K, V: ?Sized> Deref for RefMut<'_, K, V> {
     }
 }
 
-impl<K, V: ?Sized> DerefMut for RefMut<'_, K, V> {
+impl<K, V: ?Sized, L: lock_api::RawRwLock> DerefMut for RefMut<'_, K, V, L> {
     fn deref_mut(&mut self) -> &mut V {
         self.value_mut()
     }
 }
 
+pub struct RefUpgradable<'a, K, V, L: lock_api::RawRwLockUpgrade = RawRwLock> {
+    inner: tableref::one::RefUpgradable<'a, (K, V), L>,
+}
+
+impl<'a, K, V, L: lock_api::RawRwLockUpgrade> From<tableref::one::RefUpgradable<'a, (K, V), L>>
+    for RefUpgradable<'a, K, V, L>
+{
+    fn from(inner: tableref::one::RefUpgradable<'a, (K, V), L>) -> Self {
+        Self { inner }
+    }
+}
+
+impl<'a, K, V, L: lock_api::RawRwLockUpgradeDowngrade> RefUpgradable<'a, K, V, L> {
+    pub fn key(&self) -> &K {
+        self.pair().0
+    }
+
+    pub fn value(&self) -> &V {
+        self.pair().1
+    }
+
+    pub fn pair(&self) -> (&K, &V) {
+        let (k, v) = self.inner.value();
+        (k, v)
+    }
+
+    /// Blocks until all other readers of the shard have left, then returns a mutable reference to the entry.
+    ///
+    /// No other thread can modify the entry in the meantime.
+    pub fn upgrade(self) -> RefMut<'a, K, V, L> {
+        self.inner.upgrade().into()
+    }
+
+    /// Returns a mutable reference to the entry if there are no other readers of the shard.
+    pub fn try_upgrade(self) -> Result<RefMut<'a, K, V, L>, Self> {
+        self.inner
+            .try_upgrade()
+            .map(RefMut::from)
+            .map_err(|inner| Self { inner })
+    }
+
+    /// Gives up the ability to upgrade, allowing other threads to take an upgradable reference to the shard.
+    pub fn downgrade(self) -> Ref<'a, K, V, L> {
+        self.inner.downgrade().into()
+    }
+}
+
+impl<K: Debug, V: Debug, L: lock_api::RawRwLockUpgradeDowngrade> Debug
+    for RefUpgradable<'_, K, V, L>
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("RefUpgradable")
+            .field("k", &self.key())
+            .field("v", &self.value())
+            .finish()
+    }
+}
+
+impl<K, V, L: lock_api::RawRwLockUpgradeDowngrade> Deref for RefUpgradable<'_, K, V, L> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::ClashMap;
@@ -200,6 +286,49 @@ mod tests {
         };
     }
 
+    #[test]
+    fn upgrade() {
+        let data = ClashMap::new();
+        data.insert("test", 1);
+
+        let u_ref = data.get_upgradable("test").unwrap();
+        let r_ref = data.get("test").unwrap();
+        assert_eq!(*u_ref, *r_ref);
+
+        let u_ref = u_ref.try_upgrade().unwrap_err();
+        drop(r_ref);
+        let mut w_ref = u_ref.try_upgrade().unwrap();
+        *w_ref.value_mut() = 2;
+        drop(w_ref);
+
+        let u_ref = data.get_upgradable("test").unwrap();
+        assert!(data.try_get_mut("test").is_locked());
+        let r_ref = u_ref.downgrade();
+        assert!(data.get_upgradable("test").is_some());
+        assert_eq!(*r_ref, 2);
+    }
+
+    #[test]
+    fn upgrade_waits_for_readers() {
+        let data = ClashMap::new();
+        data.insert("test", 1);
+
+        std::thread::scope(|s| {
+            let r_ref = data.get("test").unwrap();
+            let handle = s.spawn(|| {
+                let u_ref = data.get_upgradable("test").unwrap();
+                let mut w_ref = u_ref.upgrade();
+                *w_ref += 1;
+            });
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            assert_eq!(*r_ref, 1);
+            drop(r_ref);
+            handle.join().unwrap();
+        });
+
+        assert_eq!(*data.get("test").unwrap(), 2);
+    }
+
     #[test]
     fn mapped_mut() {
         let data = ClashMap::new();
@@ -0,0 +1,323 @@
+//! References into a map that keep the map alive, so they do not borrow it.
+//!
+//! See [`ClashMap::get_owned`] and [`ClashMap::get_mut_owned`].
+
+use super::one::{Ref, RefMut};
+use crate::lock::RawRwLock;
+use crate::shard_selector::DefaultShardSelector;
+use crate::{ClashMap, DefaultHashBuilder};
+use alloc::sync::Arc;
+use core::fmt::{Debug, Formatter};
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+
+/// An immutable reference to an entry, holding a clone of the [`Arc`] around its map.
+///
+/// Unlike [`Ref`], this does not borrow the map, so it can be returned from a function
+/// that owns the `Arc`, or stored in a struct. The shard stays read-locked until it is dropped.
+pub struct ArcRef<
+    K,
+    V,
+    S = DefaultHashBuilder,
+    L: lock_api::RawRwLock = RawRwLock,
+    Sel = DefaultShardSelector,
+> {
+    // These point into `map`, and `lock` is held shared until this is dropped.
+    lock: NonNull<L>,
+    k: NonNull<K>,
+    v: NonNull<V>,
+    map: Arc<ClashMap<K, V, S, L, Sel>>,
+    _marker: PhantomData<L::GuardMarker>,
+}
+
+// SAFETY: this is a `Ref` into `map` together with a clone of it, and is sendable when both are.
+unsafe impl<K: Sync, V: Sync, S, L: lock_api::RawRwLock + Sync, Sel> Send
+    for ArcRef<K, V, S, L, Sel>
+where
+    L::GuardMarker: Send,
+    ClashMap<K, V, S, L, Sel>: Send + Sync,
+{
+}
+
+// SAFETY: this is a `Ref` into `map` together with a clone of it, and is shareable when both are.
+unsafe impl<K: Sync, V: Sync, S, L: lock_api::RawRwLock + Sync, Sel> Sync
+    for ArcRef<K, V, S, L, Sel>
+where
+    L::GuardMarker: Sync,
+    ClashMap<K, V, S, L, Sel>: Send + Sync,
+{
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> ArcRef<K, V, S, L, Sel> {
+    /// Takes over the lock held by `inner` for as long as `map` is kept alive.
+    ///
+    /// # Safety
+    ///
+    /// `inner` must be a reference into `map`.
+    pub(crate) unsafe fn new(inner: Ref<'_, K, V, L>, map: Arc<ClashMap<K, V, S, L, Sel>>) -> Self {
+        let (lock, k, v) = inner.into_raw();
+        Self {
+            lock: NonNull::from(lock),
+            k: NonNull::from(k),
+            v: NonNull::from(v),
+            map,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        self.pair().0
+    }
+
+    pub fn value(&self) -> &V {
+        self.pair().1
+    }
+
+    pub fn pair(&self) -> (&K, &V) {
+        // SAFETY: the entry lives in a shard of `map`, which is kept alive and read-locked.
+        // The shards cannot be replaced while `map` is shared, as that takes a mutable reference.
+        let k = unsafe { self.k.as_ref() };
+        // SAFETY: as above.
+        let v = unsafe { self.v.as_ref() };
+        (k, v)
+    }
+
+    fn lock(&self) -> &L {
+        // SAFETY: `lock` belongs to a shard of `map`, which is kept alive.
+        unsafe { self.lock.as_ref() }
+    }
+
+    /// Returns the map this reference points into.
+    pub fn map(&self) -> &Arc<ClashMap<K, V, S, L, Sel>> {
+        &self.map
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> Drop for ArcRef<K, V, S, L, Sel> {
+    fn drop(&mut self) {
+        // SAFETY: the shared lock is held by this.
+        unsafe { self.lock().unlock_shared() }
+    }
+}
+
+impl<K: Debug, V: Debug, S, L: lock_api::RawRwLock, Sel> Debug for ArcRef<K, V, S, L, Sel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ArcRef")
+            .field("k", &self.key())
+            .field("v", &self.value())
+            .finish()
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> Deref for ArcRef<K, V, S, L, Sel> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
+/// A mutable reference to an entry, holding a clone of the [`Arc`] around its map.
+///
+/// Unlike [`RefMut`], this does not borrow the map, so it can be returned from a function
+/// that owns the `Arc`, or stored in a struct. The shard stays write-locked until it is dropped.
+pub struct ArcRefMut<
+    K,
+    V,
+    S = DefaultHashBuilder,
+    L: lock_api::RawRwLock = RawRwLock,
+    Sel = DefaultShardSelector,
+> {
+    // These point into `map`, and `lock` is held exclusively until this is dropped.
+    lock: NonNull<L>,
+    k: NonNull<K>,
+    v: NonNull<V>,
+    map: Arc<ClashMap<K, V, S, L, Sel>>,
+    _marker: PhantomData<L::GuardMarker>,
+}
+
+// SAFETY: this is a `RefMut` into `map` together with a clone of it, and is sendable when both are.
+unsafe impl<K: Sync, V: Send, S, L: lock_api::RawRwLock + Sync, Sel> Send
+    for ArcRefMut<K, V, S, L, Sel>
+where
+    L::GuardMarker: Send,
+    ClashMap<K, V, S, L, Sel>: Send + Sync,
+{
+}
+
+// SAFETY: this is a `RefMut` into `map` together with a clone of it, and is shareable when both are.
+unsafe impl<K: Sync, V: Sync, S, L: lock_api::RawRwLock + Sync, Sel> Sync
+    for ArcRefMut<K, V, S, L, Sel>
+where
+    L::GuardMarker: Sync,
+    ClashMap<K, V, S, L, Sel>: Send + Sync,
+{
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> ArcRefMut<K, V, S, L, Sel> {
+    /// Takes over the lock held by `inner` for as long as `map` is kept alive.
+    ///
+    /// # Safety
+    ///
+    /// `inner` must be a reference into `map`.
+    pub(crate) unsafe fn new(
+        inner: RefMut<'_, K, V, L>,
+        map: Arc<ClashMap<K, V, S, L, Sel>>,
+    ) -> Self {
+        let (lock, k, v) = inner.into_raw();
+        Self {
+            lock: NonNull::from(lock),
+            k: NonNull::from(k),
+            v: NonNull::from(v),
+            map,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn key(&self) -> &K {
+        self.pair().0
+    }
+
+    pub fn value(&self) -> &V {
+        self.pair().1
+    }
+
+    pub fn value_mut(&mut self) -> &mut V {
+        self.pair_mut().1
+    }
+
+    pub fn pair(&self) -> (&K, &V) {
+        // SAFETY: the entry lives in a shard of `map`, which is kept alive and write-locked by this.
+        // The shards cannot be replaced while `map` is shared, as that takes a mutable reference.
+        let k = unsafe { self.k.as_ref() };
+        // SAFETY: as above.
+        let v = unsafe { self.v.as_ref() };
+        (k, v)
+    }
+
+    pub fn pair_mut(&mut self) -> (&K, &mut V) {
+        // SAFETY: as in `pair`.
+        let k = unsafe { self.k.as_ref() };
+        // SAFETY: as in `pair`, and the write lock makes this the only reference to the value.
+        let v = unsafe { self.v.as_mut() };
+        (k, v)
+    }
+
+    fn lock(&self) -> &L {
+        // SAFETY: `lock` belongs to a shard of `map`, which is kept alive.
+        unsafe { self.lock.as_ref() }
+    }
+
+    /// Returns the map this reference points into.
+    pub fn map(&self) -> &Arc<ClashMap<K, V, S, L, Sel>> {
+        &self.map
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLockDowngrade, Sel> ArcRefMut<K, V, S, L, Sel> {
+    pub fn downgrade(self) -> ArcRef<K, V, S, L, Sel> {
+        // The exclusive lock is handed over to the `ArcRef`, so it must not be released here.
+        let this = ManuallyDrop::new(self);
+        // SAFETY: the exclusive lock is held by `this`, and the `ArcRef` only reads the value.
+        unsafe { this.lock().downgrade() };
+        ArcRef {
+            lock: this.lock,
+            k: this.k,
+            v: this.v,
+            // SAFETY: `map` is moved out of `this`, which is never dropped.
+            map: unsafe { ptr::read(&this.map) },
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> Drop for ArcRefMut<K, V, S, L, Sel> {
+    fn drop(&mut self) {
+        // SAFETY: the exclusive lock is held by this.
+        unsafe { self.lock().unlock_exclusive() }
+    }
+}
+
+impl<K: Debug, V: Debug, S, L: lock_api::RawRwLock, Sel> Debug for ArcRefMut<K, V, S, L, Sel> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ArcRefMut")
+            .field("k", &self.key())
+            .field("v", &self.value())
+            .finish()
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> Deref for ArcRefMut<K, V, S, L, Sel> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        self.value()
+    }
+}
+
+impl<K, V, S, L: lock_api::RawRwLock, Sel> DerefMut for ArcRefMut<K, V, S, L, Sel> {
+    fn deref_mut(&mut self) -> &mut V {
+        self.value_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArcRef;
+    use crate::ClashMap;
+    use std::sync::Arc;
+
+    fn lookup(map: Arc<ClashMap<u32, String>>, key: u32) -> Option<ArcRef<u32, String>> {
+        map.get_owned(&key)
+    }
+
+    #[test]
+    fn test_outlives_borrow() {
+        let map = Arc::new(ClashMap::new());
+        map.insert(1, "one".to_string());
+
+        let one = lookup(map.clone(), 1).unwrap();
+        assert!(lookup(map.clone(), 2).is_none());
+        drop(map);
+        // the reference keeps the map alive
+        assert_eq!(*one, "one");
+        assert_eq!(Arc::strong_count(one.map()), 1);
+    }
+
+    #[test]
+    fn test_mut_owned() {
+        let map = Arc::new(ClashMap::new());
+        map.insert(1, 10);
+
+        let mut one = map.get_mut_owned(&1).unwrap();
+        *one += 1;
+        assert_eq!(Arc::strong_count(&map), 2);
+        let one = one.downgrade();
+        assert_eq!(*one, 11);
+        assert_eq!(*map.get(&1).unwrap(), 11);
+        drop(one);
+
+        *map.get_mut(&1).unwrap() += 1;
+        assert_eq!(*map.get(&1).unwrap(), 12);
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let names = ["Albin".to_string(), "Bosnian Bill".to_string()];
+        let map = Arc::new(ClashMap::new());
+        for name in &names {
+            map.insert(name.as_str(), name.len());
+        }
+
+        let albin = map.get_owned("Albin").unwrap();
+        let bill = std::thread::scope(|s| {
+            s.spawn(|| map.clone().get_owned("Bosnian Bill"))
+                .join()
+                .unwrap()
+        })
+        .unwrap();
+        assert_eq!((*albin, *bill), (5, 12));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::expiry::Expiries;
+use crate::layout::LayoutGuard;
 use crate::lock::{RwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
 use crate::mapref::multiple::{RefMulti, RefMutMulti};
 use crate::{tableref, ClashMap, HashMap, Shard};
@@ -75,7 +77,7 @@ where
 
     fn into_par_iter(self) -> Self::Iter {
         OwningIter {
-            shards: self.table.tables.shards,
+            shards: self.table.tables.into_shards(),
         }
     }
 }
@@ -113,14 +115,20 @@ where
     type Item = RefMulti<'a, K, V>;
 
     fn into_par_iter(self) -> Self::Iter {
+        let (layout, shards) = self.table.tables.enter();
         Iter {
-            shards: &self.table.tables.shards,
+            shards,
+            expiries: &self.expiries,
+            _layout: layout,
         }
     }
 }
 
 pub struct Iter<'a, K, V> {
     pub(super) shards: &'a [CachePadded<RwLock<HashMap<K, V>>>],
+    expiries: &'a Expiries<K>,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
 }
 
 impl<'a, K, V> ParallelIterator for Iter<'a, K, V>
@@ -134,6 +142,7 @@ where
     where
         C: UnindexedConsumer<Self::Item>,
     {
+        let expiries = self.expiries;
         self.shards
             .into_par_iter()
             .flat_map_iter(|shard| {
@@ -142,7 +151,9 @@ where
                 let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(shard.read()) };
 
                 let guard = Arc::new(guard);
-                shard.iter().map(move |kv| {
+                // entries whose time-to-live has run out are skipped
+                let live = shard.iter().filter(|(k, _v)| !expiries.is_expired(k));
+                live.map(move |kv| {
                     let guard = Arc::clone(&guard);
                     RefMulti::new(tableref::multiple::RefMulti::new(guard, kv))
                 })
@@ -161,9 +172,7 @@ where
     type Item = RefMutMulti<'a, K, V>;
 
     fn into_par_iter(self) -> Self::Iter {
-        IterMut {
-            shards: &self.table.tables.shards,
-        }
+        self.par_iter_mut()
     }
 }
 
@@ -174,14 +183,20 @@ where
 {
     // Unlike `IntoParallelRefMutIterator::par_iter_mut`, we only _need_ `&self`.
     pub fn par_iter_mut(&self) -> IterMut<'_, K, V> {
+        let (layout, shards) = self.table.tables.enter();
         IterMut {
-            shards: &self.table.tables.shards,
+            shards,
+            expiries: &self.expiries,
+            _layout: layout,
         }
     }
 }
 
 pub struct IterMut<'a, K, V> {
     shards: &'a [CachePadded<RwLock<HashMap<K, V>>>],
+    expiries: &'a Expiries<K>,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
 }
 
 impl<'a, K, V> ParallelIterator for IterMut<'a, K, V>
@@ -195,6 +210,7 @@ where
     where
         C: UnindexedConsumer<Self::Item>,
     {
+        let expiries = self.expiries;
         self.shards
             .into_par_iter()
             .flat_map_iter(|shard| {
@@ -204,7 +220,9 @@ where
                     unsafe { RwLockWriteGuardDetached::detach_from(shard.write()) };
 
                 let guard = Arc::new(guard);
-                shard.iter_mut().map(move |kv| {
+                // entries whose time-to-live has run out are skipped
+                let live = shard.iter_mut().filter(|(k, _v)| !expiries.is_expired(k));
+                live.map(move |kv| {
                     let guard = Arc::clone(&guard);
                     RefMutMulti::new(tableref::multiple::RefMutMulti::new(guard, kv))
                 })
@@ -4,7 +4,7 @@ use core::hash::{BuildHasher, Hash};
 use rayon::iter::plumbing::UnindexedConsumer;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 
-impl<K, V, S> IntoParallelIterator for ReadOnlyView<K, V, S>
+impl<K, V, S, Sel> IntoParallelIterator for ReadOnlyView<K, V, S, Sel>
 where
     K: Send + Eq + Hash,
     V: Send,
@@ -43,7 +43,7 @@ where
 }
 
 // This impl also enables `IntoParallelRefIterator::par_iter`
-impl<'a, K, V, S> IntoParallelIterator for &'a ReadOnlyView<K, V, S>
+impl<'a, K, V, S, Sel> IntoParallelIterator for &'a ReadOnlyView<K, V, S, Sel>
 where
     K: Send + Sync + Eq + Hash,
     V: Send + Sync,
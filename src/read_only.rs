@@ -1,44 +1,62 @@
-use crate::lock::RwLock;
-use crate::sharded::ClashCollection;
+#[cfg(feature = "std")]
+use crate::expiry::Expiries;
+use crate::flight::Flights;
+use crate::shard_info::ShardInfo;
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use crate::sharded::{new_shard, select_shard, ClashCollection};
 use crate::ClashMap;
 use crate::ClashTable;
 use crate::HashMap;
+#[cfg(not(feature = "std"))]
+use crate::NoDefaultHasher as RandomState;
+use crate::RawRwLock;
+use alloc::boxed::Box;
 use core::fmt;
+use core::hash::Hasher;
 use core::hash::{BuildHasher, Hash};
-use crossbeam_utils::CachePadded;
 use hashbrown::Equivalent;
+#[cfg(feature = "std")]
 use std::collections::hash_map::RandomState;
-use std::hash::Hasher;
 
 /// A read-only view into a `ClashMap`. Allows to obtain raw references to the stored values.
-pub struct ReadOnlyView<K, V, S = RandomState> {
-    shift: usize,
+pub struct ReadOnlyView<K, V, S = RandomState, Sel = DefaultShardSelector> {
     // It is necessary to re-alloc the shards here
     // to allow ReadOnlyView to be covariant over K and V
     pub(crate) shards: Box<[HashMap<K, V>]>,
+    selector: Sel,
     hasher: S,
 }
 
-impl<K: Eq + Hash + Clone, V: Clone, S: Clone> Clone for ReadOnlyView<K, V, S> {
+impl<K: Eq + Hash + Clone, V: Clone, S: Clone, Sel: Clone> Clone for ReadOnlyView<K, V, S, Sel> {
     fn clone(&self) -> Self {
         Self {
             shards: self.shards.clone(),
             hasher: self.hasher.clone(),
-            shift: self.shift,
+            selector: self.selector.clone(),
         }
     }
 }
 
-impl<K: Eq + Hash + fmt::Debug, V: fmt::Debug, S: BuildHasher> fmt::Debug
-    for ReadOnlyView<K, V, S>
+impl<K: Eq + Hash + fmt::Debug, V: fmt::Debug, S: BuildHasher, Sel: ShardSelector> fmt::Debug
+    for ReadOnlyView<K, V, S, Sel>
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_map().entries(self.iter()).finish()
     }
 }
 
-impl<K, V, S> ReadOnlyView<K, V, S> {
-    pub(crate) fn new(map: ClashMap<K, V, S>) -> Self {
+impl<K, V, S, Sel> ReadOnlyView<K, V, S, Sel> {
+    pub(crate) fn new<L: lock_api::RawRwLock>(mut map: ClashMap<K, V, S, L, Sel>) -> Self {
+        map.table.tables.settle();
+        // the view has no clock, so the entries that expired are removed now, and the others are kept for good
+        #[cfg(feature = "std")]
+        if !map.expiries.is_empty() {
+            for shard in map.table.tables.shards_mut() {
+                shard
+                    .get_mut()
+                    .retain(|(k, _v)| !map.expiries.is_expired(k));
+            }
+        }
         Self {
             shards: map
                 .table
@@ -48,31 +66,43 @@ impl<K, V, S> ReadOnlyView<K, V, S> {
                 .into_iter()
                 .map(|s| s.into_inner().into_inner())
                 .collect(),
-            shift: map.table.tables.shift,
+            selector: map.table.tables.selector,
             hasher: map.hasher,
         }
     }
 
+    pub(crate) fn from_shards(shards: Box<[HashMap<K, V>]>, selector: Sel, hasher: S) -> Self {
+        Self {
+            shards,
+            selector,
+            hasher,
+        }
+    }
+
     /// Consumes this `ReadOnlyView`, returning the underlying `ClashMap`.
-    pub fn into_inner(self) -> ClashMap<K, V, S> {
+    pub fn into_inner(self) -> ClashMap<K, V, S, RawRwLock, Sel> {
+        let tables = ClashCollection::from_shards(
+            self.shards.into_vec().into_iter().map(new_shard).collect(),
+            self.selector,
+        );
+
         ClashMap {
-            table: ClashTable {
-                tables: ClashCollection {
-                    shards: self
-                        .shards
-                        .into_vec()
-                        .into_iter()
-                        .map(|s| CachePadded::new(RwLock::new(s)))
-                        .collect(),
-                    shift: self.shift,
-                },
-            },
+            flights: Flights::new(),
+            table: ClashTable { tables },
             hasher: self.hasher,
+            listener: None,
+            #[cfg(feature = "std")]
+            expiries: Expiries::new(),
         }
     }
+
+    /// Returns the size of every shard, and statistics about how evenly the entries are spread.
+    pub fn shard_info(&self) -> ShardInfo {
+        ShardInfo::new(self.shards.iter().map(|s| (s.len(), s.capacity())))
+    }
 }
 
-impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher> ReadOnlyView<K, V, S> {
+impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher, Sel: ShardSelector> ReadOnlyView<K, V, S, Sel> {
     fn hash_u64<T: Hash>(&self, item: &T) -> u64 {
         let mut hasher = self.hasher.build_hasher();
 
@@ -82,24 +112,7 @@ impl<'a, K: 'a + Eq + Hash, V: 'a, S: BuildHasher> ReadOnlyView<K, V, S> {
     }
 
     fn _determine_shard(&self, hash: usize) -> usize {
-        // Leave the high 7 bits for the HashBrown SIMD tag.
-        let idx = (hash << 7) >> self.shift;
-
-        // hint to llvm that the panic bounds check can be removed
-        if idx >= self.shards.len() {
-            if cfg!(debug_assertions) {
-                unreachable!("invalid shard index")
-            } else {
-                // SAFETY: shards is always a power of two,
-                // and shift is calculated such that the resulting idx is always
-                // less than the shards length
-                unsafe {
-                    std::hint::unreachable_unchecked();
-                }
-            }
-        }
-
-        idx
+        select_shard(&self.selector, hash, self.shards.len())
     }
 
     /// Returns the number of elements in the map.
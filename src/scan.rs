@@ -0,0 +1,286 @@
+//! Resumable scans over a map, which hold no lock between steps.
+//!
+//! See [`ClashMap::scan`](crate::ClashMap::scan).
+
+use alloc::vec::Vec;
+use hashbrown::HashTable;
+
+/// How many hashes with no entry left a step may skip for each entry it is allowed to visit.
+const MISSES_PER_ITEM: usize = 10;
+
+/// The position of a scan started by [`ClashMap::scan`](crate::ClashMap::scan).
+///
+/// A cursor is a shard index and a position inside that shard. When the scan reaches a shard,
+/// the cursor takes the sorted hashes of the shard's entries, and the position is how many of them
+/// were visited. Every step looks up the entries of the next hashes, so the position stays valid
+/// when the shard grows or shrinks between steps, and resuming from it takes no extra work.
+///
+/// Taking the hashes of a shard hashes all of its keys under its read lock, and the hashes are kept
+/// until the scan moves on to the next shard, which takes 8 bytes per entry of the shard.
+/// Both grow with the size of the shards rather than with the step size.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Cursor {
+    /// The shard amount of the map when the scan started, or 0 if it has not started yet.
+    shard_amount: usize,
+    shard: usize,
+    /// The hashes of the entries of `shard` when the scan reached it, sorted and without duplicates.
+    /// Empty until then, as the scan moves past shards that have no entries.
+    hashes: Vec<u64>,
+    /// How many of `hashes` were visited.
+    position: usize,
+}
+
+impl Cursor {
+    /// Returns a cursor at the start of a scan.
+    pub const fn new() -> Self {
+        Self {
+            shard_amount: 0,
+            shard: 0,
+            hashes: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Returns true once every shard has been scanned.
+    pub fn is_finished(&self) -> bool {
+        self.shard_amount != 0 && self.shard >= self.shard_amount
+    }
+
+    /// Returns the index of the shard the scan continues in.
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// Restarts the scan if the map was resharded since it started,
+    /// as shard indices from before then no longer refer to the same keys.
+    pub(crate) fn start(&mut self, shard_amount: usize) {
+        if self.shard_amount != shard_amount && !self.is_finished() {
+            *self = Self {
+                shard_amount,
+                ..Self::new()
+            };
+        }
+    }
+
+    /// Returns true if the cursor has not taken the hashes of the shard it is in yet.
+    pub(crate) fn needs_hashes(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Takes the hashes of the entries of the shard this cursor is in.
+    ///
+    /// They only need to be collected under the shard's lock, this sorts them after it was released.
+    pub(crate) fn set_hashes(&mut self, mut hashes: Vec<u64>) {
+        hashes.sort_unstable();
+        hashes.dedup();
+        self.hashes = hashes;
+        self.position = 0;
+    }
+
+    /// Visits the entries of `shard`, the table of the shard this cursor is in, a hash at a time
+    /// while they fit in `budget`, and advances the cursor past them. Returns how many entries were visited.
+    ///
+    /// If `force` is set, the entries of the first hash are visited even if there are more than `budget`
+    /// of them, so that the scan makes progress. The cursor moves to the next shard once this one is done,
+    /// or right away if it took no hashes because the shard was empty.
+    pub(crate) fn step<T>(
+        &mut self,
+        shard: &HashTable<T>,
+        budget: usize,
+        force: bool,
+        hasher: impl Fn(&T) -> u64,
+        mut f: impl FnMut(&T),
+    ) -> usize {
+        let mut misses = budget.max(1).saturating_mul(MISSES_PER_ITEM);
+        let mut entries = Vec::new();
+        let mut visited = 0;
+        while let Some(&hash) = self.hashes.get(self.position) {
+            entries.clear();
+            entries.extend(shard.iter_hash(hash).filter(|&t| hasher(t) == hash));
+            if visited + entries.len() > budget && !(force && visited == 0) {
+                return visited;
+            }
+
+            entries.iter().for_each(|&t| f(t));
+            visited += entries.len();
+            if entries.is_empty() {
+                misses -= 1;
+            }
+
+            self.position += 1;
+            if visited >= budget || misses == 0 {
+                break;
+            }
+        }
+
+        if self.position == self.hashes.len() {
+            self.next_shard();
+        }
+        visited
+    }
+
+    fn next_shard(&mut self) {
+        self.shard += 1;
+        self.hashes = Vec::new();
+        self.position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Cursor;
+    use crate::test_util::{BuildConstantHasher, FnvHasher};
+    use crate::ClashMap;
+    use core::hash::BuildHasher;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    fn scan_all<S: core::hash::BuildHasher + Clone>(
+        map: &ClashMap<u32, u32, S>,
+        max_items: usize,
+    ) -> Vec<u32> {
+        let mut keys = Vec::new();
+        let mut cursor = Cursor::new();
+        while !cursor.is_finished() {
+            cursor = map.scan(cursor, max_items, |k, _| keys.push(*k));
+        }
+        keys
+    }
+
+    #[test]
+    fn test_scan_visits_every_key_once() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        let mut keys = scan_all(&map, 7);
+        keys.sort_unstable();
+        assert_eq!(keys, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scan_step_size() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut cursor = Cursor::new();
+        while !cursor.is_finished() {
+            let mut visited = 0;
+            cursor = map.scan(cursor, 10, |_, _| visited += 1);
+            assert!(visited <= 10);
+            assert!(visited > 0 || cursor.is_finished());
+        }
+
+        let mut visited = 0;
+        assert_eq!(map.scan(cursor.clone(), 10, |_, _| visited += 1), cursor);
+        assert_eq!(visited, 0);
+    }
+
+    /// Builds FNV hashers, and counts how many it has built.
+    #[derive(Clone, Default)]
+    struct CountingHasher(Arc<AtomicUsize>);
+
+    impl BuildHasher for CountingHasher {
+        type Hasher = FnvHasher;
+
+        fn build_hasher(&self) -> FnvHasher {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            FnvHasher::default()
+        }
+    }
+
+    #[test]
+    fn test_scan_step_work() {
+        let hasher = CountingHasher::default();
+        let map = ClashMap::with_hasher_and_shard_amount(hasher.clone(), 4);
+        for i in 0..10_000 {
+            map.insert(i, i);
+        }
+
+        let hashed = hasher.0.load(Ordering::Relaxed);
+        let mut keys = 0;
+        let mut cursor = Cursor::new();
+        while !cursor.is_finished() {
+            cursor = map.scan(cursor, 10, |_, _| keys += 1);
+        }
+        assert_eq!(keys, 10_000);
+        // every entry is hashed once when the scan reaches its shard, and about once more when it is
+        // looked up, rather than its whole shard being hashed again by every step
+        assert!(hasher.0.load(Ordering::Relaxed) - hashed <= 3 * 10_000);
+    }
+
+    #[test]
+    fn test_scan_with_concurrent_writes() {
+        let map = ClashMap::with_shard_amount(4);
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = Cursor::new();
+        let mut step = 0;
+        while !cursor.is_finished() {
+            cursor = map.scan(cursor, 32, |k, _| {
+                seen.insert(*k);
+            });
+
+            // grow and shrink the shards between steps
+            step += 1;
+            for i in 0..100 {
+                map.insert(1000 + step * 100 + i, 0);
+            }
+            map.retain(|&k, _| k < 1000 || k % 3 == 0);
+            map.shrink_to_fit();
+        }
+
+        assert!((0..1000).all(|k| seen.contains(&k)));
+    }
+
+    #[test]
+    fn test_scan_restarts_after_reshard() {
+        let mut map = ClashMap::with_shard_amount(4);
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+
+        let mut seen = HashSet::new();
+        let cursor = map.scan(Cursor::new(), 50, |k, _| {
+            seen.insert(*k);
+        });
+        map.reshard(16);
+
+        let mut cursor = cursor;
+        while !cursor.is_finished() {
+            cursor = map.scan(cursor, 50, |k, _| {
+                seen.insert(*k);
+            });
+        }
+        assert_eq!(seen.len(), 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "max_items must be at least 1")]
+    fn test_scan_zero_items() {
+        let map = ClashMap::with_shard_amount(4);
+        map.insert(1, 1);
+
+        map.scan(Cursor::new(), 0, |_: &i32, _: &i32| {});
+    }
+
+    #[test]
+    fn test_scan_colliding_hashes() {
+        let map: ClashMap<u32, u32, BuildConstantHasher> =
+            ClashMap::with_hasher_and_shard_amount(Default::default(), 4);
+        for i in 0..20 {
+            map.insert(i, i);
+        }
+
+        let mut keys = scan_all(&map, 3);
+        keys.sort_unstable();
+        assert_eq!(keys, (0..20).collect::<Vec<_>>());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::shard_selector::ShardSelector;
 use crate::{mapref, setref, ClashMap, ClashSet};
 use core::fmt;
 use core::hash::{BuildHasher, Hash};
@@ -64,11 +65,13 @@ where
     }
 }
 
-impl<K, V, H> Serialize for ClashMap<K, V, H>
+impl<K, V, H, L, Sel> Serialize for ClashMap<K, V, H, L, Sel>
 where
     K: Serialize + Eq + Hash,
     V: Serialize,
     H: BuildHasher,
+    L: lock_api::RawRwLock,
+    Sel: ShardSelector,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -136,10 +139,11 @@ where
     }
 }
 
-impl<K, H> Serialize for ClashSet<K, H>
+impl<K, H, L> Serialize for ClashSet<K, H, L>
 where
     K: Serialize + Eq + Hash,
     H: BuildHasher,
+    L: lock_api::RawRwLock,
 {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -165,27 +169,37 @@ macro_rules! serialize_impl {
 }
 
 // Map
-impl<K: Eq + Hash, V: Serialize> Serialize for mapref::multiple::RefMulti<'_, K, V> {
+impl<K: Eq + Hash, V: Serialize, L: lock_api::RawRwLock> Serialize
+    for mapref::multiple::RefMulti<'_, K, V, L>
+{
     serialize_impl! {}
 }
 
-impl<K: Eq + Hash, V: Serialize> Serialize for mapref::multiple::RefMutMulti<'_, K, V> {
+impl<K: Eq + Hash, V: Serialize, L: lock_api::RawRwLock> Serialize
+    for mapref::multiple::RefMutMulti<'_, K, V, L>
+{
     serialize_impl! {}
 }
 
-impl<K: Eq + Hash, V: Serialize> Serialize for mapref::one::Ref<'_, K, V> {
+impl<K: Eq + Hash, V: Serialize, L: lock_api::RawRwLock> Serialize
+    for mapref::one::Ref<'_, K, V, L>
+{
     serialize_impl! {}
 }
 
-impl<K: Eq + Hash, V: Serialize> Serialize for mapref::one::RefMut<'_, K, V> {
+impl<K: Eq + Hash, V: Serialize, L: lock_api::RawRwLock> Serialize
+    for mapref::one::RefMut<'_, K, V, L>
+{
     serialize_impl! {}
 }
 
 // Set
-impl<V: Hash + Eq + Serialize> Serialize for setref::multiple::RefMulti<'_, V> {
+impl<V: Hash + Eq + Serialize, L: lock_api::RawRwLock> Serialize
+    for setref::multiple::RefMulti<'_, V, L>
+{
     serialize_impl! {}
 }
 
-impl<V: Hash + Eq + Serialize> Serialize for setref::one::Ref<'_, V> {
+impl<V: Hash + Eq + Serialize, L: lock_api::RawRwLock> Serialize for setref::one::Ref<'_, V, L> {
     serialize_impl! {}
 }
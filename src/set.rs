@@ -1,27 +1,35 @@
-use crate::iter_set::{Iter, OwningIter};
-#[cfg(feature = "raw-api")]
-use crate::lock::RwLock;
+use crate::iter_set::{Drain, DrainMut, ExtractIf, ExtractIfMut, Iter, OwningIter};
+use crate::lock::RawRwLock;
 use crate::setref::one::Ref;
+use crate::shard_info::ShardInfo;
+#[cfg(feature = "std")]
+use crate::try_result::TryResult;
 use crate::ClashMap;
 #[cfg(feature = "raw-api")]
 use crate::HashMap;
+#[cfg(not(feature = "std"))]
+use crate::NoDefaultHasher as RandomState;
 use core::fmt;
 use core::hash::{BuildHasher, Hash};
+#[cfg(feature = "std")]
 use core::iter::FromIterator;
 #[cfg(feature = "raw-api")]
 use crossbeam_utils::CachePadded;
 use hashbrown::Equivalent;
+#[cfg(feature = "std")]
 use std::collections::hash_map::RandomState;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 /// ClashSet is a thin wrapper around [`ClashMap`] using `()` as the value type. It uses
 /// methods and types which are more convenient to work with on a set.
 ///
 /// [`ClashMap`]: struct.ClashMap.html
-pub struct ClashSet<K, S = RandomState> {
-    pub(crate) inner: ClashMap<K, (), S>,
+pub struct ClashSet<K, S = RandomState, L = RawRwLock> {
+    pub(crate) inner: ClashMap<K, (), S, L>,
 }
 
-impl<K: fmt::Debug, S> fmt::Debug for ClashSet<K, S> {
+impl<K: fmt::Debug, S, L: lock_api::RawRwLock> fmt::Debug for ClashSet<K, S, L> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut set = f.debug_set();
         self.inner.table.for_each(|(k, _)| {
@@ -31,7 +39,7 @@ impl<K: fmt::Debug, S> fmt::Debug for ClashSet<K, S> {
     }
 }
 
-impl<K: Clone, S: Clone> Clone for ClashSet<K, S> {
+impl<K: Clone, S: Clone, L: lock_api::RawRwLock> Clone for ClashSet<K, S, L> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
@@ -43,16 +51,21 @@ impl<K: Clone, S: Clone> Clone for ClashSet<K, S> {
     }
 }
 
-impl<K, S> Default for ClashSet<K, S>
+#[cfg(feature = "std")]
+impl<K, S, L> Default for ClashSet<K, S, L>
 where
     K: Eq + Hash,
     S: Default + BuildHasher,
+    L: lock_api::RawRwLock,
 {
     fn default() -> Self {
-        Self::with_hasher(Default::default())
+        Self {
+            inner: ClashMap::default(),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl<K: Eq + Hash> ClashSet<K, RandomState> {
     /// Creates a new ClashSet with a capacity of 0.
     ///
@@ -84,42 +97,7 @@ impl<K: Eq + Hash> ClashSet<K, RandomState> {
     }
 }
 
-impl<'a, K: 'a + Eq + Hash, S: BuildHasher> ClashSet<K, S> {
-    /// Creates a new ClashMap with a capacity of 0 and the provided hasher.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use clashmap::ClashSet;
-    /// use std::collections::hash_map::RandomState;
-    ///
-    /// let s = RandomState::new();
-    /// let games = ClashSet::with_hasher(s);
-    /// games.insert("Veloren");
-    /// ```
-    pub fn with_hasher(hasher: S) -> Self {
-        Self::with_capacity_and_hasher(0, hasher)
-    }
-
-    /// Creates a new ClashMap with a specified starting capacity and hasher.
-    ///
-    /// # Examples
-    ///
-    /// ```
-    /// use clashmap::ClashSet;
-    /// use std::collections::hash_map::RandomState;
-    ///
-    /// let s = RandomState::new();
-    /// let numbers = ClashSet::with_capacity_and_hasher(2, s);
-    /// numbers.insert(2);
-    /// numbers.insert(8);
-    /// ```
-    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
-        Self {
-            inner: ClashMap::with_capacity_and_hasher(capacity, hasher),
-        }
-    }
-
+impl<'a, K: 'a + Eq + Hash, S: BuildHasher, L: lock_api::RawRwLock> ClashSet<K, S, L> {
     /// Hash a given item to produce a usize.
     /// Uses the provided or default HashBuilder.
     pub fn hash_usize<T: Hash>(&self, item: &T) -> usize {
@@ -140,7 +118,7 @@ impl<'a, K: 'a + Eq + Hash, S: BuildHasher> ClashSet<K, S> {
     /// let set = ClashSet::<()>::new();
     /// println!("Amount of shards: {}", set.shards().len());
     /// ```
-    pub fn shards(&self) -> &[CachePadded<RwLock<HashMap<K, ()>>>] {
+    pub fn shards(&self) -> &[CachePadded<lock_api::RwLock<L, HashMap<K, ()>>>] {
         self.inner.shards()
     }
 
@@ -270,13 +248,33 @@ impl<'a, K: 'a + Eq + Hash, S: BuildHasher> ClashSet<K, S> {
     /// youtubers.insert("Bosnian Bill");
     /// assert_eq!(*youtubers.get("Bosnian Bill").unwrap(), "Bosnian Bill");
     /// ```
-    pub fn get<Q>(&'a self, key: &Q) -> Option<Ref<'a, K>>
+    pub fn get<Q>(&'a self, key: &Q) -> Option<Ref<'a, K, L>>
     where
         Q: Hash + Equivalent<K> + ?Sized,
     {
         self.inner.get(key).map(Ref::new)
     }
 
+    /// Changes the number of shards, moving every key to its shard in the new layout.
+    /// See [`ClashMap::reshard`].
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn reshard(&mut self, shard_amount: usize) {
+        self.inner.reshard(shard_amount)
+    }
+
+    /// Changes the number of shards while the set is shared, moving the keys of one shard at a time.
+    /// See [`ClashMap::reshard_incremental`].
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the set.
+    pub fn reshard_incremental(&self, shard_amount: usize) {
+        self.inner.reshard_incremental(shard_amount)
+    }
+
     /// Remove excess capacity to reduce memory usage.
     pub fn shrink_to_fit(&self) {
         self.inner.shrink_to_fit()
@@ -301,6 +299,59 @@ impl<'a, K: 'a + Eq + Hash, S: BuildHasher> ClashSet<K, S> {
         self.inner.retain(|k, _| f(k))
     }
 
+    /// Removes all keys from the set, yielding them one at a time.
+    ///
+    /// Only one shard is locked at a time. If the iterator is dropped early, the remaining keys are still removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    ///
+    /// let people = ClashSet::new();
+    /// people.insert("Albin");
+    /// let drained: Vec<_> = people.drain().collect();
+    /// assert_eq!(drained, vec!["Albin"]);
+    /// assert!(people.is_empty());
+    /// ```
+    pub fn drain(&self) -> Drain<'_, K, L> {
+        Drain::new(self.inner.drain())
+    }
+
+    /// Removes all keys from the set, yielding them one at a time, without taking any locks.
+    pub fn drain_mut(&mut self) -> DrainMut<'_, K, L> {
+        DrainMut::new(self.inner.drain_mut())
+    }
+
+    /// Removes the keys for which `pred` returns true, yielding them one at a time.
+    ///
+    /// Only one shard is locked at a time. If the iterator is dropped early,
+    /// the keys that have not been visited yet are kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    ///
+    /// let people = ClashSet::new();
+    /// people.insert("Albin");
+    /// people.insert("Jones");
+    /// let removed: Vec<_> = people.extract_if(|name| name.contains('i')).collect();
+    /// assert_eq!(removed, vec!["Albin"]);
+    /// assert_eq!(people.len(), 1);
+    /// ```
+    pub fn extract_if<'b>(&'b self, mut pred: impl FnMut(&K) -> bool + 'b) -> ExtractIf<'b, K, L> {
+        ExtractIf::new(self.inner.extract_if(move |k, _| pred(k)))
+    }
+
+    /// Removes the keys for which `pred` returns true, yielding them one at a time, without taking any locks.
+    pub fn extract_if_mut<'b>(
+        &'b mut self,
+        mut pred: impl FnMut(&K) -> bool + 'b,
+    ) -> ExtractIfMut<'b, K, L> {
+        ExtractIfMut::new(self.inner.extract_if_mut(move |k, _| pred(k)))
+    }
+
     /// Fetches the total number of keys stored in the set.
     ///
     /// # Examples
@@ -373,7 +424,211 @@ impl<'a, K: 'a + Eq + Hash, S: BuildHasher> ClashSet<K, S> {
     }
 }
 
-impl<K, S> ClashSet<K, S> {
+impl<K: Eq + Hash, S: BuildHasher> ClashSet<K, S> {
+    #[cfg(feature = "std")]
+    /// Creates a new ClashMap with a capacity of 0 and the provided hasher.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let games = ClashSet::with_hasher(s);
+    /// games.insert("Veloren");
+    /// ```
+    pub fn with_hasher(hasher: S) -> Self {
+        Self::with_capacity_and_hasher(0, hasher)
+    }
+
+    #[cfg(feature = "std")]
+    /// Creates a new ClashMap with a specified starting capacity and hasher.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let numbers = ClashSet::with_capacity_and_hasher(2, s);
+    /// numbers.insert(2);
+    /// numbers.insert(8);
+    /// ```
+    pub fn with_capacity_and_hasher(capacity: usize, hasher: S) -> Self {
+        Self {
+            inner: ClashMap::with_capacity_and_hasher(capacity, hasher),
+        }
+    }
+
+    /// Creates a new ClashSet with a specified hasher and shard amount
+    ///
+    /// shard_amount should be greater than 0 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let games = ClashSet::with_hasher_and_shard_amount(s, 32);
+    /// games.insert("Veloren");
+    /// ```
+    pub fn with_hasher_and_shard_amount(hasher: S, shard_amount: usize) -> Self {
+        Self::with_capacity_and_hasher_and_shard_amount(0, hasher, shard_amount)
+    }
+
+    /// Creates a new ClashSet with a specified starting capacity, hasher and shard_amount.
+    ///
+    /// shard_amount should greater than 0 and be a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::collections::hash_map::RandomState;
+    ///
+    /// let s = RandomState::new();
+    /// let numbers = ClashSet::with_capacity_and_hasher_and_shard_amount(2, s, 32);
+    /// numbers.insert(2);
+    /// numbers.insert(8);
+    /// ```
+    pub fn with_capacity_and_hasher_and_shard_amount(
+        capacity: usize,
+        hasher: S,
+        shard_amount: usize,
+    ) -> Self {
+        Self {
+            inner: ClashMap::with_capacity_and_hasher_and_shard_amount(
+                capacity,
+                hasher,
+                shard_amount,
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, K, S, L> ClashSet<K, S, L>
+where
+    K: 'a + Eq + Hash,
+    S: BuildHasher,
+    L: lock_api::RawRwLockTimed<Duration = Duration, Instant = Instant>,
+{
+    /// Get a reference to an entry in the set, waiting at most `timeout` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::time::Duration;
+    ///
+    /// let youtubers = ClashSet::new();
+    /// youtubers.insert("Bosnian Bill");
+    /// let timeout = Duration::from_millis(10);
+    /// assert_eq!(*youtubers.try_get_for("Bosnian Bill", timeout).unwrap(), "Bosnian Bill");
+    /// ```
+    pub fn try_get_for<Q>(&'a self, key: &Q, timeout: Duration) -> TryResult<Ref<'a, K, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        match self.inner.try_get_for(key, timeout) {
+            TryResult::Present(r) => TryResult::Present(Ref::new(r)),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Get a reference to an entry in the set, waiting until `deadline` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_get_until<Q>(&'a self, key: &Q, deadline: Instant) -> TryResult<Ref<'a, K, L>>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        match self.inner.try_get_until(key, deadline) {
+            TryResult::Present(r) => TryResult::Present(Ref::new(r)),
+            TryResult::Absent => TryResult::Absent,
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Inserts a key into the set, waiting at most `timeout` for the shard to be unlocked.
+    /// Returns `TryResult::Present(true)` if the key was not already in the set,
+    /// and [TryResult::Locked] if the shard was still locked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashSet;
+    /// use std::time::Duration;
+    ///
+    /// let set = ClashSet::new();
+    /// assert!(set.try_insert_for("I am the key!", Duration::from_millis(10)).unwrap());
+    /// assert!(!set.try_insert_for("I am the key!", Duration::from_millis(10)).unwrap());
+    ///
+    /// let _lock = set.get("I am the key!");
+    /// assert!(set.try_insert_for("I am the key!", Duration::from_millis(10)).is_locked());
+    /// ```
+    pub fn try_insert_for(&self, key: K, timeout: Duration) -> TryResult<bool> {
+        match self.inner.try_insert_for(key, (), timeout) {
+            TryResult::Present(()) => TryResult::Present(false),
+            TryResult::Absent => TryResult::Present(true),
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+
+    /// Inserts a key into the set, waiting until `deadline` for the shard to be unlocked.
+    ///
+    /// See [`try_insert_for`](ClashSet::try_insert_for) for the meaning of the result.
+    pub fn try_insert_until(&self, key: K, deadline: Instant) -> TryResult<bool> {
+        match self.inner.try_insert_until(key, (), deadline) {
+            TryResult::Present(()) => TryResult::Present(false),
+            TryResult::Absent => TryResult::Present(true),
+            TryResult::Locked => TryResult::Locked,
+        }
+    }
+}
+
+impl<K, S: BuildHasher, L: lock_api::RawRwLock> ClashSet<K, S, L> {
+    /// Creates a new ClashSet with a specified starting capacity and hasher,
+    /// that guards its shards with the lock `L`.
+    ///
+    /// See [`ClashMap::with_capacity_and_hasher_and_lock`] for the locks that can be used.
+    #[cfg(feature = "std")]
+    pub fn with_capacity_and_hasher_and_lock(capacity: usize, hasher: S) -> Self {
+        Self {
+            inner: ClashMap::with_capacity_and_hasher_and_lock(capacity, hasher),
+        }
+    }
+
+    /// Creates a new ClashSet with a specified starting capacity, hasher and shard amount,
+    /// that guards its shards with the lock `L`.
+    ///
+    /// See [`ClashMap::with_capacity_and_hasher_and_lock`] for the locks that can be used.
+    ///
+    /// shard_amount should greater than 0 and be a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn with_capacity_and_hasher_and_shard_amount_and_lock(
+        capacity: usize,
+        hasher: S,
+        shard_amount: usize,
+    ) -> Self {
+        Self {
+            inner: ClashMap::with_capacity_and_hasher_and_shard_amount_and_lock(
+                capacity,
+                hasher,
+                shard_amount,
+            ),
+        }
+    }
+}
+
+impl<K, S, L: lock_api::RawRwLock> ClashSet<K, S, L> {
     /// Creates an iterator over a ClashMap yielding immutable references.
     ///
     /// # Examples
@@ -385,24 +640,48 @@ impl<K, S> ClashSet<K, S> {
     /// words.insert("hello");
     /// assert_eq!(words.iter().count(), 1);
     /// ```
-    pub fn iter(&self) -> Iter<'_, K> {
+    pub fn iter(&self) -> Iter<'_, K, L> {
         let iter = self.inner.iter();
 
         Iter::new(iter)
     }
+
+    /// Returns the size of every shard, and statistics about how evenly the keys are spread.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the set.
+    pub fn shard_info(&self) -> ShardInfo {
+        self.inner.shard_info()
+    }
+}
+
+#[cfg(feature = "stats")]
+impl<K, S, L: crate::stats::RawRwLockStats> ClashSet<K, S, L> {
+    /// Returns the lock contention counters of every shard, in shard index order.
+    ///
+    /// Requires the `stats` feature to be enabled.
+    pub fn shard_stats(&self) -> Vec<crate::stats::ShardStats> {
+        self.inner.shard_stats()
+    }
+
+    /// Resets the lock contention counters of every shard to zero.
+    ///
+    /// Requires the `stats` feature to be enabled.
+    pub fn reset_shard_stats(&self) {
+        self.inner.reset_shard_stats()
+    }
 }
 
-impl<K: Eq + Hash, S: BuildHasher> IntoIterator for ClashSet<K, S> {
+impl<K: Eq + Hash, S: BuildHasher, L: lock_api::RawRwLock> IntoIterator for ClashSet<K, S, L> {
     type Item = K;
 
-    type IntoIter = OwningIter<K>;
+    type IntoIter = OwningIter<K, L>;
 
     fn into_iter(self) -> Self::IntoIter {
         OwningIter::new(self.inner.into_iter())
     }
 }
 
-impl<K: Eq + Hash, S: BuildHasher> Extend<K> for ClashSet<K, S> {
+impl<K: Eq + Hash, S: BuildHasher, L: lock_api::RawRwLock> Extend<K> for ClashSet<K, S, L> {
     fn extend<T: IntoIterator<Item = K>>(&mut self, iter: T) {
         let iter = iter.into_iter().map(|k| (k, ()));
 
@@ -410,7 +689,10 @@ impl<K: Eq + Hash, S: BuildHasher> Extend<K> for ClashSet<K, S> {
     }
 }
 
-impl<K: Eq + Hash, S: BuildHasher + Default> FromIterator<K> for ClashSet<K, S> {
+#[cfg(feature = "std")]
+impl<K: Eq + Hash, S: BuildHasher + Default, L: lock_api::RawRwLock> FromIterator<K>
+    for ClashSet<K, S, L>
+{
     fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self {
         let mut set = ClashSet::default();
 
@@ -421,7 +703,7 @@ impl<K: Eq + Hash, S: BuildHasher + Default> FromIterator<K> for ClashSet<K, S>
 }
 
 #[cfg(feature = "typesize")]
-impl<K, S> typesize::TypeSize for ClashSet<K, S>
+impl<K, S, L: lock_api::RawRwLock> typesize::TypeSize for ClashSet<K, S, L>
 where
     K: typesize::TypeSize + Eq + Hash,
     S: typesize::TypeSize + BuildHasher,
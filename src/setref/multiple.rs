@@ -1,12 +1,13 @@
+use crate::lock::RawRwLock;
 use crate::mapref;
 use core::ops::Deref;
 
-pub struct RefMulti<'a, K> {
-    inner: mapref::multiple::RefMulti<'a, K, ()>,
+pub struct RefMulti<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: mapref::multiple::RefMulti<'a, K, (), L>,
 }
 
-impl<'a, K> RefMulti<'a, K> {
-    pub(crate) fn new(inner: mapref::multiple::RefMulti<'a, K, ()>) -> Self {
+impl<'a, K, L: lock_api::RawRwLock> RefMulti<'a, K, L> {
+    pub(crate) fn new(inner: mapref::multiple::RefMulti<'a, K, (), L>) -> Self {
         Self { inner }
     }
 
@@ -15,7 +16,7 @@ impl<'a, K> RefMulti<'a, K> {
     }
 }
 
-impl<K> Deref for RefMulti<'_, K> {
+impl<K, L: lock_api::RawRwLock> Deref for RefMulti<'_, K, L> {
     type Target = K;
 
     fn deref(&self) -> &K {
@@ -1,13 +1,14 @@
+use crate::lock::RawRwLock;
 use crate::mapref;
 use core::hash::Hash;
 use core::ops::Deref;
 
-pub struct Ref<'a, K> {
-    inner: mapref::one::Ref<'a, K, ()>,
+pub struct Ref<'a, K, L: lock_api::RawRwLock = RawRwLock> {
+    inner: mapref::one::Ref<'a, K, (), L>,
 }
 
-impl<'a, K: Eq + Hash> Ref<'a, K> {
-    pub(crate) fn new(inner: mapref::one::Ref<'a, K, ()>) -> Self {
+impl<'a, K: Eq + Hash, L: lock_api::RawRwLock> Ref<'a, K, L> {
+    pub(crate) fn new(inner: mapref::one::Ref<'a, K, (), L>) -> Self {
         Self { inner }
     }
 
@@ -16,7 +17,7 @@ impl<'a, K: Eq + Hash> Ref<'a, K> {
     }
 }
 
-impl<K: Eq + Hash> Deref for Ref<'_, K> {
+impl<K: Eq + Hash, L: lock_api::RawRwLock> Deref for Ref<'_, K, L> {
     type Target = K;
 
     fn deref(&self) -> &K {
@@ -0,0 +1,172 @@
+//! How the entries of a map are spread across its shards.
+//!
+//! See [`ClashMap::shard_info`](crate::ClashMap::shard_info).
+
+use alloc::vec::Vec;
+
+/// The size of a single shard.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[non_exhaustive]
+pub struct ShardLoad {
+    /// The number of entries in the shard.
+    pub len: usize,
+    /// The number of entries the shard can hold without reallocating.
+    pub capacity: usize,
+    /// `len / capacity`, or 0 if the shard has not allocated yet.
+    pub load_factor: f64,
+}
+
+impl ShardLoad {
+    pub(crate) fn new(len: usize, capacity: usize) -> Self {
+        let load_factor = match capacity {
+            0 => 0.0,
+            capacity => len as f64 / capacity as f64,
+        };
+
+        Self {
+            len,
+            capacity,
+            load_factor,
+        }
+    }
+}
+
+/// The sizes of all the shards of a map, in shard index order, and statistics about their spread.
+///
+/// Shards are inspected one after the other, so if the map is modified concurrently
+/// the sizes may not all be from the same moment.
+///
+/// # Examples
+///
+/// ```
+/// use clashmap::ClashMap;
+///
+/// let map = ClashMap::with_shard_amount(8);
+/// for i in 0..10_000 {
+///     map.insert(i, i);
+/// }
+///
+/// let info = map.shard_info();
+/// assert_eq!(info.shards.len(), 8);
+/// assert_eq!(info.len(), 10_000);
+/// // with a good hasher, no shard is much bigger than the others
+/// assert!(info.max_imbalance() < 1.2);
+/// ```
+#[derive(Clone, PartialEq, Debug)]
+pub struct ShardInfo {
+    /// The size of every shard, in shard index order.
+    pub shards: Vec<ShardLoad>,
+}
+
+impl ShardInfo {
+    pub(crate) fn new(shards: impl IntoIterator<Item = (usize, usize)>) -> Self {
+        Self {
+            shards: shards
+                .into_iter()
+                .map(|(len, capacity)| ShardLoad::new(len, capacity))
+                .collect(),
+        }
+    }
+
+    /// Returns the number of entries in all shards.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.len).sum()
+    }
+
+    /// Returns `true` if all shards are empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of entries all shards can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.shards.iter().map(|s| s.capacity).sum()
+    }
+
+    /// Returns the mean number of entries per shard.
+    pub fn mean_len(&self) -> f64 {
+        self.len() as f64 / self.shards.len() as f64
+    }
+
+    /// Returns the ratio of the biggest shard to the mean shard size.
+    ///
+    /// This is 1 when all shards are the same size, and the number of shards when all entries
+    /// are in one shard. It is 0 for an empty map.
+    pub fn max_imbalance(&self) -> f64 {
+        let max = self.shards.iter().map(|s| s.len).max().unwrap_or(0);
+        match self.mean_len() {
+            mean if mean > 0.0 => max as f64 / mean,
+            _ => 0.0,
+        }
+    }
+
+    /// Returns the ratio of the smallest shard to the mean shard size.
+    ///
+    /// This is 1 when all shards are the same size, and 0 when any shard is empty.
+    pub fn min_imbalance(&self) -> f64 {
+        let min = self.shards.iter().map(|s| s.len).min().unwrap_or(0);
+        match self.mean_len() {
+            mean if mean > 0.0 => min as f64 / mean,
+            _ => 0.0,
+        }
+    }
+
+    /// Returns Pearson's chi-squared statistic of the shard sizes, against a uniform spread.
+    ///
+    /// If keys are hashed uniformly, this is expected to be close to the number of shards minus one.
+    /// Values many times larger point at a hasher that favours some shards. It is 0 for an empty map.
+    pub fn chi_squared(&self) -> f64 {
+        let mean = self.mean_len();
+        if mean == 0.0 {
+            return 0.0;
+        }
+
+        self.shards
+            .iter()
+            .map(|s| {
+                let diff = s.len as f64 - mean;
+                diff * diff / mean
+            })
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShardInfo;
+    use crate::test_util::BuildConstantHasher;
+    use crate::ClashMap;
+
+    #[test]
+    fn test_statistics() {
+        let info = ShardInfo::new([(2, 4), (6, 8), (4, 0), (0, 0)]);
+        assert_eq!(info.len(), 12);
+        assert_eq!(info.capacity(), 12);
+        assert_eq!(info.mean_len(), 3.0);
+        assert_eq!(info.shards[0].load_factor, 0.5);
+        assert_eq!(info.shards[2].load_factor, 0.0);
+        assert_eq!(info.max_imbalance(), 2.0);
+        assert_eq!(info.min_imbalance(), 0.0);
+        // (1 + 9 + 1 + 9) / 3
+        assert!((info.chi_squared() - 20.0 / 3.0).abs() < 1e-9);
+
+        let empty = ShardInfo::new([(0, 0), (0, 0)]);
+        assert!(empty.is_empty());
+        assert_eq!(empty.max_imbalance(), 0.0);
+        assert_eq!(empty.chi_squared(), 0.0);
+    }
+
+    #[test]
+    fn test_skewed_hasher() {
+        let map: ClashMap<u32, (), BuildConstantHasher> =
+            ClashMap::with_hasher_and_shard_amount(Default::default(), 4);
+        for i in 0..100 {
+            map.insert(i, ());
+        }
+
+        let info = map.shard_info();
+        assert_eq!(info.max_imbalance(), 4.0);
+        assert_eq!(info.chi_squared(), 300.0);
+        assert_eq!(map.into_read_only().shard_info(), info);
+    }
+}
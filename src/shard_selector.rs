@@ -0,0 +1,59 @@
+//! Choosing the shard that holds each entry.
+//!
+//! See [`ClashMap::with_hasher_and_shard_selector`](crate::ClashMap::with_hasher_and_shard_selector).
+
+/// Chooses the shard that an entry with a given hash is stored in.
+///
+/// The choice must only depend on the hash and the shard amount, and must be below the shard amount,
+/// or lookups will not find the entries again.
+///
+/// Entries whose hashes lead to the same shard share a lock, so operations over a group of them,
+/// such as [`with_shard_for`](crate::ClashMap::with_shard_for), only lock that shard.
+/// A selector that puts most entries in a few shards will make threads contend for those shards.
+///
+/// Each shard is a hashbrown table, which also uses the hash: the low bits pick the bucket,
+/// and the top 7 bits are stored as a tag that is compared before the keys are.
+/// A selector should take the shard from the bits in between, as [`DefaultShardSelector`] does.
+/// Taking it from the low bits makes all entries in a shard probe the same buckets,
+/// and taking it from the top 7 bits gives all of them the same tag.
+/// The same goes for hashers written to steer keys into shards.
+///
+/// Any `Fn(usize, usize) -> usize` closure, taking the hash and the shard amount, is a selector.
+/// The selector is the last type parameter of the map, so a map whose type has to be written out,
+/// such as a field of a struct, needs a selector type that can be named instead of a closure.
+pub trait ShardSelector: Send + Sync {
+    /// Returns the index of the shard, less than `shard_amount`, for an entry with the hash `hash`.
+    ///
+    /// `shard_amount` is always a power of two.
+    fn select_shard(&self, hash: usize, shard_amount: usize) -> usize;
+}
+
+impl<F> ShardSelector for F
+where
+    F: Fn(usize, usize) -> usize + Send + Sync,
+{
+    #[inline]
+    fn select_shard(&self, hash: usize, shard_amount: usize) -> usize {
+        self(hash, shard_amount)
+    }
+}
+
+/// The selector used by maps that are not given one.
+///
+/// It takes the bits of the hash just below the top 7, which are left for the hashbrown SIMD tag.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultShardSelector;
+
+impl DefaultShardSelector {
+    /// Returns how far a hash, shifted left by 7, is shifted right to leave only the shard index.
+    pub(crate) fn shift(shard_amount: usize) -> usize {
+        (usize::BITS - shard_amount.trailing_zeros()) as usize
+    }
+}
+
+impl ShardSelector for DefaultShardSelector {
+    #[inline]
+    fn select_shard(&self, hash: usize, shard_amount: usize) -> usize {
+        (hash << 7) >> Self::shift(shard_amount)
+    }
+}
@@ -1,66 +1,125 @@
+#[cfg(feature = "std")]
 use crate::default_shard_amount;
-use crate::lock::{RwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::layout::{Gate, Layout, LayoutGuard, NextLayout};
+use crate::lock::{RawRwLock, RawRwLockAsync, RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
 use crate::tableref::one::{Ref, RefMut};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::TypeId;
+use core::marker::PhantomData;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
 use crossbeam_utils::CachePadded;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
 /// An implementation detail of [`ClashTable`](crate::ClashTable), exposed for convenience.
 ///
 /// This implements the core sharded data structure that allows for efficient concurrency in ClashMap.
 ///
 /// Requires the `raw-api` feature to be enabled.
-pub struct ClashCollection<T> {
-    pub(crate) shift: usize,
-    pub(crate) shards: Box<[CachePadded<RwLock<T>>]>,
+pub struct ClashCollection<T, L = RawRwLock, Sel = DefaultShardSelector> {
+    /// The shards of the first layout, which hold the entries unless the collection was resharded
+    /// while shared. See [`ClashTable::reshard_incremental`](crate::ClashTable::reshard_incremental).
+    ///
+    /// Taking the collection mutably makes the current layout the first one again, see [`settle`](Self::settle).
+    pub(crate) shards: Box<[CachePadded<lock_api::RwLock<L, T>>]>,
+    pub(crate) selector: Sel,
+    pub(crate) next: NextLayout<T, L>,
+    /// The newest layout that every entry was moved to, or null if that is still the first one.
+    current: AtomicPtr<Layout<T, L>>,
+    pub(crate) gate: Gate,
+}
+
+/// A shard of a [`ClashCollection`].
+type ShardLock<T, L> = CachePadded<lock_api::RwLock<L, T>>;
+
+/// Asks `selector` for the shard of `hash`, checking that it exists.
+#[inline(always)]
+pub(crate) fn select_shard<Sel: ShardSelector>(
+    selector: &Sel,
+    hash: usize,
+    shard_amount: usize,
+) -> usize {
+    let idx = selector.select_shard(hash, shard_amount);
+    if idx >= shard_amount {
+        invalid_shard_index()
+    }
+    idx
 }
 
-impl<T: Clone> Clone for ClashCollection<T> {
+#[cold]
+#[inline(never)]
+fn invalid_shard_index() -> ! {
+    panic!("shard selector returned an invalid shard index")
+}
+
+/// Creates a shard holding `value`, behind an unlocked lock.
+pub(crate) fn new_shard<T, L: lock_api::RawRwLock>(
+    value: T,
+) -> CachePadded<lock_api::RwLock<L, T>> {
+    CachePadded::new(lock_api::RwLock::new(value))
+}
+
+impl<T: Clone, L: lock_api::RawRwLock, Sel: Clone> Clone for ClashCollection<T, L, Sel> {
     fn clone(&self) -> Self {
         let mut inner_shards = Vec::new();
 
-        for shard in self.shards.iter() {
+        let (_layout, shards) = self.enter();
+        for shard in shards.iter() {
             let shard = shard.read();
 
-            inner_shards.push(CachePadded::new(RwLock::new((*shard).clone())));
+            inner_shards.push(new_shard((*shard).clone()));
         }
 
-        Self {
-            shift: self.shift,
-            shards: inner_shards.into_boxed_slice(),
-        }
+        Self::from_shards(inner_shards.into_boxed_slice(), self.selector.clone())
     }
 }
 
-impl<T: Default> Default for ClashCollection<T> {
+#[cfg(feature = "std")]
+impl<T: Default, L: lock_api::RawRwLock, Sel: Default> Default for ClashCollection<T, L, Sel> {
     fn default() -> Self {
-        Self::new(T::default)
+        Self::build(default_shard_amount(), Sel::default(), T::default)
     }
 }
 
 #[allow(dead_code)]
-impl<T> ClashCollection<T> {
+impl<T, L: lock_api::RawRwLock, Sel> ClashCollection<T, L, Sel> {
     /// Allows you to peek at the inner shards that store your data.
-    pub fn shards(&self) -> &[CachePadded<RwLock<T>>] {
-        &self.shards
+    ///
+    /// While the collection is being resharded incrementally, these are the shards of the layout
+    /// the entries are moving out of.
+    pub fn shards(&self) -> &[CachePadded<lock_api::RwLock<L, T>>] {
+        self.current().0
     }
 
     /// Provides mutable access to the inner shards that store your data.
-    pub fn shards_mut(&mut self) -> &mut [CachePadded<RwLock<T>>] {
+    pub fn shards_mut(&mut self) -> &mut [CachePadded<lock_api::RwLock<L, T>>] {
+        self.settle();
         &mut self.shards
     }
 
     /// Consumes this `ClashCollection` and returns the inner shards.
-    pub fn into_shards(self) -> Box<[CachePadded<RwLock<T>>]> {
+    pub fn into_shards(mut self) -> Box<[CachePadded<lock_api::RwLock<L, T>>]> {
+        self.settle();
         self.shards
     }
+}
 
+#[allow(dead_code)]
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> ClashCollection<T, L, Sel> {
     /// Finds which shard a certain hash is stored in.
     pub fn determine_shard(&self, hash: usize) -> usize {
         self._determine_shard(hash)
     }
 }
 
+#[allow(dead_code)]
 impl<T> ClashCollection<T> {
     /// Creates a new `ClashCollection`.
+    #[cfg(feature = "std")]
     pub fn new(init: impl FnMut() -> T) -> Self {
         ClashCollection::with_shard_amount(default_shard_amount(), init)
     }
@@ -69,39 +128,158 @@ impl<T> ClashCollection<T> {
     ///
     /// shard_amount should greater than 0 and be a power of two.
     /// If a shard_amount which is not a power of two is provided, the function will panic.
-    pub fn with_shard_amount(shard_amount: usize, mut init: impl FnMut() -> T) -> Self {
+    pub fn with_shard_amount(shard_amount: usize, init: impl FnMut() -> T) -> Self {
+        Self::build(shard_amount, DefaultShardSelector, init)
+    }
+}
+
+/// Tells the lock of every shard its index, so that it can name the shard when reporting
+/// re-entrant locking. Only the built-in [`RawRwLock`] keeps the index, so the shards are
+/// only named if `L` turns out to be that lock.
+pub(crate) fn name_shards<T, L: lock_api::RawRwLock>(
+    shards: &[CachePadded<lock_api::RwLock<L, T>>],
+) {
+    if !is_builtin_lock::<L>() {
+        return;
+    }
+
+    for (idx, shard) in shards.iter().enumerate() {
+        // SAFETY: The raw lock is only used to record the index, it is not locked or unlocked.
+        let lock = unsafe { shard.raw() };
+        // SAFETY: `L` is `RawRwLock`, as checked above.
+        let lock = unsafe { &*(lock as *const L).cast::<RawRwLock>() };
+        lock.set_shard(idx);
+    }
+}
+
+/// Whether `L` is the built-in [`RawRwLock`].
+///
+/// [`TypeId::of`] only takes `'static` types, but the id does not depend on lifetimes,
+/// so it is taken through a trait object whose lifetime bound is extended.
+fn is_builtin_lock<L: ?Sized>() -> bool {
+    trait TypeIdOf {
+        fn type_id_of(&self) -> TypeId
+        where
+            Self: 'static;
+    }
+
+    impl<T: ?Sized> TypeIdOf for PhantomData<T> {
+        fn type_id_of(&self) -> TypeId
+        where
+            Self: 'static,
+        {
+            TypeId::of::<T>()
+        }
+    }
+
+    let lock = PhantomData::<L>;
+    let lock: &dyn TypeIdOf = &lock;
+    // SAFETY: Only the lifetime bound of the trait object changes. `type_id_of` does not use
+    // `self`, and returns the same id whatever the lifetimes in `L` are.
+    let lock = unsafe { mem::transmute::<&dyn TypeIdOf, &(dyn TypeIdOf + 'static)>(lock) };
+    lock.type_id_of() == TypeId::of::<RawRwLock>()
+}
+
+impl<T, L: lock_api::RawRwLock, Sel> ClashCollection<T, L, Sel> {
+    /// Creates a new `ClashCollection` with a specified shard amount, for any lock and selector.
+    pub(crate) fn build(shard_amount: usize, selector: Sel, mut init: impl FnMut() -> T) -> Self {
         assert!(shard_amount > 1);
         assert!(shard_amount.is_power_of_two());
 
-        let shift = (usize::BITS - shard_amount.trailing_zeros()) as usize;
+        let shards = (0..shard_amount).map(|_| new_shard(init())).collect();
+        Self::from_shards(shards, selector)
+    }
 
-        let shards = (0..shard_amount)
-            .map(|_| CachePadded::new(RwLock::new(init())))
-            .collect();
+    /// Creates a new `ClashCollection` from its shards.
+    pub(crate) fn from_shards(
+        shards: Box<[CachePadded<lock_api::RwLock<L, T>>]>,
+        selector: Sel,
+    ) -> Self {
+        name_shards(&shards);
+        Self {
+            shards,
+            selector,
+            next: NextLayout::new(),
+            current: AtomicPtr::new(ptr::null_mut()),
+            gate: Gate::new(),
+        }
+    }
 
-        Self { shift, shards }
+    /// Returns the shards of the newest layout that every entry was moved to, and the layout after it.
+    pub(crate) fn current(&self) -> (&[ShardLock<T, L>], &NextLayout<T, L>) {
+        let layout = self.current.load(Ordering::Acquire);
+        // SAFETY: Layouts are only freed by `settle` or on drop, which both take `self` mutably.
+        match unsafe { layout.as_ref() } {
+            Some(layout) => (&layout.shards, &layout.next),
+            None => (&self.shards, &self.next),
+        }
     }
 
+    /// Returns the shards of the newest layout, which entries are moving to if the collection is being resharded.
+    pub(crate) fn newest(&self) -> &[ShardLock<T, L>] {
+        let (mut shards, mut next) = self.current();
+        while let Some(layout) = next.get() {
+            shards = &layout.shards;
+            next = &layout.next;
+        }
+        shards
+    }
+
+    /// Waits for entries to stop moving to another layout, and returns the shards that hold them,
+    /// along with a guard keeping them there.
+    ///
+    /// Operations over all shards use this, so that they see every entry exactly once.
+    pub(crate) fn enter(&self) -> (LayoutGuard<'_>, &[ShardLock<T, L>]) {
+        let guard = self.gate.enter();
+        (guard, self.current().0)
+    }
+
+    /// Makes the newest layout the first one, dropping the layouts that were emptied by resharding.
+    pub(crate) fn settle(&mut self) {
+        let Some(mut layout) = self.next.take() else {
+            return;
+        };
+        while let Some(next) = layout.next.take() {
+            layout = next;
+        }
+        self.shards = mem::take(&mut layout.shards);
+        *self.current.get_mut() = ptr::null_mut();
+    }
+
+    /// Makes `layout`, whose shards now hold every entry, the current layout.
+    pub(crate) fn set_current(&self, layout: &Layout<T, L>) {
+        self.current
+            .store(layout as *const _ as *mut _, Ordering::Release);
+    }
+}
+
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> ClashCollection<T, L, Sel> {
     #[inline(always)]
     pub(crate) fn _determine_shard(&self, hash: usize) -> usize {
-        // Leave the high 7 bits for the HashBrown SIMD tag.
-        let idx = (hash << 7) >> self.shift;
-
-        // hint to llvm that the panic bounds check can be removed
-        if idx >= self.shards.len() {
-            if cfg!(debug_assertions) {
-                unreachable!("invalid shard index")
-            } else {
-                // SAFETY: shards is always a power of two,
-                // and shift is calculated such that the resulting idx is always
-                // less than the shards length
-                unsafe {
-                    std::hint::unreachable_unchecked();
+        select_shard(&self.selector, hash, self.newest().len())
+    }
+
+    /// Locks the shard holding `hash` with `lock`, following the layouts its entries were moved to.
+    /// Returns `None` if `lock` fails.
+    #[inline(always)]
+    pub(crate) fn lock_shard<'a, G>(
+        &'a self,
+        hash: u64,
+        mut lock: impl FnMut(&'a CachePadded<lock_api::RwLock<L, T>>) -> Option<G>,
+    ) -> Option<G> {
+        let (mut shards, mut next) = self.current();
+        loop {
+            let idx = select_shard(&self.selector, hash as usize, shards.len());
+            let guard = lock(&shards[idx])?;
+            match next.get() {
+                Some(layout) if layout.is_moved(idx) => {
+                    drop(guard);
+                    shards = &layout.shards;
+                    next = &layout.next;
                 }
+                _ => return Some(guard),
             }
         }
-
-        idx
     }
 
     // fn for_each(&self, mut f: impl FnMut(&T)) {
@@ -125,43 +303,40 @@ impl<T> ClashCollection<T> {
         mut r: R,
         mut f: impl FnMut(R, &T) -> Result<R, E>,
     ) -> Result<R, E> {
-        for shard in self.shards.iter() {
+        let (_layout, shards) = self.enter();
+        for shard in shards.iter() {
             let shard = shard.read();
             r = f(r, &shard)?;
         }
         Ok(r)
     }
 
-    pub fn get_read_shard(&self, hash: u64) -> Ref<'_, T> {
-        let idx = self._determine_shard(hash as usize);
-        let shard = self.shards[idx].read();
+    pub fn get_read_shard(&self, hash: u64) -> Ref<'_, T, L> {
+        let shard = self.lock_shard(hash, |shard| Some(shard.read())).unwrap();
 
         // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
         let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(shard) };
         Ref::new(guard, shard)
     }
 
-    pub fn get_write_shard(&self, hash: u64) -> RefMut<'_, T> {
-        let idx = self._determine_shard(hash as usize);
-        let shard = self.shards[idx].write();
+    pub fn get_write_shard(&self, hash: u64) -> RefMut<'_, T, L> {
+        let shard = self.lock_shard(hash, |shard| Some(shard.write())).unwrap();
 
         // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
         let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(shard) };
         RefMut::new(guard, shard)
     }
 
-    pub fn try_read_shard(&self, hash: u64) -> Option<Ref<'_, T>> {
-        let idx = self._determine_shard(hash as usize);
-        let shard = self.shards[idx].try_read()?;
+    pub fn try_read_shard(&self, hash: u64) -> Option<Ref<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_read())?;
 
         // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
         let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(shard) };
         Some(Ref::new(guard, shard))
     }
 
-    pub fn try_write_shard(&self, hash: u64) -> Option<RefMut<'_, T>> {
-        let idx = self._determine_shard(hash as usize);
-        let shard = self.shards[idx].try_write()?;
+    pub fn try_write_shard(&self, hash: u64) -> Option<RefMut<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_write())?;
 
         // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
         let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(shard) };
@@ -169,21 +344,139 @@ impl<T> ClashCollection<T> {
     }
 
     pub fn get_mut(&mut self, hash: u64) -> &mut T {
+        self.settle();
         let idx = self._determine_shard(hash as usize);
         self.shards[idx].get_mut()
     }
 }
 
+#[cfg(feature = "std")]
+impl<T, L, Sel: ShardSelector> ClashCollection<T, L, Sel>
+where
+    L: lock_api::RawRwLockTimed<Duration = Duration, Instant = Instant>,
+{
+    pub fn try_read_shard_for(&self, hash: u64, timeout: Duration) -> Option<Ref<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_read_for(timeout))?;
+
+        // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
+        let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(shard) };
+        Some(Ref::new(guard, shard))
+    }
+
+    pub fn try_read_shard_until(&self, hash: u64, deadline: Instant) -> Option<Ref<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_read_until(deadline))?;
+
+        // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
+        let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(shard) };
+        Some(Ref::new(guard, shard))
+    }
+
+    pub fn try_write_shard_for(&self, hash: u64, timeout: Duration) -> Option<RefMut<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_write_for(timeout))?;
+
+        // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
+        let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(shard) };
+        Some(RefMut::new(guard, shard))
+    }
+
+    pub fn try_write_shard_until(&self, hash: u64, deadline: Instant) -> Option<RefMut<'_, T, L>> {
+        let shard = self.lock_shard(hash, |shard| shard.try_write_until(deadline))?;
+
+        // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
+        let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(shard) };
+        Some(RefMut::new(guard, shard))
+    }
+}
+
+impl<T, L: RawRwLockAsync, Sel: ShardSelector> ClashCollection<T, L, Sel> {
+    pub async fn get_read_shard_async(&self, hash: u64) -> Ref<'_, T, L> {
+        let (mut shards, mut next) = self.current();
+        loop {
+            let idx = select_shard(&self.selector, hash as usize, shards.len());
+            let shard = &shards[idx];
+
+            // SAFETY: The raw lock is only used to acquire the lock, which is then owned by the guard.
+            let lock = unsafe { shard.raw() };
+            core::future::poll_fn(|cx| lock.poll_lock_shared(cx)).await;
+
+            // SAFETY: We have just acquired the shared lock.
+            let guard = unsafe { RwLockReadGuardDetached::from_raw(lock) };
+            match next.get() {
+                Some(layout) if layout.is_moved(idx) => {
+                    drop(guard);
+                    shards = &layout.shards;
+                    next = &layout.next;
+                }
+                _ => {
+                    // SAFETY: The data will not outlive the guard, since we pass the guard to `Ref`.
+                    let shard = unsafe { &*shard.data_ptr() };
+                    return Ref::new(guard, shard);
+                }
+            }
+        }
+    }
+
+    pub async fn get_write_shard_async(&self, hash: u64) -> RefMut<'_, T, L> {
+        let (mut shards, mut next) = self.current();
+        loop {
+            let idx = select_shard(&self.selector, hash as usize, shards.len());
+            let shard = &shards[idx];
+
+            // SAFETY: The raw lock is only used to acquire the lock, which is then owned by the guard.
+            let lock = unsafe { shard.raw() };
+            core::future::poll_fn(|cx| lock.poll_lock_exclusive(cx)).await;
+
+            // SAFETY: We have just acquired the exclusive lock.
+            let guard = unsafe { RwLockWriteGuardDetached::from_raw(lock) };
+            match next.get() {
+                Some(layout) if layout.is_moved(idx) => {
+                    drop(guard);
+                    shards = &layout.shards;
+                    next = &layout.next;
+                }
+                _ => {
+                    // SAFETY: The data will not outlive the guard, since we pass the guard to `RefMut`.
+                    let shard = unsafe { &mut *shard.data_ptr() };
+                    return RefMut::new(guard, shard);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "stats")]
+impl<T, L: crate::stats::RawRwLockStats, Sel> ClashCollection<T, L, Sel> {
+    /// Returns the lock contention counters of every shard, in shard index order.
+    pub(crate) fn shard_stats(&self) -> Vec<crate::stats::ShardStats> {
+        self.current()
+            .0
+            .iter()
+            // SAFETY: the raw lock is only used to read its counters, it is never locked or unlocked.
+            .map(|shard| unsafe { shard.raw() }.stats())
+            .collect()
+    }
+
+    /// Resets the lock contention counters of every shard to zero.
+    pub(crate) fn reset_shard_stats(&self) {
+        for shard in self.current().0.iter() {
+            // SAFETY: the raw lock is only used to reset its counters, it is never locked or unlocked.
+            unsafe { shard.raw() }.reset_stats();
+        }
+    }
+}
+
 #[cfg(feature = "typesize")]
-impl<T: typesize::TypeSize> typesize::TypeSize for ClashCollection<T> {
+impl<T: typesize::TypeSize, L: lock_api::RawRwLock, Sel: ShardSelector> typesize::TypeSize
+    for ClashCollection<T, L, Sel>
+{
     fn extra_size(&self) -> usize {
-        let acc = core::mem::size_of_val(&self.shards);
+        let acc = core::mem::size_of_val(self.current().0);
         self.fold(acc, |acc, shard| acc + shard.extra_size())
     }
 
     typesize::if_typesize_details! {
         fn get_collection_item_count(&self) -> Option<usize> {
-            Some(self.shards.len())
+            Some(self.current().0.len())
         }
     }
 }
@@ -0,0 +1,144 @@
+//! Lock contention counters for each shard.
+//!
+//! Requires the `stats` feature to be enabled.
+//!
+//! Every [`RawRwLock`](crate::lock::RawRwLock) counts how it was acquired, so that contention
+//! on a shard can be told apart from other sources of latency.
+//! See [`ClashMap::shard_stats`](crate::ClashMap::shard_stats).
+//!
+//! Other locks can report their own counters by implementing [`RawRwLockStats`].
+
+use crate::lock::Mode;
+use core::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// The widest atomic integer of the target, as not every target has 64-bit atomics.
+#[cfg(target_has_atomic = "64")]
+type AtomicCount = core::sync::atomic::AtomicU64;
+#[cfg(not(target_has_atomic = "64"))]
+type AtomicCount = core::sync::atomic::AtomicUsize;
+#[cfg(target_has_atomic = "64")]
+type Count = u64;
+#[cfg(not(target_has_atomic = "64"))]
+type Count = usize;
+
+/// The lock contention counters of a single shard.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[non_exhaustive]
+pub struct ShardStats {
+    /// The number of times the lock was acquired without waiting for it.
+    pub fast_acquisitions: u64,
+    /// The number of spin iterations made while waiting for the lock.
+    pub spins: u64,
+    /// The number of times a reader parked its thread to wait for the lock.
+    pub reader_parks: u64,
+    /// The number of times a writer, or an upgradable reader, parked its thread to wait for the lock.
+    pub writer_parks: u64,
+    /// The total time that threads spent parked waiting for the lock.
+    pub parked_time: Duration,
+}
+
+/// A raw lock that keeps lock contention counters.
+pub trait RawRwLockStats: lock_api::RawRwLock {
+    /// Returns the counters of this lock.
+    fn stats(&self) -> ShardStats;
+
+    /// Resets the counters of this lock to zero.
+    fn reset_stats(&self);
+}
+
+/// The counters kept by a single lock.
+pub(crate) struct Counters {
+    fast_acquisitions: AtomicCount,
+    spins: AtomicCount,
+    reader_parks: AtomicCount,
+    writer_parks: AtomicCount,
+    parked_nanos: AtomicCount,
+}
+
+impl Counters {
+    pub(crate) const fn new() -> Self {
+        Self {
+            fast_acquisitions: AtomicCount::new(0),
+            spins: AtomicCount::new(0),
+            reader_parks: AtomicCount::new(0),
+            writer_parks: AtomicCount::new(0),
+            parked_nanos: AtomicCount::new(0),
+        }
+    }
+
+    pub(crate) fn fast_acquisition(&self) {
+        self.fast_acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn spin(&self) {
+        self.spins.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn parked(&self, mode: Mode, time: Duration) {
+        let parks = match mode {
+            Mode::Shared => &self.reader_parks,
+            Mode::Upgradable | Mode::Exclusive => &self.writer_parks,
+        };
+        parks.fetch_add(1, Ordering::Relaxed);
+
+        let nanos = Count::try_from(time.as_nanos()).unwrap_or(Count::MAX);
+        self.parked_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> ShardStats {
+        ShardStats {
+            fast_acquisitions: load(&self.fast_acquisitions),
+            spins: load(&self.spins),
+            reader_parks: load(&self.reader_parks),
+            writer_parks: load(&self.writer_parks),
+            parked_time: Duration::from_nanos(load(&self.parked_nanos)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        self.fast_acquisitions.store(0, Ordering::Relaxed);
+        self.spins.store(0, Ordering::Relaxed);
+        self.reader_parks.store(0, Ordering::Relaxed);
+        self.writer_parks.store(0, Ordering::Relaxed);
+        self.parked_nanos.store(0, Ordering::Relaxed);
+    }
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn load(count: &AtomicCount) -> u64 {
+    count.load(Ordering::Relaxed) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ClashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn test_contended_reader_parks() {
+        let map = ClashMap::with_shard_amount(2);
+        map.insert(1, 1);
+        map.reset_shard_stats();
+
+        std::thread::scope(|s| {
+            let guard = map.get_mut(&1).unwrap();
+            let reader = s.spawn(|| *map.get(&1).unwrap());
+
+            std::thread::sleep(Duration::from_millis(50));
+            drop(guard);
+            assert_eq!(reader.join().unwrap(), 1);
+        });
+
+        let shard = map.table.tables._determine_shard(map.hash_usize(&1));
+        let stats = map.shard_stats()[shard];
+        assert_eq!(stats.fast_acquisitions, 1);
+        assert!(stats.spins > 0);
+        assert_eq!(stats.reader_parks, 1);
+        assert_eq!(stats.writer_parks, 0);
+        assert!(stats.parked_time >= Duration::from_millis(10));
+
+        map.reset_shard_stats();
+        assert!(map.shard_stats().iter().all(|s| *s == Default::default()));
+    }
+}
@@ -1,17 +1,35 @@
-use crate::sharded::ClashCollection;
+#[cfg(feature = "std")]
+use crate::default_shard_amount;
+use crate::layout::{self, Layout};
+use crate::lock::{
+    RawRwLock, RawRwLockAsync, RwLockReadGuardDetached, RwLockUpgradableReadGuardDetached,
+    RwLockWriteGuardDetached,
+};
+use crate::scan::Cursor;
+use crate::shard_info::ShardInfo;
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use crate::sharded::{name_shards, new_shard, select_shard, ClashCollection};
 use crate::tableref::entry::{AbsentEntry, Entry, OccupiedEntry, VacantEntry};
 use crate::tableref::entrymut::{EntryMut, OccupiedEntryMut, VacantEntryMut};
-use crate::tableref::iter::{Iter, IterMut, OwningIter};
-use crate::tableref::multiple::RefMulti;
-use crate::tableref::one::{Ref, RefMut};
+use crate::tableref::iter::{Drain, DrainMut, ExtractIf, ExtractIfMut, Iter, IterMut, OwningIter};
+use crate::tableref::multiple::{RefMulti, RefMutMulti};
+use crate::tableref::one::{Ref, RefMut, RefUpgradable};
 use crate::try_result::TryResult;
-use crate::{default_shard_amount, TryReserveError};
+use crate::util::abort_on_panic;
+use crate::TryReserveError;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::Infallible;
 use core::fmt;
 use hashbrown::{hash_table, HashTable};
-use std::convert::Infallible;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
-#[cfg(any(feature = "raw-api", feature = "typesize"))]
-use {crate::lock::RwLock, crossbeam_utils::CachePadded};
+use crossbeam_utils::CachePadded;
+
+type Shard<T, L> = CachePadded<lock_api::RwLock<L, HashTable<T>>>;
 
 /// ClashTable is an implementation of a concurrent hashtable in Rust.
 ///
@@ -20,11 +38,11 @@ use {crate::lock::RwLock, crossbeam_utils::CachePadded};
 ///
 /// Documentation mentioning locking behaviour acts in the reference frame of the calling thread.
 /// This means that it is safe to ignore it across multiple threads.
-pub struct ClashTable<T> {
-    pub(crate) tables: ClashCollection<HashTable<T>>,
+pub struct ClashTable<T, L = RawRwLock, Sel = DefaultShardSelector> {
+    pub(crate) tables: ClashCollection<HashTable<T>, L, Sel>,
 }
 
-impl<T: Clone> Clone for ClashTable<T> {
+impl<T: Clone, L: lock_api::RawRwLock, Sel: Clone> Clone for ClashTable<T, L, Sel> {
     fn clone(&self) -> Self {
         Self {
             tables: self.tables.clone(),
@@ -32,19 +50,22 @@ impl<T: Clone> Clone for ClashTable<T> {
     }
 }
 
-impl<T> Default for ClashTable<T> {
+#[cfg(feature = "std")]
+impl<T, L: lock_api::RawRwLock, Sel: Default> Default for ClashTable<T, L, Sel> {
     fn default() -> Self {
-        Self::new()
+        Self {
+            tables: ClashCollection::build(default_shard_amount(), Sel::default(), HashTable::new),
+        }
     }
 }
 
 #[cfg(feature = "raw-api")]
-impl<T> ClashTable<T> {
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> ClashTable<T, L, Sel> {
     /// Allows you to peek at the inner shards that store your data.
     /// You should probably not use this unless you know what you are doing.
     ///
     /// Requires the `raw-api` feature to be enabled.
-    pub fn shards(&self) -> &[CachePadded<RwLock<HashTable<T>>>] {
+    pub fn shards(&self) -> &[Shard<T, L>] {
         self.tables.shards()
     }
 
@@ -52,7 +73,7 @@ impl<T> ClashTable<T> {
     /// You should probably not use this unless you know what you are doing.
     ///
     /// Requires the `raw-api` feature to be enabled.
-    pub fn shards_mut(&mut self) -> &mut [CachePadded<RwLock<HashTable<T>>>] {
+    pub fn shards_mut(&mut self) -> &mut [Shard<T, L>] {
         self.tables.shards_mut()
     }
 
@@ -60,7 +81,7 @@ impl<T> ClashTable<T> {
     /// You should probably not use this unless you know what you are doing.
     ///
     /// Requires the `raw-api` feature to be enabled.
-    pub fn into_shards(self) -> Box<[CachePadded<RwLock<HashTable<T>>>]> {
+    pub fn into_shards(self) -> alloc::boxed::Box<[Shard<T, L>]> {
         self.tables.into_shards()
     }
 
@@ -72,6 +93,23 @@ impl<T> ClashTable<T> {
     }
 }
 
+#[cfg(feature = "stats")]
+impl<T, L: crate::stats::RawRwLockStats, Sel> ClashTable<T, L, Sel> {
+    /// Returns the lock contention counters of every shard, in shard index order.
+    ///
+    /// Requires the `stats` feature to be enabled.
+    pub fn shard_stats(&self) -> Vec<crate::stats::ShardStats> {
+        self.tables.shard_stats()
+    }
+
+    /// Resets the lock contention counters of every shard to zero.
+    ///
+    /// Requires the `stats` feature to be enabled.
+    pub fn reset_shard_stats(&self) {
+        self.tables.reset_shard_stats()
+    }
+}
+
 fn find_mut<T>(shard: &mut HashTable<T>, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<&mut T> {
     match shard.find_entry(hash, eq) {
         Ok(entry) => Some(entry.into_mut()),
@@ -79,35 +117,83 @@ fn find_mut<T>(shard: &mut HashTable<T>, hash: u64, eq: impl FnMut(&T) -> bool)
     }
 }
 
-impl<T> ClashTable<T> {
-    // /// Wraps this `ClashTable` into a read-only view. This view allows to obtain raw references to the stored values.
-    // pub fn into_read_only(self) -> ReadOnlyView<T> {
-    //     ReadOnlyView::new(self)
-    // }
+fn find_in<T, L: lock_api::RawRwLock>(
+    shard: Option<Ref<'_, HashTable<T>, L>>,
+    hash: u64,
+    eq: impl FnMut(&T) -> bool,
+) -> TryResult<Ref<'_, T, L>> {
+    let Some(shard) = shard else {
+        return TryResult::Locked;
+    };
 
-    /// Creates a new ClashTable with a capacity of 0.
-    pub fn new() -> Self {
-        ClashTable::with_capacity(0)
-    }
+    shard
+        .try_map(|shard| shard.find(hash, eq))
+        .map_or(TryResult::Absent, TryResult::Present)
+}
 
-    /// Creates a new ClashTable with a specified starting capacity.
-    pub fn with_capacity(capacity: usize) -> Self {
-        ClashTable::with_capacity_and_shard_amount(capacity, default_shard_amount())
+fn find_mut_in<T, L: lock_api::RawRwLock>(
+    shard: Option<RefMut<'_, HashTable<T>, L>>,
+    hash: u64,
+    eq: impl FnMut(&T) -> bool,
+) -> TryResult<RefMut<'_, T, L>> {
+    let Some(shard) = shard else {
+        return TryResult::Locked;
+    };
+
+    shard
+        .try_map(|shard| find_mut(shard, hash, eq))
+        .map_or(TryResult::Absent, TryResult::Present)
+}
+
+fn shard_entry<T, L: lock_api::RawRwLock>(
+    shard: RefMut<'_, HashTable<T>, L>,
+    hash: u64,
+    eq: impl FnMut(&T) -> bool,
+    hasher: impl Fn(&T) -> u64,
+) -> Entry<'_, T, L> {
+    let RefMut { guard, t } = shard;
+    match t.entry(hash, eq, hasher) {
+        hash_table::Entry::Occupied(occupied_entry) => {
+            Entry::Occupied(OccupiedEntry::new(guard, occupied_entry))
+        }
+        hash_table::Entry::Vacant(vacant_entry) => {
+            Entry::Vacant(VacantEntry::new(guard, vacant_entry))
+        }
     }
+}
 
-    /// Creates a new ClashTable with a specified shard amount
+/// Sorts the shard indices and removes duplicates, so each shard is locked once and in order.
+fn sorted_dedup<const N: usize>(mut shards: [usize; N]) -> impl Iterator<Item = usize> {
+    shards.sort_unstable();
+    let mut prev = None;
+    shards
+        .into_iter()
+        .filter(move |&idx| prev.replace(idx) != Some(idx))
+}
+
+impl<T, L: lock_api::RawRwLock> ClashTable<T, L> {
+    /// Creates a new ClashTable with a specified starting capacity, that guards its shards with the lock `L`.
     ///
-    /// shard_amount should greater than 0 and be a power of two.
-    /// If a shard_amount which is not a power of two is provided, the function will panic.
-    pub fn with_shard_amount(shard_amount: usize) -> Self {
-        Self::with_capacity_and_shard_amount(0, shard_amount)
+    /// Any [`lock_api::RawRwLock`] can be used, such as the one of `parking_lot` or a spin lock.
+    /// The `async` methods need a lock that implements [`RawRwLockAsync`](crate::RawRwLockAsync).
+    #[cfg(feature = "std")]
+    pub fn with_capacity_and_lock(capacity: usize) -> Self {
+        Self::with_capacity_and_shard_amount_and_lock(capacity, default_shard_amount())
     }
 
-    /// Creates a new ClashTable with a specified starting capacity, hasher and shard_amount.
+    /// Creates a new ClashTable with a specified starting capacity and shard amount,
+    /// that guards its shards with the lock `L`.
     ///
     /// shard_amount should greater than 0 and be a power of two.
     /// If a shard_amount which is not a power of two is provided, the function will panic.
-    pub fn with_capacity_and_shard_amount(mut capacity: usize, shard_amount: usize) -> Self {
+    pub fn with_capacity_and_shard_amount_and_lock(capacity: usize, shard_amount: usize) -> Self {
+        Self::build(capacity, shard_amount, DefaultShardSelector)
+    }
+}
+
+impl<T, L: lock_api::RawRwLock, Sel> ClashTable<T, L, Sel> {
+    /// Creates a new ClashTable with a specified starting capacity and shard amount, for any lock and selector.
+    pub(crate) fn build(mut capacity: usize, shard_amount: usize, selector: Sel) -> Self {
         if capacity != 0 {
             capacity = (capacity + (shard_amount - 1)) & !(shard_amount - 1);
         }
@@ -115,16 +201,23 @@ impl<T> ClashTable<T> {
         let cps = capacity / shard_amount;
 
         Self {
-            tables: ClashCollection::with_shard_amount(shard_amount, || {
+            tables: ClashCollection::build(shard_amount, selector, || {
                 HashTable::with_capacity(cps)
             }),
         }
     }
+}
+
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> ClashTable<T, L, Sel> {
+    // /// Wraps this `ClashTable` into a read-only view. This view allows to obtain raw references to the stored values.
+    // pub fn into_read_only(self) -> ReadOnlyView<T> {
+    //     ReadOnlyView::new(self)
+    // }
 
     /// Creates an iterator over a ClashTable yielding immutable references.
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
-    pub fn iter(&self) -> Iter<'_, T> {
+    pub fn iter(&self) -> Iter<'_, T, L> {
         Iter::new(self)
     }
 
@@ -156,14 +249,14 @@ impl<T> ClashTable<T> {
     /// Iterator over a ClashTable yielding mutable references.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
-    pub fn iter_mut(&self) -> IterMut<'_, T> {
+    pub fn iter_mut(&self) -> IterMut<'_, T, L> {
         IterMut::new(self)
     }
 
     /// Get an immutable reference to an entry in the map
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
-    pub fn find(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<Ref<'_, T>> {
+    pub fn find(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<Ref<'_, T, L>> {
         self.tables
             .get_read_shard(hash)
             .try_map(|shard| shard.find(hash, eq))
@@ -173,61 +266,338 @@ impl<T> ClashTable<T> {
     /// Get a mutable reference to an entry in the map
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
-    pub fn find_mut(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<RefMut<'_, T>> {
+    pub fn find_mut(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<RefMut<'_, T, L>> {
         self.tables
             .get_write_shard(hash)
             .try_map(|shard| find_mut(shard, hash, eq))
             .ok()
     }
 
+    /// Get immutable references to `N` entries in the map at once.
+    ///
+    /// The `eq` argument should be a closure such that `eq(i, t)` returns true if `t` is equal to
+    /// the `i`th entry to be looked up.
+    ///
+    /// Each shard involved is read-locked once, in shard index order.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    pub fn find_many<const N: usize>(
+        &self,
+        hashes: [u64; N],
+        mut eq: impl FnMut(usize, &T) -> bool,
+    ) -> [Option<RefMulti<'_, T, L>>; N] {
+        // The shards are locked by index, so entries must not move to another layout in between.
+        let (_layout, locks) = self.tables.enter();
+        let shards = hashes.map(|hash| self.tables._determine_shard(hash as usize));
+        let mut refs = [(); N].map(|()| None);
+
+        for idx in sorted_dedup(shards) {
+            let guard = locks[idx].read();
+
+            // SAFETY: we keep the guard alive with any refs produced from this shard
+            let (guard, shard) = unsafe { RwLockReadGuardDetached::detach_from(guard) };
+            let guard = Arc::new(guard);
+
+            for (i, r) in refs.iter_mut().enumerate() {
+                if shards[i] == idx {
+                    *r = shard
+                        .find(hashes[i], |t| eq(i, t))
+                        .map(|t| RefMulti::new(guard.clone(), t));
+                }
+            }
+        }
+
+        refs
+    }
+
+    /// Get mutable references to `N` entries in the map at once.
+    ///
+    /// The `eq` argument should be a closure such that `eq(i, t)` returns true if `t` is equal to
+    /// the `i`th entry to be looked up.
+    ///
+    /// Each shard involved is write-locked once, in shard index order,
+    /// so multiple entries from the same shard do not deadlock.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the hashes and `eq` pairs refer to the same entry.
+    pub fn find_many_mut<const N: usize>(
+        &self,
+        hashes: [u64; N],
+        mut eq: impl FnMut(usize, &T) -> bool,
+    ) -> [Option<RefMutMulti<'_, T, L>>; N] {
+        // The shards are locked by index, so entries must not move to another layout in between.
+        let (_layout, locks) = self.tables.enter();
+        let shards = hashes.map(|hash| self.tables._determine_shard(hash as usize));
+        let mut refs = [(); N].map(|()| None);
+
+        for idx in sorted_dedup(shards) {
+            let guard = locks[idx].write();
+
+            // SAFETY: we keep the guard alive with any refs produced from this shard
+            let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(guard) };
+            let guard = Arc::new(guard);
+
+            let found = shard.get_many_mut(hashes, |i, t| shards[i] == idx && eq(i, t));
+            for (r, t) in refs.iter_mut().zip(found) {
+                if let Some(t) = t {
+                    *r = Some(RefMutMulti::new(guard.clone(), t));
+                }
+            }
+        }
+
+        refs
+    }
+
     /// Get an immutable reference to an entry in the map, if the shard is not locked.
     /// If the shard is locked, the function will return [TryResult::Locked].
-    pub fn try_find(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> TryResult<Ref<'_, T>> {
-        let Some(shard) = self.tables.try_read_shard(hash) else {
-            return TryResult::Locked;
-        };
-
-        shard
-            .try_map(|shard| shard.find(hash, eq))
-            .map_or(TryResult::Absent, TryResult::Present)
+    pub fn try_find(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> TryResult<Ref<'_, T, L>> {
+        find_in(self.tables.try_read_shard(hash), hash, eq)
     }
 
     /// Get a mutable reference to an entry in the map, if the shard is not locked.
     /// If the shard is locked, the function will return [TryResult::Locked].
-    pub fn try_find_mut(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> TryResult<RefMut<'_, T>> {
-        let Some(shard) = self.tables.try_write_shard(hash) else {
-            return TryResult::Locked;
-        };
-
-        shard
-            .try_map(|shard| find_mut(shard, hash, eq))
-            .map_or(TryResult::Absent, TryResult::Present)
+    pub fn try_find_mut(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+    ) -> TryResult<RefMut<'_, T, L>> {
+        find_mut_in(self.tables.try_write_shard(hash), hash, eq)
     }
 
     /// Remove excess capacity to reduce memory usage.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
     pub fn shrink_to_fit(&self, hasher: impl Fn(&T) -> u64) {
-        self.tables.shards().iter().for_each(|s| {
+        let (_layout, shards) = self.tables.enter();
+        shards.iter().for_each(|s| {
             s.write().shrink_to_fit(|t| hasher(t));
         })
     }
 
+    /// Changes the number of shards, moving every entry to its shard in the new layout.
+    ///
+    /// The shard selector of the table is kept.
+    /// The lock contention counters of the `stats` feature start again from zero.
+    ///
+    /// `hasher` and the selector are called for every entry before any entry is moved,
+    /// so if either of them panics, the table is left as it was.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn reshard(&mut self, shard_amount: usize, hasher: impl Fn(&T) -> u64) {
+        assert!(shard_amount > 1);
+        assert!(shard_amount.is_power_of_two());
+
+        self.tables.settle();
+        let selector = &self.tables.selector;
+        let mut counts = vec![0; shard_amount];
+        let targets: Vec<Vec<(usize, u64)>> = self
+            .tables
+            .shards
+            .iter_mut()
+            .map(|shard| {
+                shard
+                    .get_mut()
+                    .iter()
+                    .map(|t| {
+                        let hash = hasher(t);
+                        let idx = select_shard(selector, hash as usize, shard_amount);
+                        counts[idx] += 1;
+                        (idx, hash)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        // Every new shard has room for all of its entries, so inserting never calls `hasher` to grow it.
+        let mut shards: Box<[_]> = counts
+            .into_iter()
+            .map(|count| new_shard(HashTable::with_capacity(count)))
+            .collect();
+        for (shard, targets) in self.tables.shards.iter_mut().zip(targets) {
+            // `drain` yields the entries of the unchanged table in the same order as `iter` did.
+            for (t, (idx, hash)) in shard.get_mut().drain().zip(targets) {
+                shards[idx].get_mut().insert_unique(hash, t, &hasher);
+            }
+        }
+
+        name_shards(&shards);
+        self.tables.shards = shards;
+    }
+
+    /// Changes the number of shards while the table is shared, moving the entries of one shard at a time.
+    ///
+    /// Single entry operations keep working while the entries move, finding every entry in the layout
+    /// it is in. Operations over several shards, such as iterating or [`len`](Self::len), wait for the
+    /// entries to finish moving, and a reshard waits for them to end before it starts.
+    /// Only one reshard runs at a time.
+    ///
+    /// The memory of the old shards is freed as they are emptied, apart from their locks, which are
+    /// kept until the table is next borrowed mutably.
+    ///
+    /// If `hasher` or the selector panics, the process is aborted, as the entries of a shard would be
+    /// left split across two layouts.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding an iterator into the table, and
+    /// operations over several shards may deadlock while this runs if their thread holds a reference into the table.
+    pub fn reshard_incremental(&self, shard_amount: usize, hasher: impl Fn(&T) -> u64) {
+        assert!(shard_amount > 1);
+        assert!(shard_amount.is_power_of_two());
+
+        let _moving = self.tables.gate.start_moving();
+        let (old, next) = self.tables.current();
+        let shards: Box<[_]> = (0..shard_amount)
+            .map(|_| new_shard(HashTable::new()))
+            .collect();
+        name_shards(&shards);
+        let layout = next.set(Box::new(Layout::new(shards, old.len())));
+
+        abort_on_panic(|| {
+            for (idx, shard) in old.iter().enumerate() {
+                self.move_shard(shard, idx, layout, &hasher);
+            }
+        });
+        self.tables.set_current(layout);
+    }
+
+    /// Moves the entries of the shard at `idx` in the current layout to `layout`, and marks it as moved.
+    fn move_shard(
+        &self,
+        shard: &Shard<T, L>,
+        idx: usize,
+        layout: &Layout<HashTable<T>, L>,
+        hasher: &impl Fn(&T) -> u64,
+    ) {
+        let selector = &self.tables.selector;
+        loop {
+            let mut shard = shard.write();
+            let targets: Vec<(usize, u64)> = shard
+                .iter()
+                .map(|t| {
+                    let hash = hasher(t);
+                    (
+                        select_shard(selector, hash as usize, layout.shards.len()),
+                        hash,
+                    )
+                })
+                .collect();
+
+            // The new shards can be locked by threads waiting for this one, so they are only tried,
+            // and the shard is unlocked again if any of them is taken.
+            let mut guards: Vec<_> = layout.shards.iter().map(|_| None).collect();
+            let locked = targets.iter().all(|&(target, _)| {
+                guards[target].is_some() || {
+                    guards[target] = layout.shards[target].try_write();
+                    guards[target].is_some()
+                }
+            });
+            if !locked {
+                drop(guards);
+                drop(shard);
+                layout::wait();
+                continue;
+            }
+
+            // `drain` yields the entries of the unchanged table in the same order as `iter` did.
+            for (t, (target, hash)) in shard.drain().zip(targets) {
+                let target = guards[target].as_mut().unwrap();
+                target.insert_unique(hash, t, hasher);
+            }
+            *shard = HashTable::new();
+            layout.set_moved(idx);
+            return;
+        }
+    }
+
     /// Retain elements that whose predicates return true
     /// and discard elements whose predicates return false.
     ///
     /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
     pub fn retain(&self, mut f: impl FnMut(&mut T) -> bool) {
-        self.tables.shards().iter().for_each(|s| {
+        let (_layout, shards) = self.tables.enter();
+        shards.iter().for_each(|s| {
             s.write().retain(|t| f(t));
         })
     }
 
+    /// Like [`retain`](ClashTable::retain), but also passes the index of the shard holding each entry.
+    pub(crate) fn retain_in_shards(&self, mut f: impl FnMut(usize, &mut T) -> bool) {
+        let (_layout, shards) = self.tables.enter();
+        shards
+            .iter()
+            .enumerate()
+            .for_each(|(idx, s)| s.write().retain(|t| f(idx, t)))
+    }
+
+    /// Visits every entry mutably, along with the index of its shard.
+    /// Each shard is write-locked in turn.
+    pub(crate) fn for_each_in_shards(&self, mut f: impl FnMut(usize, &mut T)) {
+        let (_layout, shards) = self.tables.enter();
+        shards
+            .iter()
+            .enumerate()
+            .for_each(|(idx, s)| s.write().iter_mut().for_each(|t| f(idx, t)))
+    }
+
+    /// Clears every shard in turn, calling `f` with the index of each shard while it is still locked.
+    pub(crate) fn clear_shards(&self, mut f: impl FnMut(usize)) {
+        let (_layout, shards) = self.tables.enter();
+        shards.iter().enumerate().for_each(|(idx, s)| {
+            let mut shard = s.write();
+            shard.clear();
+            f(idx);
+        })
+    }
+
+    /// Removes all entries from the map, yielding them one at a time.
+    ///
+    /// Only one shard is write-locked at a time, so entries inserted concurrently may or may not be drained.
+    /// If the iterator is dropped early, the remaining entries are still removed.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    pub fn drain(&self) -> Drain<'_, T, L> {
+        Drain::new(self)
+    }
+
+    /// Removes all entries from the map, yielding them one at a time, without taking any locks.
+    ///
+    /// If the iterator is dropped early, the remaining entries are still removed.
+    pub fn drain_mut(&mut self) -> DrainMut<'_, T, L> {
+        DrainMut::new(self)
+    }
+
+    /// Removes the entries for which `pred` returns true, yielding them one at a time.
+    ///
+    /// Only one shard is write-locked at a time. If the iterator is dropped early,
+    /// the entries that have not been visited yet are kept.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    pub fn extract_if<'a>(&'a self, pred: impl FnMut(&mut T) -> bool + 'a) -> ExtractIf<'a, T, L> {
+        ExtractIf::new(self, pred)
+    }
+
+    /// Removes the entries for which `pred` returns true, yielding them one at a time, without taking any locks.
+    ///
+    /// If the iterator is dropped early, the entries that have not been visited yet are kept.
+    pub fn extract_if_mut<'a>(
+        &'a mut self,
+        pred: impl FnMut(&mut T) -> bool + 'a,
+    ) -> ExtractIfMut<'a, T, L> {
+        ExtractIfMut::new(self, pred)
+    }
+
     /// Fetches the total number of key-value pairs stored in the map.
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
     pub fn len(&self) -> usize {
-        self.tables.shards().iter().map(|s| s.read().len()).sum()
+        let (_layout, shards) = self.tables.enter();
+        shards.iter().map(|s| s.read().len()).sum()
     }
 
     /// Checks if the map is empty or not.
@@ -248,11 +618,55 @@ impl<T> ClashTable<T> {
     ///
     /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
     pub fn capacity(&self) -> usize {
-        self.tables
-            .shards()
-            .iter()
-            .map(|s| s.read().capacity())
-            .sum()
+        let (_layout, shards) = self.tables.enter();
+        shards.iter().map(|s| s.read().capacity()).sum()
+    }
+
+    /// Returns the size of every shard, and statistics about how evenly the entries are spread.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    pub fn shard_info(&self) -> ShardInfo {
+        let (_layout, shards) = self.tables.enter();
+        ShardInfo::new(shards.iter().map(|s| {
+            let shard = s.read();
+            (shard.len(), shard.capacity())
+        }))
+    }
+
+    /// Visits up to `max_items` entries from `cursor` on, and returns the cursor to continue from.
+    ///
+    /// `hasher` must return the hash each entry was inserted with.
+    /// See [`ClashMap::scan`](crate::ClashMap::scan).
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding a mutable reference into the map.
+    pub fn scan(
+        &self,
+        mut cursor: Cursor,
+        max_items: usize,
+        hasher: impl Fn(&T) -> u64,
+        mut f: impl FnMut(&T),
+    ) -> Cursor {
+        assert!(max_items > 0, "max_items must be at least 1");
+        let (_layout, shards) = self.tables.enter();
+        cursor.start(shards.len());
+
+        let mut budget = max_items;
+        while budget > 0 && !cursor.is_finished() {
+            let index = cursor.shard();
+            if cursor.needs_hashes() {
+                let hashes = shards[index].read().iter().map(&hasher).collect();
+                cursor.set_hashes(hashes);
+            }
+            let shard = shards[index].read();
+            let force = budget == max_items;
+            let visited = cursor.step(&shard, budget, force, &hasher, &mut f);
+            budget = budget.saturating_sub(visited);
+            if cursor.shard() == index {
+                // The step ran out of budget, or the entries of the next hash did not fit in it.
+                break;
+            }
+        }
+        cursor
     }
 
     /// Advanced entry API that tries to mimic `std::collections::HashMap`.
@@ -281,7 +695,7 @@ impl<T> ClashTable<T> {
         &self,
         hash: u64,
         eq: impl FnMut(&T) -> bool,
-    ) -> Result<OccupiedEntry<'_, T>, AbsentEntry<'_, T>> {
+    ) -> Result<OccupiedEntry<'_, T, L>, AbsentEntry<'_, T, L>> {
         let RefMut { guard, t } = self.tables.get_write_shard(hash);
         match t.find_entry(hash, eq) {
             Ok(occupied_entry) => Ok(OccupiedEntry::new(guard, occupied_entry)),
@@ -289,6 +703,19 @@ impl<T> ClashTable<T> {
         }
     }
 
+    /// Like [`find_entry`](ClashTable::find_entry), but returns `None` if the shard is currently locked.
+    pub fn try_find_entry(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+    ) -> Option<Result<OccupiedEntry<'_, T, L>, AbsentEntry<'_, T, L>>> {
+        let RefMut { guard, t } = self.tables.try_write_shard(hash)?;
+        Some(match t.find_entry(hash, eq) {
+            Ok(occupied_entry) => Ok(OccupiedEntry::new(guard, occupied_entry)),
+            Err(absent_entry) => Err(AbsentEntry::new(guard, absent_entry)),
+        })
+    }
+
     /// Advanced entry API that tries to mimic `std::collections::HashMap`.
     /// See the documentation on `clashmap::mapref::entry` for more details.
     ///
@@ -298,16 +725,8 @@ impl<T> ClashTable<T> {
         hash: u64,
         eq: impl FnMut(&T) -> bool,
         hasher: impl Fn(&T) -> u64,
-    ) -> Entry<'_, T> {
-        let RefMut { guard, t } = self.tables.get_write_shard(hash);
-        match t.entry(hash, eq, hasher) {
-            hash_table::Entry::Occupied(occupied_entry) => {
-                Entry::Occupied(OccupiedEntry::new(guard, occupied_entry))
-            }
-            hash_table::Entry::Vacant(vacant_entry) => {
-                Entry::Vacant(VacantEntry::new(guard, vacant_entry))
-            }
-        }
+    ) -> Entry<'_, T, L> {
+        shard_entry(self.tables.get_write_shard(hash), hash, eq, hasher)
     }
 
     /// Advanced entry API that tries to mimic `std::collections::HashMap`.
@@ -319,16 +738,9 @@ impl<T> ClashTable<T> {
         hash: u64,
         eq: impl FnMut(&T) -> bool,
         hasher: impl Fn(&T) -> u64,
-    ) -> Option<Entry<'_, T>> {
-        let RefMut { guard, t } = self.tables.try_write_shard(hash)?;
-        match t.entry(hash, eq, hasher) {
-            hash_table::Entry::Occupied(occupied_entry) => {
-                Some(Entry::Occupied(OccupiedEntry::new(guard, occupied_entry)))
-            }
-            hash_table::Entry::Vacant(vacant_entry) => {
-                Some(Entry::Vacant(VacantEntry::new(guard, vacant_entry)))
-            }
-        }
+    ) -> Option<Entry<'_, T, L>> {
+        let shard = self.tables.try_write_shard(hash)?;
+        Some(shard_entry(shard, hash, eq, hasher))
     }
 
     /// Advanced entry API that tries to mimic `std::collections::HashMap::try_reserve`.
@@ -344,9 +756,9 @@ impl<T> ClashTable<T> {
         additional: usize,
         hasher: impl Fn(&T) -> u64,
     ) -> Result<(), TryReserveError> {
-        for shard in self.tables.shards().iter() {
+        for shard in self.tables.shards_mut().iter_mut() {
             shard
-                .write()
+                .get_mut()
                 .try_reserve(additional, |t| hasher(t))
                 .map_err(|_| TryReserveError {})?;
         }
@@ -354,7 +766,225 @@ impl<T> ClashTable<T> {
     }
 }
 
-impl<T: fmt::Debug> fmt::Debug for ClashTable<T> {
+impl<T> ClashTable<T> {
+    /// Creates a new ClashTable with a capacity of 0.
+    #[cfg(feature = "std")]
+    pub fn new() -> Self {
+        ClashTable::with_capacity(0)
+    }
+
+    /// Creates a new ClashTable with a specified starting capacity.
+    #[cfg(feature = "std")]
+    pub fn with_capacity(capacity: usize) -> Self {
+        ClashTable::with_capacity_and_shard_amount(capacity, default_shard_amount())
+    }
+
+    /// Creates a new ClashTable with a specified shard amount
+    ///
+    /// shard_amount should greater than 0 and be a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn with_shard_amount(shard_amount: usize) -> Self {
+        Self::with_capacity_and_shard_amount(0, shard_amount)
+    }
+
+    /// Creates a new ClashTable with a specified starting capacity, hasher and shard_amount.
+    ///
+    /// shard_amount should greater than 0 and be a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn with_capacity_and_shard_amount(capacity: usize, shard_amount: usize) -> Self {
+        Self::with_capacity_and_shard_amount_and_lock(capacity, shard_amount)
+    }
+}
+
+impl<T, Sel: ShardSelector> ClashTable<T, RawRwLock, Sel> {
+    /// Creates a new ClashTable with a specified shard amount, using `selector` to choose
+    /// the shard of each hash instead of the [`DefaultShardSelector`].
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// [`DefaultShardSelector`]: crate::shard_selector::DefaultShardSelector
+    pub fn with_shard_selector(shard_amount: usize, selector: Sel) -> Self {
+        Self::with_capacity_and_shard_selector(0, shard_amount, selector)
+    }
+
+    /// Creates a new ClashTable with a specified starting capacity and shard amount, using `selector`
+    /// to choose the shard of each hash instead of the [`DefaultShardSelector`].
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    ///
+    /// [`DefaultShardSelector`]: crate::shard_selector::DefaultShardSelector
+    pub fn with_capacity_and_shard_selector(
+        capacity: usize,
+        shard_amount: usize,
+        selector: Sel,
+    ) -> Self {
+        Self::with_capacity_and_shard_selector_and_lock(capacity, shard_amount, selector)
+    }
+}
+
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> ClashTable<T, L, Sel> {
+    /// Creates a new ClashTable with a specified starting capacity and shard amount, using `selector`
+    /// to choose the shard of each hash, that guards its shards with the lock `L`.
+    ///
+    /// shard_amount should be greater than 1 and a power of two.
+    /// If a shard_amount which is not a power of two is provided, the function will panic.
+    pub fn with_capacity_and_shard_selector_and_lock(
+        capacity: usize,
+        shard_amount: usize,
+        selector: Sel,
+    ) -> Self {
+        Self::build(capacity, shard_amount, selector)
+    }
+}
+
+impl<T, L: RawRwLockAsync, Sel: ShardSelector> ClashTable<T, L, Sel> {
+    /// Get an immutable reference to an entry in the map.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    pub async fn find_async(&self, hash: u64, eq: impl FnMut(&T) -> bool) -> Option<Ref<'_, T, L>> {
+        self.tables
+            .get_read_shard_async(hash)
+            .await
+            .try_map(|shard| shard.find(hash, eq))
+            .ok()
+    }
+
+    /// Get a mutable reference to an entry in the map.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    pub async fn find_mut_async(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+    ) -> Option<RefMut<'_, T, L>> {
+        self.tables
+            .get_write_shard_async(hash)
+            .await
+            .try_map(|shard| find_mut(shard, hash, eq))
+            .ok()
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// If the shard is locked, the returned future waits for it to be released without blocking the thread.
+    pub async fn entry_async(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        hasher: impl Fn(&T) -> u64,
+    ) -> Entry<'_, T, L> {
+        let shard = self.tables.get_write_shard_async(hash).await;
+        shard_entry(shard, hash, eq, hasher)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T, L, Sel: ShardSelector> ClashTable<T, L, Sel>
+where
+    L: lock_api::RawRwLockTimed<Duration = Duration, Instant = Instant>,
+{
+    /// Get an immutable reference to an entry in the map, waiting at most `timeout` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_find_for(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        timeout: Duration,
+    ) -> TryResult<Ref<'_, T, L>> {
+        find_in(self.tables.try_read_shard_for(hash, timeout), hash, eq)
+    }
+
+    /// Get an immutable reference to an entry in the map, waiting until `deadline` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_find_until(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        deadline: Instant,
+    ) -> TryResult<Ref<'_, T, L>> {
+        find_in(self.tables.try_read_shard_until(hash, deadline), hash, eq)
+    }
+
+    /// Get a mutable reference to an entry in the map, waiting at most `timeout` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_find_mut_for(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        timeout: Duration,
+    ) -> TryResult<RefMut<'_, T, L>> {
+        find_mut_in(self.tables.try_write_shard_for(hash, timeout), hash, eq)
+    }
+
+    /// Get a mutable reference to an entry in the map, waiting until `deadline` for the shard to be unlocked.
+    /// If the shard is still locked, the function will return [TryResult::Locked].
+    pub fn try_find_mut_until(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        deadline: Instant,
+    ) -> TryResult<RefMut<'_, T, L>> {
+        find_mut_in(self.tables.try_write_shard_until(hash, deadline), hash, eq)
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// Returns None if the shard is still locked after waiting for `timeout`.
+    pub fn try_entry_for(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        hasher: impl Fn(&T) -> u64,
+        timeout: Duration,
+    ) -> Option<Entry<'_, T, L>> {
+        let shard = self.tables.try_write_shard_for(hash, timeout)?;
+        Some(shard_entry(shard, hash, eq, hasher))
+    }
+
+    /// Advanced entry API that tries to mimic `std::collections::HashMap`.
+    /// See the documentation on `clashmap::mapref::entry` for more details.
+    ///
+    /// Returns None if the shard is still locked at `deadline`.
+    pub fn try_entry_until(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+        hasher: impl Fn(&T) -> u64,
+        deadline: Instant,
+    ) -> Option<Entry<'_, T, L>> {
+        let shard = self.tables.try_write_shard_until(hash, deadline)?;
+        Some(shard_entry(shard, hash, eq, hasher))
+    }
+}
+
+impl<T, L: lock_api::RawRwLockUpgradeDowngrade, Sel: ShardSelector> ClashTable<T, L, Sel> {
+    /// Get an upgradable reference to an entry in the map.
+    ///
+    /// The shard stays readable by other threads, but no other thread can write to it
+    /// until the reference is dropped or upgraded.
+    pub fn find_upgradable(
+        &self,
+        hash: u64,
+        eq: impl FnMut(&T) -> bool,
+    ) -> Option<RefUpgradable<'_, T, L>> {
+        let (shard, guard) = self
+            .tables
+            .lock_shard(hash, |shard| Some((&**shard, shard.upgradable_read())))
+            .unwrap();
+        // SAFETY: The data will not outlive the guard, since we pass the guard to `RefUpgradable`.
+        let (guard, table) = unsafe { RwLockUpgradableReadGuardDetached::detach_from(guard) };
+        let t = table.find(hash, eq)?;
+        Some(RefUpgradable::new(guard, shard, hash, t))
+    }
+}
+
+impl<T: fmt::Debug, L: lock_api::RawRwLock, Sel: ShardSelector> fmt::Debug
+    for ClashTable<T, L, Sel>
+{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut pmap = f.debug_list();
         self.for_each(|t| {
@@ -364,20 +994,20 @@ impl<T: fmt::Debug> fmt::Debug for ClashTable<T> {
     }
 }
 
-impl<T> IntoIterator for ClashTable<T> {
+impl<T, L: lock_api::RawRwLock, Sel> IntoIterator for ClashTable<T, L, Sel> {
     type Item = T;
 
-    type IntoIter = OwningIter<T>;
+    type IntoIter = OwningIter<T, L>;
 
     fn into_iter(self) -> Self::IntoIter {
         OwningIter::new(self)
     }
 }
 
-impl<'a, T> IntoIterator for &'a ClashTable<T> {
-    type Item = RefMulti<'a, T>;
+impl<'a, T, L: lock_api::RawRwLock, Sel: ShardSelector> IntoIterator for &'a ClashTable<T, L, Sel> {
+    type Item = RefMulti<'a, T, L>;
 
-    type IntoIter = Iter<'a, T>;
+    type IntoIter = Iter<'a, T, L>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.iter()
@@ -385,13 +1015,13 @@ impl<'a, T> IntoIterator for &'a ClashTable<T> {
 }
 
 #[cfg(feature = "typesize")]
-impl<T> typesize::TypeSize for ClashTable<T>
+impl<T, L: lock_api::RawRwLock, Sel: ShardSelector> typesize::TypeSize for ClashTable<T, L, Sel>
 where
     T: typesize::TypeSize,
 {
     fn extra_size(&self) -> usize {
-        self.tables
-            .shards()
+        let (_layout, shards) = self.tables.enter();
+        shards
             .iter()
             .map(|shard_lock| {
                 let shard = shard_lock.read();
@@ -400,7 +1030,7 @@ where
 
                 let entry_size_iter = shard.iter().map(|entry| entry.extra_size());
 
-                core::mem::size_of::<CachePadded<RwLock<HashTable<T>>>>()
+                core::mem::size_of::<Shard<T, L>>()
                     + hashtable_size
                     + entry_size_iter.sum::<usize>()
             })
@@ -1,15 +1,15 @@
 use hashbrown::hash_table;
 
 use super::one::RefMut;
-use crate::lock::RwLockWriteGuardDetached;
+use crate::lock::{RawRwLock, RwLockWriteGuardDetached};
 use core::mem;
 
-pub enum Entry<'a, T> {
-    Occupied(OccupiedEntry<'a, T>),
-    Vacant(VacantEntry<'a, T>),
+pub enum Entry<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    Occupied(OccupiedEntry<'a, T, L>),
+    Vacant(VacantEntry<'a, T, L>),
 }
 
-impl<'a, T> Entry<'a, T> {
+impl<'a, T, L: lock_api::RawRwLock> Entry<'a, T, L> {
     /// Apply a function to the stored value if it exists.
     pub fn and_modify(self, f: impl FnOnce(&mut T)) -> Self {
         match self {
@@ -25,7 +25,7 @@ impl<'a, T> Entry<'a, T> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise insert the default and return a mutable reference to that.
-    pub fn or_default(self) -> RefMut<'a, T>
+    pub fn or_default(self) -> RefMut<'a, T, L>
     where
         T: Default,
     {
@@ -37,7 +37,7 @@ impl<'a, T> Entry<'a, T> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise a provided value and return a mutable reference to that.
-    pub fn or_insert(self, value: T) -> RefMut<'a, T> {
+    pub fn or_insert(self, value: T) -> RefMut<'a, T, L> {
         match self {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => entry.insert(value),
@@ -46,7 +46,7 @@ impl<'a, T> Entry<'a, T> {
 
     /// Return a mutable reference to the element if it exists,
     /// otherwise insert the result of a provided function and return a mutable reference to that.
-    pub fn or_insert_with(self, value: impl FnOnce() -> T) -> RefMut<'a, T> {
+    pub fn or_insert_with(self, value: impl FnOnce() -> T) -> RefMut<'a, T, L> {
         match self {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => entry.insert(value()),
@@ -56,7 +56,7 @@ impl<'a, T> Entry<'a, T> {
     pub fn or_try_insert_with<E>(
         self,
         value: impl FnOnce() -> Result<T, E>,
-    ) -> Result<RefMut<'a, T>, E> {
+    ) -> Result<RefMut<'a, T, L>, E> {
         match self {
             Entry::Occupied(entry) => Ok(entry.into_mut()),
             Entry::Vacant(entry) => Ok(entry.insert(value()?)),
@@ -64,7 +64,7 @@ impl<'a, T> Entry<'a, T> {
     }
 
     /// Sets the value of the entry, and returns a reference to the inserted value.
-    pub fn insert(self, value: T) -> RefMut<'a, T> {
+    pub fn insert(self, value: T) -> RefMut<'a, T, L> {
         match self {
             Entry::Occupied(mut entry) => {
                 entry.insert(value);
@@ -80,7 +80,7 @@ impl<'a, T> Entry<'a, T> {
     /// consider [`insert`] as it doesn't need to clone the key.
     ///
     /// [`insert`]: Entry::insert
-    pub fn insert_entry(self, value: T) -> OccupiedEntry<'a, T> {
+    pub fn insert_entry(self, value: T) -> OccupiedEntry<'a, T, L> {
         match self {
             Entry::Occupied(mut entry) => {
                 entry.insert(value);
@@ -91,14 +91,14 @@ impl<'a, T> Entry<'a, T> {
     }
 }
 
-pub struct AbsentEntry<'a, T> {
-    _guard: RwLockWriteGuardDetached<'a>,
+pub struct AbsentEntry<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    _guard: RwLockWriteGuardDetached<'a, L>,
     _entry: hash_table::AbsentEntry<'a, T>,
 }
 
-impl<'a, T> AbsentEntry<'a, T> {
+impl<'a, T, L: lock_api::RawRwLock> AbsentEntry<'a, T, L> {
     pub(crate) fn new(
-        guard: RwLockWriteGuardDetached<'a>,
+        guard: RwLockWriteGuardDetached<'a, L>,
         entry: hash_table::AbsentEntry<'a, T>,
     ) -> Self {
         Self {
@@ -108,39 +108,39 @@ impl<'a, T> AbsentEntry<'a, T> {
     }
 }
 
-pub struct VacantEntry<'a, T> {
-    guard: RwLockWriteGuardDetached<'a>,
+pub struct VacantEntry<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    guard: RwLockWriteGuardDetached<'a, L>,
     entry: hash_table::VacantEntry<'a, T>,
 }
 
-impl<'a, T> VacantEntry<'a, T> {
+impl<'a, T, L: lock_api::RawRwLock> VacantEntry<'a, T, L> {
     pub(crate) fn new(
-        guard: RwLockWriteGuardDetached<'a>,
+        guard: RwLockWriteGuardDetached<'a, L>,
         entry: hash_table::VacantEntry<'a, T>,
     ) -> Self {
         Self { guard, entry }
     }
 
-    pub fn insert(self, value: T) -> RefMut<'a, T> {
+    pub fn insert(self, value: T) -> RefMut<'a, T, L> {
         let occupied = self.entry.insert(value);
 
         RefMut::new(self.guard, occupied.into_mut())
     }
 
     /// Sets the value of the entry with the VacantEntry’s key, and returns an OccupiedEntry.
-    pub fn insert_entry(self, value: T) -> OccupiedEntry<'a, T> {
+    pub fn insert_entry(self, value: T) -> OccupiedEntry<'a, T, L> {
         OccupiedEntry::new(self.guard, self.entry.insert(value))
     }
 }
 
-pub struct OccupiedEntry<'a, T> {
-    guard: RwLockWriteGuardDetached<'a>,
+pub struct OccupiedEntry<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    guard: RwLockWriteGuardDetached<'a, L>,
     entry: hash_table::OccupiedEntry<'a, T>,
 }
 
-impl<'a, T> OccupiedEntry<'a, T> {
+impl<'a, T, L: lock_api::RawRwLock> OccupiedEntry<'a, T, L> {
     pub(crate) fn new(
-        guard: RwLockWriteGuardDetached<'a>,
+        guard: RwLockWriteGuardDetached<'a, L>,
         entry: hash_table::OccupiedEntry<'a, T>,
     ) -> Self {
         Self { guard, entry }
@@ -158,7 +158,7 @@ impl<'a, T> OccupiedEntry<'a, T> {
         mem::replace(self.get_mut(), value)
     }
 
-    pub fn into_mut(self) -> RefMut<'a, T> {
+    pub fn into_mut(self) -> RefMut<'a, T, L> {
         RefMut::new(self.guard, self.entry.into_mut())
     }
 
@@ -167,6 +167,14 @@ impl<'a, T> OccupiedEntry<'a, T> {
         t
     }
 
+    /// Takes the value out of the entry, and returns it along with a vacant entry in its place.
+    ///
+    /// The shard stays locked until the returned vacant entry is dropped.
+    pub fn remove_entry(self) -> (T, VacantEntry<'a, T, L>) {
+        let (t, entry) = self.entry.remove();
+        (t, VacantEntry::new(self.guard, entry))
+    }
+
     pub fn replace_entry(self, value: T) -> T {
         let t = mem::replace(self.entry.into_mut(), value);
         t
@@ -2,21 +2,25 @@ use crossbeam_utils::CachePadded;
 use hashbrown::HashTable;
 
 use super::multiple::{RefMulti, RefMutMulti};
-use crate::lock::{RwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::layout::LayoutGuard;
+use crate::lock::{RawRwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
 use crate::table::ClashTable;
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use core::cell::RefCell;
 use core::slice;
-use std::sync::Arc;
 
 /// Iterator over a ClashTable.
-pub struct OwningIter<T> {
-    shards: std::vec::IntoIter<CachePadded<RwLock<HashTable<T>>>>,
+pub struct OwningIter<T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: alloc::vec::IntoIter<CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
     current: Option<GuardOwningIter<T>>,
 }
 
-impl<T> OwningIter<T> {
-    pub(crate) fn new(map: ClashTable<T>) -> Self {
+impl<T, L: lock_api::RawRwLock> OwningIter<T, L> {
+    pub(crate) fn new<Sel>(map: ClashTable<T, L, Sel>) -> Self {
         Self {
-            shards: map.tables.shards.into_vec().into_iter(),
+            shards: map.tables.into_shards().into_vec().into_iter(),
             current: None,
         }
     }
@@ -24,7 +28,7 @@ impl<T> OwningIter<T> {
 
 type GuardOwningIter<T> = hashbrown::hash_table::IntoIter<T>;
 
-impl<T> Iterator for OwningIter<T> {
+impl<T, L: lock_api::RawRwLock> Iterator for OwningIter<T, L> {
     type Item = T;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -43,42 +47,47 @@ impl<T> Iterator for OwningIter<T> {
     }
 }
 
-type GuardIter<'a, T> = (
-    Arc<RwLockReadGuardDetached<'a>>,
+type GuardIter<'a, T, L> = (
+    Arc<RwLockReadGuardDetached<'a, L>>,
     hashbrown::hash_table::Iter<'a, T>,
 );
 
-type GuardIterMut<'a, T> = (
-    Arc<RwLockWriteGuardDetached<'a>>,
+type GuardIterMut<'a, T, L> = (
+    Arc<RwLockWriteGuardDetached<'a, L>>,
     hashbrown::hash_table::IterMut<'a, T>,
 );
 
 /// Iterator over a ClashTable yielding immutable references.
-pub struct Iter<'a, T> {
-    shards: slice::Iter<'a, CachePadded<RwLock<HashTable<T>>>>,
-    current: Option<GuardIter<'a, T>>,
+pub struct Iter<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::Iter<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    current: Option<GuardIter<'a, T, L>>,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
 }
 
-impl<T> Clone for Iter<'_, T> {
+impl<T, L: lock_api::RawRwLock> Clone for Iter<'_, T, L> {
     fn clone(&self) -> Self {
         Self {
             shards: self.shards.clone(),
             current: self.current.clone(),
+            _layout: self._layout.clone(),
         }
     }
 }
 
-impl<'a, T: 'a> Iter<'a, T> {
-    pub(crate) fn new(map: &'a ClashTable<T>) -> Self {
+impl<'a, T: 'a, L: lock_api::RawRwLock> Iter<'a, T, L> {
+    pub(crate) fn new<Sel>(map: &'a ClashTable<T, L, Sel>) -> Self {
+        let (layout, shards) = map.tables.enter();
         Self {
-            shards: map.tables.shards.iter(),
+            shards: shards.iter(),
             current: None,
+            _layout: layout,
         }
     }
 }
 
-impl<'a, T: 'a> Iterator for Iter<'a, T> {
-    type Item = RefMulti<'a, T>;
+impl<'a, T: 'a, L: lock_api::RawRwLock> Iterator for Iter<'a, T, L> {
+    type Item = RefMulti<'a, T, L>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -100,22 +109,26 @@ impl<'a, T: 'a> Iterator for Iter<'a, T> {
 }
 
 /// Iterator over a ClashTable yielding mutable references.
-pub struct IterMut<'a, T> {
-    shards: slice::Iter<'a, CachePadded<RwLock<HashTable<T>>>>,
-    current: Option<GuardIterMut<'a, T>>,
+pub struct IterMut<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::Iter<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    current: Option<GuardIterMut<'a, T, L>>,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
 }
 
-impl<'a, T: 'a> IterMut<'a, T> {
-    pub(crate) fn new(map: &'a ClashTable<T>) -> Self {
+impl<'a, T: 'a, L: lock_api::RawRwLock> IterMut<'a, T, L> {
+    pub(crate) fn new<Sel>(map: &'a ClashTable<T, L, Sel>) -> Self {
+        let (layout, shards) = map.tables.enter();
         Self {
-            shards: map.tables.shards.iter(),
+            shards: shards.iter(),
             current: None,
+            _layout: layout,
         }
     }
 }
 
-impl<'a, T: 'a> Iterator for IterMut<'a, T> {
-    type Item = RefMutMulti<'a, T>;
+impl<'a, T: 'a, L: lock_api::RawRwLock> Iterator for IterMut<'a, T, L> {
+    type Item = RefMutMulti<'a, T, L>;
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
@@ -136,6 +149,246 @@ impl<'a, T: 'a> Iterator for IterMut<'a, T> {
     }
 }
 
+/// Draining iterator over a ClashTable, removing every entry as it is yielded.
+///
+/// Only one shard is write-locked at a time. If the iterator is dropped early,
+/// the remaining entries are still removed.
+pub struct Drain<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::Iter<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    // The drain must be dropped before the guard of its shard.
+    current: Option<(
+        hashbrown::hash_table::Drain<'a, T>,
+        RwLockWriteGuardDetached<'a, L>,
+    )>,
+    // Index of the shard being visited.
+    shard: usize,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
+}
+
+impl<'a, T, L: lock_api::RawRwLock> Drain<'a, T, L> {
+    pub(crate) fn new<Sel>(map: &'a ClashTable<T, L, Sel>) -> Self {
+        let (layout, shards) = map.tables.enter();
+        Self {
+            shards: shards.iter(),
+            current: None,
+            shard: usize::MAX,
+            _layout: layout,
+        }
+    }
+
+    /// Returns the index of the shard that the last yielded entry was removed from.
+    pub(crate) fn shard(&self) -> usize {
+        self.shard
+    }
+}
+
+impl<T, L: lock_api::RawRwLock> Iterator for Drain<'_, T, L> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(t) = current.0.next() {
+                    return Some(t);
+                }
+            }
+
+            // release the previous shard before locking the next one
+            self.current = None;
+            let guard = self.shards.next()?.write();
+            self.shard = self.shard.wrapping_add(1);
+
+            // SAFETY: we keep the guard alive for as long as the drain borrowing the shard.
+            let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(guard) };
+            self.current = Some((shard.drain(), guard));
+        }
+    }
+}
+
+impl<T, L: lock_api::RawRwLock> Drop for Drain<'_, T, L> {
+    fn drop(&mut self) {
+        self.current = None;
+        for shard in self.shards.by_ref() {
+            shard.write().clear();
+        }
+    }
+}
+
+/// Draining iterator over a ClashTable that is borrowed mutably, so it takes no locks.
+///
+/// If the iterator is dropped early, the remaining entries are still removed.
+pub struct DrainMut<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::IterMut<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    current: Option<hashbrown::hash_table::Drain<'a, T>>,
+    // Index of the shard being visited.
+    shard: usize,
+}
+
+impl<'a, T, L: lock_api::RawRwLock> DrainMut<'a, T, L> {
+    pub(crate) fn new<Sel>(map: &'a mut ClashTable<T, L, Sel>) -> Self {
+        Self {
+            shards: map.tables.shards_mut().iter_mut(),
+            current: None,
+            shard: usize::MAX,
+        }
+    }
+
+    /// Returns the index of the shard that the last yielded entry was removed from.
+    pub(crate) fn shard(&self) -> usize {
+        self.shard
+    }
+}
+
+impl<T, L: lock_api::RawRwLock> Iterator for DrainMut<'_, T, L> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(t) = current.next() {
+                    return Some(t);
+                }
+            }
+
+            self.current = Some(self.shards.next()?.get_mut().drain());
+            self.shard = self.shard.wrapping_add(1);
+        }
+    }
+}
+
+impl<T, L: lock_api::RawRwLock> Drop for DrainMut<'_, T, L> {
+    fn drop(&mut self) {
+        self.current = None;
+        for shard in self.shards.by_ref() {
+            shard.get_mut().clear();
+        }
+    }
+}
+
+/// The predicate of an [`ExtractIf`], shared by the extracting iterators of each shard.
+type SharedPredicate<'a, T> = Rc<RefCell<dyn FnMut(&mut T) -> bool + 'a>>;
+
+type ShardPredicate<'a, T> = Box<dyn FnMut(&mut T) -> bool + 'a>;
+
+type GuardExtractIf<'a, T, L> = (
+    hashbrown::hash_table::ExtractIf<'a, T, ShardPredicate<'a, T>>,
+    RwLockWriteGuardDetached<'a, L>,
+);
+
+fn shard_predicate<'a, T: 'a>(pred: &SharedPredicate<'a, T>) -> ShardPredicate<'a, T> {
+    let pred = pred.clone();
+    Box::new(move |t| (pred.borrow_mut())(t))
+}
+
+/// Iterator over a ClashTable that removes and yields the entries matching a predicate.
+///
+/// Only one shard is write-locked at a time. If the iterator is dropped early,
+/// the entries that have not been visited yet are kept.
+pub struct ExtractIf<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::Iter<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    pred: SharedPredicate<'a, T>,
+    // The extractor must be dropped before the guard of its shard.
+    current: Option<GuardExtractIf<'a, T, L>>,
+    // Index of the shard being visited.
+    shard: usize,
+    // Keeps the entries from moving to another layout while the shards are visited.
+    _layout: LayoutGuard<'a>,
+}
+
+impl<'a, T: 'a, L: lock_api::RawRwLock> ExtractIf<'a, T, L> {
+    pub(crate) fn new<Sel>(
+        map: &'a ClashTable<T, L, Sel>,
+        pred: impl FnMut(&mut T) -> bool + 'a,
+    ) -> Self {
+        let (layout, shards) = map.tables.enter();
+        Self {
+            shards: shards.iter(),
+            pred: Rc::new(RefCell::new(pred)),
+            current: None,
+            shard: usize::MAX,
+            _layout: layout,
+        }
+    }
+
+    /// Returns the index of the shard that the last yielded entry was removed from.
+    pub(crate) fn shard(&self) -> usize {
+        self.shard
+    }
+}
+
+impl<'a, T: 'a, L: lock_api::RawRwLock> Iterator for ExtractIf<'a, T, L> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(t) = current.0.next() {
+                    return Some(t);
+                }
+            }
+
+            // release the previous shard before locking the next one
+            self.current = None;
+            let guard = self.shards.next()?.write();
+            self.shard = self.shard.wrapping_add(1);
+
+            // SAFETY: we keep the guard alive for as long as the extractor borrowing the shard.
+            let (guard, shard) = unsafe { RwLockWriteGuardDetached::detach_from(guard) };
+            self.current = Some((shard.extract_if(shard_predicate(&self.pred)), guard));
+        }
+    }
+}
+
+/// Iterator over a ClashTable that is borrowed mutably, which removes and yields
+/// the entries matching a predicate without taking any locks.
+///
+/// If the iterator is dropped early, the entries that have not been visited yet are kept.
+pub struct ExtractIfMut<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    shards: slice::IterMut<'a, CachePadded<lock_api::RwLock<L, HashTable<T>>>>,
+    pred: SharedPredicate<'a, T>,
+    current: Option<hashbrown::hash_table::ExtractIf<'a, T, ShardPredicate<'a, T>>>,
+    // Index of the shard being visited.
+    shard: usize,
+}
+
+impl<'a, T: 'a, L: lock_api::RawRwLock> ExtractIfMut<'a, T, L> {
+    pub(crate) fn new<Sel>(
+        map: &'a mut ClashTable<T, L, Sel>,
+        pred: impl FnMut(&mut T) -> bool + 'a,
+    ) -> Self {
+        Self {
+            shards: map.tables.shards_mut().iter_mut(),
+            pred: Rc::new(RefCell::new(pred)),
+            current: None,
+            shard: usize::MAX,
+        }
+    }
+
+    /// Returns the index of the shard that the last yielded entry was removed from.
+    pub(crate) fn shard(&self) -> usize {
+        self.shard
+    }
+}
+
+impl<'a, T: 'a, L: lock_api::RawRwLock> Iterator for ExtractIfMut<'a, T, L> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(t) = current.next() {
+                    return Some(t);
+                }
+            }
+
+            let shard = self.shards.next()?.get_mut();
+            self.current = Some(shard.extract_if(shard_predicate(&self.pred)));
+            self.shard = self.shard.wrapping_add(1);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::hash_map::RandomState;
@@ -165,7 +418,7 @@ mod tests {
 
         let mut c = 0;
 
-        for shard in map.tables.shards.iter() {
+        for shard in map.tables.shards().iter() {
             c += shard.write().iter().count();
         }
 
@@ -248,4 +501,33 @@ mod tests {
         assert_eq!(iter.count(), 1);
         assert_eq!(iter2.count(), 1);
     }
+
+    #[test]
+    fn drain_and_extract_if() {
+        let mut map = ClashTable::with_shard_amount(4);
+        let hasher = RandomState::new();
+
+        for i in 0..32 {
+            map.entry(hash_one(&hasher, i), |&t| t == i, |t| hash_one(&hasher, t))
+                .or_insert(i);
+        }
+
+        let mut even: Vec<_> = map.extract_if(|t| *t % 2 == 0).collect();
+        even.sort();
+        assert_eq!(even, (0..32).step_by(2).collect::<Vec<_>>());
+        assert_eq!(map.len(), 16);
+
+        let mut three: Vec<_> = map.extract_if_mut(|t| *t % 3 == 0).collect();
+        three.sort();
+        assert_eq!(three, vec![3, 9, 15, 21, 27]);
+
+        // dropping the drain early still empties the table
+        assert!(map.drain().next().is_some());
+        assert!(map.is_empty());
+
+        map.entry(hash_one(&hasher, 1), |&t| t == 1, |t| hash_one(&hasher, t))
+            .or_insert(1);
+        assert_eq!(map.drain_mut().collect::<Vec<_>>(), vec![1]);
+        assert!(map.is_empty());
+    }
 }
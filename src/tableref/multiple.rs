@@ -1,13 +1,13 @@
-use crate::lock::{RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::lock::{RawRwLock, RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use alloc::sync::Arc;
 use core::ops::{Deref, DerefMut};
-use std::sync::Arc;
 
-pub struct RefMulti<'a, T> {
-    _guard: Arc<RwLockReadGuardDetached<'a>>,
+pub struct RefMulti<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    _guard: Arc<RwLockReadGuardDetached<'a, L>>,
     t: &'a T,
 }
 
-impl<T> Clone for RefMulti<'_, T> {
+impl<T, L: lock_api::RawRwLock> Clone for RefMulti<'_, T, L> {
     fn clone(&self) -> Self {
         Self {
             _guard: self._guard.clone(),
@@ -16,8 +16,8 @@ impl<T> Clone for RefMulti<'_, T> {
     }
 }
 
-impl<'a, T> RefMulti<'a, T> {
-    pub(crate) fn new(guard: Arc<RwLockReadGuardDetached<'a>>, v: &'a T) -> Self {
+impl<'a, T, L: lock_api::RawRwLock> RefMulti<'a, T, L> {
+    pub(crate) fn new(guard: Arc<RwLockReadGuardDetached<'a, L>>, v: &'a T) -> Self {
         Self {
             _guard: guard,
             t: v,
@@ -29,7 +29,7 @@ impl<'a, T> RefMulti<'a, T> {
     }
 }
 
-impl<T> Deref for RefMulti<'_, T> {
+impl<T, L: lock_api::RawRwLock> Deref for RefMulti<'_, T, L> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -37,13 +37,13 @@ impl<T> Deref for RefMulti<'_, T> {
     }
 }
 
-pub struct RefMutMulti<'a, T> {
-    _guard: Arc<RwLockWriteGuardDetached<'a>>,
+pub struct RefMutMulti<'a, T, L: lock_api::RawRwLock = RawRwLock> {
+    _guard: Arc<RwLockWriteGuardDetached<'a, L>>,
     t: &'a mut T,
 }
 
-impl<'a, T> RefMutMulti<'a, T> {
-    pub(crate) fn new(guard: Arc<RwLockWriteGuardDetached<'a>>, t: &'a mut T) -> Self {
+impl<'a, T, L: lock_api::RawRwLock> RefMutMulti<'a, T, L> {
+    pub(crate) fn new(guard: Arc<RwLockWriteGuardDetached<'a, L>>, t: &'a mut T) -> Self {
         Self { _guard: guard, t }
     }
 
@@ -56,7 +56,7 @@ impl<'a, T> RefMutMulti<'a, T> {
     }
 }
 
-impl<T> Deref for RefMutMulti<'_, T> {
+impl<T, L: lock_api::RawRwLock> Deref for RefMutMulti<'_, T, L> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -64,7 +64,7 @@ impl<T> Deref for RefMutMulti<'_, T> {
     }
 }
 
-impl<T> DerefMut for RefMutMulti<'_, T> {
+impl<T, L: lock_api::RawRwLock> DerefMut for RefMutMulti<'_, T, L> {
     fn deref_mut(&mut self) -> &mut T {
         self.value_mut()
     }
@@ -1,18 +1,21 @@
-use crate::lock::{RwLockReadGuardDetached, RwLockWriteGuardDetached};
+use crate::lock::{
+    RawRwLock, RwLockReadGuardDetached, RwLockUpgradableReadGuardDetached, RwLockWriteGuardDetached,
+};
 use crate::util::try_map;
+use core::fmt::{Debug, Formatter};
 use core::ops::{Deref, DerefMut};
-use std::fmt::{Debug, Formatter};
+use hashbrown::HashTable;
 
-pub struct Ref<'a, T: ?Sized> {
-    pub(crate) _guard: RwLockReadGuardDetached<'a>,
+pub struct Ref<'a, T: ?Sized, L: lock_api::RawRwLock = RawRwLock> {
+    pub(crate) _guard: RwLockReadGuardDetached<'a, L>,
     pub(crate) t: &'a T,
 }
 
 /// Kept for backwards compatiblity.
-pub type MappedRef<'a, T> = Ref<'a, T>;
+pub type MappedRef<'a, T, L = RawRwLock> = Ref<'a, T, L>;
 
-impl<'a, T: ?Sized> Ref<'a, T> {
-    pub(crate) fn new(guard: RwLockReadGuardDetached<'a>, t: &'a T) -> Self {
+impl<'a, T: ?Sized, L: lock_api::RawRwLock> Ref<'a, T, L> {
+    pub(crate) fn new(guard: RwLockReadGuardDetached<'a, L>, t: &'a T) -> Self {
         Self { _guard: guard, t }
     }
 
@@ -20,7 +23,7 @@ impl<'a, T: ?Sized> Ref<'a, T> {
         self.t
     }
 
-    pub fn map<F, U: ?Sized>(self, f: F) -> MappedRef<'a, U>
+    pub fn map<F, U: ?Sized>(self, f: F) -> MappedRef<'a, U, L>
     where
         F: FnOnce(&T) -> &U,
     {
@@ -30,7 +33,7 @@ impl<'a, T: ?Sized> Ref<'a, T> {
         }
     }
 
-    pub fn try_map<F, U: ?Sized>(self, f: F) -> Result<MappedRef<'a, U>, Self>
+    pub fn try_map<F, U: ?Sized>(self, f: F) -> Result<MappedRef<'a, U, L>, Self>
     where
         F: FnOnce(&T) -> Option<&U>,
     {
@@ -45,13 +48,13 @@ impl<'a, T: ?Sized> Ref<'a, T> {
     }
 }
 
-impl<T: Debug> Debug for Ref<'_, T> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<T: Debug, L: lock_api::RawRwLock> Debug for Ref<'_, T, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         self.t.fmt(f)
     }
 }
 
-impl<T> Deref for Ref<'_, T> {
+impl<T, L: lock_api::RawRwLock> Deref for Ref<'_, T, L> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -59,28 +62,30 @@ impl<T> Deref for Ref<'_, T> {
     }
 }
 
-impl<T: std::fmt::Display + ?Sized> std::fmt::Display for Ref<'_, T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Display::fmt(self.value(), f)
+impl<T: core::fmt::Display + ?Sized, L: lock_api::RawRwLock> core::fmt::Display for Ref<'_, T, L> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self.value(), f)
     }
 }
 
-impl<T: AsRef<TDeref> + ?Sized, TDeref: ?Sized> AsRef<TDeref> for Ref<'_, T> {
+impl<T: AsRef<TDeref> + ?Sized, TDeref: ?Sized, L: lock_api::RawRwLock> AsRef<TDeref>
+    for Ref<'_, T, L>
+{
     fn as_ref(&self) -> &TDeref {
         self.value().as_ref()
     }
 }
 
-pub struct RefMut<'a, T: ?Sized> {
-    pub(crate) guard: RwLockWriteGuardDetached<'a>,
+pub struct RefMut<'a, T: ?Sized, L: lock_api::RawRwLock = RawRwLock> {
+    pub(crate) guard: RwLockWriteGuardDetached<'a, L>,
     pub(crate) t: &'a mut T,
 }
 
 /// Kept for backwards compatiblity.
-pub type MappedRefMut<'a, T> = RefMut<'a, T>;
+pub type MappedRefMut<'a, T, L = RawRwLock> = RefMut<'a, T, L>;
 
-impl<'a, T: ?Sized> RefMut<'a, T> {
-    pub(crate) fn new(guard: RwLockWriteGuardDetached<'a>, t: &'a mut T) -> Self {
+impl<'a, T: ?Sized, L: lock_api::RawRwLock> RefMut<'a, T, L> {
+    pub(crate) fn new(guard: RwLockWriteGuardDetached<'a, L>, t: &'a mut T) -> Self {
         Self { guard, t }
     }
 
@@ -92,15 +97,7 @@ impl<'a, T: ?Sized> RefMut<'a, T> {
         self.t
     }
 
-    pub fn downgrade(self) -> Ref<'a, T> {
-        Ref::new(
-            // SAFETY: `Ref` will prevent writes to the data.
-            unsafe { RwLockWriteGuardDetached::downgrade(self.guard) },
-            self.t,
-        )
-    }
-
-    pub fn map<F, U: ?Sized>(self, f: F) -> MappedRefMut<'a, U>
+    pub fn map<F, U: ?Sized>(self, f: F) -> MappedRefMut<'a, U, L>
     where
         F: FnOnce(&mut T) -> &mut U,
     {
@@ -110,7 +107,7 @@ impl<'a, T: ?Sized> RefMut<'a, T> {
         }
     }
 
-    pub fn try_map<F, U: 'a + ?Sized>(self, f: F) -> Result<MappedRefMut<'a, U>, Self>
+    pub fn try_map<F, U: 'a + ?Sized>(self, f: F) -> Result<MappedRefMut<'a, U, L>, Self>
     where
         F: FnOnce(&mut T) -> Option<&mut U>,
     {
@@ -122,13 +119,23 @@ impl<'a, T: ?Sized> RefMut<'a, T> {
     }
 }
 
-impl<T: Debug + ?Sized> Debug for RefMut<'_, T> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+impl<'a, T: ?Sized, L: lock_api::RawRwLockDowngrade> RefMut<'a, T, L> {
+    pub fn downgrade(self) -> Ref<'a, T, L> {
+        Ref::new(
+            // SAFETY: `Ref` will prevent writes to the data.
+            unsafe { RwLockWriteGuardDetached::downgrade(self.guard) },
+            self.t,
+        )
+    }
+}
+
+impl<T: Debug + ?Sized, L: lock_api::RawRwLock> Debug for RefMut<'_, T, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         self.t.fmt(f)
     }
 }
 
-impl<T: ?Sized> Deref for RefMut<'_, T> {
+impl<T: ?Sized, L: lock_api::RawRwLock> Deref for RefMut<'_, T, L> {
     type Target = T;
 
     fn deref(&self) -> &T {
@@ -136,12 +143,108 @@ impl<T: ?Sized> Deref for RefMut<'_, T> {
     }
 }
 
-impl<T: ?Sized> DerefMut for RefMut<'_, T> {
+impl<T: ?Sized, L: lock_api::RawRwLock> DerefMut for RefMut<'_, T, L> {
     fn deref_mut(&mut self) -> &mut T {
         self.value_mut()
     }
 }
 
+pub struct RefUpgradable<'a, T, L: lock_api::RawRwLockUpgrade = RawRwLock> {
+    guard: RwLockUpgradableReadGuardDetached<'a, L>,
+    shard: &'a lock_api::RwLock<L, HashTable<T>>,
+    hash: u64,
+    t: &'a T,
+}
+
+impl<'a, T, L: lock_api::RawRwLockUpgradeDowngrade> RefUpgradable<'a, T, L> {
+    pub(crate) fn new(
+        guard: RwLockUpgradableReadGuardDetached<'a, L>,
+        shard: &'a lock_api::RwLock<L, HashTable<T>>,
+        hash: u64,
+        t: &'a T,
+    ) -> Self {
+        Self {
+            guard,
+            shard,
+            hash,
+            t,
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        self.t
+    }
+
+    /// Blocks until all other readers of the shard have left, then returns a mutable reference to the entry.
+    ///
+    /// No writer can modify the entry in the meantime.
+    pub fn upgrade(self) -> RefMut<'a, T, L> {
+        let Self {
+            guard,
+            shard,
+            hash,
+            t,
+        } = self;
+        upgraded(guard.upgrade(), shard, hash, t as *const T)
+    }
+
+    /// Returns a mutable reference to the entry if there are no other readers of the shard.
+    pub fn try_upgrade(self) -> Result<RefMut<'a, T, L>, Self> {
+        let Self {
+            guard,
+            shard,
+            hash,
+            t,
+        } = self;
+        match guard.try_upgrade() {
+            Ok(guard) => Ok(upgraded(guard, shard, hash, t as *const T)),
+            Err(guard) => Err(Self {
+                guard,
+                shard,
+                hash,
+                t,
+            }),
+        }
+    }
+
+    /// Gives up the ability to upgrade, allowing other threads to take an upgradable reference to the shard.
+    pub fn downgrade(self) -> Ref<'a, T, L> {
+        Ref::new(self.guard.downgrade(), self.t)
+    }
+}
+
+/// Finds `target` again through the now exclusively locked shard, to get a mutable reference to it.
+///
+/// Takes a pointer rather than a reference, so no shared reference to the entry is live
+/// while the mutable one is created.
+fn upgraded<'a, T, L: lock_api::RawRwLock>(
+    guard: RwLockWriteGuardDetached<'a, L>,
+    shard: &'a lock_api::RwLock<L, HashTable<T>>,
+    hash: u64,
+    target: *const T,
+) -> RefMut<'a, T, L> {
+    // SAFETY: We hold the exclusive lock, and the data will not outlive the guard, since we pass the guard to `RefMut`.
+    let table = unsafe { &mut *shard.data_ptr() };
+    match table.find_mut(hash, |t| core::ptr::eq(t, target)) {
+        Some(t) => RefMut::new(guard, t),
+        None => unreachable!("entries cannot move while the shard is locked"),
+    }
+}
+
+impl<T: Debug, L: lock_api::RawRwLockUpgrade> Debug for RefUpgradable<'_, T, L> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        self.t.fmt(f)
+    }
+}
+
+impl<T, L: lock_api::RawRwLockUpgrade> Deref for RefUpgradable<'_, T, L> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.t
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::hash_map::RandomState;
@@ -0,0 +1,38 @@
+//! Fixtures shared by the unit tests.
+
+use core::hash::{BuildHasherDefault, Hasher};
+
+/// The 64-bit FNV-1a hash, for tests that need the same hashes on every run.
+pub(crate) struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// A hasher that gives every key the same hash, so that they all end up in the same shard.
+#[derive(Default)]
+pub(crate) struct ConstantHasher;
+
+impl Hasher for ConstantHasher {
+    fn finish(&self) -> u64 {
+        0
+    }
+
+    fn write(&mut self, _: &[u8]) {}
+}
+
+pub(crate) type BuildConstantHasher = BuildHasherDefault<ConstantHasher>;
@@ -0,0 +1,394 @@
+//! Atomic operations over a fixed set of keys.
+//!
+//! See [`ClashMap::transaction`] and [`ClashMap::transaction_with`].
+
+use crate::lock::RawRwLock;
+use crate::shard_selector::{DefaultShardSelector, ShardSelector};
+use crate::{ClashMap, HashMap};
+use alloc::vec::Vec;
+use core::hash::{BuildHasher, Hash, Hasher};
+use hashbrown::{hash_table, Equivalent};
+use lock_api::RwLockWriteGuard;
+
+type ShardGuard<'a, K, V, L> = RwLockWriteGuard<'a, L, HashMap<K, V>>;
+type ShardGuards<'a, K, V, L> = Vec<(usize, ShardGuard<'a, K, V, L>)>;
+
+/// A view into a [`ClashMap`] restricted to the keys declared when the transaction was started.
+///
+/// All shards holding those keys stay write-locked for the lifetime of the transaction,
+/// so no other thread can observe the changes until the transaction completes.
+///
+/// # Panics
+///
+/// Every method panics if called with a key whose hash is not the hash of any key that was
+/// declared when starting the transaction. Only the hashes are compared, so a key that was
+/// not declared but has the same hash as a declared key is accepted. It is stored in a shard
+/// that the transaction already holds, so accessing it is still atomic.
+pub struct Transaction<'a, K, V, S, L: lock_api::RawRwLock = RawRwLock, Sel = DefaultShardSelector>
+{
+    map: &'a ClashMap<K, V, S, L, Sel>,
+    hashes: Vec<u64>,
+    shards: ShardGuards<'a, K, V, L>,
+}
+
+impl<'a, K, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>
+    Transaction<'a, K, V, S, L, Sel>
+{
+    fn hash_u64<T: Hash + ?Sized>(&self, item: &T) -> u64 {
+        let mut hasher = self.map.hasher.build_hasher();
+        item.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn shard_index(&self, hash: u64) -> usize {
+        assert!(
+            self.hashes.contains(&hash),
+            "key was not declared when starting the transaction"
+        );
+
+        let idx = self.map.table.tables._determine_shard(hash as usize);
+        match self.shards.iter().position(|(i, _)| *i == idx) {
+            Some(pos) => pos,
+            None => unreachable!("shard of a declared key is always locked"),
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let pos = self.shard_index(hash);
+        self.shards[pos]
+            .1
+            .find(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Returns a mutable reference to the value corresponding to the key.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let pos = self.shard_index(hash);
+        self.shards[pos]
+            .1
+            .find_mut(hash, |(k, _v)| key.equivalent(k))
+            .map(|(_k, v)| v)
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a key and a value into the map. Returns the old value associated with the key if there was one.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>
+    where
+        K: Eq + Hash,
+    {
+        let hash = self.hash_u64(&key);
+        let pos = self.shard_index(hash);
+        let hasher = &self.map.hasher;
+        let (idx, shard) = &mut self.shards[pos];
+        let notifier = self.map.notifier(*idx);
+        match shard.entry(
+            hash,
+            |(k, _v)| k == &key,
+            |(k, _v)| {
+                let mut hasher = hasher.build_hasher();
+                k.hash(&mut hasher);
+                hasher.finish()
+            },
+        ) {
+            hash_table::Entry::Occupied(mut entry) => {
+                let old = core::mem::replace(&mut entry.get_mut().1, value);
+                let (k, v) = entry.get();
+                notifier.overwritten(k, v);
+                Some(old)
+            }
+            hash_table::Entry::Vacant(entry) => {
+                let entry = entry.insert((key, value));
+                let (k, v) = entry.get();
+                notifier.inserted(k, v);
+                None
+            }
+        }
+    }
+
+    /// Removes an entry from the map, returning the key and value if they existed in the map.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hash = self.hash_u64(key);
+        let pos = self.shard_index(hash);
+        let (idx, shard) = &mut self.shards[pos];
+        match shard.find_entry(hash, |(k, _v)| key.equivalent(k)) {
+            Ok(entry) => {
+                let (k, v) = entry.remove().0;
+                self.map.notifier(*idx).removed(&k, &v);
+                Some((k, v))
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// A shard that needs to be locked by a transaction, and which map it belongs to.
+struct LockRequest<'a, T, L> {
+    lock: &'a lock_api::RwLock<L, T>,
+    map: usize,
+    idx: usize,
+}
+
+/// Locks the shards of all the requested maps for the given hashes.
+/// `hashes[i]` holds the hashes of the keys requested from `maps[i]`.
+///
+/// The shards are found by index, so the caller keeps the entries of every map from moving to another layout.
+///
+/// Shards are always locked in order of their address. Shards of a single map are laid out
+/// in index order, so this agrees with the locking order of all other multi-shard operations,
+/// and two transactions can never deadlock with each other, even when they span several maps.
+fn lock_shards<'a, K, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector>(
+    maps: &[&'a ClashMap<K, V, S, L, Sel>],
+    hashes: &[&[u64]],
+) -> Vec<ShardGuards<'a, K, V, L>> {
+    let mut requests = Vec::new();
+    for (map_idx, (map, hashes)) in maps.iter().zip(hashes).enumerate() {
+        let shards = map.table.tables.shards();
+        for &hash in hashes.iter() {
+            let idx = map.table.tables._determine_shard(hash as usize);
+            requests.push(LockRequest {
+                lock: &*shards[idx],
+                map: map_idx,
+                idx,
+            });
+        }
+    }
+
+    requests.sort_unstable_by_key(|r| r.lock as *const lock_api::RwLock<L, _> as usize);
+    requests.dedup_by_key(|r| r.lock as *const lock_api::RwLock<L, _> as usize);
+
+    let mut guards: Vec<_> = maps.iter().map(|_| Vec::new()).collect();
+    for r in requests {
+        guards[r.map].push((r.idx, r.lock.write()));
+    }
+    guards
+}
+
+impl<K, V, S: BuildHasher, L: lock_api::RawRwLock, Sel: ShardSelector> ClashMap<K, V, S, L, Sel> {
+    /// Runs a closure with exclusive access to a fixed set of keys.
+    ///
+    /// The write locks for every shard holding one of `keys` are taken up front in a
+    /// deadlock-free order, and held until the closure returns. No other thread can observe
+    /// the map in a state where only part of the closure's changes have been applied.
+    ///
+    /// The closure may only access the declared keys. Accessing any other key will panic,
+    /// unless it happens to have the same hash as a declared key. See [`Transaction`] for details.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into the map.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let balances = ClashMap::new();
+    /// balances.insert("Alice", 100);
+    /// balances.insert("Bob", 50);
+    ///
+    /// balances.transaction(&["Alice", "Bob"], |tx| {
+    ///     *tx.get_mut("Alice").unwrap() -= 30;
+    ///     *tx.get_mut("Bob").unwrap() += 30;
+    /// });
+    ///
+    /// assert_eq!(*balances.get("Alice").unwrap(), 70);
+    /// assert_eq!(*balances.get("Bob").unwrap(), 80);
+    /// ```
+    pub fn transaction<Q, R>(
+        &self,
+        keys: &[&Q],
+        f: impl FnOnce(&mut Transaction<'_, K, V, S, L, Sel>) -> R,
+    ) -> R
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        let hashes: Vec<u64> = keys.iter().map(|key| self.hash_u64(key)).collect();
+        let _layout = self.table.tables.gate.enter();
+        let mut guards = lock_shards(&[self], &[&hashes]);
+
+        let mut tx = Transaction {
+            map: self,
+            hashes,
+            shards: guards.pop().unwrap_or_default(),
+        };
+        f(&mut tx)
+    }
+
+    /// Runs a closure with exclusive access to a fixed set of keys in two different maps.
+    ///
+    /// This behaves like [`transaction`](ClashMap::transaction), but locks the shards of both maps
+    /// before running the closure, which allows entries to be moved between maps atomically.
+    ///
+    /// **Locking behaviour:** May deadlock if called when holding any sort of reference into either map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self` and `other` are the same map, as its shards would be locked twice.
+    /// Use [`transaction`](ClashMap::transaction) with both sets of keys instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use clashmap::ClashMap;
+    ///
+    /// let pending = ClashMap::new();
+    /// let done = ClashMap::with_hasher(pending.hasher().clone());
+    /// pending.insert(1, "write docs");
+    ///
+    /// pending.transaction_with(&[&1], &done, &[&1], |pending, done| {
+    ///     if let Some((id, task)) = pending.remove(&1) {
+    ///         done.insert(id, task);
+    ///     }
+    /// });
+    ///
+    /// assert!(!pending.contains_key(&1));
+    /// assert_eq!(*done.get(&1).unwrap(), "write docs");
+    /// ```
+    pub fn transaction_with<Q, R>(
+        &self,
+        keys: &[&Q],
+        other: &Self,
+        other_keys: &[&Q],
+        f: impl FnOnce(
+            &mut Transaction<'_, K, V, S, L, Sel>,
+            &mut Transaction<'_, K, V, S, L, Sel>,
+        ) -> R,
+    ) -> R
+    where
+        Q: Hash + Equivalent<K> + ?Sized,
+    {
+        assert!(
+            !core::ptr::eq(self, other),
+            "transaction_with called with the same map twice"
+        );
+
+        let hashes: Vec<u64> = keys.iter().map(|key| self.hash_u64(key)).collect();
+        let other_hashes: Vec<u64> = other_keys.iter().map(|key| other.hash_u64(key)).collect();
+        let _layout = self.table.tables.gate.enter();
+        let _other_layout = other.table.tables.gate.enter();
+        let mut guards = lock_shards(&[self, other], &[&hashes, &other_hashes]);
+
+        let mut other_tx = Transaction {
+            map: other,
+            hashes: other_hashes,
+            shards: guards.pop().unwrap_or_default(),
+        };
+        let mut tx = Transaction {
+            map: self,
+            hashes,
+            shards: guards.pop().unwrap_or_default(),
+        };
+        f(&mut tx, &mut other_tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::BuildConstantHasher;
+    use crate::ClashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_transfer_is_atomic() {
+        let map = Arc::new(ClashMap::with_shard_amount(4));
+        for i in 0..16 {
+            map.insert(i, 100);
+        }
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = map.clone();
+                s.spawn(move || {
+                    for n in 0..1000 {
+                        let from = (t * 7 + n) % 16;
+                        let to = (from + 1 + n % 15) % 16;
+                        map.transaction(&[&from, &to], |tx| {
+                            *tx.get_mut(&from).unwrap() -= 1;
+                            *tx.get_mut(&to).unwrap() += 1;
+                        });
+                    }
+                });
+            }
+
+            let keys: Vec<i32> = (0..16).collect();
+            let keys: Vec<&i32> = keys.iter().collect();
+            for _ in 0..100 {
+                let total = map.transaction(&keys, |tx| {
+                    keys.iter().map(|k| *tx.get(*k).unwrap()).sum::<i32>()
+                });
+                assert_eq!(total, 1600);
+            }
+        });
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let map = ClashMap::new();
+        map.insert(1, 1);
+
+        let old = map.transaction(&[&1, &2], |tx| {
+            assert!(tx.insert(2, 2).is_none());
+            tx.remove(&1)
+        });
+
+        assert_eq!(old, Some((1, 1)));
+        assert!(!map.contains_key(&1));
+        assert_eq!(*map.get(&2).unwrap(), 2);
+    }
+
+    #[test]
+    #[should_panic = "not declared"]
+    fn test_undeclared_key() {
+        let map = ClashMap::<i32, i32>::new();
+        map.transaction(&[&1], |tx| tx.insert(2, 2));
+    }
+
+    #[test]
+    fn test_colliding_key_is_accepted() {
+        let map = ClashMap::with_hasher(BuildConstantHasher::default());
+        map.insert(1, 1);
+
+        map.transaction(&[&1], |tx| assert!(tx.insert(2, 2).is_none()));
+        assert_eq!(*map.get(&2).unwrap(), 2);
+    }
+
+    #[test]
+    #[should_panic = "same map twice"]
+    fn test_same_map_twice() {
+        let map = ClashMap::<i32, i32>::new();
+        map.transaction_with(&[&1], &map, &[&2], |_, _| ());
+    }
+
+    #[test]
+    fn test_move_between_maps() {
+        let a = ClashMap::new();
+        let b = ClashMap::with_hasher(a.hasher().clone());
+        a.insert("key", 1);
+
+        a.transaction_with(&["key"], &b, &["key"], |a, b| {
+            let (k, v) = a.remove("key").unwrap();
+            b.insert(k, v);
+        });
+
+        assert!(a.is_empty());
+        assert_eq!(*b.get("key").unwrap(), 1);
+    }
+}
@@ -1,8 +1,11 @@
 //! Clever hacks
 
-use std::{marker::PhantomData, mem::ManuallyDrop};
+use core::{marker::PhantomData, mem::ManuallyDrop};
 
-use lock_api::{RawRwLock, RawRwLockDowngrade, RwLockReadGuard, RwLockWriteGuard};
+use lock_api::{
+    RawRwLock, RawRwLockDowngrade, RawRwLockUpgrade, RawRwLockUpgradeDowngrade, RwLockReadGuard,
+    RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
 
 pub(crate) fn try_map<F, T: ?Sized, U: ?Sized>(mut t: &mut T, f: F) -> Result<&mut U, &mut T>
 where
@@ -17,6 +20,24 @@ where
     Err(t)
 }
 
+/// Replaces `*dest` with `f(*dest)`, aborting the process if `f` panics.
+///
+/// Without `std` the process cannot be aborted directly, so this panics again instead,
+/// which aborts as the first panic is still unwinding.
+pub(crate) fn replace_with_or_abort<T, F: FnOnce(T) -> T>(dest: &mut T, f: F) {
+    #[cfg(feature = "std")]
+    replace_with::replace_with_or_abort(dest, f);
+    #[cfg(not(feature = "std"))]
+    replace_with::replace_with(dest, || panic!("panicked while replacing a value"), f);
+}
+
+/// Runs `f`, aborting the process if it panics, like [`replace_with_or_abort`].
+pub(crate) fn abort_on_panic<R, F: FnOnce() -> R>(f: F) -> R {
+    let mut r = None;
+    replace_with_or_abort(&mut r, |_| Some(f()));
+    r.unwrap()
+}
+
 /// A [`RwLockReadGuard`], without the data
 pub(crate) struct RwLockReadGuardDetached<'a, R: RawRwLock> {
     lock: &'a R,
@@ -47,7 +68,34 @@ impl<R: RawRwLock> Drop for RwLockWriteGuardDetached<'_, R> {
     }
 }
 
+/// A [`RwLockUpgradableReadGuard`], without the data
+pub(crate) struct RwLockUpgradableReadGuardDetached<'a, R: RawRwLockUpgrade> {
+    lock: &'a R,
+    _marker: PhantomData<R::GuardMarker>,
+}
+
+impl<R: RawRwLockUpgrade> Drop for RwLockUpgradableReadGuardDetached<'_, R> {
+    fn drop(&mut self) {
+        // Safety: An RwLockUpgradableReadGuardDetached always holds an upgradable lock.
+        unsafe {
+            self.lock.unlock_upgradable();
+        }
+    }
+}
+
 impl<'a, R: RawRwLock> RwLockReadGuardDetached<'a, R> {
+    /// Takes ownership of a shared lock that was acquired through the raw lock
+    ///
+    /// # Safety
+    ///
+    /// The shared lock must be held by the caller, and must not be released other than by this guard
+    pub(crate) unsafe fn from_raw(lock: &'a R) -> Self {
+        RwLockReadGuardDetached {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+
     /// Separates the data from the [`RwLockReadGuard`]
     ///
     /// # Safety
@@ -67,9 +115,26 @@ impl<'a, R: RawRwLock> RwLockReadGuardDetached<'a, R> {
         };
         (guard, data)
     }
+
+    /// Gives up the guard without releasing the shared lock, which the caller then owns
+    pub(crate) fn into_raw(self) -> &'a R {
+        ManuallyDrop::new(self).lock
+    }
 }
 
 impl<'a, R: RawRwLock> RwLockWriteGuardDetached<'a, R> {
+    /// Takes ownership of an exclusive lock that was acquired through the raw lock
+    ///
+    /// # Safety
+    ///
+    /// The exclusive lock must be held by the caller, and must not be released other than by this guard
+    pub(crate) unsafe fn from_raw(lock: &'a R) -> Self {
+        RwLockWriteGuardDetached {
+            lock,
+            _marker: PhantomData,
+        }
+    }
+
     /// Separates the data from the [`RwLockWriteGuard`]
     ///
     /// # Safety
@@ -89,6 +154,11 @@ impl<'a, R: RawRwLock> RwLockWriteGuardDetached<'a, R> {
         };
         (guard, data)
     }
+
+    /// Gives up the guard without releasing the exclusive lock, which the caller then owns
+    pub(crate) fn into_raw(self) -> &'a R {
+        ManuallyDrop::new(self).lock
+    }
 }
 
 impl<'a, R: RawRwLockDowngrade> RwLockWriteGuardDetached<'a, R> {
@@ -108,3 +178,70 @@ impl<'a, R: RawRwLockDowngrade> RwLockWriteGuardDetached<'a, R> {
         }
     }
 }
+
+impl<'a, R: RawRwLockUpgrade> RwLockUpgradableReadGuardDetached<'a, R> {
+    /// Separates the data from the [`RwLockUpgradableReadGuard`]
+    ///
+    /// # Safety
+    ///
+    /// The data must not outlive the detached guard
+    pub(crate) unsafe fn detach_from<T>(
+        guard: RwLockUpgradableReadGuard<'a, R, T>,
+    ) -> (Self, &'a T) {
+        let rwlock = RwLockUpgradableReadGuard::rwlock(&ManuallyDrop::new(guard));
+
+        // Safety: There will be no concurrent writes as we are "forgetting" the existing guard,
+        // with the safety assumption that the caller will not drop the new detached guard early.
+        let data = unsafe { &*rwlock.data_ptr() };
+        let guard = RwLockUpgradableReadGuardDetached {
+            // Safety: We are imitating the original RwLockUpgradableReadGuard. It's the callers
+            // responsibility to not drop the guard early.
+            lock: unsafe { rwlock.raw() },
+            _marker: PhantomData,
+        };
+        (guard, data)
+    }
+
+    /// Blocks until all other readers have left, then turns the upgradable lock into an exclusive lock.
+    pub(crate) fn upgrade(self) -> RwLockWriteGuardDetached<'a, R> {
+        // Do not drop the upgradable guard - otherwise we will trigger an upgrade + unlock_upgradable,
+        // which is incorrect
+        let this = ManuallyDrop::new(self);
+
+        // Safety: An RwLockUpgradableReadGuardDetached always holds an upgradable lock.
+        unsafe { this.lock.upgrade() }
+        RwLockWriteGuardDetached {
+            lock: this.lock,
+            _marker: this._marker,
+        }
+    }
+
+    /// Turns the upgradable lock into an exclusive lock, if there are no other readers.
+    pub(crate) fn try_upgrade(self) -> Result<RwLockWriteGuardDetached<'a, R>, Self> {
+        // Safety: An RwLockUpgradableReadGuardDetached always holds an upgradable lock.
+        if unsafe { self.lock.try_upgrade() } {
+            let this = ManuallyDrop::new(self);
+            Ok(RwLockWriteGuardDetached {
+                lock: this.lock,
+                _marker: this._marker,
+            })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<'a, R: RawRwLockUpgradeDowngrade> RwLockUpgradableReadGuardDetached<'a, R> {
+    pub(crate) fn downgrade(self) -> RwLockReadGuardDetached<'a, R> {
+        // Do not drop the upgradable guard - otherwise we will trigger a downgrade + unlock_upgradable,
+        // which is incorrect
+        let this = ManuallyDrop::new(self);
+
+        // Safety: An RwLockUpgradableReadGuardDetached always holds an upgradable lock.
+        unsafe { this.lock.downgrade_upgradable() }
+        RwLockReadGuardDetached {
+            lock: this.lock,
+            _marker: this._marker,
+        }
+    }
+}
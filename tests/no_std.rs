@@ -0,0 +1,104 @@
+//! Tests that only use the API available without the `std` feature.
+//!
+//! CI runs them with `cargo test --no-default-features --test no_std`.
+
+use clashmap::{ClashMap, ClashSet};
+use core::hash::{BuildHasherDefault, Hasher};
+use std::sync::Arc;
+use std::thread;
+
+/// The 64-bit FNV-1a hash, as `RandomState` needs `std`.
+struct FnvHasher(u64);
+
+impl Default for FnvHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FnvHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+type BuildFnvHasher = BuildHasherDefault<FnvHasher>;
+
+fn new_map<K: Eq + core::hash::Hash, V>() -> ClashMap<K, V, BuildFnvHasher> {
+    ClashMap::with_hasher_and_shard_amount(BuildFnvHasher::default(), 4)
+}
+
+#[test]
+fn test_map_basic() {
+    let map = new_map();
+
+    map.insert(1, "one");
+    map.insert(2, "two");
+
+    assert_eq!(map.len(), 2);
+    assert_eq!(*map.get(&1).unwrap(), "one");
+
+    *map.get_mut(&2).unwrap() = "deux";
+    assert_eq!(map.remove(&2), Some((2, "deux")));
+    assert!(!map.contains_key(&2));
+}
+
+#[test]
+fn test_set_basic() {
+    let set = ClashSet::with_hasher_and_shard_amount(BuildFnvHasher::default(), 4);
+
+    assert!(set.insert(1));
+    assert!(!set.insert(1));
+    assert!(set.contains(&1));
+    assert_eq!(set.remove(&1), Some(1));
+    assert!(set.is_empty());
+}
+
+#[test]
+fn test_read_only_round_trip() {
+    let map = new_map();
+
+    for i in 0..100 {
+        map.insert(i, i * 2);
+    }
+
+    let view = map.into_read_only();
+
+    assert_eq!(view.len(), 100);
+    assert_eq!(view.get(&21), Some(&42));
+
+    let map = view.into_inner();
+
+    map.insert(100, 200);
+    assert_eq!(map.len(), 101);
+}
+
+#[test]
+fn test_concurrent_inserts() {
+    // Without `std` the shards use the spinning lock.
+    let map = Arc::new(new_map());
+
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let map = Arc::clone(&map);
+
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    map.insert(t * 1000 + i, i);
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(map.len(), 4000);
+}